@@ -0,0 +1,261 @@
+//! NixOS release branches like `nixos-25.05`, for the `nixos-stable` target alias and warning
+//! about releases past their end of life.
+
+use std::{fmt, time::SystemTime};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+
+use crate::command;
+
+/// The target resolving to the newest NixOS release branch.
+pub const STABLE_ALIAS: &str = "nixos-stable";
+
+const NIXPKGS_GIT_URL: &str = "https://github.com/NixOS/nixpkgs.git";
+
+/// A NixOS release, named after the year and month it's released in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Release {
+    /// The last two digits of the year.
+    pub year: u8,
+    pub month: u8,
+}
+
+impl Release {
+    /// Parses a release branch name like `nixos-25.05` or `nixpkgs-25.05-darwin`.
+    ///
+    /// Variants like `nixos-25.05-small` are parsed as their release.
+    #[must_use]
+    pub fn from_branch(branch: &str) -> Option<Self> {
+        let version = branch
+            .strip_prefix("nixos-")
+            .or_else(|| branch.strip_prefix("nixpkgs-"))?;
+        let version = version
+            .split_once('-')
+            .map_or(version, |(version, _)| version);
+        let (year, month) = version.split_once('.')?;
+        if year.len() != 2 || month.len() != 2 {
+            return None;
+        }
+        Some(Self {
+            year: year.parse().ok()?,
+            month: month.parse().ok()?,
+        })
+    }
+
+    /// Returns the release that would come out in the current month.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the system clock is set before 1970.
+    pub fn current() -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .wrap_err("The system clock is before 1970")?;
+        Ok(Self::at(now.as_secs()))
+    }
+
+    /// Returns the release that would come out in the month of the Unix timestamp `secs`, in UTC.
+    #[must_use]
+    pub const fn at(secs: u64) -> Self {
+        // Howard Hinnant's `civil_from_days`, with days counted from 0000-03-01 so leap days
+        // are at the end of the year
+        let days = secs / 86_400 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_based_month = (5 * day_of_year + 2) / 153;
+        let (month, year_offset) = if march_based_month < 10 {
+            (march_based_month + 3, 0)
+        } else {
+            (march_based_month - 9, 1)
+        };
+        let year = era * 400 + year_of_era + year_offset;
+        #[expect(clippy::cast_possible_truncation, reason = "Both are less than 100")]
+        Self {
+            year: (year % 100) as u8,
+            month: month as u8,
+        }
+    }
+
+    /// Returns the last month the release is supported in. Releases are supported until a month
+    /// after the next release, which comes six months later.
+    #[must_use]
+    pub const fn end_of_support(self) -> Self {
+        let months = self.month + 7;
+        if months > 12 {
+            Self {
+                year: self.year + 1,
+                month: months - 12,
+            }
+        } else {
+            Self {
+                year: self.year,
+                month: months,
+            }
+        }
+    }
+
+    /// Returns whether the release is no longer supported in the month `now`.
+    #[must_use]
+    pub fn is_end_of_life(self, now: Self) -> bool {
+        self.end_of_support() < now
+    }
+
+    /// Returns the branch `branch` would be for `release`, keeping prefixes and suffixes like
+    /// `nixpkgs-` and `-darwin`.
+    #[must_use]
+    pub fn retarget_branch(self, branch: &str, release: Self) -> String {
+        branch.replacen(&self.to_string(), &release.to_string(), 1)
+    }
+
+    /// Returns the name of the NixOS release branch.
+    #[must_use]
+    pub fn branch(self) -> String {
+        format!("nixos-{self}")
+    }
+}
+
+impl fmt::Display for Release {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}.{:02}", self.year, self.month)
+    }
+}
+
+/// Lists the NixOS release branches of nixpkgs with `git ls-remote`.
+///
+/// # Errors
+///
+/// Fails if `git ls-remote` can't be run or fails.
+pub fn list_releases() -> Result<Vec<Release>> {
+    let output = command::output_with_retries(
+        "git",
+        &[
+            "ls-remote",
+            "--heads",
+            "--",
+            NIXPKGS_GIT_URL,
+            "refs/heads/nixos-*",
+        ],
+        None,
+    )?;
+    if !output.status.success() {
+        bail!(
+            "Failed to list the branches of nixpkgs: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once("\trefs/heads/"))
+        .filter_map(|(_, branch)| Release::from_branch(branch))
+        .collect())
+}
+
+/// Returns the newest release out by `now`. Release branches are created a few weeks before
+/// the release, so a branch of a later month is still a beta.
+#[must_use]
+pub fn newest_released(releases: &[Release], now: Release) -> Option<Release> {
+    releases
+        .iter()
+        .copied()
+        .filter(|release| *release <= now)
+        .max()
+}
+
+/// Returns the newest NixOS release, listing the branches of nixpkgs.
+///
+/// # Errors
+///
+/// Fails if the branches can't be listed or there are no release branches.
+pub fn stable() -> Result<Release> {
+    newest_released(&list_releases()?, Release::current()?)
+        .ok_or_eyre("nixpkgs has no NixOS release branches")
+}
+
+/// Resolves [`STABLE_ALIAS`] to the newest NixOS release branch of nixpkgs.
+///
+/// Returns `None` for other targets.
+///
+/// # Errors
+///
+/// Fails if the target is the alias and the release branches can't be listed.
+pub fn resolve_alias(target: &str) -> Result<Option<String>> {
+    if target != STABLE_ALIAS {
+        return Ok(None);
+    }
+    let release = stable()?;
+    tracing::info!(%release, "Resolved {STABLE_ALIAS}");
+    Ok(Some(format!("github:NixOS/nixpkgs/{}", release.branch())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newest_released_branch() {
+        let releases: Vec<_> = [
+            "nixos-24.11",
+            "nixos-25.05-small",
+            "nixos-25.05",
+            "nixos-25.11",
+        ]
+        .into_iter()
+        .filter_map(Release::from_branch)
+        .collect();
+        assert_eq!(releases.len(), 4);
+        let may = Release { year: 25, month: 5 };
+        assert_eq!(newest_released(&releases, may), Some(may));
+        let november = Release {
+            year: 25,
+            month: 11,
+        };
+        assert_eq!(newest_released(&releases, november), Some(november));
+        assert_eq!(Release::from_branch("nixos-unstable"), None);
+        assert_eq!(may.branch(), "nixos-25.05");
+    }
+
+    #[test]
+    fn release_at_timestamp() {
+        // 2025-05-31T23:59:59Z
+        assert_eq!(Release::at(1_748_735_999), Release { year: 25, month: 5 });
+        // 2025-06-01T00:00:00Z
+        assert_eq!(Release::at(1_748_736_000), Release { year: 25, month: 6 });
+        // 2024-02-29T12:00:00Z
+        assert_eq!(Release::at(1_709_208_000), Release { year: 24, month: 2 });
+        // 2000-12-31T00:00:00Z
+        assert_eq!(Release::at(978_220_800), Release { year: 0, month: 12 });
+        assert_eq!(Release::at(0), Release { year: 70, month: 1 });
+    }
+
+    #[test]
+    fn end_of_life() {
+        let may = Release { year: 25, month: 5 };
+        let november = Release {
+            year: 25,
+            month: 11,
+        };
+        assert_eq!(
+            may.end_of_support(),
+            Release {
+                year: 25,
+                month: 12
+            }
+        );
+        assert_eq!(november.end_of_support(), Release { year: 26, month: 6 });
+        assert!(!may.is_end_of_life(Release {
+            year: 25,
+            month: 12
+        }));
+        assert!(may.is_end_of_life(Release { year: 26, month: 1 }));
+        assert_eq!(
+            may.retarget_branch("nixpkgs-25.05-darwin", november),
+            "nixpkgs-25.11-darwin"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Running commands without being interrupted by <kbd>Ctrl</kbd>+<kbd>C</kbd>, which only
+//! interrupts the command.
+//!
+//! Nix commands that use the network are killed after a timeout and retried after transient
+//! failures, see [`init_retries`].
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    process::{Command, Output, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use color_eyre::{Result, eyre::bail};
+
+use crate::sigint_guard;
+
+static RETRIES: OnceLock<Retries> = OnceLock::new();
+
+/// Messages in the standard error of Nix and curl for failures worth retrying.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "Could not resolve host",
+    "Couldn't connect to server",
+    "Connection reset by peer",
+    "Connection timed out",
+    "Timeout was reached",
+    "SSL connect error",
+    "HTTP error 429",
+    "HTTP error 5",
+];
+
+/// The delay before the first retry, doubled for each one after it.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How commands using the network are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct Retries {
+    /// How long an attempt may run before it's killed, or `None` to wait indefinitely.
+    pub timeout: Option<Duration>,
+    /// How many times a command is retried after timing out or failing transiently.
+    pub retries: u32,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(10 * 60)),
+            retries: 2,
+        }
+    }
+}
+
+/// Sets how commands using the network are retried. Defaults to [`Retries::default`] if not
+/// called.
+pub fn init_retries(retries: Retries) {
+    let _ = RETRIES.set(retries);
+}
+
+/// Runs the given command like [`cmd_output`], but with a timeout and retries as set with
+/// [`init_retries`]. Its standard error is captured so that transient failures can be detected.
+///
+/// Returns the output of the last attempt, or an error naming the command if it timed out.
+///
+/// # Errors
+///
+/// Fails if the command can't be started or its last attempt timed out.
+pub fn output_with_retries(program: &str, args: &[&str], dir: Option<&Path>) -> Result<Output> {
+    retrying(program, args, dir, None::<&fn(&[u8])>)
+}
+
+/// Runs the given command like [`output_with_retries`], but passes its standard error to `tee`
+/// as it's written, like Nix's download progress or a prompt to trust a flake's `nixConfig`.
+///
+/// Only the last line of standard error is kept in the output, which is enough to detect
+/// transient failures.
+///
+/// # Errors
+///
+/// Fails like [`output_with_retries`].
+pub fn output_with_retries_teeing(
+    program: &str,
+    args: &[&str],
+    dir: Option<&Path>,
+    tee: impl FnMut(&[u8]) + Clone + Send + 'static,
+) -> Result<Output> {
+    retrying(program, args, dir, Some(&tee))
+}
+
+fn retrying(
+    program: &str,
+    args: &[&str],
+    dir: Option<&Path>,
+    tee: Option<&(impl FnMut(&[u8]) + Clone + Send + 'static)>,
+) -> Result<Output> {
+    let retries = RETRIES.get_or_init(Retries::default);
+    let command_line = || format!("`{program} {}`", args.join(" "));
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let _span = tracing::debug_span!("command", program, ?args, ?dir, attempt).entered();
+        let mut command = Command::new(program);
+        command.args(args).stderr(Stdio::piped());
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+
+        let output = match tee {
+            Some(tee) => {
+                sigint_guard::output_teeing_stderr(&mut command, retries.timeout, tee.clone())
+            }
+            None => sigint_guard::output_with_timeout(&mut command, retries.timeout),
+        };
+        let failure = match output {
+            Ok(output) => {
+                tracing::debug!(status = %output.status, "Command exited");
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if output.status.success() || !is_transient(&stderr) || attempt >= retries.retries {
+                    return Ok(output);
+                }
+                stderr.lines().last().unwrap_or_default().to_owned()
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                let timeout = humantime::format_duration(retries.timeout.unwrap_or_default());
+                if attempt >= retries.retries {
+                    bail!(
+                        "{} timed out after {timeout} {} times. Check your network connection",
+                        command_line(),
+                        attempt + 1
+                    );
+                }
+                format!("Timed out after {timeout}")
+            }
+            Err(err) => return Err(err.into()),
+        };
+        tracing::warn!(
+            "{} failed, retrying in {}: {failure}",
+            command_line(),
+            humantime::format_duration(backoff)
+        );
+        std::thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Runs the given command with [`output_with_retries_teeing`], writing its standard error as
+/// it runs, and returns whether it was successful.
+///
+/// # Errors
+///
+/// Fails if the command can't be started or timed out.
+pub fn run_with_retries(program: &str, args: &[&str], dir: &Path) -> Result<bool> {
+    let output = output_with_retries_teeing(program, args, Some(dir), |chunk| {
+        let _ = io::stderr().write_all(chunk);
+    })?;
+    Ok(output.status.success())
+}
+
+/// Returns whether the standard error of a failed command indicates a failure worth retrying.
+pub(crate) fn is_transient(stderr: &str) -> bool {
+    TRANSIENT_ERRORS
+        .iter()
+        .any(|message| stderr.contains(message))
+}
+
+/// Runs the given command and returns whether it was successful.
+///
+/// # Errors
+///
+/// Fails if the command can't be started.
+pub fn run_cmd(program: &str, args: &[&str], dir: &Path) -> Result<bool> {
+    let _span = tracing::debug_span!("command", program, ?args, dir = %dir.display()).entered();
+    let status = sigint_guard::status(Command::new(program).args(args).current_dir(dir))?;
+    tracing::debug!(%status, "Command exited");
+    Ok(status.success())
+}
+
+/// Runs the given command and returns its standard output if it was successful.
+///
+/// # Errors
+///
+/// Fails if the command can't be started or its output isn't UTF-8.
+pub fn cmd_output(program: &str, args: &[&str], dir: &Path) -> Result<Option<String>> {
+    let _span = tracing::debug_span!("command", program, ?args, dir = %dir.display()).entered();
+    let output = sigint_guard::output(
+        Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .stderr(Stdio::inherit()),
+    )?;
+    tracing::debug!(status = %output.status, "Command exited");
+
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8(output.stdout))
+        .transpose()?)
+}
@@ -0,0 +1,175 @@
+//! User configuration in `$XDG_CONFIG_HOME/nixpkgsupd/config.toml`.
+//!
+//! Command line flags take precedence over the configuration.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt},
+};
+use fs_err as fs;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::system::SystemKind;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Default for `--commit-message-template`.
+    pub commit_message_template: Option<String>,
+    /// Default for `--commit-type`.
+    pub commit_type: Option<String>,
+    /// Default for `--commit-scope`.
+    pub commit_scope: Option<String>,
+    /// Default for `--pre-hook`.
+    pub pre_hook: Option<String>,
+    /// Default for `--post-hook`.
+    pub post_hook: Option<String>,
+    /// Default for `--elevate-with`.
+    pub elevate_with: Option<String>,
+    /// Default for `--editor`.
+    pub editor: Option<String>,
+    /// Regular expressions matching the names of build result links besides `result`, `result-*`,
+    /// `result.drv` and `outputs`.
+    #[serde(default)]
+    gcroot_patterns: Vec<String>,
+    /// Settings for single flakes, keyed by their directory. A leading `~` is the home directory.
+    #[serde(default)]
+    flakes: BTreeMap<PathBuf, FlakeConfig>,
+    /// Settings for single inputs, keyed by their ID.
+    #[serde(default)]
+    inputs: BTreeMap<String, InputConfig>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FlakeConfig {
+    /// Shell commands run in the flake's directory after locking it, before committing.
+    ///
+    /// For regeneration steps like `npm run generate`.
+    #[serde(default)]
+    pub post_update: Vec<String>,
+    /// Marks the flake as a system configuration, which includes it without gcroots and offers
+    /// switching to it. Only needed when it isn't detected, like a NixOS configuration outside
+    /// of `/etc/nixos`.
+    pub system: Option<SystemKind>,
+    /// Marks the flake as a standalone home-manager configuration. Only needed when it isn't in
+    /// `~/.config/home-manager`.
+    #[serde(default)]
+    pub home_manager: bool,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct InputConfig {
+    /// Default for `--ref-match-age` when targeting the input, like `3d` for an input following
+    /// an unstable branch.
+    ref_match_age: Option<String>,
+    /// Default for `--target` when targeting the input, like `github:NixOS/nixpkgs/nixos-25.05`
+    /// for a `nixpkgs-stable` input.
+    target: Option<String>,
+}
+
+impl Config {
+    fn path() -> Result<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok_or_eyre("Neither XDG_CONFIG_HOME nor HOME is set")?;
+        Ok(config_home.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+    }
+
+    /// Loads the configuration, which is empty if the file doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be read or parsed, or if neither `XDG_CONFIG_HOME` nor `HOME` is
+    /// set.
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&path)?;
+        let mut config: Self = toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        if let Some(home) = std::env::var_os("HOME") {
+            config.flakes = std::mem::take(&mut config.flakes)
+                .into_iter()
+                .map(|(dir, flake)| match dir.strip_prefix("~") {
+                    Ok(rest) => (Path::new(&home).join(rest), flake),
+                    Err(_) => (dir, flake),
+                })
+                .collect();
+        }
+        Ok(config)
+    }
+
+    /// Compiles `gcroot-patterns`.
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first pattern that isn't a valid regex.
+    pub fn gcroot_patterns(&self) -> Result<Vec<Regex>> {
+        self.gcroot_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).wrap_err_with(|| format!("Invalid gcroot pattern {pattern}"))
+            })
+            .collect()
+    }
+
+    /// Returns the directories of flakes configured as system configurations.
+    pub fn system_flakes(&self) -> impl Iterator<Item = (&Path, SystemKind)> {
+        self.flakes
+            .iter()
+            .filter_map(|(dir, flake)| Some((dir.as_path(), flake.system?)))
+    }
+
+    /// Returns the directories of flakes configured as home-manager configurations.
+    pub fn home_manager_flakes(&self) -> impl Iterator<Item = &Path> {
+        self.flakes
+            .iter()
+            .filter(|(_, flake)| flake.home_manager)
+            .map(|(dir, _)| dir.as_path())
+    }
+
+    /// Returns the configured `ref-match-age` of the input `input_id`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the configured age isn't a duration like `2weeks`.
+    pub fn ref_match_age(&self, input_id: &str) -> Result<Option<Duration>> {
+        self.inputs
+            .get(input_id)
+            .and_then(|input| input.ref_match_age.as_deref())
+            .map(|age| {
+                humantime::parse_duration(age).wrap_err_with(|| {
+                    format!("Invalid ref-match-age {age} for the input {input_id}")
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the configured target of the input `input_id`, if any.
+    #[must_use]
+    pub fn target(&self, input_id: &str) -> Option<&str> {
+        self.inputs
+            .get(input_id)
+            .and_then(|input| input.target.as_deref())
+    }
+
+    /// Returns the settings for the flake in `directory`, if there are any.
+    #[must_use]
+    pub fn flake(&self, directory: &Path) -> Option<&FlakeConfig> {
+        self.flakes.get(directory)
+    }
+}
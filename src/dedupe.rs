@@ -0,0 +1,65 @@
+//! Consolidating duplicate inputs of a flake, like a second nixpkgs locked by an input, with
+//! `follows`.
+
+use color_eyre::{Result, eyre::bail};
+use fs_err as fs;
+
+use nixpkgsupd::{flake_nix, lockfile};
+
+use crate::{DedupeArgs, progress, ui::Themed, update};
+
+/// Adds `follows` to the inputs of `flake.nix` duplicating an input of the flake, shows the
+/// diff, relocks and reports how many lock nodes were removed.
+pub fn dedupe(args: &DedupeArgs) -> Result<()> {
+    let flake_nix_path = args.directory.join("flake.nix");
+    let lockfile_path = args.directory.join("flake.lock");
+    if !flake_nix_path.exists() {
+        bail!("{} does not exist", flake_nix_path.display());
+    }
+
+    let graph = lockfile::load_lockfile_graph(&lockfile_path)?;
+    let duplicates = graph.duplicate_inputs();
+    if duplicates.is_empty() {
+        println!("{}", "No duplicate inputs".good());
+        return Ok(());
+    }
+
+    let old_contents = fs::read_to_string(&flake_nix_path)?;
+    let mut new_contents = old_contents.clone();
+    for duplicate in &duplicates {
+        println!(
+            "{} {} {}",
+            format_args!("{}/{}", duplicate.parent, duplicate.input_id).value(),
+            "can follow".muted(),
+            duplicate.follows.value()
+        );
+        new_contents = flake_nix::add_follows(
+            &new_contents,
+            &duplicate.parent,
+            &duplicate.input_id,
+            &duplicate.follows,
+        )?;
+    }
+    update::print_diff(&old_contents, &new_contents, args.diff_context);
+
+    if !args.allow_write {
+        eprintln!("{}", "Dry run, not modifying files".warning());
+        return Ok(());
+    }
+    fs::write(&flake_nix_path, &new_contents)?;
+    // `nix flake lock` relocks every changed input
+    if !progress::run_with_spinner("Locking", ("nix", vec!["flake", "lock"]), &args.directory)? {
+        bail!("Failed to relock. Try manually editing flake.nix.");
+    }
+
+    let nodes_before = graph.nodes.len();
+    let nodes_after = lockfile::load_lockfile_graph(&lockfile_path)?.nodes.len();
+    println!(
+        "{} {} {} {}",
+        "Lock nodes:".muted(),
+        nodes_before.value(),
+        "→".muted(),
+        nodes_after.good()
+    );
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Editing devenv's `devenv.yaml`.
+
+use color_eyre::eyre::{OptionExt, Result, bail, eyre};
+
+/// Returns the indentation and the key of a `key:` or `key: value` line.
+fn key(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('#') {
+        return None;
+    }
+    let (key, _) = trimmed.split_once(':')?;
+    Some((line.len() - trimmed.len(), key.trim()))
+}
+
+/// Returns the line index and indentation of the direct child `name` of the key at `parent`, or
+/// of the top level if `parent` is `None`.
+fn child(lines: &[&str], parent: Option<usize>, name: &str) -> Option<(usize, usize)> {
+    let (start, parent_indent) = match parent {
+        Some(parent) => (parent + 1, Some(key(lines[parent])?.0)),
+        None => (0, None),
+    };
+    let mut child_indent = None;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let Some((indent, key)) = key(line) else {
+            continue;
+        };
+        // The block of the parent ends at the first line indented at most as much as it
+        if parent_indent.is_some_and(|parent_indent| indent <= parent_indent) {
+            break;
+        }
+        let child_indent = *child_indent.get_or_insert(indent);
+        if indent == child_indent && key.trim_matches(['"', '\'']) == name {
+            return Some((i, indent));
+        }
+    }
+    None
+}
+
+/// Replaces `inputs.<input id>.url` in `devenv.yaml` with `new_flake_ref`.
+///
+/// Only the block style written by `devenv init` is supported:
+///
+/// ```yaml
+/// inputs:
+///   nixpkgs:
+///     url: github:cachix/devenv-nixpkgs/rolling
+/// ```
+///
+/// # Errors
+///
+/// Fails if `devenv.yaml` has no `url` for the input.
+pub fn replace_input_url(
+    new_flake_ref: &str,
+    old_contents: &str,
+    input_id: &str,
+) -> Result<String> {
+    let lines = old_contents.split_inclusive('\n').collect::<Vec<_>>();
+
+    let (inputs, _) = child(&lines, None, "inputs").ok_or_eyre("devenv.yaml has no inputs")?;
+    let (input, _) = child(&lines, Some(inputs), input_id)
+        .ok_or_else(|| eyre!("devenv.yaml has no input {input_id}"))?;
+    let Some((url, url_indent)) = child(&lines, Some(input), "url") else {
+        bail!("The input {input_id} in devenv.yaml has no url");
+    };
+
+    let line_ending = if lines[url].ends_with('\n') { "\n" } else { "" };
+    let new_line = format!(
+        "{}url: {new_flake_ref}{line_ending}",
+        " ".repeat(url_indent)
+    );
+
+    let mut new_contents = String::with_capacity(old_contents.len());
+    for (i, line) in lines.iter().enumerate() {
+        new_contents.push_str(if i == url { &new_line } else { line });
+    }
+    Ok(new_contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVENV_YAML: &str = "\
+# yaml-language-server: $schema=https://devenv.sh/devenv.schema.json
+inputs:
+  nixpkgs:
+    # The nixpkgs used by devenv
+    url: github:cachix/devenv-nixpkgs/rolling
+  nixpkgs-python:
+    url: github:cachix/nixpkgs-python
+    inputs:
+      nixpkgs:
+        follows: nixpkgs
+imports:
+  - ./frontend
+";
+
+    #[test]
+    fn replace_url() {
+        let new = replace_input_url(
+            "github:NixOS/nixpkgs/nixos-unstable",
+            DEVENV_YAML,
+            "nixpkgs",
+        )
+        .unwrap();
+        assert_eq!(
+            new,
+            DEVENV_YAML.replace(
+                "url: github:cachix/devenv-nixpkgs/rolling",
+                "url: github:NixOS/nixpkgs/nixos-unstable"
+            )
+        );
+    }
+
+    #[test]
+    fn missing_input_or_url() {
+        assert!(replace_input_url("github:a/b", DEVENV_YAML, "flake-utils").is_err());
+        assert!(replace_input_url("github:a/b", "inputs:\n  nixpkgs:\n", "nixpkgs").is_err());
+        assert!(replace_input_url("github:a/b", "imports: []\n", "nixpkgs").is_err());
+    }
+}
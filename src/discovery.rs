@@ -0,0 +1,500 @@
+//! Finding the flakes used on this machine from Nix's garbage collector roots and system
+//! configurations.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::Context};
+use fs_err as fs;
+use iddqd::{
+    IdHashItem, IdHashMap,
+    id_hash_map::{Entry as IdHashMapEntry, RefMut},
+};
+use nix::unistd::{AccessFlags, access};
+use regex::Regex;
+
+use crate::{
+    command::run_with_retries,
+    config::Config,
+    devenv, envrc, flake_nix,
+    lockfile::{self, LockGraph},
+    store, system,
+};
+
+#[expect(clippy::struct_excessive_bools, reason = "These are independent flags")]
+/// A flake or devenv project and how it's used.
+#[derive(Clone)]
+pub struct Flake<'cli> {
+    /// Key in `inputs`
+    pub id: &'cli str,
+    /// Parent of `flake.lock`
+    pub directory: PathBuf,
+    /// Paths of the gcroots. Below `directory`, except for lorri's
+    pub gcroots: Vec<PathBuf>,
+    /// Whether the flake has build result gcroots
+    pub has_build_result: bool,
+    /// Whether the flake has direnv gcroots, including lorri's
+    pub has_direnv_gc_roots: bool,
+    /// Whether the flake has lorri gcroots
+    pub has_lorri_gc_roots: bool,
+    /// Path of `flake.lock` or `devenv.lock`
+    pub lockfile_path: PathBuf,
+    pub project: ProjectKind,
+    /// Directory of the `.envrc` using the flake, if it's known from a direnv gcroot
+    pub envrc_directory: Option<PathBuf>,
+    /// The system configuration tool using the flake, if any
+    pub system: Option<system::SystemKind>,
+    /// Whether the flake is a standalone home-manager configuration
+    pub home_manager: bool,
+}
+
+/// What pins the inputs of a project.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectKind {
+    Flake,
+    /// A devenv project, whose `devenv.lock` has the same format as `flake.lock`.
+    Devenv,
+}
+
+impl ProjectKind {
+    /// Returns the name of the file declaring the inputs.
+    #[must_use]
+    pub const fn manifest(self) -> &'static str {
+        match self {
+            Self::Flake => "flake.nix",
+            Self::Devenv => "devenv.yaml",
+        }
+    }
+
+    /// Returns the name of the lock file.
+    #[must_use]
+    pub const fn lockfile(self) -> &'static str {
+        match self {
+            Self::Flake => "flake.lock",
+            Self::Devenv => "devenv.lock",
+        }
+    }
+
+    /// Returns the files committed after updating an input.
+    #[must_use]
+    pub const fn files(self) -> [&'static str; 2] {
+        [self.manifest(), self.lockfile()]
+    }
+
+    /// Returns the program and arguments locking changed inputs.
+    #[must_use]
+    pub fn lock_command(self, input_id: &str) -> (&'static str, Vec<&str>) {
+        match self {
+            Self::Flake => ("nix", vec!["flake", "lock"]),
+            // devenv has no command that only locks changed inputs
+            Self::Devenv => self.update_input_command(input_id),
+        }
+    }
+
+    /// Returns the program and arguments updating `input_id` to its latest version.
+    #[must_use]
+    pub fn update_input_command(self, input_id: &str) -> (&'static str, Vec<&str>) {
+        match self {
+            Self::Flake => ("nix", vec!["flake", "update", input_id]),
+            Self::Devenv => ("devenv", vec!["update", input_id]),
+        }
+    }
+
+    /// Locks changed inputs in `directory` and returns whether it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the lock command can't be started.
+    pub fn lock(self, directory: &Path, input_id: &str) -> Result<bool> {
+        let (program, args) = self.lock_command(input_id);
+        run_with_retries(program, &args, directory)
+    }
+
+    /// Updates `input_id` to its latest version and returns whether it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the update command can't be started.
+    pub fn update_input(self, directory: &Path, input_id: &str) -> Result<bool> {
+        let (program, args) = self.update_input_command(input_id);
+        run_with_retries(program, &args, directory)
+    }
+
+    /// Replaces the URL of `input_id` in `old_contents` of the manifest and returns the new
+    /// contents.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `devenv.yaml` has no `url` for the input, or if `flake.nix` can't be parsed or
+    /// the input's URL isn't a string literal.
+    pub fn replace_input_url(
+        self,
+        new_flake_ref: &str,
+        old_contents: &str,
+        input_id: &str,
+    ) -> Result<String> {
+        match self {
+            Self::Flake => {
+                flake_nix::replace_flake_input_url(new_flake_ref, old_contents, input_id)
+            }
+            Self::Devenv => devenv::replace_input_url(new_flake_ref, old_contents, input_id),
+        }
+    }
+}
+
+impl Flake<'_> {
+    /// Returns the directory to run direnv in.
+    #[must_use]
+    pub fn envrc_directory(&self) -> &Path {
+        self.envrc_directory.as_deref().unwrap_or(&self.directory)
+    }
+
+    /// Returns whether the flake is inside the read-only Nix store, like a source whose
+    /// `.direnv` or lorri's `nix_file` was copied there.
+    #[must_use]
+    pub fn is_in_store(&self) -> bool {
+        store::get().contains(&self.directory)
+    }
+
+    /// Returns whether the flake's `flake.nix` or `devenv.yaml` exists. Stale gcroots of a deleted
+    /// project can keep a lock file around without it.
+    #[must_use]
+    pub fn has_manifest(&self) -> bool {
+        self.directory.join(self.project.manifest()).exists()
+    }
+
+    /// Returns whether the current user can write the flake's directory and lock file, unlike
+    /// a root-owned `/etc/nixos`.
+    #[must_use]
+    pub fn is_writable(&self) -> bool {
+        [&self.directory, &self.lockfile_path]
+            .into_iter()
+            .all(|path| access(path.as_path(), AccessFlags::W_OK).is_ok())
+    }
+}
+
+impl IdHashItem for Flake<'_> {
+    type Key<'a>
+        = &'a Path
+    where
+        Self: 'a;
+
+    fn key(&self) -> Self::Key<'_> {
+        &self.directory
+    }
+    iddqd::id_upcast!();
+}
+
+/// What created a gcroot.
+#[derive(PartialEq, Eq)]
+pub enum GcrootKind {
+    /// nix-direnv's `.direnv/flake-profile-*`
+    Direnv {
+        /// The directory of `.envrc` and `.direnv`, which may differ from the flake's
+        envrc_dir: PathBuf,
+    },
+    /// lorri's `~/.cache/lorri/gc_roots/<hash>/gc_root/shell_gc_root`
+    Lorri,
+    /// devenv's `.devenv/gc/*`
+    Devenv,
+    /// `result` and `result-*` symlinks from `nix build`
+    BuildResult,
+}
+
+/// Returns whether `name` is a link name created by `nix build`, `nix-build` or
+/// `nix-instantiate --add-root` by default, like `result`, `result-dev` or `result.drv`.
+#[must_use]
+pub fn is_build_result_name(name: &str) -> bool {
+    name == "result" || name == "result.drv" || name == "outputs" || name.starts_with("result-")
+}
+
+/// Returns the symbolic links next to `gcroot` pointing to it, like the
+/// `.direnv/flake-profile-*` profile of nix-direnv pointing to its `-1-link` generation, which
+/// dangle once the gcroot is deleted.
+pub fn profile_links(gcroot: &Path) -> Vec<PathBuf> {
+    let (Some(parent), Some(name)) = (gcroot.parent(), gcroot.file_name()) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path != gcroot)
+        .filter(|path| {
+            fs::read_link(path)
+                .is_ok_and(|target| target == Path::new(name) || parent.join(target) == gcroot)
+        })
+        .collect()
+}
+
+/// Returns the project directory a gcroot belongs to.
+///
+/// `extra_result_names` match the names of additional build result links.
+#[must_use]
+pub fn gcroot_owner(gcroot: &Path, extra_result_names: &[Regex]) -> Option<(PathBuf, GcrootKind)> {
+    if let Some((directory, kind)) = gcroot_owner_by_path(gcroot, extra_result_names) {
+        // The `.envrc` may use a flake in another directory
+        if let GcrootKind::Direnv { envrc_dir } = &kind {
+            if let Some(flake_directory) = envrc::flake_directory(envrc_dir) {
+                return Some((flake_directory, kind));
+            }
+        }
+        return Some((directory, kind));
+    }
+
+    // lorri keeps its gcroots outside of the project, next to a `nix_file` symlink pointing to
+    // the project's `shell.nix` or `flake.nix`
+    if gcroot.file_name()? == "shell_gc_root" {
+        let nix_file = fs::read_link(gcroot.parent()?.join("nix_file")).ok()?;
+        return Some((nix_file.parent()?.to_owned(), GcrootKind::Lorri));
+    }
+
+    None
+}
+
+/// Returns the project directory a gcroot belongs to like [`gcroot_owner`], but only by its
+/// path, for gcroots of another machine.
+///
+/// The `.envrc` of a direnv gcroot is taken to use the flake in its own directory, and lorri's
+/// gcroots aren't recognized.
+#[must_use]
+pub fn gcroot_owner_by_path(
+    gcroot: &Path,
+    extra_result_names: &[Regex],
+) -> Option<(PathBuf, GcrootKind)> {
+    if let Some(direnv_path) = gcroot
+        .ancestors()
+        .find(|path| path.file_name().is_some_and(|name| name == ".direnv"))
+    {
+        let envrc_dir = direnv_path.parent()?.to_owned();
+        return Some((envrc_dir.clone(), GcrootKind::Direnv { envrc_dir }));
+    }
+
+    if let Some(devenv_path) = gcroot
+        .ancestors()
+        .find(|path| path.file_name().is_some_and(|name| name == ".devenv"))
+    {
+        return Some((devenv_path.parent()?.to_owned(), GcrootKind::Devenv));
+    }
+
+    let name = gcroot.file_name()?.to_str()?;
+    if is_build_result_name(name) || extra_result_names.iter().any(|re| re.is_match(name)) {
+        return Some((gcroot.parent()?.to_owned(), GcrootKind::BuildResult));
+    }
+
+    None
+}
+
+fn filter_gcroot<'cli>(
+    entry: &fs::DirEntry,
+    flakes: &mut IdHashMap<Flake<'cli>>,
+    flake_id: &'cli str,
+    extra_result_names: &[Regex],
+) -> Result<()> {
+    let gcroot = fs::read_link(entry.path())?;
+    if !store::get().gcroot_exists(&gcroot) {
+        return Ok(());
+    }
+
+    let Some((directory, kind)) = gcroot_owner(&gcroot, extra_result_names) else {
+        tracing::trace!(gcroot = %gcroot.display(), "Skipping gcroot without a project");
+        return Ok(());
+    };
+    tracing::trace!(gcroot = %gcroot.display(), directory = %directory.display(), "Found gcroot");
+    // Symlinked directories like `/etc/nixos` or macOS's `/etc` -> `/private/etc` would
+    // otherwise list the same flake twice
+    let directory = fs::canonicalize(&directory).unwrap_or(directory);
+
+    add_gcroot(flakes, flake_id, gcroot, &directory, kind, |directory| {
+        [ProjectKind::Flake, ProjectKind::Devenv]
+            .into_iter()
+            .find(|project| directory.join(project.lockfile()).exists())
+    });
+    Ok(())
+}
+
+/// Adds `gcroot` to the flake in `directory`, adding the flake if `project` finds the kind of its
+/// lock file.
+pub fn add_gcroot<'cli>(
+    flakes: &mut IdHashMap<Flake<'cli>>,
+    flake_id: &'cli str,
+    gcroot: PathBuf,
+    directory: &Path,
+    kind: GcrootKind,
+    project: impl FnOnce(&Path) -> Option<ProjectKind>,
+) {
+    let is_direnv = matches!(kind, GcrootKind::Direnv { .. } | GcrootKind::Lorri);
+    let is_lorri = kind == GcrootKind::Lorri;
+    let is_build_result = kind == GcrootKind::BuildResult;
+
+    match flakes.entry(directory) {
+        IdHashMapEntry::Occupied(mut occupied) => {
+            let mut existing = occupied.get_mut();
+            existing.gcroots.push(gcroot);
+            existing.has_direnv_gc_roots |= is_direnv;
+            existing.has_lorri_gc_roots |= is_lorri;
+            existing.has_build_result |= is_build_result;
+            if let GcrootKind::Direnv { envrc_dir } = kind {
+                existing.envrc_directory.get_or_insert(envrc_dir);
+            }
+        }
+        IdHashMapEntry::Vacant(vacant) => {
+            let Some(project) = project(directory) else {
+                return;
+            };
+
+            vacant.insert(Flake {
+                id: flake_id,
+                project,
+                directory: directory.to_owned(),
+                gcroots: vec![gcroot],
+                has_direnv_gc_roots: is_direnv,
+                has_lorri_gc_roots: is_lorri,
+                has_build_result: is_build_result,
+                lockfile_path: directory.join(project.lockfile()),
+                envrc_directory: match kind {
+                    GcrootKind::Direnv { envrc_dir } => Some(envrc_dir),
+                    _ => None,
+                },
+                system: None,
+                home_manager: false,
+            });
+        }
+    }
+}
+
+/// Returns the flake in `directory`, adding it without gcroots if it has a `flake.lock`.
+///
+/// System configurations are used without leaving gcroots in their directory.
+pub fn configuration_flake<'a, 'cli>(
+    flakes: &'a mut IdHashMap<Flake<'cli>>,
+    flake_id: &'cli str,
+    directory: &Path,
+) -> Option<RefMut<'a, Flake<'cli>>> {
+    let directory = fs::canonicalize(directory).unwrap_or_else(|_| directory.to_owned());
+    let directory = directory.as_path();
+    match flakes.entry(directory) {
+        IdHashMapEntry::Occupied(occupied) => Some(occupied.into_mut()),
+        IdHashMapEntry::Vacant(vacant) => {
+            let lockfile_path = directory.join(ProjectKind::Flake.lockfile());
+            if !lockfile_path.exists() {
+                return None;
+            }
+            Some(vacant.insert(Flake {
+                id: flake_id,
+                project: ProjectKind::Flake,
+                directory: directory.to_owned(),
+                gcroots: Vec::new(),
+                has_direnv_gc_roots: false,
+                has_lorri_gc_roots: false,
+                has_build_result: false,
+                lockfile_path,
+                envrc_directory: None,
+                system: None,
+                home_manager: false,
+            }))
+        }
+    }
+}
+
+/// Finds flakes from Nix's automatic garbage collector roots and system configurations.
+///
+/// The automatic gcroots are in the same place on Linux and in macOS multi-user installs, where
+/// `/nix` is a separate APFS volume.
+/// `input_id` is the input looked for in the flakes, and `config` adds build result link names and
+/// configuration flakes.
+///
+/// # Errors
+///
+/// Fails if the gcroots directory or a profile can't be read.
+pub fn discover_flakes<'a>(input_id: &'a str, config: &Config) -> Result<IdHashMap<Flake<'a>>> {
+    let mut flakes = IdHashMap::new();
+
+    let extra_result_names = config.gcroot_patterns()?;
+    for entry in fs::read_dir(store::get().gcroots_dir())? {
+        let entry = entry?;
+
+        if let Err(err) = filter_gcroot(&entry, &mut flakes, input_id, &extra_result_names)
+            .wrap_err_with(|| format!("Failed to filter gcroot {}", entry.path().display()))
+        {
+            tracing::warn!("{err:?}");
+        }
+    }
+
+    let systems = system::nixos_flake_directory()
+        .map(|directory| (directory, system::SystemKind::NixOS))
+        .into_iter()
+        .chain(
+            system::darwin_flake_directory()
+                .map(|directory| (directory, system::SystemKind::Darwin)),
+        )
+        .chain(
+            config
+                .system_flakes()
+                .map(|(directory, system)| (directory.to_owned(), system)),
+        );
+    for (directory, system) in systems {
+        if let Some(mut flake) = configuration_flake(&mut flakes, input_id, &directory) {
+            flake.system = Some(system);
+        }
+    }
+    let home_manager_flakes = system::home_manager_flake_directory()
+        .into_iter()
+        .chain(config.home_manager_flakes().map(Path::to_owned));
+    for directory in home_manager_flakes {
+        if let Some(mut flake) = configuration_flake(&mut flakes, input_id, &directory) {
+            flake.home_manager = true;
+        }
+    }
+
+    tracing::info!(count = flakes.len(), "Discovered flakes");
+    Ok(flakes)
+}
+
+/// Returns the indices of the flakes by the ID of each of their inputs matching `pattern`, like
+/// `nixpkgs` and `nixpkgs-stable` for `nixpkgs*`.
+///
+/// Flakes without a matching input are left out, with a warning if their lock file can't be read.
+pub fn flakes_by_input_id(flakes: &[Flake], pattern: &Regex) -> BTreeMap<String, Vec<usize>> {
+    let mut flakes_by_input = BTreeMap::<String, Vec<usize>>::new();
+    for (index, flake) in flakes.iter().enumerate() {
+        let inputs = match lockfile::load_lockfile_inputs(&flake.lockfile_path, pattern) {
+            Ok(inputs) => inputs,
+            Err(err) => {
+                tracing::warn!(directory = %flake.directory.display(), "Failed to load the lockfile: {err:?}");
+                continue;
+            }
+        };
+        for (input_id, _) in inputs {
+            flakes_by_input.entry(input_id).or_default().push(index);
+        }
+    }
+    flakes_by_input
+}
+
+/// Returns the ID of the input `select` picks from the lock file of each flake by the flake's
+/// directory, like [`LockGraph::nixpkgs_input`].
+///
+/// Flakes without one are left out, with a warning if their lock file can't be read.
+pub fn select_input_ids<'a>(
+    flakes: impl IntoIterator<Item = &'a Flake<'a>>,
+    select: impl Fn(&LockGraph) -> Option<&str>,
+) -> HashMap<PathBuf, String> {
+    flakes
+        .into_iter()
+        .filter(|flake| flake.has_manifest())
+        .filter_map(|flake| {
+            let graph = lockfile::load_lockfile_graph(&flake.lockfile_path)
+                .inspect_err(|err| {
+                    tracing::warn!(directory = %flake.directory.display(), "Failed to load the lockfile: {err:?}");
+                })
+                .ok()?;
+            let input_id = select(&graph)?;
+            Some((flake.directory.clone(), input_id.to_owned()))
+        })
+        .collect()
+}
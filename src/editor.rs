@@ -0,0 +1,156 @@
+//! Choosing the editor the `edit` prompt command opens `flake.nix` in.
+
+use std::{ffi::OsString, path::Path, process::Command};
+
+use color_eyre::{Result, eyre::OptionExt};
+
+/// Editors tried in order when neither `--editor`, `$VISUAL` nor `$EDITOR` is set.
+const FALLBACKS: &[&str] = &["nano", "vi"];
+
+/// Returns the command opening `path` in `editor`, which is `--editor` or `editor` in the
+/// configuration file, or otherwise `$VISUAL`, `$EDITOR` or the first of [`FALLBACKS`] found in
+/// `PATH`.
+///
+/// Like Git, the editor is run by `sh`, so it may include quoted arguments, like `code --wait`.
+/// Editors known to accept a position open the file at the 1-based line and column `position`.
+pub fn command(
+    editor: Option<&str>,
+    path: &Path,
+    position: Option<(usize, usize)>,
+) -> Result<Command> {
+    let editor = editor
+        .map(OsString::from)
+        .or_else(|| env_editor("VISUAL"))
+        .or_else(|| env_editor("EDITOR"))
+        .or_else(|| {
+            FALLBACKS
+                .iter()
+                .find(|program| in_path(program))
+                .map(OsString::from)
+        })
+        .ok_or_eyre("No editor found. Set --editor, VISUAL or EDITOR")?;
+    let program = first_word(&editor.to_string_lossy()).ok_or_eyre("The editor is empty")?;
+    // The editor is `$0` and the path arguments are `$@`
+    let mut script = editor.clone();
+    script.push(r#" "$@""#);
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script).arg(editor);
+    command.args(path_args(&program, path, position));
+    Ok(command)
+}
+
+/// Returns the first word of the shell command `editor` with its quotes removed, like `sh` would,
+/// to know which editor it runs.
+fn first_word(editor: &str) -> Option<String> {
+    let mut word = String::new();
+    let mut chars = editor.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => break,
+            '\'' => word.extend(chars.by_ref().take_while(|&c| c != '\'')),
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => word.extend(['\\', c]),
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.extend(chars.next()),
+            c => word.push(c),
+        }
+    }
+    (!word.is_empty()).then_some(word)
+}
+
+/// Returns the arguments opening `path` at `position` in the editor `program`, in the syntax of
+/// the editor.
+fn path_args(program: &str, path: &Path, position: Option<(usize, usize)>) -> Vec<OsString> {
+    let Some((line, column)) = position else {
+        return vec![path.into()];
+    };
+    let name = Path::new(program)
+        .file_name()
+        .map_or_else(|| program.into(), |name| name.to_string_lossy());
+    let with_position = |separator: &str| {
+        let mut arg = OsString::from(path);
+        arg.push(format!("{separator}{line}:{column}"));
+        arg
+    };
+    match &*name {
+        "vi" | "vim" | "nvim" | "gvim" => vec![format!("+{line}").into(), path.into()],
+        "nano" => vec![format!("+{line},{column}").into(), path.into()],
+        "emacs" | "emacsclient" | "kak" | "micro" => {
+            vec![format!("+{line}:{column}").into(), path.into()]
+        }
+        "code" | "code-insiders" | "codium" | "cursor" => {
+            vec!["--goto".into(), with_position(":")]
+        }
+        "hx" | "helix" | "subl" | "zed" => vec![with_position(":")],
+        _ => vec![path.into()],
+    }
+}
+
+fn env_editor(name: &str) -> Option<OsString> {
+    std::env::var_os(name).filter(|editor| !editor.is_empty())
+}
+
+/// Returns whether `program` is an executable in `PATH`.
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| Path::new(&dir).join(program).is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editor_with_quoted_arguments() {
+        let output = command(
+            Some(r#"printf '%s|' "two words""#),
+            Path::new("flake.nix"),
+            None,
+        )
+        .unwrap()
+        .output()
+        .unwrap();
+        assert_eq!(output.stdout, b"two words|flake.nix|");
+    }
+
+    #[test]
+    fn quoted_program() {
+        assert_eq!(first_word("code --wait").as_deref(), Some("code"));
+        assert_eq!(
+            first_word(r#""/opt/My Editor/bin/code" --wait"#).as_deref(),
+            Some("/opt/My Editor/bin/code")
+        );
+        assert_eq!(
+            first_word(r"/opt/My\ Editor/'bin'/code").as_deref(),
+            Some("/opt/My Editor/bin/code")
+        );
+        assert_eq!(first_word("  "), None);
+    }
+
+    #[test]
+    fn position_args() {
+        let path = Path::new("flake.nix");
+        assert_eq!(
+            path_args("/usr/bin/nvim", path, Some((3, 19))),
+            ["+3", "flake.nix"]
+        );
+        assert_eq!(
+            path_args("code", path, Some((3, 19))),
+            ["--goto", "flake.nix:3:19"]
+        );
+        assert_eq!(path_args("hx", path, Some((3, 19))), ["flake.nix:3:19"]);
+        assert_eq!(path_args("ed", path, Some((3, 19))), ["flake.nix"]);
+        assert_eq!(path_args("nvim", path, None), ["flake.nix"]);
+    }
+}
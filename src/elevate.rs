@@ -0,0 +1,81 @@
+//! Running the steps that modify a flake the user can't write, like a root-owned `/etc/nixos`,
+//! with `--elevate-with`.
+
+use std::{
+    borrow::Cow,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use color_eyre::{Result, eyre::bail};
+use fs_err as fs;
+
+use nixpkgsupd::{command::run_cmd, sigint_guard};
+
+use crate::{progress, ui::Themed};
+
+/// The default for `--elevate-with`.
+pub const DEFAULT_PROGRAM: &str = "sudo";
+
+/// Runs the command in `dir` with the elevating program, if any, and returns whether it
+/// succeeded.
+///
+/// Without one, it runs with a spinner showing `message` and is retried like other Nix commands.
+/// With one, it runs once with the terminal attached, so the password prompt isn't mixed with
+/// the spinner or asked again for each retry.
+pub fn run(
+    elevate: Option<&str>,
+    message: impl Into<Cow<'static, str>>,
+    (program, args): (&str, Vec<&str>),
+    dir: &Path,
+) -> Result<bool> {
+    let Some(elevate) = elevate else {
+        return progress::run_with_spinner(message, (program, args), dir);
+    };
+    eprintln!(
+        "{}",
+        format_args!("{} with {elevate}", message.into()).muted()
+    );
+    let args: Vec<_> = std::iter::once(program).chain(args).collect();
+    run_cmd(elevate, &args, dir)
+}
+
+/// Writes `contents` to the existing file `path`, which keeps its owner and mode.
+pub fn write(elevate: Option<&str>, path: &Path, contents: &str) -> Result<()> {
+    let Some(elevate) = elevate else {
+        fs::write(path, contents)?;
+        return Ok(());
+    };
+    // The program asks for a password on the terminal rather than standard input
+    let status = sigint_guard::status_with_input(
+        Command::new(elevate)
+            .arg("tee")
+            .arg("--")
+            .arg(path)
+            .stdout(Stdio::null()),
+        contents.as_bytes().to_owned(),
+    )?;
+    if !status.success() {
+        bail!(
+            "Writing {} with {elevate} failed with {status}",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Removes the file `path`.
+pub fn remove_file(elevate: Option<&str>, path: &Path) -> Result<()> {
+    let Some(elevate) = elevate else {
+        fs::remove_file(path)?;
+        return Ok(());
+    };
+    let status = sigint_guard::status(Command::new(elevate).arg("rm").arg("--").arg(path))?;
+    if !status.success() {
+        bail!(
+            "Removing {} with {elevate} failed with {status}",
+            path.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! Inspection of direnv's `.envrc` and nix-direnv's `.direnv` cache.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use fs_err as fs;
+
+/// What the cached direnv environment of a flake was built from.
+#[derive(Debug, Default)]
+pub struct DirenvProvenance {
+    /// Flake reference passed to `use flake` with `--override-input <input id>`.
+    pub override_input: Option<String>,
+    /// Whether the newest cached nix-direnv profile is older than `flake.lock`.
+    pub cache_predates_lockfile: bool,
+}
+
+impl DirenvProvenance {
+    /// Returns whether the cached environment may not reflect `flake.lock`.
+    #[must_use]
+    pub const fn diverges(&self) -> bool {
+        self.override_input.is_some() || self.cache_predates_lockfile
+    }
+}
+
+/// Inspects `.envrc` and the `.direnv` cache in `directory`.
+///
+/// Missing or unreadable files are treated as having no provenance information.
+#[must_use]
+pub fn provenance(directory: &Path, lockfile_path: &Path, input_id: &str) -> DirenvProvenance {
+    let override_input = fs::read_to_string(directory.join(".envrc"))
+        .ok()
+        .and_then(|envrc| override_input(&envrc, input_id));
+
+    let cache_predates_lockfile = newest_cached_profile(&directory.join(".direnv"))
+        .zip(modified(lockfile_path))
+        .is_some_and(|(profile, lockfile)| profile < lockfile);
+
+    DirenvProvenance {
+        override_input,
+        cache_predates_lockfile,
+    }
+}
+
+/// How the `.envrc` of a project loads its Nix environment, which decides how to refresh it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DirenvSetup {
+    /// The `use` line loading the environment, if any.
+    pub loader: Option<NixLoader>,
+    /// Whether `.envrc` watches `flake.lock` with `watch_file` or `nix_direnv_watch_file`.
+    pub watches_lockfile: bool,
+    /// The nix-direnv version required with `nix_direnv_version` or fetched with `source_url`.
+    pub nix_direnv_version: Option<String>,
+    /// Whether `nix_direnv_manual_reload` is set, so only `nix-direnv-reload` rebuilds the cache.
+    pub manual_reload: bool,
+    /// Whether `.direnv/bin/nix-direnv-reload` exists, which nix-direnv 2.3 and later create.
+    pub has_reload_script: bool,
+}
+
+/// The direnv function loading a Nix environment, built into direnv and replaced by nix-direnv.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NixLoader {
+    /// `use flake`
+    Flake,
+    /// `use nix`, whose cache doesn't watch `flake.lock` by default.
+    Nix,
+}
+
+impl DirenvSetup {
+    /// The path of `nix-direnv-reload` relative to the directory of `.envrc`.
+    pub const RELOAD_SCRIPT: &str = ".direnv/bin/nix-direnv-reload";
+}
+
+/// Inspects how the `.envrc` in `directory` loads its environment.
+#[must_use]
+pub fn setup(directory: &Path) -> DirenvSetup {
+    let mut setup = fs::read_to_string(directory.join(".envrc"))
+        .map(|envrc| parse_setup(&envrc))
+        .unwrap_or_default();
+    setup.has_reload_script = directory.join(DirenvSetup::RELOAD_SCRIPT).is_file();
+    setup
+}
+
+fn parse_setup(envrc: &str) -> DirenvSetup {
+    let mut setup = DirenvSetup::default();
+    for line in envrc.lines() {
+        let words: Vec<_> = line
+            .split_whitespace()
+            .take_while(|word| !word.starts_with('#'))
+            .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+            .collect();
+        match words.as_slice() {
+            ["use", "flake", ..] | ["use_flake", ..] => setup.loader = Some(NixLoader::Flake),
+            ["use", "nix", ..] | ["use_nix", ..] => setup.loader = Some(NixLoader::Nix),
+            ["watch_file" | "nix_direnv_watch_file", files @ ..] => {
+                setup.watches_lockfile |= files.iter().any(|file| file.ends_with("flake.lock"));
+            }
+            ["nix_direnv_version", version, ..] => {
+                setup.nix_direnv_version = Some((*version).to_owned());
+            }
+            ["nix_direnv_manual_reload", ..] => setup.manual_reload = true,
+            ["source_url", url, ..] => {
+                if let Some(version) = url
+                    .split_once("/nix-direnv/")
+                    .and_then(|(_, rest)| rest.split('/').next())
+                {
+                    setup.nix_direnv_version = Some(version.to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    setup
+}
+
+/// Returns the flake reference given to `--override-input <input_id>` on a `use flake` line.
+pub fn override_input(envrc: &str, input_id: &str) -> Option<String> {
+    envrc.lines().filter_map(use_flake_args).find_map(|args| {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--override-input" {
+                let id = args.next()?;
+                let flake_ref = args.next()?;
+                if id == input_id {
+                    return Some(flake_ref.clone());
+                }
+            }
+        }
+        None
+    })
+}
+
+/// Returns the directory of the local flake given to `use flake` in the `.envrc` of `directory`,
+/// if it's another directory.
+///
+/// nix-direnv keeps the `.direnv` cache next to `.envrc`, not next to the flake.
+pub fn flake_directory(directory: &Path) -> Option<PathBuf> {
+    let envrc = fs::read_to_string(directory.join(".envrc")).ok()?;
+    let flake_ref = envrc
+        .lines()
+        .filter_map(use_flake_args)
+        .find_map(|args| args.into_iter().next())?;
+    let flake_dir = fs::canonicalize(directory.join(local_flake_path(&flake_ref)?)).ok()?;
+    (flake_dir != fs::canonicalize(directory).ok()?).then_some(flake_dir)
+}
+
+/// Returns the path of a local flake reference like `../dir#devShell` or `path:./dir`.
+fn local_flake_path(flake_ref: &str) -> Option<&str> {
+    let path = flake_ref.strip_prefix("path:").unwrap_or(flake_ref);
+    let path = path.split(['#', '?']).next()?;
+    (path.starts_with('.') || path.starts_with('/')).then_some(path)
+}
+
+/// Splits the arguments of a `use flake` line.
+///
+/// Only handles simple quoting, which is enough for the usual `.envrc`.
+fn use_flake_args(line: &str) -> Option<Vec<String>> {
+    let line = line.trim();
+    let rest = line
+        .strip_prefix("use flake")
+        .or_else(|| line.strip_prefix("use_flake"))?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(
+        rest.split_whitespace()
+            .take_while(|arg| !arg.starts_with('#'))
+            .map(|arg| arg.trim_matches(|c| c == '"' || c == '\'').to_owned())
+            .collect(),
+    )
+}
+
+/// Returns the modification time of the newest `flake-profile-*` entry.
+fn newest_cached_profile(direnv_dir: &Path) -> Option<SystemTime> {
+    fs::read_dir(direnv_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .as_encoded_bytes()
+                .starts_with(b"flake-profile-")
+        })
+        .filter_map(|entry| modified(&entry.path()))
+        .max()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::symlink_metadata(path).ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_flake_paths() {
+        assert_eq!(local_flake_path("../other#devShell"), Some("../other"));
+        assert_eq!(local_flake_path("path:./dir?dir=sub"), Some("./dir"));
+        assert_eq!(local_flake_path("/abs/dir"), Some("/abs/dir"));
+        assert_eq!(local_flake_path("github:NixOS/nixpkgs"), None);
+        assert_eq!(local_flake_path("--impure"), None);
+    }
+
+    #[test]
+    fn direnv_setups() {
+        let setup = parse_setup(
+            "if ! has nix_direnv_version || ! nix_direnv_version 3.0.6; then\n\
+             source_url \"https://raw.githubusercontent.com/nix-community/nix-direnv/3.0.6/direnvrc\" \"sha256-x\"\n\
+             fi\n\
+             nix_direnv_manual_reload\n\
+             use flake\n",
+        );
+        assert_eq!(setup.nix_direnv_version.as_deref(), Some("3.0.6"));
+        assert_eq!(setup.loader, Some(NixLoader::Flake));
+        assert!(setup.manual_reload);
+
+        let setup = parse_setup("use nix # shell.nix\nwatch_file flake.lock\n");
+        assert_eq!(setup.loader, Some(NixLoader::Nix));
+        assert!(setup.watches_lockfile);
+        assert_eq!(setup.nix_direnv_version, None);
+    }
+
+    #[test]
+    fn use_flake_line() {
+        assert_eq!(
+            use_flake_args("use flake ../other#devShell --impure # comment"),
+            Some(vec!["../other#devShell".to_owned(), "--impure".to_owned()])
+        );
+        assert_eq!(use_flake_args("use_flake"), Some(vec![]));
+        assert_eq!(use_flake_args("use flakes"), None);
+    }
+}
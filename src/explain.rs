@@ -0,0 +1,174 @@
+//! `--explain`, which shows which checks against the target each flake passed and why it was or
+//! wasn't skipped.
+
+use std::{
+    fmt::Display,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+use color_eyre::Result;
+
+use nixpkgsupd::{
+    discovery::Flake,
+    lockfile::{Locked, LockfileNode},
+    registry,
+    target::MatchTarget,
+};
+
+use crate::{Cli, follow_path_input, is_up_to_date, print_flake_markers, ui::Themed};
+
+/// Prints why the flake in `directory` was skipped before its lock file was read.
+pub fn print_filtered(directory: &Path, reason: impl Display) {
+    println!(
+        "{}{} {}",
+        directory.display().muted(),
+        ":".muted(),
+        format_args!("skipped, {reason}").muted()
+    );
+}
+
+/// Prints each check of the locked input against the target and whether the flake is skipped.
+pub fn print_checks(
+    flake: &Flake<'_>,
+    cli: &Cli,
+    target: &MatchTarget,
+    lockfile_node: &LockfileNode,
+) -> Result<()> {
+    print_flake_markers(flake, cli);
+    println!("{}", ":".muted());
+
+    let followed = follow_path_input(flake, cli, target, lockfile_node);
+    if let Some((directory, _)) = &followed {
+        println!(
+            "  {:<10} {}",
+            "path".muted(),
+            format_args!(
+                "checking the input in {} instead",
+                directory.join("flake.lock").display()
+            )
+            .value()
+        );
+    }
+    let lockfile_node = followed.as_ref().map_or(lockfile_node, |(_, node)| node);
+
+    let locked = &lockfile_node.locked;
+    let checks = target.checks(lockfile_node, cli.ref_match_age())?;
+    print_check(
+        "ref",
+        checks.ref_,
+        registry::resolved_ref(&lockfile_node.original.inner).as_deref(),
+        registry::resolved_ref(target.original()).as_deref(),
+    );
+    if let Some(url) = locked.git_url() {
+        let url = format!("{url} {}", locked.git_ref().unwrap_or_default());
+        let target_url = target.locked().git_url().map(|target_url| {
+            format!(
+                "{target_url} {}",
+                target.locked().git_ref().unwrap_or_default()
+            )
+        });
+        print_check("git url", checks.git_url, Some(&url), target_url.as_deref());
+    }
+    match locked.last_modified() {
+        Some(ts) => {
+            let ts = SystemTime::UNIX_EPOCH + Duration::from_secs(ts);
+            let matches = checks.timestamp;
+            print_result(
+                "timestamp",
+                matches,
+                format_args!(
+                    "last modified {}, {} --ref-match-age of {}, which only counts with the ref or git url",
+                    chrono_humanize::HumanTime::from(ts),
+                    if matches { "within" } else { "outside" },
+                    humantime::format_duration(cli.ref_match_age())
+                ),
+            );
+        }
+        None => print_result("timestamp", false, "not locked"),
+    }
+    print_check("rev", checks.rev, locked.rev(), target.locked().rev());
+    print_check(
+        "url",
+        checks.url,
+        locked.url_no_git(),
+        target.locked().url_no_git(),
+    );
+    print_check(
+        "narHash",
+        checks.nar_hash,
+        locked.nar_hash(),
+        target.locked().nar_hash(),
+    );
+    print_path_check(checks.path_last_modified, lockfile_node);
+    for mismatch in &cli.mismatch {
+        let name = mismatch
+            .to_possible_value()
+            .map_or_else(String::new, |value| value.get_name().to_owned());
+        let applies = mismatch.applies(target, lockfile_node, cli.ref_match_age())?;
+        println!(
+            "  {:<10} {}",
+            "--mismatch".muted(),
+            format_args!(
+                "{name} {}",
+                if applies { "applies" } else { "doesn't apply" }
+            )
+            .value()
+        );
+    }
+
+    let matches_target = checks.matches();
+    let verdict = if is_up_to_date(cli, target, None, lockfile_node)? {
+        if matches_target {
+            "skipped, it matches the target".good()
+        } else {
+            "skipped, it doesn't differ in a way given with --mismatch".good()
+        }
+    } else {
+        "outdated".bad()
+    };
+    println!("  {:<10} {verdict}", "=>".muted());
+    Ok(())
+}
+
+/// Prints whether the `path:` input was modified after the target, if its own lock file couldn't
+/// be followed.
+fn print_path_check(matches: bool, lockfile_node: &LockfileNode) {
+    let locked = &lockfile_node.locked;
+    if let (Locked::Path { .. }, Some(ts)) = (locked, locked.last_modified()) {
+        print_result(
+            "modified",
+            matches,
+            format_args!(
+                "the path was last modified {}, which has to be after the target",
+                chrono_humanize::HumanTime::from(SystemTime::UNIX_EPOCH + Duration::from_secs(ts))
+            ),
+        );
+    }
+}
+
+/// Prints whether `value` of the input matches `target_value` of the target.
+fn print_check(name: &str, matches: bool, value: Option<&str>, target_value: Option<&str>) {
+    match (value, target_value) {
+        (None, _) => print_result(name, false, "not locked"),
+        (Some(value), _) if matches => {
+            print_result(name, true, format_args!("{value} matches the target"));
+        }
+        (Some(value), Some(target_value)) => print_result(
+            name,
+            false,
+            format_args!("{value} differs from the target's {target_value}"),
+        ),
+        (Some(value), None) => print_result(
+            name,
+            false,
+            format_args!("{value}, which the target doesn't have"),
+        ),
+    }
+}
+
+fn print_result(name: &str, passed: bool, explanation: impl Display) {
+    let mark = if passed { "pass".good() } else { "fail".bad() };
+    println!("  {:<10} {mark} {}", name.muted(), explanation.value());
+}
@@ -1,60 +1,296 @@
 use color_eyre::{
+    Report,
     eyre::{Context, Result},
-    owo_colors::OwoColorize,
 };
+use rnix::ast::{self, HasEntry};
+use rowan::ast::AstNode;
 
+/// The error returned when the URL of an input is computed, like from a `let` binding or an
+/// interpolated string, so it can't be replaced and has to be edited manually.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicInputUrl {
+    /// The 1-based line of the expression.
+    pub line: usize,
+    /// The 1-based column of the expression.
+    pub column: usize,
+}
+
+impl std::fmt::Display for DynamicInputUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The input URL is computed at line {}, column {} and must be edited manually",
+            self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for DynamicInputUrl {}
+
+impl DynamicInputUrl {
+    /// Returns the error if `err` was caused by a computed input URL.
+    #[must_use]
+    pub fn find(err: &Report) -> Option<Self> {
+        err.chain()
+            .find_map(|err| err.downcast_ref::<Self>())
+            .copied()
+    }
+}
+
+/// Replaces the URL of the input `flake_id` in the contents of a `flake.nix`.
+///
+/// An existing URL is replaced in place however the attribute path `inputs.<id>.url` is split
+/// across nested attribute sets, like `inputs = { nixpkgs = { url = "..."; }; };`. Otherwise
+/// `inputs.<id>.url` is added. Fails with [`DynamicInputUrl`] if the URL isn't a string literal.
+///
+/// # Errors
+///
+/// Fails if the contents can't be parsed or the URL isn't a string literal.
 pub fn replace_flake_input_url(
     new_flake_ref: &str,
     old_contents: &str,
     flake_id: &str,
 ) -> Result<String> {
+    let new_value = format!("{new_flake_ref:?}");
+    match find_input_url(old_contents, flake_id) {
+        Some(InputUrl::Literal(url)) => {
+            let range = url.syntax().text_range();
+            let mut new_contents = old_contents.to_owned();
+            new_contents.replace_range(
+                usize::from(range.start())..usize::from(range.end()),
+                &new_value,
+            );
+            return Ok(new_contents);
+        }
+        Some(InputUrl::Computed(expr)) => {
+            let (line, column) = line_column(old_contents, expr.syntax());
+            return Err(DynamicInputUrl { line, column }.into());
+        }
+        None => {}
+    }
+
     let input_url_path = &format!("inputs.{flake_id}.url");
 
-    let new_contents =
-        nix_editor::write::write(old_contents, input_url_path, &format!("{new_flake_ref:?}"))
-            .wrap_err("Invalid flake.nix")?;
+    let new_contents = nix_editor::write::write(old_contents, input_url_path, &new_value)
+        .wrap_err("Invalid flake.nix")?;
     Ok(new_contents)
 }
 
-pub fn print_diff(old_contents: &str, new_contents: &str, update_args: &crate::UpdateArgs) {
-    let diff = diff::lines(old_contents, new_contents);
-    let diff = reduce_diff_context(&diff, update_args.diff_context);
-    for line in diff {
-        match line {
-            diff::Result::Left(line) => println!("{}", format_args!("-{line}").red()),
-            diff::Result::Both(line, _) => println!(" {line}"),
-            diff::Result::Right(line) => println!("{}", format_args!("+{line}").green()),
+/// Returns the 1-based line and column of the URL of the input `flake_id` in the contents of a
+/// `flake.nix`, if it's there.
+#[must_use]
+pub fn input_url_position(contents: &str, flake_id: &str) -> Option<(usize, usize)> {
+    let expr = match find_input_url(contents, flake_id)? {
+        InputUrl::Literal(url) => url.syntax().clone(),
+        InputUrl::Computed(expr) => expr.syntax().clone(),
+    };
+    Some(line_column(contents, &expr))
+}
+
+/// Returns the 1-based line and column where `node` starts in `contents`.
+fn line_column(contents: &str, node: &rnix::SyntaxNode) -> (usize, usize) {
+    let before = &contents[..usize::from(node.text_range().start())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// How `inputs.<id>.url` is defined in a `flake.nix`.
+enum InputUrl {
+    /// A string without interpolation.
+    Literal(ast::Str),
+    /// Any other expression, or one that isn't an attribute set on the way to the URL, like
+    /// `inputs = import ./inputs.nix;`.
+    Computed(ast::Expr),
+}
+
+/// Returns how `inputs.<flake_id>.url` is defined in the top-level attribute set of a
+/// `flake.nix`, which may be `rec` or the body of `let ... in`.
+fn find_input_url(contents: &str, flake_id: &str) -> Option<InputUrl> {
+    let root = rnix::Root::parse(contents).tree();
+    let mut expr = root.expr()?;
+    let attr_set = loop {
+        match expr {
+            ast::Expr::AttrSet(attr_set) => break attr_set,
+            ast::Expr::LetIn(let_in) => expr = let_in.body()?,
+            ast::Expr::Paren(paren) => expr = paren.expr()?,
+            _ => return None,
+        }
+    };
+    match find_attr(&attr_set, &["inputs", flake_id, "url"])? {
+        (ast::Expr::Str(url), true)
+            if url
+                .parts()
+                .all(|part| matches!(part, ast::InterpolPart::Literal(_))) =>
+        {
+            Some(InputUrl::Literal(url))
         }
+        (expr, _) => Some(InputUrl::Computed(expr)),
     }
 }
 
-fn reduce_diff_context<T: PartialEq>(
-    input: &[diff::Result<T>],
-    context: usize,
-) -> Vec<&diff::Result<T>> {
-    let mut result = Vec::new();
-
-    let mut diff_indices = Vec::new();
-    for (idx, res) in input.iter().enumerate() {
-        if matches!(res, diff::Result::Left(_) | diff::Result::Right(_)) {
-            diff_indices.push(idx);
+/// Returns the value of the attribute at `path` in `attr_set`, following nested attribute sets,
+/// and `true`. If a value on the way isn't an attribute set, returns it and `false`.
+fn find_attr(attr_set: &ast::AttrSet, path: &[&str]) -> Option<(ast::Expr, bool)> {
+    attr_set.attrpath_values().find_map(|entry| {
+        let keys = attr_names(&entry.attrpath()?)?;
+        let rest = path
+            .get(keys.len()..)
+            .filter(|_| keys.iter().zip(path).all(|(key, name)| key == name))?;
+        let value = entry.value()?;
+        if rest.is_empty() {
+            return Some((value, true));
+        }
+        match value {
+            ast::Expr::AttrSet(nested) => find_attr(&nested, rest),
+            value => Some((value, false)),
         }
+    })
+}
+
+/// Returns the names in an attribute path, or `None` if one is computed, like `${name}`.
+fn attr_names(attrpath: &ast::Attrpath) -> Option<Vec<String>> {
+    attrpath
+        .attrs()
+        .map(|attr| match attr {
+            ast::Attr::Ident(ident) => Some(ident.ident_token()?.text().to_owned()),
+            ast::Attr::Str(str) => match str.normalized_parts().as_slice() {
+                [ast::InterpolPart::Literal(name)] => Some(name.clone()),
+                _ => None,
+            },
+            ast::Attr::Dynamic(_) => None,
+        })
+        .collect()
+}
+
+/// Makes the input `input_id` of the input `parent` follow the input `follows` in the contents
+/// of a `flake.nix`.
+///
+/// # Errors
+///
+/// Fails if the contents can't be parsed as Nix.
+pub fn add_follows(
+    old_contents: &str,
+    parent: &str,
+    input_id: &str,
+    follows: &str,
+) -> Result<String> {
+    let follows_path = &format!("inputs.{parent}.inputs.{input_id}.follows");
+
+    let new_contents =
+        nix_editor::write::write(old_contents, follows_path, &format!("{follows:?}"))
+            .wrap_err("Invalid flake.nix")?;
+    Ok(new_contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEW_URL: &str = "github:NixOS/nixpkgs/nixos-unstable";
+
+    fn assert_replaced(flake_nix: &str) {
+        let new = replace_flake_input_url(NEW_URL, flake_nix, "nixpkgs").unwrap();
+        assert_eq!(
+            new,
+            flake_nix.replace("github:NixOS/nixpkgs/nixos-24.05", NEW_URL)
+        );
     }
 
-    let len = input.len();
+    #[test]
+    fn replace_dotted_url() {
+        assert_replaced(
+            r#"{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-24.05";
+  outputs = { nixpkgs, ... }: { };
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn replace_nested_url() {
+        assert_replaced(
+            r#"{
+  inputs = {
+    nixpkgs = {
+      url = "github:NixOS/nixpkgs/nixos-24.05";
+    };
+    "flake-utils".url = "github:numtide/flake-utils";
+  };
+  outputs = { nixpkgs, ... }: { };
+}
+"#,
+        );
+        assert_replaced(
+            r#"{
+  inputs = {
+    "nixpkgs".url = "github:NixOS/nixpkgs/nixos-24.05";
+  };
+  outputs = { nixpkgs, ... }: { };
+}
+"#,
+        );
+        assert_replaced(
+            r#"{
+  inputs.nixpkgs = { url = "github:NixOS/nixpkgs/nixos-24.05"; };
+  outputs = { nixpkgs, ... }: { };
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn add_missing_url() {
+        let flake_nix = "{\n  inputs = { };\n  outputs = { ... }: { };\n}\n";
+        let new = replace_flake_input_url(NEW_URL, flake_nix, "nixpkgs").unwrap();
+        assert!(
+            matches!(find_input_url(&new, "nixpkgs"), Some(InputUrl::Literal(url)) if url.to_string() == format!("{NEW_URL:?}"))
+        );
+    }
 
-    for &diff_idx in &diff_indices {
-        // Determine the range: `Both` lines before and after the diff
-        let start = diff_idx.saturating_sub(context);
-        let end = (diff_idx + context + 1).min(len);
+    #[test]
+    fn url_position() {
+        let flake_nix = "{\n  inputs = {\n    nixpkgs.url = \"github:NixOS/nixpkgs\";\n  };\n}\n";
+        assert_eq!(input_url_position(flake_nix, "nixpkgs"), Some((3, 19)));
+        assert_eq!(input_url_position(flake_nix, "flake-utils"), None);
+    }
 
-        for diff in input.iter().take(end).skip(start) {
-            // Avoid duplicates
-            if !result.contains(&diff) {
-                result.push(diff);
-            }
+    #[test]
+    fn let_in_and_rec() {
+        let url = "inputs.nixpkgs.url = \"github:NixOS/nixpkgs\";";
+        for flake_nix in [
+            format!("let\n  x = 1;\nin\n{{\n  {url}\n}}\n"),
+            format!("rec {{\n  {url}\n}}\n"),
+            format!("let x = 1; in (rec {{\n  {url}\n}})\n"),
+        ] {
+            assert!(matches!(
+                find_input_url(&flake_nix, "nixpkgs"),
+                Some(InputUrl::Literal(_))
+            ));
         }
+        let flake_nix =
+            "let\n  url = \"github:NixOS/nixpkgs\";\nin\n{\n  inputs.nixpkgs.url = url;\n}\n";
+        let err = replace_flake_input_url(NEW_URL, flake_nix, "nixpkgs").unwrap_err();
+        let dynamic = DynamicInputUrl::find(&err).unwrap();
+        assert_eq!((dynamic.line, dynamic.column), (5, 24));
     }
 
-    result
+    #[test]
+    fn computed_url() {
+        for (flake_nix, column) in [
+            (
+                "{\n  inputs.nixpkgs.url = \"github:NixOS/nixpkgs/${branch}\";\n}\n",
+                24,
+            ),
+            ("{\n  inputs.nixpkgs.url = nixpkgsUrl;\n}\n", 24),
+            ("{\n  inputs.nixpkgs = nixpkgsInput;\n}\n", 20),
+        ] {
+            let err = replace_flake_input_url(NEW_URL, flake_nix, "nixpkgs").unwrap_err();
+            let dynamic = DynamicInputUrl::find(&err).unwrap();
+            assert_eq!((dynamic.line, dynamic.column), (2, column));
+        }
+    }
 }
@@ -0,0 +1,404 @@
+//! Native parsing and formatting of flake references.
+//!
+//! Mirrors `builtins.parseFlakeRef` and `builtins.flakeRefToString` for the URL-like syntax.
+//! The attribute set form is the same as `original` in lockfiles.
+//!
+//! <https://nix.dev/manual/nix/2.28/command-ref/new-cli/nix3-flake.html#flake-references>
+
+use std::fmt::Write;
+
+use color_eyre::{
+    Result,
+    eyre::{OptionExt, bail},
+};
+use serde_json::{Map, Value};
+
+/// Attribute set form of a flake reference.
+pub type Attrs = Map<String, Value>;
+
+const GIT_SERVICES: &[&str] = &["github", "gitlab", "sourcehut"];
+
+const TARBALL_EXTENSIONS: &[&str] = &[
+    ".zip", ".tar", ".tgz", ".tar.gz", ".tar.xz", ".tar.bz2", ".tar.zst",
+];
+
+/// Parameters that are booleans in the attribute set form and `0`/`1` in URLs.
+const BOOLEAN_PARAMS: &[&str] = &["shallow", "submodules", "allRefs", "exportIgnore", "lfs"];
+
+/// Parameters that are integers in the attribute set form.
+const INTEGER_PARAMS: &[&str] = &["lastModified", "revCount"];
+
+/// Parses a URL-like flake reference into its attribute set form.
+///
+/// Relative paths are not supported because they depend on the current directory.
+///
+/// # Errors
+///
+/// Fails on unsupported schemes and malformed references.
+pub fn parse(flake_ref: &str) -> Result<Attrs> {
+    let (flake_ref, query) = flake_ref
+        .split_once('?')
+        .map_or((flake_ref, None), |(flake_ref, query)| {
+            (flake_ref, Some(query))
+        });
+
+    let mut attrs = Attrs::new();
+
+    if let Some((scheme, rest)) = flake_ref.split_once(':') {
+        if GIT_SERVICES.contains(&scheme) {
+            let mut segments = rest.splitn(3, '/');
+            let (Some(owner), Some(repo)) = (segments.next(), segments.next()) else {
+                bail!("Expected `{scheme}:<owner>/<repo>`");
+            };
+            attrs.insert("owner".to_owned(), owner.into());
+            attrs.insert("repo".to_owned(), repo.into());
+            if let Some(rev_or_ref) = segments.next() {
+                insert_rev_or_ref(&mut attrs, rev_or_ref);
+            }
+            attrs.insert("type".to_owned(), scheme.into());
+        } else if scheme == "flake" {
+            parse_indirect(&mut attrs, rest)?;
+        } else if scheme == "path" {
+            attrs.insert("path".to_owned(), percent_decode(rest)?.into());
+            attrs.insert("type".to_owned(), "path".into());
+        } else if let Some((type_, url)) = scheme
+            .split_once('+')
+            .map(|(type_, transport)| (type_, format!("{transport}:{rest}")))
+        {
+            let type_ = match type_ {
+                "git" | "hg" | "tarball" | "file" => type_,
+                _ => bail!("Unsupported flake reference type `{type_}`"),
+            };
+            attrs.insert("type".to_owned(), type_.into());
+            attrs.insert("url".to_owned(), url.into());
+        } else if matches!(scheme, "http" | "https") {
+            let type_ = if has_tarball_extension(rest) {
+                "tarball"
+            } else {
+                "file"
+            };
+            attrs.insert("type".to_owned(), type_.into());
+            attrs.insert("url".to_owned(), flake_ref.into());
+        } else {
+            bail!("Unsupported flake reference scheme `{scheme}`");
+        }
+    } else if flake_ref.starts_with('/') {
+        attrs.insert("path".to_owned(), percent_decode(flake_ref)?.into());
+        attrs.insert("type".to_owned(), "path".into());
+    } else {
+        parse_indirect(&mut attrs, flake_ref)?;
+    }
+
+    for param in query.into_iter().flat_map(|query| query.split('&')) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode(value)?;
+        let value = if BOOLEAN_PARAMS.contains(&name) {
+            Value::Bool(value == "1")
+        } else if INTEGER_PARAMS.contains(&name) {
+            Value::Number(value.parse::<u64>()?.into())
+        } else {
+            value.into()
+        };
+        attrs.insert(name.to_owned(), value);
+    }
+
+    Ok(attrs)
+}
+
+fn parse_indirect(attrs: &mut Attrs, rest: &str) -> Result<()> {
+    let mut segments = rest.splitn(3, '/');
+    let id = segments.next().unwrap_or_default();
+    if !id.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!("Invalid flake ID `{id}`");
+    }
+    attrs.insert("id".to_owned(), id.into());
+    if let Some(rev_or_ref) = segments.next() {
+        insert_rev_or_ref(attrs, rev_or_ref);
+    }
+    if let Some(rev) = segments.next() {
+        attrs.insert("rev".to_owned(), rev.into());
+    }
+    attrs.insert("type".to_owned(), "indirect".into());
+    Ok(())
+}
+
+fn insert_rev_or_ref(attrs: &mut Attrs, rev_or_ref: &str) {
+    let key = if is_rev(rev_or_ref) { "rev" } else { "ref" };
+    attrs.insert(key.to_owned(), rev_or_ref.into());
+}
+
+fn is_rev(s: &str) -> bool {
+    s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn has_tarball_extension(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    TARBALL_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+/// Formats the attribute set form of a flake reference as a URL-like flake reference.
+///
+/// # Errors
+///
+/// Fails if the type is missing or unsupported, or an attribute it needs is missing.
+pub fn to_string(attrs: &Attrs) -> Result<String> {
+    let get_str = |name: &str| attrs.get(name).and_then(Value::as_str);
+    let type_ = get_str("type").ok_or_eyre("Flake reference has no type")?;
+
+    let (base, used): (String, &[&str]) = match type_ {
+        "indirect" => {
+            let mut base = format!("flake:{}", get_str("id").ok_or_eyre("Missing `id`")?);
+            if let Some(ref_) = get_str("ref") {
+                base.push('/');
+                base.push_str(ref_);
+            }
+            if let Some(rev) = get_str("rev") {
+                base.push('/');
+                base.push_str(rev);
+            }
+            (base, &["type", "id", "ref", "rev"])
+        }
+        type_ if GIT_SERVICES.contains(&type_) => {
+            let mut base = format!(
+                "{type_}:{}/{}",
+                get_str("owner").ok_or_eyre("Missing `owner`")?,
+                get_str("repo").ok_or_eyre("Missing `repo`")?
+            );
+            match (get_str("ref"), get_str("rev")) {
+                (Some(_), Some(_)) => bail!("Both `ref` and `rev` are set"),
+                (Some(rev_or_ref), None) | (None, Some(rev_or_ref)) => {
+                    base.push('/');
+                    base.push_str(rev_or_ref);
+                }
+                (None, None) => {}
+            }
+            (base, &["type", "owner", "repo", "ref", "rev"])
+        }
+        "path" => (
+            format!("path:{}", get_str("path").ok_or_eyre("Missing `path`")?),
+            &["type", "path"],
+        ),
+        "git" | "hg" => (
+            format!("{type_}+{}", get_str("url").ok_or_eyre("Missing `url`")?),
+            &["type", "url"],
+        ),
+        "tarball" | "file" => {
+            let url = get_str("url").ok_or_eyre("Missing `url`")?;
+            let base = if has_tarball_extension(url) == (type_ == "tarball") {
+                url.to_owned()
+            } else {
+                format!("{type_}+{url}")
+            };
+            (base, &["type", "url"])
+        }
+        _ => bail!("Unsupported flake reference type `{type_}`"),
+    };
+
+    // Sorted because `Map` is a `BTreeMap` without the `preserve_order` feature, like in Nix
+    let query = attrs
+        .iter()
+        .filter(|(name, _)| !used.contains(&name.as_str()))
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => percent_encode(value),
+                Value::Bool(value) => u8::from(*value).to_string(),
+                Value::Number(value) => value.to_string(),
+                _ => bail!("Unsupported value for parameter `{name}`"),
+            };
+            Ok(format!("{name}={value}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if query.is_empty() {
+        Ok(base)
+    } else {
+        let separator = if base.contains('?') { '&' } else { '?' };
+        Ok(format!("{base}{separator}{}", query.join("&")))
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~:@/".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().ok_or_eyre("Truncated percent encoding")?,
+                bytes.next().ok_or_eyre("Truncated percent encoding")?,
+            ];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for flake_ref in [
+            "flake:nixpkgs",
+            "flake:nixpkgs/nixos-unstable",
+            "github:NixOS/nixpkgs/nixos-unstable",
+            "github:NixOS/nixpkgs/6e987485eb2c77e5dcc5af4e3c70843711ef9251",
+            "gitlab:mirrors/nixpkgs?host=gitlab.example.com",
+            "sourcehut:~axelkar/overlay/main?dir=pkgs",
+            "path:/home/axel/dev/nixpkgs",
+            "git+https://github.com/NixOS/nixpkgs.git?ref=nixos-unstable&shallow=1",
+            "https://nixos.org/channels/nixos-unstable/nixexprs.tar.xz",
+            "tarball+https://example.org/archive",
+            "hg+https://hg.example.com/nixpkgs",
+        ] {
+            assert_eq!(to_string(&parse(flake_ref).unwrap()).unwrap(), flake_ref);
+        }
+    }
+
+    #[test]
+    fn parses_like_nix() {
+        let attrs = parse("nixpkgs/nixos-unstable").unwrap();
+        assert_eq!(
+            Value::Object(attrs),
+            serde_json::json!({ "id": "nixpkgs", "ref": "nixos-unstable", "type": "indirect" })
+        );
+
+        let attrs = parse("git+ssh://git@example.org/repo?shallow=1&revCount=3").unwrap();
+        assert_eq!(
+            Value::Object(attrs),
+            serde_json::json!({
+                "revCount": 3,
+                "shallow": true,
+                "type": "git",
+                "url": "ssh://git@example.org/repo"
+            })
+        );
+    }
+
+    /// Differential tests against the `nix-instantiate` in `PATH`.
+    #[cfg(feature = "nix-differential")]
+    mod differential {
+        use std::process::Command;
+
+        use proptest::prelude::*;
+
+        use super::super::*;
+
+        fn nix_eval(expr: &str, flake_ref: &str) -> Option<Value> {
+            let output = Command::new("nix-instantiate")
+                .args([
+                    "--extra-experimental-features",
+                    "nix-command flakes",
+                    "--eval",
+                    "--json",
+                    "--strict",
+                    "--expr",
+                    &format!("{{ s }}: {expr}"),
+                    "--argstr",
+                    "s",
+                    flake_ref,
+                ])
+                .output()
+                .expect("nix-instantiate must be installed for differential tests");
+            output
+                .status
+                .success()
+                .then(|| serde_json::from_slice(&output.stdout).unwrap())
+        }
+
+        fn flake_ref() -> impl Strategy<Value = String> {
+            let rev_or_ref = prop_oneof!["[a-z][a-z0-9.-]{0,15}", "[0-9a-f]{40}"];
+            let params = proptest::collection::btree_map(
+                prop_oneof![Just("dir"), Just("host"), Just("narHash"), Just("shallow")],
+                prop_oneof!["[a-z0-9/+=-]{1,10}", Just("1".to_owned())],
+                0..3,
+            )
+            .prop_map(|params| {
+                params
+                    .into_iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>()
+                    .join("&")
+            });
+            let base = prop_oneof![
+                (
+                    "[a-z][a-z0-9-]{0,10}",
+                    proptest::option::of(rev_or_ref.clone())
+                )
+                    .prop_map(|(id, rev_or_ref)| {
+                        rev_or_ref.map_or_else(
+                            || format!("flake:{id}"),
+                            |rev_or_ref| format!("flake:{id}/{rev_or_ref}"),
+                        )
+                    }),
+                (
+                    prop_oneof![Just("github"), Just("gitlab"), Just("sourcehut")],
+                    "[A-Za-z0-9~-]{1,10}",
+                    "[A-Za-z0-9-]{1,10}",
+                    proptest::option::of(rev_or_ref)
+                )
+                    .prop_map(|(type_, owner, repo, rev_or_ref)| {
+                        let base = format!("{type_}:{owner}/{repo}");
+                        rev_or_ref.map_or_else(
+                            || base.clone(),
+                            |rev_or_ref| format!("{base}/{rev_or_ref}"),
+                        )
+                    }),
+                "path:/[a-z/]{1,20}",
+                "git\\+(https|ssh)://[a-z]{1,10}\\.org/[a-z]{1,10}",
+                "https://[a-z]{1,10}\\.org/[a-z]{1,10}\\.tar\\.(gz|xz)",
+            ];
+            (base, params).prop_map(|(base, params)| {
+                if params.is_empty() {
+                    base
+                } else {
+                    format!("{base}?{params}")
+                }
+            })
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn parse_matches_nix(flake_ref in flake_ref()) {
+                let Some(Value::Object(expected)) =
+                    nix_eval("builtins.parseFlakeRef s", &flake_ref)
+                else {
+                    // Nix rejects it, so the native result doesn't matter
+                    return Ok(());
+                };
+                prop_assert_eq!(parse(&flake_ref).ok(), Some(expected));
+            }
+
+            #[test]
+            fn to_string_matches_nix(flake_ref in flake_ref()) {
+                let (Some(Value::Object(attrs)), Some(Value::String(expected))) = (
+                    nix_eval("builtins.parseFlakeRef s", &flake_ref),
+                    nix_eval("builtins.flakeRefToString (builtins.parseFlakeRef s)", &flake_ref),
+                ) else {
+                    return Ok(());
+                };
+                prop_assert_eq!(to_string(&attrs).ok(), Some(expected));
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Queries to Git forges about [`Locked::GitService`] inputs.
+//!
+//! Requests are made with `curl` like other external tools are spawned in this crate.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+use serde::{Deserialize, de::DeserializeOwned};
+
+use nixpkgsupd::lockfile::{GitServiceType, Locked};
+
+/// Commits between two revisions of a repository.
+pub struct Comparison {
+    pub total_commits: usize,
+    /// First lines of the commit messages, oldest first. May be truncated by the forge.
+    pub titles: Vec<String>,
+}
+
+/// Fetches the commits from `base` to `head` in the repository of `locked`.
+pub fn compare(locked: &Locked, base: &str, head: &str) -> Result<Comparison> {
+    let Locked::GitService {
+        type_,
+        owner,
+        repo,
+        host,
+        ..
+    } = locked
+    else {
+        bail!("Only GitHub and GitLab inputs are supported");
+    };
+
+    match type_ {
+        GitServiceType::GitHub => {
+            #[derive(Deserialize)]
+            struct GitHubComparison {
+                total_commits: usize,
+                commits: Vec<GitHubCommit>,
+            }
+            #[derive(Deserialize)]
+            struct GitHubCommit {
+                commit: GitHubCommitDetails,
+            }
+            #[derive(Deserialize)]
+            struct GitHubCommitDetails {
+                message: String,
+            }
+
+            let (api, headers) = github_api(host.as_deref());
+            let comparison: GitHubComparison = fetch_json(
+                &format!("{api}/repos/{owner}/{repo}/compare/{base}...{head}"),
+                &headers,
+            )?;
+
+            Ok(Comparison {
+                total_commits: comparison.total_commits,
+                titles: comparison
+                    .commits
+                    .into_iter()
+                    .map(|commit| first_line(&commit.commit.message))
+                    .collect(),
+            })
+        }
+        GitServiceType::GitLab => {
+            #[derive(Deserialize)]
+            struct GitLabComparison {
+                commits: Vec<GitLabCommit>,
+            }
+            #[derive(Deserialize)]
+            struct GitLabCommit {
+                title: String,
+            }
+
+            let host = host.as_deref().unwrap_or("gitlab.com");
+            let project = format!("{owner}/{repo}").replace('/', "%2F");
+            let comparison: GitLabComparison = fetch_json(
+                &format!(
+                    "https://{host}/api/v4/projects/{project}/repository/compare?from={base}&to={head}"
+                ),
+                &[],
+            )?;
+
+            Ok(Comparison {
+                total_commits: comparison.commits.len(),
+                titles: comparison
+                    .commits
+                    .into_iter()
+                    .map(|commit| commit.title)
+                    .collect(),
+            })
+        }
+        GitServiceType::Sourcehut => bail!("Sourcehut doesn't have a public compare API"),
+    }
+}
+
+/// How far a fork's ref has diverged from upstream.
+pub struct Divergence {
+    /// Commits in the fork that upstream doesn't have.
+    pub ahead_by: usize,
+    /// Commits in upstream that the fork doesn't have.
+    pub behind_by: usize,
+}
+
+/// Compares `fork_ref` of the fork `fork` to `base` in its upstream repository `upstream`.
+///
+/// Only GitHub can compare across forks.
+pub fn fork_divergence(
+    fork: &Locked,
+    fork_ref: &str,
+    upstream: &Locked,
+    base: &str,
+) -> Result<Divergence> {
+    #[derive(Deserialize)]
+    struct GitHubComparison {
+        ahead_by: usize,
+        behind_by: usize,
+    }
+
+    let (
+        Locked::GitService {
+            type_: GitServiceType::GitHub,
+            owner: fork_owner,
+            ..
+        },
+        Locked::GitService {
+            type_: GitServiceType::GitHub,
+            owner,
+            repo,
+            host,
+            ..
+        },
+    ) = (fork, upstream)
+    else {
+        bail!("Only forks on GitHub are supported");
+    };
+
+    let (api, headers) = github_api(host.as_deref());
+    let comparison: GitHubComparison = fetch_json(
+        &format!("{api}/repos/{owner}/{repo}/compare/{base}...{fork_owner}:{fork_ref}"),
+        &headers,
+    )?;
+    Ok(Divergence {
+        ahead_by: comparison.ahead_by,
+        behind_by: comparison.behind_by,
+    })
+}
+
+/// Returns the API URL of GitHub or a GitHub Enterprise `host`, and the headers to send, with
+/// `GITHUB_TOKEN` if it's set.
+fn github_api(host: Option<&str>) -> (String, Vec<String>) {
+    let api = host.map_or_else(
+        || "https://api.github.com".to_owned(),
+        |host| format!("https://{host}/api/v3"),
+    );
+    let mut headers = vec!["Accept: application/vnd.github+json".to_owned()];
+    if let Some(token) = std::env::var_os("GITHUB_TOKEN") {
+        headers.push(format!("Authorization: Bearer {}", token.to_string_lossy()));
+    }
+    (api, headers)
+}
+
+/// Returns a web page showing the changes from `base` to `head` in the repository of `locked`.
+pub fn compare_url(locked: &Locked, base: &str, head: &str) -> Option<String> {
+    let Locked::GitService {
+        type_,
+        owner,
+        repo,
+        host,
+        ..
+    } = locked
+    else {
+        return None;
+    };
+
+    Some(match type_ {
+        GitServiceType::GitHub => {
+            let host = host.as_deref().unwrap_or("github.com");
+            format!("https://{host}/{owner}/{repo}/compare/{base}...{head}")
+        }
+        GitServiceType::GitLab => {
+            let host = host.as_deref().unwrap_or("gitlab.com");
+            format!("https://{host}/{owner}/{repo}/-/compare/{base}...{head}")
+        }
+        // No compare view, so show the history leading to `head`
+        GitServiceType::Sourcehut => {
+            let host = host.as_deref().unwrap_or("git.sr.ht");
+            format!("https://{host}/{owner}/{repo}/log/{head}")
+        }
+    })
+}
+
+fn first_line(message: &str) -> String {
+    message.lines().next().unwrap_or_default().to_owned()
+}
+
+/// Headers are written to curl's standard input rather than passed as arguments, so a token in
+/// them doesn't show up in `ps`.
+fn fetch_json<T: DeserializeOwned>(url: &str, headers: &[String]) -> Result<T> {
+    let mut child = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--header", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    {
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_eyre("curl has no standard input")?;
+        for header in headers {
+            writeln!(stdin, "{header}")?;
+        }
+    }
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        bail!("Command failed with {}", output.status);
+    }
+
+    serde_json::from_slice(&output.stdout).wrap_err("Failed to parse response")
+}
@@ -0,0 +1,355 @@
+//! Rendering of a flake's lockfile node graph.
+
+use std::{fmt::Write, path::Path};
+
+use color_eyre::Result;
+
+use nixpkgsupd::lockfile::{InputEdge, LockGraph, LockfileNode, load_lockfile_graph};
+
+use crate::ui::Themed;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+    /// Indented text tree, like `list --tree`
+    Tree,
+}
+
+/// How a node is highlighted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Highlight {
+    None,
+    /// The node the targeted input of the root points to.
+    Target,
+    /// Another node locking the same repository as the target.
+    Duplicate,
+}
+
+/// Decides the highlight of nodes by the target input of the root.
+struct Highlighter<'a> {
+    target_id: Option<&'a str>,
+    target_key: Option<String>,
+}
+
+impl<'a> Highlighter<'a> {
+    fn new(graph: &'a LockGraph, input_id: &str) -> Self {
+        let target_id = graph.resolve(&graph.root_id, input_id);
+        let target_key = target_id
+            .and_then(|id| graph.nodes.get(id)?.decode().ok())
+            .and_then(|node| node.locked.repository_key());
+        Self {
+            target_id,
+            target_key,
+        }
+    }
+
+    fn highlight(&self, id: &str, decoded: Option<&LockfileNode>) -> Highlight {
+        if Some(id) == self.target_id {
+            Highlight::Target
+        } else if self.target_key.is_some()
+            && decoded.and_then(|node| node.locked.repository_key()) == self.target_key
+        {
+            Highlight::Duplicate
+        } else {
+            Highlight::None
+        }
+    }
+}
+
+/// Returns the original flake reference of the node.
+fn original_flake_ref(node: &LockfileNode) -> Option<String> {
+    serde_json::to_value(&node.original)
+        .ok()
+        .and_then(|attrs| nixpkgsupd::flake_ref::to_string(attrs.as_object()?).ok())
+}
+
+struct Node {
+    id: String,
+    label: String,
+    highlight: Highlight,
+}
+
+struct Edge<'a> {
+    from: &'a str,
+    to: &'a str,
+    input_id: &'a str,
+    follows: bool,
+}
+
+/// Renders the node graph of `directory/flake.lock`.
+pub fn render_graph(directory: &Path, input_id: &str, format: GraphFormat) -> Result<String> {
+    render_lockfile(&directory.join("flake.lock"), input_id, format)
+}
+
+/// Renders the node graph of the lockfile at `path`.
+pub fn render_lockfile(path: &Path, input_id: &str, format: GraphFormat) -> Result<String> {
+    Ok(render(&load_lockfile_graph(path)?, input_id, format))
+}
+
+fn render(graph: &LockGraph, input_id: &str, format: GraphFormat) -> String {
+    let highlighter = Highlighter::new(graph, input_id);
+    match format {
+        GraphFormat::Dot => {
+            let (nodes, edges) = nodes_and_edges(graph, &highlighter);
+            render_dot(&nodes, &edges)
+        }
+        GraphFormat::Mermaid => {
+            let (nodes, edges) = nodes_and_edges(graph, &highlighter);
+            render_mermaid(&nodes, &edges)
+        }
+        GraphFormat::Tree => {
+            let mut out = String::new();
+            render_tree(
+                &mut out,
+                graph,
+                &highlighter,
+                &graph.root_id,
+                "",
+                &mut vec![graph.root_id.as_str()],
+            );
+            out
+        }
+    }
+}
+
+/// Returns the labeled nodes and the resolved edges of the graph.
+fn nodes_and_edges<'a>(
+    graph: &'a LockGraph,
+    highlighter: &Highlighter,
+) -> (Vec<Node>, Vec<Edge<'a>>) {
+    let nodes = graph
+        .nodes
+        .iter()
+        .map(|(id, node)| {
+            let decoded = node.decode().ok();
+            let highlight = highlighter.highlight(id, decoded.as_ref());
+
+            let mut label = id.clone();
+            if let Some(decoded) = &decoded {
+                if let Some(flake_ref) = original_flake_ref(decoded) {
+                    label.push('\n');
+                    label.push_str(&flake_ref);
+                }
+                if let Some(rev) = decoded.locked.rev() {
+                    label.push('\n');
+                    label.push_str(rev.get(..7).unwrap_or(rev));
+                }
+            }
+
+            Node {
+                id: id.clone(),
+                label,
+                highlight,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let edges = graph
+        .nodes
+        .iter()
+        .flat_map(|(from, node)| {
+            node.inputs.iter().filter_map(move |(input_id, edge)| {
+                Some(Edge {
+                    from,
+                    to: graph.resolve(from, input_id)?,
+                    input_id,
+                    follows: matches!(edge, InputEdge::Follows(_)),
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (nodes, edges)
+}
+
+/// Writes the inputs of `node_id` and their inputs as a tree. `ancestors` guards against cycles.
+fn render_tree<'a>(
+    out: &mut String,
+    graph: &'a LockGraph,
+    highlighter: &Highlighter,
+    node_id: &str,
+    prefix: &str,
+    ancestors: &mut Vec<&'a str>,
+) {
+    let Some(node) = graph.nodes.get(node_id) else {
+        return;
+    };
+    for (index, (input_id, edge)) in node.inputs.iter().enumerate() {
+        let last = index + 1 == node.inputs.len();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        let _ = write!(out, "{}", format_args!("{prefix}{branch}").muted());
+        let child_id = match edge {
+            InputEdge::Follows(path) => {
+                let _ = writeln!(
+                    out,
+                    "{input_id} {}",
+                    format_args!("follows {}", path.join("/")).muted()
+                );
+                continue;
+            }
+            InputEdge::Node(child_id) => child_id.as_str(),
+        };
+
+        let decoded = graph
+            .nodes
+            .get(child_id)
+            .and_then(|node| node.decode().ok());
+        match highlighter.highlight(child_id, decoded.as_ref()) {
+            Highlight::Target => {
+                let _ = write!(out, "{}", input_id.good());
+            }
+            Highlight::Duplicate => {
+                let _ = write!(out, "{}", input_id.warning());
+            }
+            Highlight::None => out.push_str(input_id),
+        }
+        if let Some(decoded) = &decoded {
+            if let Some(flake_ref) = original_flake_ref(decoded) {
+                let _ = write!(out, " {}", flake_ref.muted());
+            }
+            if let Some(rev) = decoded.locked.rev() {
+                let _ = write!(out, " {}", rev.get(..7).unwrap_or(rev).value());
+            }
+        }
+        if ancestors.contains(&child_id) {
+            let _ = writeln!(out, " {}", "(cycle)".bad());
+            continue;
+        }
+        out.push('\n');
+
+        ancestors.push(child_id);
+        render_tree(
+            out,
+            graph,
+            highlighter,
+            child_id,
+            &format!("{prefix}{indent}"),
+            ancestors,
+        );
+        ancestors.pop();
+    }
+}
+
+fn render_dot(nodes: &[Node], edges: &[Edge]) -> String {
+    let mut out = String::from("digraph \"flake.lock\" {\n  node [shape=box];\n");
+    for node in nodes {
+        let _ = write!(out, "  {:?} [label={:?}", node.id, node.label);
+        match node.highlight {
+            Highlight::Target => out.push_str(", style=filled, fillcolor=palegreen"),
+            Highlight::Duplicate => out.push_str(", style=filled, fillcolor=lightsalmon"),
+            Highlight::None => {}
+        }
+        out.push_str("];\n");
+    }
+    for edge in edges {
+        let _ = write!(
+            out,
+            "  {:?} -> {:?} [label={:?}",
+            edge.from, edge.to, edge.input_id
+        );
+        if edge.follows {
+            out.push_str(", style=dashed");
+        }
+        out.push_str("];\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn render_mermaid(nodes: &[Node], edges: &[Edge]) -> String {
+    // Node IDs may contain characters Mermaid doesn't accept in identifiers
+    let index_of = |id: &str| nodes.iter().position(|node| node.id == id);
+
+    let mut out = String::from("flowchart LR\n");
+    for (index, node) in nodes.iter().enumerate() {
+        let label = node.label.replace('"', "#quot;").replace('\n', "<br/>");
+        let _ = writeln!(out, "  n{index}[\"{label}\"]");
+    }
+    for edge in edges {
+        let (Some(from), Some(to)) = (index_of(edge.from), index_of(edge.to)) else {
+            continue;
+        };
+        let arrow = if edge.follows { "-.->" } else { "-->" };
+        let _ = writeln!(out, "  n{from} {arrow}|{}| n{to}", edge.input_id);
+    }
+    out.push_str("  classDef target fill:#98fb98\n  classDef duplicate fill:#ffa07a\n");
+    for (index, node) in nodes.iter().enumerate() {
+        match node.highlight {
+            Highlight::Target => {
+                let _ = writeln!(out, "  class n{index} target");
+            }
+            Highlight::Duplicate => {
+                let _ = writeln!(out, "  class n{index} duplicate");
+            }
+            Highlight::None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_graph(name: &str) -> LockGraph {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/lockfiles")
+            .join(name);
+        load_lockfile_graph(&path).unwrap()
+    }
+
+    #[test]
+    fn dot_highlights_target_and_follows() {
+        let dot = render(
+            &fixture_graph("github-follows.lock"),
+            "nixpkgs",
+            GraphFormat::Dot,
+        );
+        assert!(dot.contains("\"root\" -> \"nixpkgs\" [label=\"nixpkgs\"];"));
+        assert!(dot.contains("\"home-manager\" -> \"nixpkgs\" [label=\"nixpkgs\", style=dashed];"));
+        assert!(dot.contains(
+            "\"nixpkgs\" [label=\"nixpkgs\\ngithub:NixOS/nixpkgs/nixos-unstable\\n6e98748\", style=filled, fillcolor=palegreen];"
+        ));
+    }
+
+    #[test]
+    fn mermaid_lists_every_edge() {
+        let mermaid = render(
+            &fixture_graph("github-follows.lock"),
+            "nixpkgs",
+            GraphFormat::Mermaid,
+        );
+        assert_eq!(mermaid.matches("-->").count(), 4);
+        assert_eq!(mermaid.matches("-.->").count(), 1);
+        assert_eq!(mermaid.matches("class n").count(), 1);
+    }
+
+    #[test]
+    fn tree_nests_inputs() {
+        let tree = render(
+            &fixture_graph("github-follows.lock"),
+            "nixpkgs",
+            GraphFormat::Tree,
+        );
+        let tree = regex::Regex::new("\x1b\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(&tree, "");
+        assert_eq!(
+            tree,
+            "\
+├── flake-utils github:numtide/flake-utils 11707dc
+│   └── systems github:nix-systems/default da67096
+├── home-manager github:nix-community/home-manager d0300c8
+│   └── nixpkgs follows nixpkgs
+└── nixpkgs github:NixOS/nixpkgs/nixos-unstable 6e98748
+"
+        );
+    }
+}
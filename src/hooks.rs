@@ -0,0 +1,48 @@
+//! User commands run before and after updating a flake.
+
+use std::process::Command;
+
+use color_eyre::Result;
+
+use nixpkgsupd::{discovery::Flake, sigint_guard};
+
+use crate::ui::Themed;
+
+/// Revisions of the input passed to hooks in the environment.
+pub struct HookRevs<'a> {
+    pub old: Option<&'a str>,
+    pub new: Option<&'a str>,
+}
+
+/// Runs `command` with `sh -c` in the flake's directory and returns whether it succeeded.
+///
+/// The command gets `NIXPKGSUPD_FLAKE_DIR`, `NIXPKGSUPD_INPUT` and, when known,
+/// `NIXPKGSUPD_OLD_REV` and `NIXPKGSUPD_NEW_REV` in its environment.
+pub fn run_hook(name: &str, command: &str, flake: &Flake, revs: &HookRevs) -> Result<bool> {
+    let _span = tracing::debug_span!("hook", name, command).entered();
+
+    eprintln!(
+        "{} {}",
+        format_args!("Running {name}:").muted(),
+        command.command()
+    );
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command])
+        .current_dir(&flake.directory)
+        .env("NIXPKGSUPD_FLAKE_DIR", &flake.directory)
+        .env("NIXPKGSUPD_INPUT", flake.id);
+    if let Some(rev) = revs.old {
+        cmd.env("NIXPKGSUPD_OLD_REV", rev);
+    }
+    if let Some(rev) = revs.new {
+        cmd.env("NIXPKGSUPD_NEW_REV", rev);
+    }
+
+    let status = sigint_guard::status(&mut cmd)?;
+    tracing::debug!(%status, "Hook exited");
+    let success = status.success();
+    if !success {
+        eprintln!("{}", format_args!("The {name} failed").bad());
+    }
+    Ok(success)
+}
@@ -0,0 +1,37 @@
+//! Finding the flakes used on a machine from Nix's garbage collector roots and checking whether
+//! an input of them matches a target, as done by the `nixpkgsupd` command.
+//!
+//! ```no_run
+//! use nixpkgsupd::{config::Config, discovery, lockfile, target};
+//!
+//! # fn main() -> color_eyre::Result<()> {
+//! let target = target::resolve_target("github:NixOS/nixpkgs/nixos-unstable", None)?;
+//! for flake in &discovery::discover_flakes("nixpkgs", &Config::default())? {
+//!     let input = lockfile::load_lockfile_input(&flake.lockfile_path, flake.id)?;
+//!     let month = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+//!     if !target.is_up_to_date(&input, month)? {
+//!         println!("{} is outdated", flake.directory.display());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod channels;
+pub mod command;
+pub mod config;
+pub mod devenv;
+pub mod discovery;
+pub mod envrc;
+pub mod flake_nix;
+pub mod flake_ref;
+pub mod lockfile;
+#[cfg(feature = "nix-c-api")]
+pub mod nix_c;
+pub mod registry;
+mod serde_int_tag_hack;
+pub mod sigint_guard;
+pub mod store;
+pub mod system;
+pub mod tags;
+pub mod target;
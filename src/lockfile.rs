@@ -1,41 +1,405 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
 
 use color_eyre::eyre::{OptionExt, Result, WrapErr};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, value::RawValue};
 
-use crate::{Cli, serde_int_tag_hack::Version};
+use crate::{registry, serde_int_tag_hack::Version};
 
+/// A `flake.lock` whose nodes are only parsed when they're needed.
 #[derive(Deserialize)]
-#[serde(untagged)]
-pub enum Lockfile {
-    V7 {
-        #[serde(rename = "version")]
-        _version: Version<7>,
-        #[serde(rename = "root")]
-        root_id: String,
-        #[serde(rename = "nodes")]
-        raw_nodes: HashMap<String, Value>,
-    },
+pub struct Lockfile {
+    #[serde(rename = "version")]
+    _version: Version<7>,
+    #[serde(rename = "root")]
+    root_id: String,
+    /// Unparsed JSON of each node, so only the nodes on the way to an input are parsed.
+    #[serde(rename = "nodes")]
+    raw_nodes: HashMap<String, Box<RawValue>>,
 }
+
+/// Only the edges of a node, skipping everything else without allocating.
+#[derive(Deserialize)]
+struct NodeInputs {
+    #[serde(default)]
+    inputs: BTreeMap<String, InputEdge>,
+}
+
 impl Lockfile {
-    pub fn extract_input(self, input_id: &str) -> Result<LockfileNode> {
-        let Self::V7 {
-            root_id, raw_nodes, ..
-        } = self;
-        let raw_node = raw_nodes
-            .get(&root_id)
-            .and_then(|root_node| {
-                let child_id = root_node.get("inputs")?.get(input_id)?.as_str()?;
-                raw_nodes.get(child_id)
-            })
+    /// Returns the node of the input `input_id` of the root node.
+    ///
+    /// Only the inputs of the nodes followed to it and the node itself are parsed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the root node has no such input or a node can't be deserialized.
+    pub fn extract_input(&self, input_id: &str) -> Result<LockfileNode> {
+        let node_id = self
+            .resolve(&self.root_id, input_id, 0)?
             .ok_or_eyre("could not locate target node in lockfile")?;
+        let raw = self
+            .raw_nodes
+            .get(&node_id)
+            .ok_or_eyre("could not locate target node in lockfile")?;
+        serde_json::from_str(raw.get()).wrap_err("failed to deserialize node")
+    }
+
+    /// Returns the nodes of the inputs of the root node whose IDs match `pattern`, by their IDs.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the root node or one of the matching nodes can't be deserialized.
+    pub fn extract_inputs(&self, pattern: &Regex) -> Result<Vec<(String, LockfileNode)>> {
+        let raw = self
+            .raw_nodes
+            .get(&self.root_id)
+            .ok_or_eyre("could not locate root node in lockfile")?;
+        let NodeInputs { inputs } = serde_json::from_str(raw.get())
+            .wrap_err("failed to deserialize inputs of root node")?;
+        inputs
+            .into_keys()
+            .filter(|input_id| pattern.is_match(input_id))
+            .map(|input_id| {
+                let node = self.extract_input(&input_id)?;
+                Ok((input_id, node))
+            })
+            .collect()
+    }
+
+    /// Returns the ID of the node that the input `input_id` of `node_id` points to, like
+    /// [`LockGraph::resolve`].
+    fn resolve(&self, node_id: &str, input_id: &str, depth: usize) -> Result<Option<String>> {
+        if depth > MAX_FOLLOWS_DEPTH {
+            return Ok(None);
+        }
+        let Some(raw) = self.raw_nodes.get(node_id) else {
+            return Ok(None);
+        };
+        let NodeInputs { mut inputs } = serde_json::from_str(raw.get())
+            .wrap_err_with(|| format!("failed to deserialize inputs of node {node_id}"))?;
+        match inputs.remove(input_id) {
+            None => Ok(None),
+            Some(InputEdge::Node(child_id)) => Ok(Some(child_id)),
+            Some(InputEdge::Follows(path)) => {
+                let mut node_id = self.root_id.clone();
+                for input_id in &path {
+                    match self.resolve(&node_id, input_id, depth + 1)? {
+                        Some(child_id) => node_id = child_id,
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(node_id))
+            }
+        }
+    }
+
+    /// Returns the typed node graph.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a node can't be deserialized or refers to a missing node.
+    pub fn into_graph(self) -> Result<LockGraph> {
+        let nodes = self
+            .raw_nodes
+            .into_iter()
+            .map(|(id, raw)| {
+                let NodeInputs { inputs } = serde_json::from_str(raw.get())
+                    .wrap_err_with(|| format!("failed to deserialize inputs of node {id}"))?;
+                Ok((id, GraphNode { inputs, raw }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(LockGraph {
+            root_id: self.root_id,
+            nodes,
+        })
+    }
+}
+
+/// The node graph of a lockfile.
+pub struct LockGraph {
+    pub root_id: String,
+    pub nodes: BTreeMap<String, GraphNode>,
+}
+
+/// Guards against cyclic `follows` paths.
+const MAX_FOLLOWS_DEPTH: usize = 32;
+
+impl LockGraph {
+    /// Returns the ID of the node that the input `input_id` of `node_id` points to.
+    #[must_use]
+    pub fn resolve(&self, node_id: &str, input_id: &str) -> Option<&str> {
+        self.resolve_inner(node_id, input_id, 0)
+    }
+
+    fn resolve_inner(&self, node_id: &str, input_id: &str, depth: usize) -> Option<&str> {
+        if depth > MAX_FOLLOWS_DEPTH {
+            return None;
+        }
+        match self.nodes.get(node_id)?.inputs.get(input_id)? {
+            InputEdge::Node(child_id) => Some(child_id),
+            InputEdge::Follows(path) => path
+                .iter()
+                .try_fold(self.root_id.as_str(), |node_id, input_id| {
+                    self.resolve_inner(node_id, input_id, depth + 1)
+                }),
+        }
+    }
+}
+
+/// An input of an input of the root node locking the same repository and ref as an input of the
+/// root node, which it can follow instead. [`LockGraph::duplicate_inputs`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateInput {
+    /// The input of the root node the duplicate is an input of.
+    pub parent: String,
+    pub input_id: String,
+    /// The input of the root node to follow.
+    pub follows: String,
+}
+
+impl LockGraph {
+    /// Returns the inputs of the root node's inputs that lock the same repository as an input of
+    /// the root node but aren't already the same node, like a second nixpkgs.
+    ///
+    /// Duplicates nested deeper, of repositories the root node has no input for, or following
+    /// another ref, like the `nixpkgs` of a home-manager release, aren't returned.
+    #[must_use]
+    pub fn duplicate_inputs(&self) -> Vec<DuplicateInput> {
+        let Some(root) = self.nodes.get(&self.root_id) else {
+            return Vec::new();
+        };
+        let duplicate_key = |node_id: &str| {
+            let node = self.nodes.get(node_id)?.decode().ok()?;
+            let ref_ = registry::resolved_ref(&node.original.inner).map(Cow::into_owned);
+            Some((node.locked.repository_key()?, ref_))
+        };
+
+        let mut root_inputs = BTreeMap::new();
+        for input_id in root.inputs.keys() {
+            if let Some(key) = self
+                .resolve(&self.root_id, input_id)
+                .and_then(duplicate_key)
+            {
+                root_inputs.entry(key).or_insert(input_id.as_str());
+            }
+        }
+
+        let mut duplicates = Vec::new();
+        for (parent, edge) in &root.inputs {
+            let InputEdge::Node(parent_id) = edge else {
+                continue;
+            };
+            let Some(parent_node) = self.nodes.get(parent_id) else {
+                continue;
+            };
+            for (input_id, edge) in &parent_node.inputs {
+                let InputEdge::Node(node_id) = edge else {
+                    continue;
+                };
+                let Some(&follows) = duplicate_key(node_id).and_then(|key| root_inputs.get(&key))
+                else {
+                    continue;
+                };
+                if self.resolve(&self.root_id, follows) != Some(node_id.as_str()) {
+                    duplicates.push(DuplicateInput {
+                        parent: parent.clone(),
+                        input_id: input_id.clone(),
+                        follows: follows.to_owned(),
+                    });
+                }
+            }
+        }
+        duplicates
+    }
+}
+
+/// Another node locking the same repository as an input of the root node, reached through other
+/// inputs. [`LockGraph::transitive_duplicates`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransitiveInput {
+    /// Input IDs from the root node to the node, like `["home-manager", "nixpkgs"]`.
+    pub path: Vec<String>,
+    /// ID of the node, like `nixpkgs_2`.
+    pub node_id: String,
+}
+
+impl TransitiveInput {
+    /// Returns the input path as accepted by `nix flake update`, like `home-manager/nixpkgs`.
+    #[must_use]
+    pub fn input_path(&self) -> String {
+        self.path.join("/")
+    }
+}
+
+impl LockGraph {
+    /// Returns the other nodes locking the same repository as the input `input_id` of the root
+    /// node at any depth, like a `nixpkgs_2` locked by home-manager, with the shortest path of
+    /// inputs to each.
+    #[must_use]
+    pub fn transitive_duplicates(&self, input_id: &str) -> Vec<TransitiveInput> {
+        let repository_key = |node_id: &str| {
+            self.nodes
+                .get(node_id)?
+                .decode()
+                .ok()?
+                .locked
+                .repository_key()
+        };
+        let Some(target_id) = self.resolve(&self.root_id, input_id) else {
+            return Vec::new();
+        };
+        let Some(key) = repository_key(target_id) else {
+            return Vec::new();
+        };
+
+        let mut duplicates = Vec::new();
+        let mut visited = HashSet::from([self.root_id.as_str()]);
+        let mut queue = VecDeque::from([(self.root_id.as_str(), Vec::new())]);
+        while let Some((node_id, path)) = queue.pop_front() {
+            let Some(node) = self.nodes.get(node_id) else {
+                continue;
+            };
+            for (child_input, edge) in &node.inputs {
+                // Followed nodes are reached through their own path
+                let InputEdge::Node(child_id) = edge else {
+                    continue;
+                };
+                if !visited.insert(child_id) {
+                    continue;
+                }
+                let mut child_path = path.clone();
+                child_path.push(child_input.clone());
+                if child_id != target_id && repository_key(child_id).as_ref() == Some(&key) {
+                    duplicates.push(TransitiveInput {
+                        path: child_path.clone(),
+                        node_id: child_id.clone(),
+                    });
+                }
+                queue.push_back((child_id, child_path));
+            }
+        }
+        duplicates
+    }
+}
+
+impl LockGraph {
+    /// Returns the IDs of the root node's inputs whose original flake reference matches
+    /// `pattern`, like `pkgs` for `github:NixOS/nixpkgs/nixos-unstable`.
+    pub fn inputs_matching_url(&self, pattern: &Regex) -> Vec<&str> {
+        let Some(root) = self.nodes.get(&self.root_id) else {
+            return Vec::new();
+        };
+        root.inputs
+            .keys()
+            .filter(|input_id| {
+                self.resolve(&self.root_id, input_id)
+                    .and_then(|node_id| self.nodes.get(node_id)?.decode().ok())
+                    .and_then(|node| node.original.to_flake_ref().ok())
+                    .is_some_and(|url| pattern.is_match(&url))
+            })
+            .map(String::as_str)
+            .collect()
+    }
 
-        let node =
-            serde_json::from_value(raw_node.clone()).wrap_err("failed to deserialize node")?;
+    /// Returns the IDs of the root node's inputs locking the repository with
+    /// [`Locked::repository_key`] `repository_key`, like `pkgs` for `github:nixos/nixpkgs`.
+    pub fn inputs_locking(&self, repository_key: &str) -> Vec<&str> {
+        let Some(root) = self.nodes.get(&self.root_id) else {
+            return Vec::new();
+        };
+        root.inputs
+            .keys()
+            .filter(|input_id| {
+                self.resolve(&self.root_id, input_id)
+                    .and_then(|node_id| self.nodes.get(node_id)?.decode().ok())
+                    .and_then(|node| node.locked.repository_key())
+                    .is_some_and(|key| key == repository_key)
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Returns the root node's input locking `github:NixOS/nixpkgs`, preferring one called
+    /// `nixpkgs`, for `--input-id auto`.
+    #[must_use]
+    pub fn nixpkgs_input(&self) -> Option<&str> {
+        prefer(&self.inputs_locking("github:nixos/nixpkgs"), "nixpkgs")
+    }
 
-        Ok(node)
+    /// Returns the root node's input whose original flake reference matches `pattern`,
+    /// preferring `preferred`, for `--match-url`.
+    #[must_use]
+    pub fn input_matching_url(&self, pattern: &Regex, preferred: &str) -> Option<&str> {
+        prefer(&self.inputs_matching_url(pattern), preferred)
     }
+
+    /// Returns the root node's input standing for the `path:` input `input_id` of a flake whose
+    /// path this graph is locked in: `input_id` itself if it's also a path or locks the
+    /// repository `repository_key`, or else the first input locking it.
+    fn path_flake_input(
+        &self,
+        input_id: &str,
+        repository_key: Option<&str>,
+    ) -> Option<(String, LockfileNode)> {
+        let decode = |input_id: &str| {
+            self.nodes
+                .get(self.resolve(&self.root_id, input_id)?)?
+                .decode()
+                .ok()
+        };
+        if let Some(node) = decode(input_id) {
+            if matches!(node.locked, Locked::Path { .. })
+                || repository_key
+                    .is_none_or(|key| node.locked.repository_key().as_deref() == Some(key))
+            {
+                return Some((input_id.to_owned(), node));
+            }
+        }
+        let input_id = *self.inputs_locking(repository_key?).first()?;
+        Some((input_id.to_owned(), decode(input_id)?))
+    }
+}
+
+/// Returns `preferred` if it's one of `input_ids`, or else the first of them.
+fn prefer<'a>(input_ids: &[&'a str], preferred: &str) -> Option<&'a str> {
+    input_ids
+        .iter()
+        .find(|input_id| **input_id == preferred)
+        .or_else(|| input_ids.first())
+        .copied()
+}
+
+/// A node in [`LockGraph`]. Only the edges are decoded up front.
+pub struct GraphNode {
+    pub inputs: BTreeMap<String, InputEdge>,
+    raw: Box<RawValue>,
+}
+
+impl GraphNode {
+    /// Decodes `locked` and `original`, which the root node doesn't have.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `locked` or `original` is malformed.
+    pub fn decode(&self) -> Result<LockfileNode> {
+        serde_json::from_str(self.raw.get()).wrap_err("failed to deserialize node")
+    }
+}
+
+/// An input of a [`GraphNode`].
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum InputEdge {
+    /// ID of the node.
+    Node(String),
+    /// Path of input IDs starting from the root node.
+    Follows(Vec<String>),
 }
 
 /// The shape of the one node we actually want to fully decode.
@@ -43,12 +407,20 @@ impl Lockfile {
 pub struct LockfileNode {
     pub locked: Locked,
     pub original: OriginalExtra,
+    /// Whether the input is a flake. Inputs declared with `flake = false` are only fetched as
+    /// source trees.
+    #[serde(default = "default_flake")]
+    pub flake: bool,
+}
+
+const fn default_flake() -> bool {
+    true
 }
 
 /// Description of the version currently used. [`LockfileNode::locked`]
 ///
 /// <https://nix.dev/manual/nix/2.28/command-ref/new-cli/nix3-flake.html#types>
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(
     tag = "type",
     rename_all = "lowercase",
@@ -60,6 +432,8 @@ pub enum Locked {
         /// From flake inputs saved to the Nix registry using NixOS or home-manager
         rev: Option<String>,
         last_modified: u64,
+        /// The SRI hash of the source's NAR serialization, like `sha256-...`.
+        nar_hash: Option<String>,
     },
     Tarball {
         url: String,
@@ -67,6 +441,7 @@ pub enum Locked {
         rev: Option<String>,
         // Provided by server
         last_modified: Option<u64>,
+        nar_hash: Option<String>,
     },
     Git {
         /// The commit time of the revision `rev` as an integer denoting the number of seconds since 1970.
@@ -78,6 +453,7 @@ pub enum Locked {
         rev: String,
         shallow: Option<bool>,
         url: String,
+        nar_hash: Option<String>,
     },
     #[serde(untagged)]
     GitService {
@@ -89,6 +465,7 @@ pub enum Locked {
         /// The commit time of the revision `rev` as an integer denoting the number of seconds since 1970.
         last_modified: Option<u64>,
         host: Option<String>,
+        nar_hash: Option<String>,
     },
     #[serde(untagged)]
     Other {
@@ -97,17 +474,26 @@ pub enum Locked {
         rev: Option<String>,
         url: Option<String>,
         last_modified: Option<u64>,
+        nar_hash: Option<String>,
     },
 }
 impl Locked {
+    /// Returns the locked revision, which for a tarball without one from the server is the one
+    /// embedded in its URL, if any.
+    #[must_use]
     pub fn rev(&self) -> Option<&str> {
         match self {
-            Self::Path { rev, .. } | Self::Tarball { rev, .. } | Self::Other { rev, .. } => {
-                rev.as_deref()
-            }
+            Self::Tarball { rev, url, .. } => rev.as_deref().or_else(|| url_rev(url)),
+            Self::Path { rev, .. } | Self::Other { rev, .. } => rev.as_deref(),
             Self::GitService { rev, .. } | Self::Git { rev, .. } => Some(rev),
         }
     }
+    /// Returns the revision embedded in a tarball's URL, like in
+    /// `https://github.com/NixOS/nixpkgs/archive/<rev>.tar.gz` or a `FlakeHub` URL.
+    pub fn url_rev(&self) -> Option<&str> {
+        self.url_no_git().and_then(url_rev)
+    }
+    #[must_use]
     pub fn url_no_git(&self) -> Option<&str> {
         match self {
             Self::Tarball { url, .. } => Some(url),
@@ -116,6 +502,101 @@ impl Locked {
             }
         }
     }
+    /// Returns the URL of a Git input normalized to compare it however it's written, like
+    /// `example.com/owner/repo` for both `ssh://git@Example.com/owner/repo.git` and
+    /// `https://example.com/owner/repo`.
+    #[must_use]
+    pub fn git_url(&self) -> Option<String> {
+        let Self::Git { url, .. } = self else {
+            return None;
+        };
+        Some(normalize_git_url(url))
+    }
+    /// Returns the ref a Git input was locked from, like `refs/heads/main`.
+    #[must_use]
+    pub fn git_ref(&self) -> Option<&str> {
+        match self {
+            Self::Git { ref_, .. } => Some(ref_),
+            _ => None,
+        }
+    }
+    /// Returns the owner of the fork if this is a fork of `upstream` on the same forge, like
+    /// `github:someone/nixpkgs` of `github:NixOS/nixpkgs`.
+    #[must_use]
+    pub fn fork_owner(&self, upstream: &Self) -> Option<&str> {
+        let (
+            Self::GitService {
+                type_,
+                owner,
+                repo,
+                host,
+                ..
+            },
+            Self::GitService {
+                type_: upstream_type,
+                owner: upstream_owner,
+                repo: upstream_repo,
+                host: upstream_host,
+                ..
+            },
+        ) = (self, upstream)
+        else {
+            return None;
+        };
+        let is_fork = type_ == upstream_type
+            && host == upstream_host
+            && repo.eq_ignore_ascii_case(upstream_repo)
+            && !owner.eq_ignore_ascii_case(upstream_owner);
+        is_fork.then_some(owner.as_str())
+    }
+    /// Returns a key identifying the repository regardless of the version, such as
+    /// `github:nixos/nixpkgs`.
+    #[must_use]
+    pub fn repository_key(&self) -> Option<String> {
+        match self {
+            Self::GitService {
+                type_,
+                owner,
+                repo,
+                host,
+                ..
+            } => {
+                let type_ = serde_json::to_value(type_).ok()?;
+                let mut key = format!("{}:{owner}/{repo}", type_.as_str()?).to_lowercase();
+                if let Some(host) = host {
+                    key.push_str("?host=");
+                    key.push_str(&host.to_lowercase());
+                }
+                Some(key)
+            }
+            Self::Git { url, .. } => Some(format!("git+{url}")),
+            Self::Path { .. } | Self::Tarball { .. } | Self::Other { .. } => None,
+        }
+    }
+    /// Returns whether this is a Git input locked without its history.
+    #[must_use]
+    pub const fn is_shallow(&self) -> bool {
+        matches!(
+            self,
+            Self::Git {
+                shallow: Some(true),
+                ..
+            }
+        )
+    }
+    /// Returns the hash of the locked source, which is the same for the same contents wherever
+    /// they were fetched from.
+    #[must_use]
+    pub fn nar_hash(&self) -> Option<&str> {
+        match self {
+            Self::Path { nar_hash, .. }
+            | Self::Tarball { nar_hash, .. }
+            | Self::Git { nar_hash, .. }
+            | Self::GitService { nar_hash, .. }
+            | Self::Other { nar_hash, .. } => nar_hash.as_deref(),
+        }
+    }
+    #[must_use]
     pub const fn last_modified(&self) -> Option<u64> {
         match self {
             Self::Path { last_modified, .. } => Some(*last_modified),
@@ -132,7 +613,7 @@ impl Locked {
 /// Git services know whether a rev or ref was specified in `rev-or-ref`.
 ///
 /// <https://nix.dev/manual/nix/2.28/command-ref/new-cli/nix3-flake.html#types>
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Original {
     /// Performs a lookup of
@@ -159,6 +640,7 @@ pub enum Original {
         // shallow: Option<bool>,
         // url: String,
     },
+    #[serde(rename = "hg")]
     Mercurial,
 
     /// Form: `github:<owner>/<repo>(/<rev-or-ref>)?(\?<params>)?`
@@ -185,6 +667,7 @@ pub enum Original {
     },
 }
 impl Original {
+    #[must_use]
     pub fn ref_(&self) -> Option<&str> {
         match self {
             Self::Indirect { ref_, .. }
@@ -208,7 +691,21 @@ pub struct OriginalExtra {
     extra: HashMap<String, Value>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+impl OriginalExtra {
+    /// Returns the URL-like flake reference, like `github:NixOS/nixpkgs/nixos-unstable`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the attributes aren't a valid flake reference.
+    pub fn to_flake_ref(&self) -> Result<String> {
+        let Value::Object(attrs) = serde_json::to_value(self)? else {
+            unreachable!("a struct serializes to an object");
+        };
+        crate::flake_ref::to_string(&attrs)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum GitServiceType {
     GitHub,
@@ -216,13 +713,595 @@ pub enum GitServiceType {
     Sourcehut,
 }
 
-pub fn load_lockfile_input(path: &Path, cli: &Cli) -> Result<LockfileNode> {
-    let input_id = &cli.input_id;
+/// Returns the commit hash in `url`, preferring a `rev` query parameter to a path segment like
+/// `<rev>.tar.gz` or `FlakeHub`'s `0.1.0+rev-<rev>`.
+fn url_rev(url: &str) -> Option<&str> {
+    let is_rev = |part: &&str| part.len() == 40 && part.bytes().all(|b| b.is_ascii_hexdigit());
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("rev="))
+        .find(is_rev)
+        .or_else(|| path.split(['/', '.', '-', '+']).find(is_rev))
+}
+
+/// Drops the scheme, user, port, query and `.git` suffix of a Git URL and lowercases the host,
+/// so the SSH and HTTPS URLs of a repository are the same.
+fn normalize_git_url(url: &str) -> String {
+    let url = url.strip_prefix("git+").unwrap_or(url);
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let (authority, path) = match url.split_once("://") {
+        Some((_scheme, rest)) => rest.split_once('/').unwrap_or((rest, "")),
+        // scp-like syntax, like `git@example.com:owner/repo`
+        None => url.split_once(':').unwrap_or(("", url)),
+    };
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_user, host)| host);
+    let host = host.split_once(':').map_or(host, |(host, _port)| host);
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    format!("{}/{path}", host.to_lowercase())
+}
+
+/// Reads the lock file at `path` and returns the node of the input `input_id`.
+///
+/// # Errors
+///
+/// Fails if the file can't be read, or like [`parse_lockfile_input`].
+pub fn load_lockfile_input(path: &Path, input_id: &str) -> Result<LockfileNode> {
     let contents = fs::read(path)?;
+    parse_lockfile_input(&contents, input_id)
+}
+
+/// Parses the contents of a lock file and returns the node of the input `input_id`.
+///
+/// # Errors
+///
+/// Fails if the contents aren't a lock file or have no input `input_id`.
+pub fn parse_lockfile_input(contents: &[u8], input_id: &str) -> Result<LockfileNode> {
     let lockfile: Lockfile =
-        serde_json::from_slice(&contents).wrap_err("failed to parse top level of lockfile")?;
+        serde_json::from_slice(contents).wrap_err("failed to parse top level of lockfile")?;
 
     let node = lockfile.extract_input(input_id)?;
 
     Ok(node)
 }
+
+/// Reads the node graph of the lock file at `path`.
+///
+/// # Errors
+///
+/// Fails if the file can't be read or isn't a lock file.
+pub fn load_lockfile_graph(path: &Path) -> Result<LockGraph> {
+    let lockfile: Lockfile = serde_json::from_slice(&fs::read(path)?)
+        .wrap_err("failed to parse top level of lockfile")?;
+    lockfile.into_graph()
+}
+
+/// Reads the lock file at `path` and returns the nodes of the inputs whose IDs match `pattern`.
+///
+/// # Errors
+///
+/// Fails if the file can't be read or one of the nodes can't be deserialized.
+pub fn load_lockfile_inputs(path: &Path, pattern: &Regex) -> Result<Vec<(String, LockfileNode)>> {
+    let lockfile: Lockfile = serde_json::from_slice(&fs::read(path)?)
+        .wrap_err("failed to parse top level of lockfile")?;
+    lockfile.extract_inputs(pattern)
+}
+
+/// Returns the directory of the flake the `path:` input `node` points to and that flake's own
+/// input locking the repository with [`Locked::repository_key`] `repository_key`.
+///
+/// `directory` is where `node` is locked as `input_id`. The path flake's input of the same name
+/// is preferred, but it may name it differently, like `pkgs`. The input is followed again while
+/// it's also a path, like the `nixpkgs` of `path:../common` locked by `../common/flake.lock`.
+/// Returns `None` if `node` isn't a path or a lock file along the way doesn't lock the
+/// repository.
+#[must_use]
+pub fn follow_path_input(
+    directory: &Path,
+    node: &LockfileNode,
+    input_id: &str,
+    repository_key: Option<&str>,
+) -> Option<(PathBuf, LockfileNode)> {
+    let Locked::Path { path, .. } = &node.locked else {
+        return None;
+    };
+    let mut directory = join_path_input(directory, path);
+    let mut input_id = input_id.to_owned();
+    for _ in 0..MAX_FOLLOWS_DEPTH {
+        let lockfile: Lockfile =
+            serde_json::from_slice(&fs::read(directory.join("flake.lock")).ok()?).ok()?;
+        let (path_input_id, node) = lockfile
+            .into_graph()
+            .ok()?
+            .path_flake_input(&input_id, repository_key)?;
+        let Locked::Path { path, .. } = &node.locked else {
+            return Some((directory, node));
+        };
+        directory = join_path_input(&directory, path);
+        input_id = path_input_id;
+    }
+    None
+}
+
+/// Returns the directory of the path input `path` of the flake in `directory`, which is
+/// relative to it unless it's absolute.
+#[must_use]
+pub fn join_path_input(directory: &Path, path: &str) -> PathBuf {
+    directory.join(path.strip_prefix("./").unwrap_or(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn fixture_input(name: &str) -> LockfileNode {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/lockfiles")
+            .join(name);
+        let contents = fs::read(&path).unwrap();
+        let lockfile: Lockfile = serde_json::from_slice(&contents).unwrap();
+        lockfile.extract_input("nixpkgs").unwrap()
+    }
+
+    #[test]
+    fn github_with_follows() {
+        let node = fixture_input("github-follows.lock");
+        assert!(matches!(
+            node.locked,
+            Locked::GitService {
+                type_: GitServiceType::GitHub,
+                ..
+            }
+        ));
+        assert_eq!(
+            node.locked.rev(),
+            Some("6e987485eb2c77e5dcc5af4e3c70843711ef9251")
+        );
+        assert_eq!(node.original.inner.ref_(), Some("nixos-unstable"));
+        assert!(node.flake);
+    }
+
+    #[test]
+    fn non_flake() {
+        let node = fixture_input("non-flake.lock");
+        assert!(!node.flake);
+        assert_eq!(node.original.inner.ref_(), Some("nixos-unstable"));
+    }
+
+    #[test]
+    fn root_input_follows_another_flake() {
+        let node = fixture_input("root-follows.lock");
+        assert_eq!(
+            node.locked.rev(),
+            Some("88983d4b665fb491861005137ce2b11a9f89f203")
+        );
+        assert_eq!(node.original.inner.ref_(), Some("nixos-25.05"));
+    }
+
+    #[test]
+    fn indirect() {
+        let node = fixture_input("indirect.lock");
+        assert!(
+            matches!(node.original.inner, Original::Indirect { ref id, .. } if id == "nixpkgs")
+        );
+        assert_eq!(node.original.inner.ref_(), Some("nixos-unstable"));
+    }
+
+    #[test]
+    fn tarball() {
+        let node = fixture_input("tarball.lock");
+        assert!(matches!(node.locked, Locked::Tarball { .. }));
+        assert!(matches!(node.original.inner, Original::Tarball {}));
+        assert!(node.locked.url_no_git().is_some());
+        assert_eq!(node.locked.last_modified(), Some(1_752_480_373));
+        assert!(
+            node.locked
+                .nar_hash()
+                .is_some_and(|hash| hash.starts_with("sha256-"))
+        );
+    }
+
+    #[test]
+    fn git() {
+        let node = fixture_input("git.lock");
+        assert!(matches!(node.locked, Locked::Git { .. }));
+        assert!(node.locked.is_shallow());
+        assert_eq!(node.original.inner.ref_(), Some("nixos-unstable"));
+        assert_eq!(node.locked.url_no_git(), None);
+    }
+
+    #[test]
+    fn fork() {
+        let upstream = fixture_input("github-follows.lock").locked;
+        let Locked::GitService {
+            type_, repo, rev, ..
+        } = &upstream
+        else {
+            panic!("Expected a GitHub input");
+        };
+        let fork = Locked::GitService {
+            type_: *type_,
+            owner: "someone".to_owned(),
+            repo: repo.clone(),
+            rev: rev.clone(),
+            last_modified: None,
+            host: None,
+            nar_hash: None,
+        };
+        assert_eq!(fork.fork_owner(&upstream), Some("someone"));
+        assert_eq!(upstream.fork_owner(&upstream), None);
+        assert_eq!(fixture_input("git.lock").locked.fork_owner(&upstream), None);
+    }
+
+    #[test]
+    fn path() {
+        let node = fixture_input("path.lock");
+        assert!(matches!(node.locked, Locked::Path { .. }));
+        assert_eq!(node.original.inner, Original::Path);
+    }
+
+    #[test]
+    fn gitlab_with_host() {
+        let node = fixture_input("gitlab-host.lock");
+        assert!(matches!(
+            node.locked,
+            Locked::GitService {
+                type_: GitServiceType::GitLab,
+                host: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(node.original.inner.ref_(), Some("nixos-unstable"));
+    }
+
+    #[test]
+    fn sourcehut_with_dir_param() {
+        let node = fixture_input("sourcehut-dir.lock");
+        assert!(matches!(
+            node.locked,
+            Locked::GitService {
+                type_: GitServiceType::Sourcehut,
+                ..
+            }
+        ));
+        assert_eq!(node.original.inner.ref_(), Some("main"));
+        assert!(node.original.extra.contains_key("dir"));
+    }
+
+    #[test]
+    fn mercurial() {
+        let node = fixture_input("mercurial.lock");
+        assert!(matches!(node.locked, Locked::Other { ref type_, .. } if type_ == "hg"));
+        assert_eq!(node.original.inner, Original::Mercurial);
+    }
+
+    #[test]
+    fn duplicate_inputs() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lockfiles");
+        let lockfile: Lockfile =
+            serde_json::from_slice(&fs::read(path.join("duplicates.lock")).unwrap()).unwrap();
+        assert_eq!(
+            lockfile.into_graph().unwrap().duplicate_inputs(),
+            [DuplicateInput {
+                parent: "home-manager".to_owned(),
+                input_id: "nixpkgs".to_owned(),
+                follows: "nixpkgs".to_owned(),
+            }]
+        );
+
+        let lockfile: Lockfile =
+            serde_json::from_slice(&fs::read(path.join("github-follows.lock")).unwrap()).unwrap();
+        assert!(lockfile.into_graph().unwrap().duplicate_inputs().is_empty());
+
+        let mut release: Value =
+            serde_json::from_slice(&fs::read(path.join("duplicates.lock")).unwrap()).unwrap();
+        release["nodes"]["nixpkgs_2"]["original"]["ref"] = "nixos-25.05".into();
+        let lockfile: Lockfile = serde_json::from_value(release).unwrap();
+        assert!(lockfile.into_graph().unwrap().duplicate_inputs().is_empty());
+    }
+
+    #[test]
+    fn transitive_duplicates() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lockfiles");
+        let lockfile: Lockfile =
+            serde_json::from_slice(&fs::read(path.join("duplicates.lock")).unwrap()).unwrap();
+        let graph = lockfile.into_graph().unwrap();
+        assert_eq!(graph.inputs_locking("github:nixos/nixpkgs"), ["nixpkgs"]);
+        let duplicates = graph.transitive_duplicates("nixpkgs");
+        assert_eq!(
+            duplicates,
+            [TransitiveInput {
+                path: vec!["home-manager".to_owned(), "nixpkgs".to_owned()],
+                node_id: "nixpkgs_2".to_owned(),
+            }]
+        );
+        assert_eq!(duplicates[0].input_path(), "home-manager/nixpkgs");
+        assert!(graph.transitive_duplicates("treefmt-nix").is_empty());
+    }
+
+    #[test]
+    fn inputs_matching_url() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lockfiles");
+        let lockfile: Lockfile =
+            serde_json::from_slice(&fs::read(path.join("duplicates.lock")).unwrap()).unwrap();
+        let graph = lockfile.into_graph().unwrap();
+        assert_eq!(
+            graph.inputs_matching_url(&crate::tags::wildcard("github:NixOS/nixpkgs*")),
+            ["nixpkgs"]
+        );
+        assert_eq!(
+            graph.inputs_matching_url(&crate::tags::wildcard_ignoring_case(
+                "github:nixos/nixpkgs*"
+            )),
+            ["nixpkgs"]
+        );
+        assert!(
+            graph
+                .inputs_matching_url(&crate::tags::wildcard("github:nix-community/nixvim*"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn preferred_input() {
+        let graph = load_lockfile_graph(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lockfiles/duplicates.lock"),
+        )
+        .unwrap();
+        assert_eq!(graph.nixpkgs_input(), Some("nixpkgs"));
+        let github = crate::tags::wildcard("github:*");
+        assert_eq!(
+            graph.input_matching_url(&github, "treefmt-nix"),
+            Some("treefmt-nix")
+        );
+        assert_eq!(
+            graph.input_matching_url(&github, "nixvim"),
+            Some("home-manager")
+        );
+    }
+
+    #[test]
+    fn cyclic_follows() {
+        let lockfile: Lockfile = serde_json::from_str(
+            r#"{
+                "nodes": { "root": { "inputs": { "nixpkgs": ["nixpkgs"] } } },
+                "root": "root",
+                "version": 7
+            }"#,
+        )
+        .unwrap();
+        assert!(lockfile.extract_input("nixpkgs").is_err());
+    }
+
+    #[test]
+    fn unrelated_nodes_are_not_parsed() {
+        let lockfile: Lockfile = serde_json::from_str(
+            r#"{
+                "nodes": {
+                    "root": { "inputs": { "nixpkgs": "nixpkgs", "other": "other" } },
+                    "nixpkgs": {
+                        "locked": { "type": "path", "path": "/nix/store/abc-source", "lastModified": 1752687322 },
+                        "original": { "type": "path", "path": "/tmp/nixpkgs" }
+                    },
+                    "other": { "inputs": 42, "locked": null }
+                },
+                "root": "root",
+                "version": 7
+            }"#,
+        )
+        .unwrap();
+        let node = lockfile.extract_input("nixpkgs").unwrap();
+        assert!(matches!(node.locked, Locked::Path { .. }));
+        assert!(lockfile.extract_input("other").is_err());
+    }
+
+    #[test]
+    fn path_input_lockfile() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/path-input");
+        let node = load_lockfile_input(&directory.join("flake.lock"), "nixpkgs").unwrap();
+        let repository_key = Some("github:nixos/nixpkgs");
+        // `common` names its input `pkgs`
+        let (path_directory, path_node) =
+            follow_path_input(&directory, &node, "nixpkgs", repository_key).unwrap();
+        assert_eq!(path_directory, directory.join("common"));
+        assert!(matches!(path_node.locked, Locked::GitService { .. }));
+        assert!(follow_path_input(&directory, &node, "nixpkgs", Some("github:a/b")).is_none());
+        assert!(follow_path_input(&directory, &path_node, "nixpkgs", repository_key).is_none());
+    }
+
+    #[test]
+    fn tarball_url_revs() {
+        let rev = "6e987485eb2c77e5dcc5af4e3c70843711ef9251";
+        for url in [
+            format!("https://github.com/NixOS/nixpkgs/archive/{rev}.tar.gz"),
+            format!("https://gitlab.com/owner/repo/-/archive/{rev}/repo-{rev}.tar.gz"),
+            format!(
+                "https://api.flakehub.com/f/pinned/NixOS/nixpkgs/0.1.826543+rev-{rev}/0198/source.tar.gz"
+            ),
+            format!("https://example.com/nixpkgs.tar.gz?ref=nixos-unstable&rev={rev}"),
+        ] {
+            assert_eq!(url_rev(&url), Some(rev), "{url}");
+        }
+        assert_eq!(
+            url_rev("https://channels.nixos.org/nixos-unstable/nixexprs.tar.xz"),
+            None
+        );
+    }
+
+    #[test]
+    fn normalized_git_urls() {
+        for url in [
+            "ssh://git@Example.com/owner/repo.git",
+            "https://example.com/owner/repo",
+            "git+https://example.com:443/owner/repo.git/?ref=main",
+            "git@example.com:owner/repo.git",
+        ] {
+            assert_eq!(normalize_git_url(url), "example.com/owner/repo", "{url}");
+        }
+        assert_eq!(normalize_git_url("file:///srv/repo"), "/srv/repo");
+    }
+
+    #[test]
+    fn inputs_matching_pattern() {
+        let lockfile: Lockfile = serde_json::from_str(
+            r#"{
+                "nodes": {
+                    "root": { "inputs": { "nixpkgs": "nixpkgs", "nixpkgs-stable": ["nixpkgs"], "utils": "utils" } },
+                    "nixpkgs": {
+                        "locked": { "type": "path", "path": "/nix/store/abc-source", "lastModified": 1752687322 },
+                        "original": { "type": "path", "path": "/tmp/nixpkgs" }
+                    },
+                    "utils": { "inputs": 42, "locked": null }
+                },
+                "root": "root",
+                "version": 7
+            }"#,
+        )
+        .unwrap();
+        let inputs = lockfile
+            .extract_inputs(&Regex::new("^nixpkgs.*$").unwrap())
+            .unwrap();
+        let input_ids: Vec<_> = inputs
+            .iter()
+            .map(|(input_id, _)| input_id.as_str())
+            .collect();
+        assert_eq!(input_ids, ["nixpkgs", "nixpkgs-stable"]);
+    }
+
+    fn rev() -> impl Strategy<Value = String> {
+        "[0-9a-f]{40}"
+    }
+
+    fn nar_hash() -> impl Strategy<Value = String> {
+        "sha256-[A-Za-z0-9+/]{43}="
+    }
+
+    fn git_service_type() -> impl Strategy<Value = GitServiceType> {
+        prop_oneof![
+            Just(GitServiceType::GitHub),
+            Just(GitServiceType::GitLab),
+            Just(GitServiceType::Sourcehut),
+        ]
+    }
+
+    fn locked() -> impl Strategy<Value = Locked> {
+        prop_oneof![
+            (
+                "/nix/store/[a-z0-9]{32}-source",
+                proptest::option::of(rev()),
+                any::<u64>(),
+                proptest::option::of(nar_hash())
+            )
+                .prop_map(|(path, rev, last_modified, nar_hash)| Locked::Path {
+                    path,
+                    rev,
+                    last_modified,
+                    nar_hash,
+                }),
+            (
+                "https://[a-z]{1,10}\\.org/[a-z/]{0,20}\\.tar\\.gz",
+                proptest::option::of(rev()),
+                proptest::option::of(any::<u64>()),
+                proptest::option::of(nar_hash())
+            )
+                .prop_map(|(url, rev, last_modified, nar_hash)| Locked::Tarball {
+                    url,
+                    rev,
+                    last_modified,
+                    nar_hash,
+                }),
+            (
+                proptest::option::of(any::<u64>()),
+                "refs/heads/[a-z0-9.-]{1,20}",
+                rev(),
+                proptest::option::of(any::<bool>()),
+                "(https|ssh)://[a-z]{1,10}\\.org/[a-z]{1,10}\\.git",
+                proptest::option::of(nar_hash())
+            )
+                .prop_map(|(last_modified, ref_, rev, shallow, url, nar_hash)| {
+                    Locked::Git {
+                        last_modified,
+                        ref_,
+                        rev,
+                        shallow,
+                        url,
+                        nar_hash,
+                    }
+                }),
+            (
+                git_service_type(),
+                "[A-Za-z0-9~_-]{1,20}",
+                "[A-Za-z0-9_-]{1,20}",
+                rev(),
+                proptest::option::of(any::<u64>()),
+                proptest::option::of("[a-z]{1,10}\\.[a-z]{2,3}"),
+                proptest::option::of(nar_hash())
+            )
+                .prop_map(
+                    |(type_, owner, repo, rev, last_modified, host, nar_hash)| {
+                        Locked::GitService {
+                            type_,
+                            owner,
+                            repo,
+                            rev,
+                            last_modified,
+                            host,
+                            nar_hash,
+                        }
+                    }
+                ),
+            (
+                // Known types would be classified as their own variants
+                "[a-z]{1,10}".prop_filter("known type", |type_| !matches!(
+                    type_.as_str(),
+                    "path" | "tarball" | "git" | "github" | "gitlab" | "sourcehut"
+                )),
+                proptest::option::of(rev()),
+                proptest::option::of("https://[a-z]{1,10}\\.org"),
+                proptest::option::of(any::<u64>()),
+                proptest::option::of(nar_hash())
+            )
+                .prop_map(|(type_, rev, url, last_modified, nar_hash)| Locked::Other {
+                    type_,
+                    rev,
+                    url,
+                    last_modified,
+                    nar_hash,
+                }),
+        ]
+    }
+
+    fn original() -> impl Strategy<Value = Original> {
+        let ref_ = || proptest::option::of("[a-z0-9.-]{1,20}");
+        prop_oneof![
+            ("[a-z-]{1,10}", proptest::option::of(rev()), ref_())
+                .prop_map(|(id, rev, ref_)| Original::Indirect { id, rev, ref_ }),
+            Just(Original::Path),
+            Just(Original::Tarball {}),
+            Just(Original::File),
+            ref_().prop_map(|ref_| Original::Git { ref_ }),
+            Just(Original::Mercurial),
+            (git_service_type(), ref_())
+                .prop_map(|(type_, ref_)| Original::GitService { _type: type_, ref_ }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn locked_round_trips(locked in locked()) {
+            let json = serde_json::to_value(&locked).unwrap();
+            prop_assert_eq!(serde_json::from_value::<Locked>(json).unwrap(), locked);
+        }
+
+        #[test]
+        fn original_round_trips(original in original()) {
+            let json = serde_json::to_value(&original).unwrap();
+            prop_assert_eq!(serde_json::from_value::<Original>(json).unwrap(), original);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Diagnostic logging with `tracing`, separate from the regular output.
+//!
+//! `-v` logs what's being done, `-vv` also every subprocess and `-vvv` everything.
+//! `NIXPKGSUPD_LOG` takes filter directives like `nixpkgsupd=debug` and overrides `-v`.
+
+use color_eyre::{Result, eyre::Context};
+use tracing_subscriber::EnvFilter;
+
+const ENV_VAR: &str = "NIXPKGSUPD_LOG";
+
+/// Starts logging to standard error. Only warnings are logged without `-v`.
+pub fn init(verbosity: u8, ansi: bool) -> Result<()> {
+    let filter = match std::env::var(ENV_VAR) {
+        Ok(directives) if !directives.is_empty() => EnvFilter::try_new(&directives)
+            .wrap_err_with(|| format!("Invalid {ENV_VAR} {directives:?}"))?,
+        _ => {
+            let level = match verbosity {
+                0 => "warn",
+                1 => "info",
+                2 => "debug",
+                _ => "trace",
+            };
+            // Dependencies are only interesting when they fail
+            EnvFilter::new(format!("warn,{}={level}", env!("CARGO_CRATE_NAME")))
+        }
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| crate::progress::LogWriter)
+        .with_ansi(ansi)
+        .init();
+    Ok(())
+}
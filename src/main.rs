@@ -1,305 +1,612 @@
-mod flake_nix;
-mod lockfile;
-mod serde_int_tag_hack;
-mod sigint_guard;
+mod dedupe;
+mod editor;
+mod elevate;
+mod explain;
+mod forge;
+mod graph;
+mod hooks;
+mod logging;
+mod notification;
+mod patch;
+mod plan;
+mod progress;
+mod prompt;
+mod pull_request;
+mod rebuild;
+mod remote;
+mod state;
+mod status;
+mod ui;
 mod update;
+mod vcs;
+mod worktree;
 
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     io::IsTerminal,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::ExitCode,
     time::{Duration, SystemTime},
 };
 
-use clap::{Args, Parser, Subcommand, builder::ArgPredicate};
+use clap::{Args, Parser, Subcommand};
 use color_eyre::{
     Result,
-    eyre::{Context, OptionExt, bail},
+    eyre::{Context, bail},
 };
-use fs_err as fs;
-use iddqd::{IdHashItem, IdHashMap, id_hash_map::Entry as IdHashMapEntry};
-use owo_colors::{OwoColorize, colors::xterm};
-use serde::Deserialize;
-
-use crate::lockfile::{Locked, LockfileNode, Original, load_lockfile_input};
-
-struct Flake<'cli> {
-    // Currently just the flake ID passed in.
-    /// Key in `inputs`
-    id: &'cli str,
-    /// Parent of `flake.lock`
-    directory: PathBuf,
-    /// Paths of the gcroots. Below `directory`
-    gcroots: Vec<PathBuf>,
-    /// Whether the flake has build result gcroots
-    has_build_result: bool,
-    /// Whether the flake has direnv gcroots
-    has_direnv_gc_roots: bool,
-    /// Path of `flake.lock`
-    lockfile_path: PathBuf,
-}
+use indicatif::HumanBytes;
+use nixpkgsupd::{
+    channels, command, config, discovery,
+    discovery::{Flake, ProjectKind},
+    envrc, flake_ref,
+    lockfile::{self, LockGraph, LockfileNode, TransitiveInput, load_lockfile_input},
+    registry, store, tags,
+    target::{MatchTarget, MetadataCache, Mismatch, timestamp_matches},
+};
+use regex::Regex;
 
-impl Flake<'_> {
-    pub fn in_git_repo(&self) -> bool {
-        self.directory
-            .ancestors()
-            .any(|path| path.join(".git").is_dir())
-    }
-}
+use crate::ui::Themed;
 
-impl IdHashItem for Flake<'_> {
-    type Key<'a>
-        = &'a Path
-    where
-        Self: 'a;
+/// What happened to the flakes, which decides the exit code.
+#[derive(Default)]
+struct Outcome {
+    outdated: usize,
+    failed: usize,
+}
 
-    fn key(&self) -> Self::Key<'_> {
-        &self.directory
+impl Outcome {
+    /// Returns 2 if a flake failed, 1 if a flake was outdated with `list --fail-if-outdated` and
+    /// 0 otherwise. Other errors also exit with 2.
+    fn exit_code(&self, command: &FlakeCommand) -> ExitCode {
+        let (fail_if_outdated, allow_errors) = match command {
+            FlakeCommand::List(list_args) => (list_args.fail_if_outdated, list_args.allow_errors),
+            _ => (false, false),
+        };
+        if self.failed > 0 && !allow_errors {
+            ExitCode::from(2)
+        } else if self.outdated > 0 && fail_if_outdated {
+            ExitCode::from(1)
+        } else {
+            ExitCode::SUCCESS
+        }
     }
-    iddqd::id_upcast!();
 }
 
-fn filter_gcroot<'cli>(
-    entry: &fs::DirEntry,
-    flakes: &mut IdHashMap<Flake<'cli>>,
-    flake_id: &'cli str,
-) -> Result<()> {
-    let gcroot = fs::read_link(entry.path())?;
-    if !gcroot.exists() {
-        return Ok(());
-    }
-
-    let Some((directory, is_direnv, is_build_result)) = {
-        gcroot
-            .ancestors()
-            .find(|path| path.file_name().is_some_and(|name| name == ".direnv"))
-            .and_then(|direnv_path| direnv_path.parent())
-            .map(|path| (path, true, false))
-    }
-    .or_else(|| {
-        gcroot
-            .file_name()
-            .is_some_and(|name| name == "result" || name.as_encoded_bytes().starts_with(b"result-"))
-            .then(|| gcroot.parent())
-            .flatten()
-            .map(|path| (path, false, true))
-    }) else {
-        return Ok(());
-    };
-
-    match flakes.entry(directory) {
-        IdHashMapEntry::Occupied(mut occupied) => {
-            let mut existing = occupied.get_mut();
-            existing.gcroots.push(gcroot.clone());
-            existing.has_direnv_gc_roots |= is_direnv;
-            existing.has_build_result |= is_build_result;
-        }
-        IdHashMapEntry::Vacant(vacant) => {
-            let lockfile_path = directory.join("flake.lock");
-            if !lockfile_path.exists() {
-                return Ok(());
+/// Processes the flake and returns whether it was outdated.
+fn process_flake(
+    flake: &Flake,
+    cli: &Cli,
+    command: &FlakeCommand,
+    target: &MatchTarget,
+    flake_index: usize,
+    flakes_count: usize,
+    session: &mut update::Session,
+) -> Result<bool> {
+    if !flake.has_manifest() {
+        match command {
+            FlakeCommand::Update(update_args) => {
+                update::delete_stale_gcroots(flake, cli, update_args)?;
             }
-
-            vacant.insert(Flake {
-                id: flake_id,
-                directory: directory.to_owned(),
-                gcroots: vec![gcroot.clone()],
-                has_direnv_gc_roots: is_direnv,
-                has_build_result: is_build_result,
-                lockfile_path,
-            });
+            _ => progress::suspend(|| print_missing_manifest(flake, cli)),
         }
+        return Ok(false);
     }
 
-    Ok(())
-}
+    let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
 
-/// `nix flake metadata --json` output
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct NixFlakeMetadata {
-    //description: Option<String>,
-    //fingerprint: String,
-    // lastModified = locked.lastModified?
-    locked: Locked,
-    locks: lockfile::Lockfile,
-    //original: Original,
-    //original_url: String,
-    /// Equal to `original` except when `original` is indirect.
-    resolved: Original,
-    resolved_url: String,
-    // unused: url: String,
-}
+    if !lockfile_node.flake {
+        progress::suspend(|| print_non_flake_input(flake, cli));
+        return Ok(false);
+    }
+    if cli.explain {
+        progress::suspend(|| explain::print_checks(flake, cli, target, &lockfile_node))?;
+    }
+    let transitive = outdated_transitive_inputs(flake, cli, target)?;
+    if is_up_to_date(cli, target, Some(flake), &lockfile_node)? && transitive.is_empty() {
+        return Ok(false);
+    }
+
+    match command {
+        FlakeCommand::List(list_args) => {
+            progress::suspend(|| -> Result<()> {
+                let last_updated = session.state().last_updated(&flake.directory);
+                print_flake_info(
+                    flake,
+                    cli,
+                    target,
+                    &lockfile_node,
+                    &transitive,
+                    last_updated,
+                )?;
+                if list_args.sizes {
+                    print_closure_size(flake);
+                }
+                if list_args.check_forks {
+                    print_fork_divergence(target, &lockfile_node);
+                }
+                if list_args.tree {
+                    let tree = graph::render_lockfile(
+                        &flake.lockfile_path,
+                        flake.id,
+                        graph::GraphFormat::Tree,
+                    )?;
+                    for line in tree.lines() {
+                        println!("  {line}");
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        FlakeCommand::Diff(diff_args) => {
+            progress::suspend(|| -> Result<()> {
+                let last_updated = session.state().last_updated(&flake.directory);
+                print_flake_info(
+                    flake,
+                    cli,
+                    target,
+                    &lockfile_node,
+                    &transitive,
+                    last_updated,
+                )?;
+                patch::diff_flake(flake, target, diff_args)
+            })?;
+        }
+        FlakeCommand::Update(update_args) => {
+            update::update_flake(
+                flake,
+                cli,
+                target,
+                flake_index,
+                flakes_count,
+                update_args,
+                session,
+            )?;
+        }
+    }
 
-enum MatchTarget {
-    /// Target a flake's flake ref
-    FlakeMetadata(NixFlakeMetadata),
-    /// Target a flake's input's flake ref
-    FlakeInput {
-        input: LockfileNode,
-        flake_ref_url: String,
-    },
+    Ok(true)
 }
 
-impl MatchTarget {
-    /// Returns the `locked` key.
-    const fn locked(&self) -> &Locked {
-        match self {
-            Self::FlakeMetadata(metadata) => &metadata.locked,
-            Self::FlakeInput { input, .. } => &input.locked,
-        }
+/// Prints the directory of the flake and what it's used by.
+fn print_flake_markers(flake: &Flake<'_>, cli: &Cli) {
+    if let Some(host) = &cli.host {
+        print!("{}", format_args!("{host}:").muted());
     }
-    /// Returns the `original` key.
-    const fn original(&self) -> &Original {
-        match self {
-            Self::FlakeMetadata(metadata) => &metadata.resolved,
-            Self::FlakeInput { input, .. } => &input.original.inner,
-        }
+    print!("{}", flake.directory.display().muted());
+    if flake.has_direnv_gc_roots {
+        print!("{}", " (direnv)".good());
     }
-    /// Returns the URL-like flake ref with `indirect` flakes resolved for [`MatchTarget::FlakeMetadata`].
-    fn flake_ref_url(&self) -> &str {
-        match self {
-            Self::FlakeMetadata(metadata) => &metadata.resolved_url,
-            Self::FlakeInput { flake_ref_url, .. } => flake_ref_url,
-        }
+    if flake.envrc_directory() != flake.directory {
+        print!(
+            " {}",
+            format_args!("(.envrc in {})", flake.envrc_directory().display()).muted()
+        );
     }
-    fn matches_ref(&self, lockfile_node: &LockfileNode) -> bool {
-        lockfile_node
-            .original
-            .inner
-            .ref_()
-            .is_some_and(|ref_| Some(ref_) == self.original().ref_())
+    if flake.project == ProjectKind::Devenv {
+        print!("{}", " (devenv)".good());
     }
-    fn matches_rev(&self, lockfile_node: &LockfileNode) -> bool {
-        lockfile_node
-            .locked
-            .rev()
-            .is_some_and(|rev| Some(rev) == self.locked().rev())
+    if flake.has_lorri_gc_roots {
+        print!("{}", " (lorri)".good());
     }
-    fn matches_url(&self, lockfile_node: &LockfileNode) -> bool {
-        lockfile_node
-            .locked
-            .url_no_git()
-            .is_some_and(|url| Some(url) == self.locked().url_no_git())
+    if flake.has_build_result {
+        print!("{}", " (build result)".good());
     }
-}
-
-/// Complementary to [`MatchTarget::matches_ref`].
-fn timestamp_matches(cli: &Cli, last_modified: u64) -> Result<(SystemTime, bool)> {
-    let last_modified = SystemTime::UNIX_EPOCH
-        .checked_add(Duration::from_secs(last_modified))
-        .ok_or_eyre("Invalid last_modified")?;
-    let elapsed = last_modified.elapsed().unwrap();
-
-    Ok((last_modified, elapsed < cli.ref_match_age))
-}
-
-fn process_flake(
-    flake: &Flake,
-    cli: &Cli,
-    target: &MatchTarget,
-    flake_index: usize,
-    flakes_count: usize,
-) -> Result<()> {
-    let lockfile_node = load_lockfile_input(&flake.lockfile_path, cli)?;
-
-    // filter!
-    if (target.matches_ref(&lockfile_node)
-        && lockfile_node
-            .locked
-            .last_modified()
-            .map(|ts| timestamp_matches(cli, ts))
-            .transpose()?
-            .is_some_and(|x| x.1))
-        || target.matches_rev(&lockfile_node)
-        || target.matches_url(&lockfile_node)
-    {
-        return Ok(());
+    if let Some(system) = flake.system {
+        print!(" {}", format_args!("({system})").good());
     }
-
-    match &cli.command {
-        CliCommand::List => {
-            print_flake_info(flake, cli, target, &lockfile_node)?;
-        }
-        CliCommand::Update(update_args) => {
-            update::update_flake(flake, cli, target, flake_index, flakes_count, update_args)?;
+    if flake.home_manager {
+        print!("{}", " (home-manager)".good());
+    }
+    if flake.is_in_store() {
+        print!("{}", " (read-only in the Nix store)".warning());
+    } else if cli.host.is_none() && !flake.is_writable() {
+        print!("{}", " (not writable by you)".warning());
+    }
+    // The working tree is only inspected locally
+    if cli.host.is_none() {
+        if let Some(checkout @ (vcs::GitCheckout::Worktree | vcs::GitCheckout::Submodule)) =
+            vcs::git_checkout(&flake.directory)
+        {
+            print!(" {}", format_args!("({checkout})").muted());
         }
     }
-
-    Ok(())
 }
 
+/// Prints the flake's locked input compared to the target and returns whether it matches.
+///
+/// `transitive` are the outdated other lock nodes of the input's repository, and `last_updated` is
+/// when `update` last locked the flake, if it has.
 fn print_flake_info(
     flake: &Flake<'_>,
     cli: &Cli,
     target: &MatchTarget,
     lockfile_node: &LockfileNode,
+    transitive: &[(TransitiveInput, LockfileNode)],
+    last_updated: Option<SystemTime>,
 ) -> Result<bool> {
-    print!("{}", flake.directory.display().fg::<xterm::Gray>(),);
-    if flake.has_direnv_gc_roots {
-        print!("{}", " (direnv)".green());
-    }
-    if flake.has_build_result {
-        print!("{}", " (build result)".green());
-    }
-    print!("{}", ":".fg::<xterm::Gray>(),);
+    print_flake_markers(flake, cli);
+    print!("{}", ":".muted());
 
     let mut printed = false;
 
-    let ref_matches_target = target.matches_ref(lockfile_node);
-    if let Some(ref_) = lockfile_node.original.inner.ref_() {
-        if ref_matches_target {
-            print!(" {}", ref_.green());
+    let checks = target.checks(lockfile_node, cli.ref_match_age())?;
+    // A Git input without a ref follows the default branch, which is only known once locked
+    let ref_ = registry::resolved_ref(&lockfile_node.original.inner)
+        .or_else(|| lockfile_node.locked.git_ref().map(Cow::Borrowed));
+    if let Some(ref_) = ref_ {
+        if checks.ref_ || checks.git_url {
+            print!(" {}", ref_.good());
         } else {
-            print!(" {}", ref_.red());
+            print!(" {}", ref_.bad());
         }
         printed = true;
     }
 
-    let rev_matches_target = target.matches_rev(lockfile_node);
     if let Some(rev) = lockfile_node.locked.rev() {
-        if rev_matches_target {
+        if checks.rev {
             if !printed {
-                print!(" {}", rev.green());
+                print!(" {}", rev.good());
             }
         } else {
-            print!(" {}", rev.red());
+            print!(" {}", rev.bad());
         }
         printed = true;
     }
 
-    let url_matches_target = target.matches_url(lockfile_node);
     if let Some(url) = lockfile_node.locked.url_no_git() {
-        if url_matches_target {
+        if checks.url {
             if !printed {
-                print!(" {}", url.green());
+                print!(" {}", url.good());
             }
         } else {
-            print!(" {}", url.red());
+            print!(" {}", url.bad());
         }
     }
 
-    let timestamp_matches = if let Some(ts) = lockfile_node.locked.last_modified() {
-        let (ts, matches) = timestamp_matches(cli, ts)?;
+    if checks.nar_hash && !checks.rev && !checks.url {
+        print!(" {}", "(same contents as the target)".good());
+    }
+
+    if lockfile_node.locked.is_shallow() {
+        print!(" {}", "(shallow)".muted());
+    }
+
+    if let Some(ts) = lockfile_node.locked.last_modified() {
+        let ts = SystemTime::UNIX_EPOCH + Duration::from_secs(ts);
         print!(
             " {} {}",
-            "last updated".fg::<xterm::Gray>(),
-            chrono_humanize::HumanTime::from(ts).cyan(),
+            "last updated".muted(),
+            chrono_humanize::HumanTime::from(ts).value(),
+        );
+    }
+    if let Some(last_updated) = last_updated {
+        print!(
+            " {}",
+            format_args!(
+                "(updated by {} {})",
+                env!("CARGO_PKG_NAME"),
+                chrono_humanize::HumanTime::from(last_updated)
+            )
+            .muted()
+        );
+    }
+
+    print_direnv_provenance(flake, cli);
+    println!();
+
+    if let lockfile::Original::Indirect { id, rev, ref_ } = &lockfile_node.original.inner {
+        print_indirect_resolution(id, ref_.as_deref(), rev.as_deref());
+    }
+    let path_matches_target = print_path_input(flake, cli, target, lockfile_node)?;
+    print_fork_owner(target, lockfile_node);
+    print_transitive_inputs(cli, transitive)?;
+    if let Some(release) = end_of_life_release(lockfile_node)? {
+        let end = release.end_of_support();
+        println!(
+            "  {}",
+            format_args!(
+                "NixOS {release} reached its end of life after 20{:02}-{:02} and no longer gets security updates",
+                end.year, end.month
+            )
+            .bad()
         );
-        matches
+    }
+
+    Ok(checks.matches() || path_matches_target)
+}
+
+/// Returns the other lock nodes of the input's repository that aren't up to date, with
+/// `--transitive`.
+fn outdated_transitive_inputs(
+    flake: &Flake<'_>,
+    cli: &Cli,
+    target: &MatchTarget,
+) -> Result<Vec<(TransitiveInput, LockfileNode)>> {
+    // The lock file is only read locally
+    if !cli.transitive || cli.host.is_some() || flake.project != ProjectKind::Flake {
+        return Ok(Vec::new());
+    }
+    let graph = lockfile::load_lockfile_graph(&flake.lockfile_path)?;
+    target.outdated_transitive_inputs(&graph, flake.id, cli.ref_match_age(), &cli.mismatch)
+}
+
+/// Prints the outdated other lock nodes of the input's repository.
+fn print_transitive_inputs(
+    cli: &Cli,
+    transitive: &[(TransitiveInput, LockfileNode)],
+) -> Result<()> {
+    for (input, node) in transitive {
+        print!(
+            "  {} {} {}",
+            format_args!("{} through", input.node_id).muted(),
+            input.input_path().value(),
+            "is outdated:".warning()
+        );
+        if let Some(ref_) = registry::resolved_ref(&node.original.inner) {
+            print!(" {}", ref_.bad());
+        }
+        if let Some(rev) = node.locked.rev() {
+            print!(" {}", rev.bad());
+        }
+        if let Some(ts) = node.locked.last_modified() {
+            let (ts, _) = timestamp_matches(ts, cli.ref_match_age())?;
+            print!(
+                " {} {}",
+                "last updated".muted(),
+                chrono_humanize::HumanTime::from(ts).value()
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Returns the store paths the flake's gcroots point to.
+fn gcroot_store_paths(flake: &Flake<'_>) -> Vec<PathBuf> {
+    let store = store::get();
+    flake
+        .gcroots
+        .iter()
+        .filter_map(|gcroot| store.store_path(gcroot))
+        .collect()
+}
+
+/// Prints how much space the flake's gcroots keep alive in the store.
+fn print_closure_size(flake: &Flake<'_>) {
+    if flake.gcroots.is_empty() {
+        return;
+    }
+    match store::get().closure_size(&gcroot_store_paths(flake)) {
+        Ok(size) => println!(
+            "  {}",
+            format_args!("gcroots keep {} alive", HumanBytes(size)).muted()
+        ),
+        Err(err) => tracing::warn!("Failed to query the closure size of the gcroots: {err:?}"),
+    }
+}
+
+/// Prints that the flake's input isn't a flake, so it's skipped.
+fn print_non_flake_input(flake: &Flake<'_>, cli: &Cli) {
+    print_flake_markers(flake, cli);
+    println!(
+        "{} {}",
+        ":".muted(),
+        format_args!("{} is a non-flake input (flake = false)", flake.id).warning()
+    );
+    println!(
+        "  {}",
+        "It's only fetched as a source tree and may not follow the target, so it's skipped."
+            .muted()
+    );
+}
+
+/// Prints whether direnv overrides the input or its cache is older than the lock file, on the
+/// line of [`print_flake_info`].
+fn print_direnv_provenance(flake: &Flake<'_>, cli: &Cli) {
+    // direnv's cache is only inspected locally
+    if cli.host.is_some() || !flake.has_direnv_gc_roots {
+        return;
+    }
+    let provenance = envrc::provenance(flake.envrc_directory(), &flake.lockfile_path, flake.id);
+    if let Some(flake_ref) = &provenance.override_input {
+        print!(
+            " {} {}",
+            "direnv overrides input with".warning(),
+            flake_ref.notice()
+        );
+    }
+    if provenance.cache_predates_lockfile {
+        print!(" {}", "(direnv cache is older than flake.lock)".warning());
+    }
+}
+
+/// Prints what the input points to if it's a `path:` input and returns whether it matches the
+/// target.
+///
+/// Whatever is at the path is locked, so it's compared by the input locking the target's
+/// repository in the path's own lock file, or by when it was last modified if it doesn't have
+/// one. Updating it to the target would replace the path, so the advice is to update the path.
+fn print_path_input(
+    flake: &Flake<'_>,
+    cli: &Cli,
+    target: &MatchTarget,
+    lockfile_node: &LockfileNode,
+) -> Result<bool> {
+    let lockfile::Locked::Path { path, .. } = &lockfile_node.locked else {
+        return Ok(false);
+    };
+    println!(
+        "  {} {}",
+        format_args!("{} is a path input to", flake.id).notice(),
+        lockfile::join_path_input(&flake.directory, path)
+            .display()
+            .value()
+    );
+    let followed = follow_path_input(flake, cli, target, lockfile_node);
+    let (comparison, matches, advice) = if let Some((directory, node)) = &followed {
+        let comparison = format!(
+            "Its own {} locks {}",
+            directory.join("flake.lock").display(),
+            node.original.to_flake_ref()?
+        );
+        (
+            comparison,
+            target.is_up_to_date(node, cli.ref_match_age())?,
+            "Update it there first, then relock this flake with",
+        )
     } else {
-        false
+        let comparison = lockfile_node.locked.last_modified().map_or_else(
+            || "It has no modification time".to_owned(),
+            |ts| {
+                let ts = SystemTime::UNIX_EPOCH + Duration::from_secs(ts);
+                format!(
+                    "It was last modified {}",
+                    chrono_humanize::HumanTime::from(ts)
+                )
+            },
+        );
+        (
+            comparison,
+            target.matches_path_last_modified(lockfile_node),
+            "Pull or update the checkout there, then relock this flake with",
+        )
     };
+    if matches {
+        println!(
+            "  {}",
+            format_args!("{comparison}, up to date with the target").good()
+        );
+    } else {
+        let (program, args) = flake.project.update_input_command(flake.id);
+        println!(
+            "  {}",
+            format_args!("{comparison}, behind the target {}", target.flake_ref_url()).bad()
+        );
+        println!(
+            "  {} {}",
+            advice.muted(),
+            format_args!("{program} {}", args.join(" ")).command()
+        );
+    }
+    Ok(matches)
+}
 
-    println!();
+/// Returns the input the `path:` input is compared by instead, see
+/// [`MatchTarget::follow_path_input`]. The path's lock file is only read locally.
+fn follow_path_input(
+    flake: &Flake<'_>,
+    cli: &Cli,
+    target: &MatchTarget,
+    lockfile_node: &LockfileNode,
+) -> Option<(PathBuf, LockfileNode)> {
+    if cli.host.is_some() {
+        return None;
+    }
+    target.follow_path_input(&flake.directory, flake.id, lockfile_node)
+}
 
-    // TODO: warn on indirect flakes!!
+/// Prints who the input's repository belongs to, if it's a fork of the target's.
+fn print_fork_owner(target: &MatchTarget, lockfile_node: &LockfileNode) {
+    if let Some(owner) = lockfile_node.locked.fork_owner(target.locked()) {
+        println!(
+            "  {}",
+            format_args!("The input is a fork by {owner} of the target's repository").notice()
+        );
+    }
+}
 
-    let matches_target =
-        (ref_matches_target && timestamp_matches) || rev_matches_target || url_matches_target;
-    Ok(matches_target)
+/// Prints how far the input's fork is behind the target, if it's from a fork.
+fn print_fork_divergence(target: &MatchTarget, lockfile_node: &LockfileNode) {
+    if lockfile_node.locked.fork_owner(target.locked()).is_none() {
+        return;
+    }
+    let (Some(fork_ref), Some(base)) = (
+        registry::resolved_ref(&lockfile_node.original.inner)
+            .or_else(|| lockfile_node.locked.rev().map(Cow::Borrowed)),
+        target.locked().rev(),
+    ) else {
+        return;
+    };
+    match forge::fork_divergence(&lockfile_node.locked, &fork_ref, target.locked(), base) {
+        Ok(divergence) => println!(
+            "  {}",
+            format_args!(
+                "{fork_ref} is {} commits behind and {} commits ahead of the target",
+                divergence.behind_by, divergence.ahead_by
+            )
+            .muted()
+        ),
+        Err(err) => tracing::warn!("Failed to compare the fork to the target: {err:?}"),
+    }
 }
 
+/// Returns the release of the input's NixOS release branch, like `nixos-24.05`, if it's past its
+/// end of life.
+fn end_of_life_release(lockfile_node: &LockfileNode) -> Result<Option<channels::Release>> {
+    let Some(release) = registry::resolved_ref(&lockfile_node.original.inner)
+        .and_then(|ref_| channels::Release::from_branch(&ref_))
+    else {
+        return Ok(None);
+    };
+    Ok(release
+        .is_end_of_life(channels::Release::current()?)
+        .then_some(release))
+}
+
+/// Prints a flake whose `flake.nix` is missing and the gcroots that may be stale.
+fn print_missing_manifest(flake: &Flake<'_>, cli: &Cli) {
+    print_flake_markers(flake, cli);
+    println!(
+        "{} {}",
+        ":".muted(),
+        format_args!("{} is missing", flake.project.manifest()).bad()
+    );
+    println!(
+        "  {}",
+        "The project was likely deleted or moved, or the flake is in a subdirectory with `?dir=`."
+            .warning()
+    );
+    if flake.gcroots.is_empty() {
+        return;
+    }
+    println!("  {}", "Its gcroots may be stale:".warning());
+    for gcroot in &flake.gcroots {
+        println!("    {}", gcroot.display().value());
+    }
+    println!(
+        "  {} {} {}",
+        "Delete them with".muted(),
+        "update --allow-write".command(),
+        "or rm".muted()
+    );
+}
+
+/// Prints what an indirect input, which is locked to whatever the flake registries pointed to at
+/// the time, currently resolves to.
+fn print_indirect_resolution(id: &str, ref_: Option<&str>, rev: Option<&str>) {
+    let resolved = registry::Registry::get()
+        .resolve(&registry::indirect(id, ref_, rev))
+        .and_then(|resolved| flake_ref::to_string(&resolved).ok());
+    match resolved {
+        Some(resolved) => println!(
+            "  {} {}",
+            format_args!("Indirect input {id} resolves to").warning(),
+            resolved.notice()
+        ),
+        None => println!(
+            "  {}",
+            format_args!("Indirect input {id} isn't in any flake registry").bad()
+        ),
+    }
+}
+
+/// `--input-id` picking the input locking nixpkgs in each flake.
+const AUTO_INPUT_ID: &str = "auto";
+
+/// The default `--ref-match-age` of one month, 30.44 days like humantime's `1M`.
+const DEFAULT_REF_MATCH_AGE: Duration = Duration::from_secs(2_630_016);
+
 /// Nix garbage collector root flake updater
 ///
 /// Looks for Nix garbage collector roots in `/nix/var/nix/gcroots/auto` and filters them for
@@ -308,8 +615,16 @@ fn print_flake_info(
 /// Then allows the user to execute operations on the found flakes interactively.
 #[derive(Parser)]
 #[command(author, version)]
+#[expect(clippy::struct_excessive_bools, reason = "These are independent flags")]
 struct Cli {
     /// The name of the input to look for in flakes.
+    ///
+    /// `auto` picks the input of each flake locking `github:NixOS/nixpkgs`, whatever it's called,
+    /// like `pkgs`.
+    ///
+    /// `*` wildcards select every matching input of each flake, like `nixpkgs*` for `nixpkgs`
+    /// and `nixpkgs-stable`. Each is checked against its configured target, and inputs other
+    /// than `nixpkgs` without one are skipped unless `--target` is given.
     #[arg(long, default_value = "nixpkgs")]
     input_id: String,
 
@@ -319,33 +634,322 @@ struct Cli {
     ///
     /// Use a hash symbol to reference an input of a flake. For example: `./my-nixos-config#nixpkgs`.
     ///
-    /// Defaults to `github:NixOS/nixpkgs/nixos-unstable` when `input-id` is set to `nixpkgs`.
-    #[arg(long, default_value_if("input_id", ArgPredicate::Equals("nixpkgs".into()), "github:NixOS/nixpkgs/nixos-unstable"))]
-    target: String,
+    /// A full commit hash targets that commit of `--target-repo`. It's only fetched to compare
+    /// inputs locked without a rev, like tarballs.
+    ///
+    /// `nixos-stable` is the newest NixOS release branch, like `github:NixOS/nixpkgs/nixos-25.05`.
+    ///
+    /// Defaults to `github:NixOS/nixpkgs/nixos-unstable` when `input-id` is set to `nixpkgs` or `auto`.
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Targets the newest tag of the target's repository matching a semver requirement, like
+    /// `>=1.4, <2`.
+    ///
+    /// The target's ref can instead or also have `*` wildcards, like `github:owner/repo/v*`.
+    #[arg(long, value_name = "REQUIREMENT", value_parser = semver::VersionReq::parse)]
+    target_semver: Option<semver::VersionReq>,
+
+    /// The repository of a `--target` that's a commit hash, like `github:NixOS/nixpkgs`.
+    ///
+    /// Defaults to `github:NixOS/nixpkgs` when `input-id` is set to `nixpkgs` or `auto`.
+    #[arg(long, value_name = "FLAKE_REF")]
+    target_repo: Option<String>,
+
+    /// Targets the input locked in a lockfile, like `/etc/nixos/flake.lock#nixpkgs`, instead of
+    /// fetching `--target`.
+    ///
+    /// The input defaults to `--input-id`.
+    #[arg(long, value_name = "PATH[#INPUT]", conflicts_with_all = ["target", "target_semver", "target_repo"])]
+    target_lockfile: Option<String>,
+
+    /// Uses the target's metadata saved by a previous run if fetching it fails, e.g. when offline.
+    #[arg(long)]
+    fall_back_to_saved_target: bool,
 
     /// Minimum `last_modified` from before now when only `ref` matching skips flakes.
     ///
     /// Supported suffixes: y, M, w, d, h, m, s
     ///
     /// Set to `0` to use only skip flakes if the locked `rev` or `url` match.
-    #[arg(long, default_value = "1 month", value_parser = humantime::parse_duration, value_name = "DURATION")]
-    ref_match_age: Duration,
+    ///
+    /// Defaults to the input's `ref-match-age` in the configuration, or 1 month.
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    ref_match_age: Option<Duration>,
+
+    /// Only includes flakes in Git repositories, e.g. for a pass with the commit workflow.
+    #[arg(long, conflicts_with = "only_non_git")]
+    only_git: bool,
+
+    /// Only includes flakes outside of Git repositories, e.g. for a pass only refreshing locks.
+    #[arg(long)]
+    only_non_git: bool,
+
+    /// Also includes the flakes snoozed with `s` at the prompt or the `snooze` subcommand.
+    #[arg(long)]
+    include_snoozed: bool,
+
+    /// Only includes flakes whose input differs from the target in one of these ways, like
+    /// `ref` for flakes following another branch entirely.
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "KIND")]
+    mismatch: Vec<Mismatch>,
+
+    /// Prints which checks against the target each flake passed and why it was or wasn't
+    /// skipped, also for the flakes that are skipped.
+    #[arg(long)]
+    explain: bool,
+
+    /// Also checks the other lock nodes of the input's repository, like `nixpkgs_2` locked by
+    /// home-manager, which are often what's actually outdated.
+    ///
+    /// Only nodes following the target's ref are checked. Flakes are included if one of them is
+    /// outdated, and `update` relocks them with `nix flake update <input path>` after locking.
+    #[arg(long)]
+    transitive: bool,
+
+    /// Finds the input by its flake reference instead of `--input-id`, where `*` matches
+    /// anything, like `github:NixOS/nixpkgs*` for flakes calling nixpkgs `pkgs` or `unstable`.
+    /// Case is ignored, like forges do for owners and repositories.
+    ///
+    /// The input's own ID is used to edit `flake.nix` and to lock. Flakes without a matching
+    /// input are left out. If several inputs match, `--input-id` is preferred, then the first
+    /// by name.
+    #[arg(long, value_name = "PATTERN", conflicts_with = "host")]
+    match_url: Option<String>,
+
+    /// Lists the flakes of another machine over SSH, like `user@machine`.
+    ///
+    /// Only works with `list`. To update the flakes, run nixpkgsupd on the machine. Its gcroots,
+    /// lock files, store directory and user and system flake registries are read over SSH, while
+    /// the target is fetched locally and the global flake registry is the local copy.
+    #[arg(long, value_name = "DESTINATION")]
+    host: Option<String>,
+
+    /// Root of a local chroot store like `~/my-nix`, like `nix --store`.
+    ///
+    /// Also passed to the `nix` commands run. `NIX_STORE_DIR` and `NIX_STATE_DIR` are honored
+    /// like Nix does.
+    #[arg(long, value_name = "PATH", conflicts_with = "host")]
+    store: Option<PathBuf>,
+
+    /// How long a Nix command using the network may run before it's killed and retried.
+    ///
+    /// Set to `0` to wait indefinitely.
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    command_timeout: Duration,
+
+    /// How many times a Nix command is retried after timing out or failing to reach the network.
+    #[arg(long, default_value_t = 2, value_name = "COUNT")]
+    retries: u32,
+
+    /// Logs what's being done to standard error. Repeat for more detail, up to `-vvv`.
+    ///
+    /// `NIXPKGSUPD_LOG` overrides this with filter directives like `nixpkgsupd=debug`.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Color theme for output.
+    ///
+    /// Defaults to `dark` when writing to a terminal and `none` otherwise or when `NO_COLOR` is set.
+    #[arg(long, value_enum, value_name = "THEME")]
+    theme: Option<ui::Theme>,
 
     #[command(subcommand)]
     command: CliCommand,
+
+    /// Loaded from the configuration file.
+    #[arg(skip)]
+    config: config::Config,
+}
+
+impl Cli {
+    /// Returns `--ref-match-age`, defaulting to 1 month.
+    fn ref_match_age(&self) -> Duration {
+        self.ref_match_age.unwrap_or(DEFAULT_REF_MATCH_AGE)
+    }
+
+    /// Returns the input ID the target's defaults and the input's configuration are looked up by,
+    /// which is `nixpkgs` for `--input-id auto`.
+    fn target_input_id(&self) -> &str {
+        if self.input_id == AUTO_INPUT_ID {
+            "nixpkgs"
+        } else {
+            &self.input_id
+        }
+    }
+
+    /// Returns the pattern of `--input-id` if it has wildcards.
+    fn input_id_pattern(&self) -> Option<Regex> {
+        self.input_id
+            .contains('*')
+            .then(|| tags::wildcard(&self.input_id))
+    }
+
+    /// Returns the target flake reference, defaulting to nixos-unstable for `nixpkgs`.
+    fn target(&self) -> Result<&str> {
+        match &self.target {
+            Some(target) => Ok(target),
+            None if self.target_input_id() == "nixpkgs" => {
+                Ok("github:NixOS/nixpkgs/nixos-unstable")
+            }
+            None => bail!("--target is required when --input-id isn't `nixpkgs`"),
+        }
+    }
+}
+
+/// The subcommands that process each discovered flake. The others return before the flakes are
+/// discovered.
+#[expect(
+    clippy::large_enum_variant,
+    reason = "created once, and boxing would prevent matching on the fields of `UpdateArgs`"
+)]
+enum FlakeCommand {
+    List(ListArgs),
+    Diff(DiffArgs),
+    Update(UpdateArgs),
 }
 
 #[derive(Subcommand)]
+#[expect(
+    clippy::large_enum_variant,
+    reason = "parsed once, and boxing would prevent matching on the fields of `UpdateArgs`"
+)]
 enum CliCommand {
     /// Lists the flakes and does not apply any operations on them.
-    List,
+    ///
+    /// Exits with 2 if a flake couldn't be checked and, with `--fail-if-outdated`, with 1 if a
+    /// flake is outdated.
+    List(ListArgs),
     /// Updates Nix flake inputs based on a target.
     ///
     /// Updating only works when the new `nix` command is enabled.
     Update(UpdateArgs),
+    /// Prints the `flake.nix` change `update` would propose for every outdated flake, without
+    /// prompting or writing the flakes.
+    Diff(DiffArgs),
+    /// Prints the input graph of a flake's lockfile.
+    ///
+    /// The node of the targeted input is highlighted in green and other nodes locking the same
+    /// repository in red.
+    Graph(GraphArgs),
+    /// Makes the inputs of a flake's inputs that lock the same repository and ref as one of its
+    /// own inputs, like a second nixpkgs, follow it and relocks.
+    Dedupe(DedupeArgs),
+    /// Checks every flake on a schedule without prompting.
+    ///
+    /// Writes the outdated flakes to `$XDG_STATE_HOME/nixpkgsupd/status.json` after every check.
+    Watch(WatchArgs),
+    /// Checks every flake once and sends a desktop notification when the outdated flakes reach a
+    /// threshold since the previous check, e.g. for a systemd timer.
+    Notify(NotifyArgs),
+    /// Hides a flake from `list`, `update` and `watch` for a while, like `s` at the prompt.
+    Snooze(SnoozeArgs),
+    /// Manages the entry for `--input-id` in the user's flake registry.
+    #[command(subcommand)]
+    Registry(RegistryCommand),
+}
+
+#[derive(Subcommand)]
+enum RegistryCommand {
+    /// Pins the input ID to the locked target in `~/.config/nix/registry.json`.
+    ///
+    /// Indirect references like `nixpkgs` in `nix shell nixpkgs#hello` then use the same revision
+    /// as the flakes updated to the target.
+    Pin,
+    /// Removes the input ID from `~/.config/nix/registry.json`.
+    Unpin,
+}
+
+#[derive(Args, Clone)]
+#[expect(clippy::struct_excessive_bools, reason = "These are independent flags")]
+struct ListArgs {
+    /// Exits with 1 if a flake is outdated, e.g. for CI.
+    #[arg(long)]
+    fail_if_outdated: bool,
+    /// Exits with 0 even if a flake couldn't be checked.
+    #[arg(long)]
+    allow_errors: bool,
+    /// Shows the inputs of each flake as a tree, highlighting the targeted input.
+    #[arg(long)]
+    tree: bool,
+    /// Shows how much space the gcroots of each flake keep alive in the store, and in total.
+    #[arg(long)]
+    sizes: bool,
+    /// Checks with the GitHub API how far inputs from forks of the target's repository are
+    /// behind it.
+    #[arg(long)]
+    check_forks: bool,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Time between checks.
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration, value_name = "DURATION")]
+    interval: Duration,
+    /// Checks once and exits, e.g. for a systemd timer.
+    #[arg(long)]
+    once: bool,
+    /// Sends a desktop notification when the number of outdated flakes reaches this.
+    #[arg(long, value_name = "COUNT")]
+    notify_threshold: Option<usize>,
+    /// Also writes the status as Prometheus metrics to this file, e.g. in `node_exporter`'s
+    /// textfile collector directory.
+    #[arg(long, value_name = "PATH")]
+    prometheus_textfile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct NotifyArgs {
+    /// The number of outdated flakes to notify at.
+    #[arg(long, default_value_t = 1, value_name = "COUNT")]
+    threshold: usize,
+}
+
+#[derive(Args)]
+struct SnoozeArgs {
+    /// Directory containing `flake.nix`.
+    directory: PathBuf,
+    /// How long to hide the flake, like `2w`. Use `0s` to show it again.
+    #[arg(value_parser = humantime::parse_duration)]
+    duration: Duration,
+}
+
+#[derive(Args, Clone)]
+struct DiffArgs {
+    /// Writes each change as a patch to this directory instead of printing it.
+    ///
+    /// Apply one with `git apply` or `patch -p1` in the flake's directory.
+    #[arg(long, value_name = "DIR")]
+    patch_dir: Option<PathBuf>,
+    /// The number of lines to give as context in the diff.
+    #[arg(long, default_value_t = 3)]
+    diff_context: usize,
+}
+
+#[derive(Args)]
+struct DedupeArgs {
+    /// Directory containing `flake.nix`.
+    #[arg(default_value = ".")]
+    directory: PathBuf,
+    /// Allows writing to files. This flag being unset means a dry run.
+    #[arg(long)]
+    allow_write: bool,
+    /// The number of lines to give as context in the diff.
+    #[arg(long, default_value_t = 3)]
+    diff_context: usize,
 }
 
 #[derive(Args)]
+struct GraphArgs {
+    /// Directory containing `flake.lock`.
+    #[arg(default_value = ".")]
+    directory: PathBuf,
+    #[arg(long, value_enum, default_value = "dot")]
+    format: graph::GraphFormat,
+}
+
+#[derive(Args, Clone)]
+#[expect(clippy::struct_excessive_bools, reason = "These are independent flags")]
 struct UpdateArgs {
     /// Allows writing to files. This flag being unset means a dry run.
     #[arg(long)]
@@ -353,12 +957,193 @@ struct UpdateArgs {
     /// The number of lines to give as context in the diff.
     #[arg(long, default_value_t = 3)]
     diff_context: usize,
+    /// Applies, locks and commits in a temporary Git worktree on a separate branch.
+    ///
+    /// The branch is only merged into the checkout after confirmation, which keeps uncommitted
+    /// work untouched.
+    #[arg(long)]
+    worktree: bool,
+    /// What pressing Enter at the prompt does: `auto`, `none` or a prompt command like `n`.
+    ///
+    /// `auto` applies the diff when there are changes and goes to the next flake otherwise.
+    /// `none` prints help.
+    #[arg(long, default_value = "auto", value_name = "ACTION")]
+    default_action: update::DefaultAction,
+    /// How the prompt presents the available commands.
+    ///
+    /// `numbers` lists them with numbers which can be typed instead of the mnemonics.
+    #[arg(long, value_enum, default_value_t = update::MenuStyle::Mnemonic)]
+    menu: update::MenuStyle,
+    /// Refreshes direnv after locking without asking.
+    ///
+    /// Answering `Y!` or `N!` at the prompt answers it for the rest of the run instead.
+    #[arg(long)]
+    always_direnv: bool,
+    /// Commits `flake.nix` and `flake.lock` after locking without asking.
+    ///
+    /// Answering `Y!` or `N!` at the prompt answers it for the rest of the run instead.
+    #[arg(long)]
+    always_commit: bool,
+    /// Pushes the branch to its upstream after committing without asking.
+    ///
+    /// Answering `Y!` or `N!` at the prompt answers it for the rest of the run instead.
+    #[arg(long)]
+    always_push: bool,
+    /// Stashes other uncommitted changes in the repository while committing without asking, so
+    /// that commit hooks only see `flake.nix` and `flake.lock`.
+    ///
+    /// Otherwise it's asked when there are other changes, except without prompting.
+    #[arg(long)]
+    stash: bool,
+    /// Amends the last commit without asking if it's an unpushed bump of the same input, instead
+    /// of committing again.
+    ///
+    /// Otherwise it's asked, except without prompting.
+    #[arg(long)]
+    amend: bool,
+    /// The message for commits of `flake.nix` and `flake.lock`.
+    ///
+    /// Placeholders: `{input}`, `{old_rev}`, `{new_rev}`, `{old_ref}` and `{new_ref}`. Old values
+    /// are from `HEAD` and are empty when unknown.
+    ///
+    /// Defaults to `commit-message-template` in the configuration file or
+    /// `chore: bump flake input {input}`.
+    #[arg(long, value_name = "TEMPLATE")]
+    commit_message_template: Option<String>,
+    /// The Conventional Commits type of the default commit message, like `build` or `deps`.
+    ///
+    /// Defaults to `commit-type` in the configuration file or `chore`.
+    #[arg(long, value_name = "TYPE", conflicts_with = "commit_message_template")]
+    commit_type: Option<String>,
+    /// The Conventional Commits scope of the default commit message, like `deps` for
+    /// `chore(deps): bump flake input nixpkgs`.
+    ///
+    /// Defaults to `commit-scope` in the configuration file.
+    #[arg(long, value_name = "SCOPE", conflicts_with = "commit_message_template")]
+    commit_scope: Option<String>,
+    /// The name of the branch used by `--worktree` and the `pr` prompt command. It must not
+    /// exist yet, so commits left on it by an earlier run aren't lost.
+    ///
+    /// Placeholders: `{input}`.
+    #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), "/bump-{input}"), value_name = "TEMPLATE")]
+    git_branch: String,
+    /// A shell command to run in the flake's directory before showing the diff.
+    ///
+    /// The flake is skipped if the command fails. It gets `NIXPKGSUPD_FLAKE_DIR`,
+    /// `NIXPKGSUPD_INPUT` and `NIXPKGSUPD_OLD_REV` in its environment.
+    ///
+    /// Defaults to `pre-hook` in the configuration file.
+    #[arg(long, value_name = "COMMAND")]
+    pre_hook: Option<String>,
+    /// A shell command to run in the flake's directory after successfully locking it.
+    ///
+    /// It runs after refreshing direnv and committing, and gets `NIXPKGSUPD_NEW_REV` in its
+    /// environment in addition to those of `--pre-hook`.
+    ///
+    /// Defaults to `post-hook` in the configuration file.
+    #[arg(long, value_name = "COMMAND")]
+    post_hook: Option<String>,
+    /// Signs commits with `git commit -S`, optionally with the given key.
+    #[arg(
+        long,
+        visible_alias = "gpg-sign",
+        value_name = "KEYID",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    sign_commits: Option<String>,
+    /// Overrides Git's `gpg.format` when signing commits, for example to sign with an SSH key.
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "sign_commits")]
+    signing_format: Option<update::SigningFormat>,
+    /// The program running the steps that modify flakes you can't write, like `doas`.
+    ///
+    /// You're asked before it's used. Committing and refreshing direnv still run as you.
+    ///
+    /// Defaults to `elevate-with` in the configuration file or `sudo`.
+    #[arg(long, value_name = "PROGRAM")]
+    elevate_with: Option<String>,
+    /// The editor the `edit` prompt command opens `flake.nix` in, which may include arguments.
+    ///
+    /// Defaults to `editor` in the configuration file, `$VISUAL`, `$EDITOR`, then `nano` or `vi`.
+    #[arg(long, value_name = "PROGRAM")]
+    editor: Option<String>,
+    /// Deletes the gcroots of every flake already matching the target first, after confirming
+    /// once.
+    #[arg(long)]
+    clean_matching: bool,
+    /// Deletes the gcroots with `--clean-matching` without confirming. Required with
+    /// `--non-interactive`.
+    ///
+    /// The profile links pointing to the gcroots are kept unless `--delete-profile-links` is
+    /// given.
+    #[arg(long, requires = "clean_matching")]
+    yes: bool,
+    /// Also deletes the profile links pointing to the gcroots deleted by `--clean-matching
+    /// --yes`, which would dangle otherwise.
+    #[arg(long, requires = "yes")]
+    delete_profile_links: bool,
+    /// Writes what would be done to each outdated flake to a JSON file instead of prompting,
+    /// without modifying anything: the change to `flake.nix`, whether the lock file needs to be
+    /// refreshed and whether direnv and committing apply.
+    #[arg(long, value_name = "FILE", conflicts_with = "clean_matching")]
+    plan: Option<PathBuf>,
+    /// Applies a plan written by `--plan` without prompting, skipping flakes whose `flake.nix`
+    /// or lock file changed since. Fails if the target moved to another revision since.
+    ///
+    /// Like `--non-interactive`, direnv is only refreshed and changes are only committed with
+    /// `--always-direnv` and `--always-commit`.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["plan", "pick", "clean_matching"])]
+    apply_plan: Option<PathBuf>,
+    /// Picks the outdated flakes to walk through with a fuzzy search first.
+    #[arg(long, conflicts_with = "non_interactive")]
+    pick: bool,
+    /// Applies and locks every flake without prompting, like answering `A` at the first prompt.
+    ///
+    /// Direnv is only refreshed and changes are only committed with `--always-direnv` and
+    /// `--always-commit`. Required when standard input is not a terminal.
+    #[arg(long)]
+    non_interactive: bool,
     // TODO: target vs flake-ref vs source??
     // TODO: also support non-gcroot mode with more sources or destinations or targets or flakes!!!
     // TODO: also support taking flakes by recursively finding flake.nix's
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Prints the target's ref or rev and its age.
+fn print_target(cli: &Cli, target: &MatchTarget) {
+    print!("{} {}", cli.input_id.value(), "target:".muted());
+
+    if let Some(ref_) = target.original().ref_() {
+        print!(" {}", ref_.good());
+    } else if let Some(rev) = target.locked().rev() {
+        print!(" {}", rev.good());
+    } else if let Some(url) = target.locked().url_no_git() {
+        print!(" {}", url.good());
+    }
+
+    if let Some(last_modified) = target.locked().last_modified() {
+        let last_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified);
+        print!(
+            " {} {}",
+            "last updated".muted(),
+            chrono_humanize::HumanTime::from(last_modified).value(),
+        );
+    }
+
+    println!();
+}
+
+fn run() -> Result<ExitCode> {
     color_eyre::config::HookBuilder::default()
         .theme(if std::io::stderr().is_terminal() {
             color_eyre::config::Theme::dark()
@@ -368,125 +1153,680 @@ fn main() -> Result<()> {
         })
         .install()?;
 
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    // The store directories of the machine are used with `--host`
+    if cli.host.is_none() {
+        store::init(cli.store.as_deref().map(std::path::absolute).transpose()?);
+    }
+    command::init_retries(command::Retries {
+        timeout: Some(cli.command_timeout).filter(|timeout| !timeout.is_zero()),
+        retries: cli.retries,
+    });
+    ui::init(cli.theme.unwrap_or_else(ui::Theme::detect));
+    logging::init(cli.verbose, ui::is_styled())?;
+
+    // A broken configuration file shouldn't make every subcommand fail
+    let config = config::Config::load().unwrap_or_else(|err| {
+        tracing::warn!("Ignoring the configuration file: {err:?}");
+        config::Config::default()
+    });
+    apply_config(&mut cli, config)?;
 
-    if let CliCommand::Update(UpdateArgs {
+    let command = match &cli.command {
+        CliCommand::Graph(graph_args) => {
+            print!(
+                "{}",
+                graph::render_graph(&graph_args.directory, &cli.input_id, graph_args.format)?
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::Dedupe(dedupe_args) => {
+            dedupe::dedupe(dedupe_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::Snooze(snooze_args) => {
+            snooze(snooze_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::Registry(command) => {
+            run_registry_command(&cli, command)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::Watch(watch_args) => {
+            check_usage(&cli)?;
+            status::watch(&cli, watch_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::Notify(notify_args) => {
+            check_usage(&cli)?;
+            status::notify(&cli, notify_args.threshold)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        CliCommand::List(list_args) => FlakeCommand::List(list_args.clone()),
+        CliCommand::Diff(diff_args) => FlakeCommand::Diff(diff_args.clone()),
+        CliCommand::Update(update_args) => FlakeCommand::Update(update_args.clone()),
+    };
+
+    check_usage(&cli)?;
+
+    if let FlakeCommand::Update(UpdateArgs {
         allow_write: false, ..
-    }) = cli.command
+    }) = command
     {
         println!(
             "{}{}",
-            "Note: This is a dry run. To modify files and run commands, run again with "
-                .yellow()
-                .bold(),
-            "--allow-write".cyan().bold()
+            "Note: This is a dry run. To modify files and run commands, run again with ".notice(),
+            "--allow-write".highlight()
         );
     }
 
-    let target = if let Some((flake_ref, input_id)) = cli.target.rsplit_once('#') {
-        let metadata = get_flake_ref_metadata(flake_ref)
-            .wrap_err("Failed to get metadata of flake reference")?;
-        let input = metadata
-            .locks
-            .extract_input(input_id)
-            .wrap_err("Failed to extract input of flake reference")?;
-        MatchTarget::FlakeInput {
-            flake_ref_url: get_flake_ref_url(&input)
-                .wrap_err("Failed to convert flake reference to URL-like format")?,
-            input,
-        }
-    } else {
-        MatchTarget::FlakeMetadata(
-            get_flake_ref_metadata(&cli.target)
-                .wrap_err("Failed to get metadata of flake reference")?,
-        )
+    if let Some(pattern) = cli.input_id_pattern() {
+        return run_input_pattern(cli, &command, &pattern);
+    }
+
+    if let Some(host) = &cli.host {
+        return run_remote(host, &cli, &command);
+    }
+
+    let target = {
+        let _spinner = progress::spinner("Resolving the target");
+        resolve_target(&cli)?
     };
 
-    print!("{} {}", cli.input_id.cyan(), "target:".fg::<xterm::Gray>(),);
+    print_target(&cli, &target);
 
-    if let Some(ref_) = target.original().ref_() {
-        print!(" {}", ref_.green());
-    } else if let Some(rev) = target.locked().rev() {
-        print!(" {}", rev.green());
-    } else if let Some(url) = target.locked().url_no_git() {
-        print!(" {}", url.green());
+    let flakes = {
+        let _spinner = progress::spinner("Looking for flakes");
+        discovery::discover_flakes(&cli.input_id, &cli.config)?
+    };
+
+    let input_ids = selected_input_ids(&cli, &flakes);
+    let flakes = flakes.into_iter().filter_map(|mut flake| {
+        if let Some((input_ids, reason)) = &input_ids {
+            let Some(input_id) = input_ids.get(&flake.directory) else {
+                if cli.explain {
+                    explain::print_filtered(&flake.directory, reason);
+                }
+                return None;
+            };
+            flake.id = input_id;
+        }
+        Some(flake)
+    });
+
+    let state = state::State::load_or_default();
+    let flakes = filter_flakes(&cli, flakes, &state);
+    process_flakes(&cli, &command, &target, flakes, state)
+}
+
+/// Lists the flakes of `host`, resolving indirect inputs and the target with its flake
+/// registries.
+fn run_remote(host: &str, cli: &Cli, command: &FlakeCommand) -> Result<ExitCode> {
+    {
+        let _spinner = progress::spinner("Reading the flake registries of the machine");
+        remote::load_registries(host)?;
     }
+    let target = {
+        let _spinner = progress::spinner("Resolving the target");
+        resolve_target(cli)?
+    };
+    print_target(cli, &target);
 
-    if let Some(last_modified) = target.locked().last_modified() {
-        let last_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified);
-        print!(
-            " {} {}",
-            "last updated".fg::<xterm::Gray>(),
-            chrono_humanize::HumanTime::from(last_modified).cyan(),
+    let outcome = remote::list_flakes(host, cli, &target)?;
+    Ok(outcome.exit_code(command))
+}
+
+/// Processes the flakes once for each input matching the `--input-id` wildcards, with the
+/// input's configured target and `ref-match-age` unless they were given on the command line.
+fn run_input_pattern(mut cli: Cli, command: &FlakeCommand, pattern: &Regex) -> Result<ExitCode> {
+    // The flakes' IDs are replaced with the matching inputs' IDs
+    let input_id_pattern = cli.input_id.clone();
+    let flakes = {
+        let _spinner = progress::spinner("Looking for flakes");
+        discovery::discover_flakes(&input_id_pattern, &cli.config)?
+    };
+    let state = state::State::load_or_default();
+    let flakes = filter_flakes(&cli, flakes, &state);
+    drop(state);
+    let flakes_by_input = discovery::flakes_by_input_id(&flakes, pattern);
+    if cli.explain {
+        for (index, flake) in flakes.iter().enumerate() {
+            if !flakes_by_input
+                .values()
+                .any(|indices| indices.contains(&index))
+            {
+                explain::print_filtered(&flake.directory, "no input matches --input-id");
+            }
+        }
+    }
+    if flakes_by_input.is_empty() {
+        eprintln!(
+            "{}",
+            format_args!("No flake has an input matching {}", cli.input_id).warning()
         );
     }
 
-    println!();
+    let target = cli.target.take();
+    let ref_match_age = cli.ref_match_age;
+    let mut exit_code = ExitCode::SUCCESS;
+    for (input_id, indices) in &flakes_by_input {
+        cli.input_id.clone_from(input_id);
+        cli.target = target
+            .clone()
+            .or_else(|| cli.config.target(input_id).map(str::to_owned));
+        if cli.target.is_none() && input_id != "nixpkgs" {
+            eprintln!(
+                "{}",
+                format_args!(
+                    "Skipping the input {input_id} of {} flakes, which has no target. Set `target` in `[inputs.{input_id}]` of the configuration or give --target",
+                    indices.len()
+                )
+                .notice()
+            );
+            continue;
+        }
+        cli.ref_match_age = match ref_match_age {
+            Some(age) => Some(age),
+            None => cli.config.ref_match_age(input_id)?,
+        };
+        let input_target = {
+            let _spinner = progress::spinner("Resolving the target");
+            resolve_target(&cli)
+        };
+        let input_target = match input_target {
+            Ok(input_target) => input_target,
+            Err(err) => {
+                eprintln!(
+                    "{:?}",
+                    err.wrap_err(format!("Failed to resolve the target of {input_id}"))
+                );
+                exit_code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        print_target(&cli, &input_target);
 
-    let mut flakes = IdHashMap::new();
+        let input_flakes = indices
+            .iter()
+            .map(|&index| Flake {
+                id: input_id,
+                ..flakes[index].clone()
+            })
+            .collect();
+        let state = state::State::load_or_default();
+        match process_flakes(&cli, command, &input_target, input_flakes, state)? {
+            ExitCode::SUCCESS => {}
+            // Interrupted
+            code if code == ExitCode::from(130) => return Ok(code),
+            code => exit_code = code,
+        }
+    }
+    Ok(exit_code)
+}
 
-    for entry in fs::read_dir("/nix/var/nix/gcroots/auto")? {
-        let entry = entry?;
+/// Returns the ID of the input chosen by `--match-url` or `--input-id auto` of each flake by its
+/// directory, and why the flakes without one are skipped.
+fn selected_input_ids<'a>(
+    cli: &Cli,
+    flakes: impl IntoIterator<Item = &'a Flake<'a>>,
+) -> Option<(HashMap<PathBuf, String>, &'static str)> {
+    if let Some(pattern) = &cli.match_url {
+        let pattern = tags::wildcard_ignoring_case(pattern);
+        return Some((
+            discovery::select_input_ids(flakes, |graph| {
+                graph.input_matching_url(&pattern, &cli.input_id)
+            }),
+            "no input matches --match-url",
+        ));
+    }
+    (cli.input_id == AUTO_INPUT_ID).then(|| {
+        (
+            discovery::select_input_ids(flakes, LockGraph::nixpkgs_input),
+            "no input locks github:NixOS/nixpkgs",
+        )
+    })
+}
 
-        if let Err(err) = filter_gcroot(&entry, &mut flakes, &cli.input_id)
-            .wrap_err_with(|| format!("Failed to filter gcroot {}", entry.path().display()))
-        {
-            eprintln!("{err:?}");
+/// Leaves out snoozed flakes unless `--include-snoozed` is given, and flakes outside or inside of
+/// Git repositories with `--only-git` or `--only-non-git`.
+fn filter_flakes<'a>(
+    cli: &Cli,
+    flakes: impl IntoIterator<Item = Flake<'a>>,
+    state: &state::State,
+) -> Vec<Flake<'a>> {
+    let (snoozed, flakes): (Vec<_>, Vec<_>) = flakes
+        .into_iter()
+        .partition(|flake| !cli.include_snoozed && state.is_snoozed(&flake.directory));
+    if cli.explain {
+        for flake in &snoozed {
+            explain::print_filtered(&flake.directory, "snoozed");
         }
+    } else if !snoozed.is_empty() {
+        println!(
+            "{}",
+            format_args!(
+                "Hiding {} snoozed flakes. Show them with --include-snoozed",
+                snoozed.len()
+            )
+            .muted()
+        );
+    }
+
+    if cli.only_git || cli.only_non_git {
+        flakes
+            .into_iter()
+            .filter(|flake| {
+                let included = vcs::in_git_repo(&flake.directory) == cli.only_git;
+                if cli.explain && !included {
+                    explain::print_filtered(
+                        &flake.directory,
+                        if cli.only_git {
+                            "not in a Git repository"
+                        } else {
+                            "in a Git repository"
+                        },
+                    );
+                }
+                included
+            })
+            .collect()
+    } else {
+        flakes
     }
+}
+
+/// A flake as listed by `update --pick`.
+struct PickedFlake<'a> {
+    flake: Flake<'a>,
+    label: String,
+}
+
+impl std::fmt::Display for PickedFlake<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.label)
+    }
+}
 
+/// Lets the user pick which of the outdated flakes to update. Flakes whose lock file can't be
+/// read are listed too, so their errors aren't hidden.
+fn pick_flakes<'a>(
+    cli: &Cli,
+    target: &MatchTarget,
+    flakes: Vec<Flake<'a>>,
+) -> Result<Vec<Flake<'a>>> {
+    let options = flakes
+        .into_iter()
+        .filter(Flake::has_manifest)
+        .filter_map(|flake| {
+            let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id).ok();
+            if let Some(lockfile_node) = &lockfile_node {
+                if is_up_to_date(cli, target, Some(&flake), lockfile_node).unwrap_or(false) {
+                    return None;
+                }
+            }
+            let directory = flake.directory.display();
+            let label = lockfile_node
+                .as_ref()
+                .and_then(|node| registry::resolved_ref(&node.original.inner))
+                .map_or_else(
+                    || directory.to_string(),
+                    |ref_| format!("{directory} ({ref_})"),
+                );
+            Some(PickedFlake { flake, label })
+        })
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(prompt::pick("Flakes to update:", options)?
+        .into_iter()
+        .map(|picked| picked.flake)
+        .collect())
+}
+
+/// Processes the discovered flakes and returns the exit code.
+fn process_flakes(
+    cli: &Cli,
+    command: &FlakeCommand,
+    target: &MatchTarget,
+    flakes: Vec<Flake>,
+    state: state::State,
+) -> Result<ExitCode> {
+    if let FlakeCommand::Update(
+        update_args @ UpdateArgs {
+            clean_matching: true,
+            ..
+        },
+    ) = command
+    {
+        match update::clean_matching(cli, target, &flakes, update_args) {
+            Err(err) if prompt::is_interrupted(&err) => return Ok(ExitCode::from(130)),
+            result => result?,
+        }
+    }
+    let flakes = if let FlakeCommand::Update(UpdateArgs { pick: true, .. }) = command {
+        match pick_flakes(cli, target, flakes) {
+            Err(err) if prompt::is_interrupted(&err) => return Ok(ExitCode::from(130)),
+            picked => picked?,
+        }
+    } else {
+        flakes
+    };
+    if let FlakeCommand::Update(UpdateArgs {
+        plan: Some(path),
+        diff_context,
+        ..
+    }) = command
+    {
+        let outcome = plan::write_plan(cli, target, &flakes, *diff_context, path)?;
+        return Ok(outcome.exit_code(command));
+    }
+    if let FlakeCommand::Update(
+        update_args @ UpdateArgs {
+            apply_plan: Some(path),
+            ..
+        },
+    ) = command
+    {
+        let outcome = plan::apply_plan(cli, target, &flakes, update_args, path, state)?;
+        return Ok(outcome.exit_code(command));
+    }
     let flakes_count = flakes.len();
+    let non_interactive = matches!(
+        command,
+        FlakeCommand::Update(UpdateArgs {
+            non_interactive: true,
+            ..
+        })
+    );
+    let mut session = update::Session::new(state, non_interactive);
+    let mut outcome = Outcome::default();
+    // Updating prompts for each flake, so the bar would only be in the way
+    let bar = matches!(command, FlakeCommand::List(_) | FlakeCommand::Diff(_))
+        .then(|| progress::flakes_bar(flakes_count));
+    let sizes = matches!(command, FlakeCommand::List(ListArgs { sizes: true, .. }));
+    // Of the listed flakes, for the total
+    let mut listed_store_paths = Vec::new();
     for (flake_index, flake) in flakes.into_iter().enumerate() {
-        if let Err(err) = process_flake(&flake, &cli, &target, flake_index, flakes_count)
-            .wrap_err_with(|| format!("Failed to process flake {}", flake.directory.display()))
+        let _span = tracing::info_span!("flake", directory = %flake.directory.display()).entered();
+        match process_flake(
+            &flake,
+            cli,
+            command,
+            target,
+            flake_index,
+            flakes_count,
+            &mut session,
+        )
+        .wrap_err_with(|| format!("Failed to process flake {}", flake.directory.display()))
         {
-            eprintln!("{err:?}");
+            Ok(outdated) => {
+                outcome.outdated += usize::from(outdated);
+                outcome.failed += usize::from(session.take_step_failed());
+                if sizes && outdated {
+                    listed_store_paths.extend(gcroot_store_paths(&flake));
+                }
+            }
+            Err(err) if prompt::is_interrupted(&err) => {
+                if prompt::confirm_quit()? {
+                    // Like the default action of SIGINT
+                    return Ok(ExitCode::from(130));
+                }
+                eprintln!("{}", "Skipped the flake".muted());
+            }
+            Err(err) => {
+                progress::suspend(|| eprintln!("{err:?}"));
+                outcome.failed += 1;
+            }
         }
+        if let Some(bar) = &bar {
+            bar.inc(1);
+        }
+    }
+
+    if sizes && !listed_store_paths.is_empty() {
+        print_total_closure_size(&listed_store_paths)?;
     }
 
+    Ok(outcome.exit_code(command))
+}
+
+/// Prints the closure size of the gcroots of all listed flakes together.
+fn print_total_closure_size(store_paths: &[PathBuf]) -> Result<()> {
+    let total = store::get()
+        .closure_size(store_paths)
+        .wrap_err("Failed to query the closure size of the gcroots")?;
+    progress::suspend(|| {
+        println!(
+            "\n{} {}",
+            "Total:".heading(),
+            format_args!(
+                "the gcroots of the listed flakes keep {} alive",
+                HumanBytes(total)
+            )
+            .value()
+        );
+    });
     Ok(())
 }
 
-fn get_flake_ref_metadata(flake_ref: &str) -> Result<NixFlakeMetadata> {
-    let output = {
-        let _guard = crate::sigint_guard::SigintGuard::new();
+fn run_registry_command(cli: &Cli, command: &RegistryCommand) -> Result<()> {
+    match command {
+        RegistryCommand::Pin => {
+            let target = resolve_target(cli)?;
+            let serde_json::Value::Object(mut to) = serde_json::to_value(target.locked())? else {
+                bail!("Locked flake reference isn't an object");
+            };
+            to.retain(|_, value| !value.is_null());
+            let url = flake_ref::to_string(&to)
+                .unwrap_or_else(|_| serde_json::Value::Object(to.clone()).to_string());
+            registry::pin(&cli.input_id, to)?;
+            println!(
+                "{} {} {}",
+                "Pinned".good(),
+                cli.input_id.value(),
+                format_args!("to {url}").muted()
+            );
+        }
+        RegistryCommand::Unpin => {
+            if registry::unpin(&cli.input_id)? {
+                println!("{} {}", "Unpinned".good(), cli.input_id.value());
+            } else {
+                println!(
+                    "{}",
+                    format_args!("{} isn't in the user registry", cli.input_id).muted()
+                );
+            }
+        }
+    }
+    Ok(())
+}
 
-        Command::new("nix")
-            .args(["flake", "metadata", "--json", "--", flake_ref])
-            .stdin(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?
-    };
+/// Snoozes the flake in the directory for the duration.
+fn snooze(args: &SnoozeArgs) -> Result<()> {
+    let directory = fs_err::canonicalize(&args.directory)?;
+    let mut state = state::State::load().wrap_err("Failed to load state")?;
+    state.snooze(&directory, args.duration);
+    state.save().wrap_err("Failed to save state")?;
+    if args.duration.is_zero() {
+        println!("{} {}", "Unsnoozed".good(), directory.display().value());
+    } else {
+        println!(
+            "{} {} {}",
+            "Snoozed".good(),
+            directory.display().value(),
+            format_args!("for {}", humantime::format_duration(args.duration)).good()
+        );
+    }
+    Ok(())
+}
 
-    if !output.status.success() {
-        bail!("Command failed with {}", output.status);
-    }
-
-    serde_json::from_slice(&output.stdout).wrap_err("Failed to parse output")
-}
-
-fn get_flake_ref_url(input: &LockfileNode) -> Result<String> {
-    let json = serde_json::to_string(&input.original)?;
-    let output = {
-        // `--argstr` doesn't work at all with `nix eval`
-        Command::new("nix-instantiate")
-            .args([
-                "--eval",
-                "--expr",
-                "{ json }: builtins.flakeRefToString (builtins.fromJSON json)",
-                "--raw",
-                "--argstr",
-                "json",
-                &json,
-            ])
-            .stdin(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?
-    };
+/// Rejects option combinations that can't work before doing anything.
+fn check_usage(cli: &Cli) -> Result<()> {
+    if cli.host.is_some() && !matches!(cli.command, CliCommand::List(_)) {
+        bail!(
+            "--host only works with `list`. To update the flakes of the machine, run {} there, e.g. with `ssh -t`",
+            env!("CARGO_PKG_NAME")
+        );
+    }
+
+    if cli.host.is_some() && (cli.only_git || cli.only_non_git) {
+        bail!("--only-git and --only-non-git don't work with --host");
+    }
+
+    if cli.host.is_some() && matches!(cli.command, CliCommand::List(ListArgs { sizes: true, .. })) {
+        bail!("--sizes doesn't work with --host");
+    }
+
+    if cli.input_id == AUTO_INPUT_ID
+        && (cli.host.is_some()
+            || cli.match_url.is_some()
+            || matches!(cli.command, CliCommand::Watch(_) | CliCommand::Notify(_)))
+    {
+        bail!("--input-id auto doesn't work with --host, --match-url, `watch` or `notify`");
+    }
+
+    if cli.match_url.is_some()
+        && matches!(cli.command, CliCommand::Watch(_) | CliCommand::Notify(_))
+    {
+        bail!("--match-url doesn't work with `watch` or `notify`");
+    }
+
+    if cli.input_id_pattern().is_some() {
+        if cli.host.is_some() || cli.match_url.is_some() {
+            bail!("--input-id with wildcards doesn't work with --host or --match-url");
+        }
+        if matches!(
+            cli.command,
+            CliCommand::Watch(_)
+                | CliCommand::Notify(_)
+                | CliCommand::Update(
+                    UpdateArgs { plan: Some(_), .. }
+                        | UpdateArgs {
+                            apply_plan: Some(_),
+                            ..
+                        }
+                )
+        ) {
+            bail!("--input-id with wildcards doesn't work with `watch`, `notify` or plans");
+        }
+    }
+
+    if let CliCommand::Update(UpdateArgs {
+        clean_matching: true,
+        non_interactive: true,
+        yes: false,
+        ..
+    }) = cli.command
+    {
+        bail!(
+            "--clean-matching with --non-interactive deletes gcroots without asking, so it requires --yes"
+        );
+    }
 
-    if !output.status.success() {
-        bail!("Command failed with {}", output.status);
+    if let CliCommand::Update(UpdateArgs {
+        non_interactive: false,
+        plan: None,
+        apply_plan: None,
+        ..
+    }) = cli.command
+    {
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "Standard input is not a terminal, so the prompt can't be answered. \
+                Run again with --non-interactive to apply and lock every flake without prompting"
+            );
+        }
     }
+    Ok(())
+}
 
-    Ok(String::from_utf8(output.stdout)?)
+/// Fills in options that weren't given on the command line from the configuration and keeps the
+/// rest in `cli.config`.
+fn apply_config(cli: &mut Cli, config: config::Config) -> Result<()> {
+    if cli.ref_match_age.is_none() {
+        cli.ref_match_age = config.ref_match_age(cli.target_input_id())?;
+    }
+    if cli.target.is_none() {
+        cli.target = config.target(cli.target_input_id()).map(str::to_owned);
+    }
+    if let CliCommand::Update(update_args) = &mut cli.command {
+        // A type or scope on the command line is for the default template
+        if update_args.commit_message_template.is_none()
+            && update_args.commit_type.is_none()
+            && update_args.commit_scope.is_none()
+        {
+            update_args
+                .commit_message_template
+                .clone_from(&config.commit_message_template);
+        }
+        for (arg, value) in [
+            (&mut update_args.commit_type, &config.commit_type),
+            (&mut update_args.commit_scope, &config.commit_scope),
+            (&mut update_args.pre_hook, &config.pre_hook),
+            (&mut update_args.post_hook, &config.post_hook),
+            (&mut update_args.elevate_with, &config.elevate_with),
+            (&mut update_args.editor, &config.editor),
+        ] {
+            if arg.is_none() {
+                arg.clone_from(value);
+            }
+        }
+        update::validate_commit_message_template(&update::commit_message_template(update_args))?;
+        update::validate_branch_template(&update_args.git_branch)?;
+    }
+    cli.config = config;
+    Ok(())
+}
+
+/// Fetches the metadata of the `--target` flake reference, or reads `--target-lockfile`.
+///
+/// The global flake registry is downloaded here if it's outdated, since matching indirect inputs
+/// only reads the saved copy.
+fn resolve_target(cli: &Cli) -> Result<MatchTarget> {
+    if let Err(err) = registry::refresh_global_registry() {
+        tracing::warn!("{err:?}");
+    }
+    if let Some(target_lockfile) = &cli.target_lockfile {
+        let (path, input_id) = target_lockfile
+            .rsplit_once('#')
+            .unwrap_or_else(|| (target_lockfile, cli.target_input_id()));
+        return nixpkgsupd::target::target_from_lockfile(Path::new(path), input_id);
+    }
+    let cache = MetadataCache {
+        dir: state::state_dir()?.join("target-metadata"),
+        fall_back: cli.fall_back_to_saved_target,
+    };
+    let target = cli.target()?;
+    if nixpkgsupd::target::is_rev(target) {
+        let repo = match &cli.target_repo {
+            Some(repo) => repo,
+            None if cli.target_input_id() == "nixpkgs" => "github:NixOS/nixpkgs",
+            None => {
+                bail!("--target-repo is required for a commit hash when --input-id isn't `nixpkgs`")
+            }
+        };
+        return nixpkgsupd::target::target_rev(repo, target);
+    }
+    let alias_target = channels::resolve_alias(target)?;
+    let target = alias_target.as_deref().unwrap_or(target);
+    let tag_target = tags::resolve_tag_target(target, cli.target_semver.as_ref())?;
+    nixpkgsupd::target::resolve_target(tag_target.as_deref().unwrap_or(target), Some(&cache))
+}
+
+/// Returns whether the locked input matches `--target`, or doesn't differ from it in a way given
+/// with `--mismatch`, so the flake is skipped. Non-flake inputs are always skipped.
+///
+/// A `path:` input of `flake` is compared by the input in the path's own lock file, if `flake` is
+/// given because its files can be read.
+fn is_up_to_date(
+    cli: &Cli,
+    target: &MatchTarget,
+    flake: Option<&Flake<'_>>,
+    lockfile_node: &LockfileNode,
+) -> Result<bool> {
+    let followed =
+        flake.and_then(|flake| target.follow_path_input(&flake.directory, flake.id, lockfile_node));
+    let lockfile_node = followed.as_ref().map_or(lockfile_node, |(_, node)| node);
+    target.is_skipped(lockfile_node, cli.ref_match_age(), &cli.mismatch)
 }
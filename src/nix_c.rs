@@ -0,0 +1,372 @@
+//! Evaluating with the Nix C API instead of running `nix` and `nix-instantiate`, which saves
+//! starting Nix for every flake reference.
+//!
+//! Only fetching flake metadata and formatting the flake references [`crate::flake_ref`] doesn't
+//! support go through the C API. Flake references are always parsed natively by
+//! [`crate::flake_ref`], which doesn't start Nix either.
+//!
+//! Enabled with the `nix-c-api` feature, which links to `libnixexprc`, `libnixstorec` and
+//! `libnixutilc` from Nix 2.26 or later.
+//!
+//! <https://nix.dev/manual/nix/2.28/c-api.html>
+
+use std::{
+    ffi::{CStr, CString, c_char, c_uint, c_void},
+    ptr,
+    sync::{Mutex, PoisonError},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{OptionExt, bail, eyre},
+};
+use serde_json::Value;
+
+use crate::{flake_ref, registry::Registry};
+
+#[expect(
+    non_camel_case_types,
+    reason = "mirrors the C API's names to make it easier to compare"
+)]
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uint, c_void};
+
+    #[repr(C)]
+    pub struct nix_c_context {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct Store {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct EvalState {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct nix_value {
+        _private: [u8; 0],
+    }
+
+    pub type nix_err = c_int;
+    pub const NIX_OK: nix_err = 0;
+
+    pub type nix_get_string_callback =
+        unsafe extern "C" fn(start: *const c_char, n: c_uint, user_data: *mut c_void);
+
+    #[link(name = "nixutilc")]
+    unsafe extern "C" {
+        pub fn nix_c_context_create() -> *mut nix_c_context;
+        pub fn nix_libutil_init(context: *mut nix_c_context) -> nix_err;
+        pub fn nix_setting_set(
+            context: *mut nix_c_context,
+            key: *const c_char,
+            value: *const c_char,
+        ) -> nix_err;
+        pub fn nix_err_msg(
+            context: *mut nix_c_context,
+            read_context: *const nix_c_context,
+            n: *mut c_uint,
+        ) -> *const c_char;
+    }
+
+    #[link(name = "nixstorec")]
+    unsafe extern "C" {
+        pub fn nix_libstore_init(context: *mut nix_c_context) -> nix_err;
+        pub fn nix_store_open(
+            context: *mut nix_c_context,
+            uri: *const c_char,
+            params: *mut *mut *const c_char,
+        ) -> *mut Store;
+    }
+
+    #[link(name = "nixexprc")]
+    unsafe extern "C" {
+        pub fn nix_libexpr_init(context: *mut nix_c_context) -> nix_err;
+        pub fn nix_state_create(
+            context: *mut nix_c_context,
+            lookup_path: *mut *const c_char,
+            store: *mut Store,
+        ) -> *mut EvalState;
+        pub fn nix_alloc_value(
+            context: *mut nix_c_context,
+            state: *mut EvalState,
+        ) -> *mut nix_value;
+        pub fn nix_value_decref(context: *mut nix_c_context, value: *mut nix_value) -> nix_err;
+        pub fn nix_expr_eval_from_string(
+            context: *mut nix_c_context,
+            state: *mut EvalState,
+            expr: *const c_char,
+            path: *const c_char,
+            value: *mut nix_value,
+        ) -> nix_err;
+        pub fn nix_init_string(
+            context: *mut nix_c_context,
+            value: *mut nix_value,
+            s: *const c_char,
+        ) -> nix_err;
+        pub fn nix_value_call(
+            context: *mut nix_c_context,
+            state: *mut EvalState,
+            function: *mut nix_value,
+            arg: *mut nix_value,
+            value: *mut nix_value,
+        ) -> nix_err;
+        pub fn nix_value_force(
+            context: *mut nix_c_context,
+            state: *mut EvalState,
+            value: *mut nix_value,
+        ) -> nix_err;
+        pub fn nix_get_string(
+            context: *mut nix_c_context,
+            value: *const nix_value,
+            callback: nix_get_string_callback,
+            user_data: *mut c_void,
+        ) -> nix_err;
+    }
+}
+
+/// Returns the metadata `nix flake metadata --json` would as JSON, with the fields read into
+/// [`NixFlakeMetadata`](crate::target::NixFlakeMetadata).
+///
+/// The source is fetched with `builtins.getFlake` and its `flake.lock` is read as is.
+const METADATA_EXPR: &CStr = cr#"
+ref:
+let
+  flake = builtins.getFlake ref;
+  dir = (builtins.parseFlakeRef ref).dir or "";
+  lockPath = flake.outPath + (if dir == "" then "" else "/${dir}") + "/flake.lock";
+in
+builtins.toJSON {
+  lastModified = flake.lastModified or null;
+  narHash = flake.narHash;
+  rev = flake.rev or null;
+  locks =
+    if builtins.pathExists lockPath then
+      builtins.fromJSON (builtins.readFile lockPath)
+    else
+      { version = 7; root = "root"; nodes.root = { }; };
+}
+"#;
+
+const FLAKE_REF_TO_STRING_EXPR: &CStr = c"json: builtins.flakeRefToString (builtins.fromJSON json)";
+
+/// The evaluator is created on first use. Nix's evaluator isn't thread-safe.
+static EVALUATOR: Mutex<Option<Evaluator>> = Mutex::new(None);
+
+struct Evaluator {
+    context: *mut ffi::nix_c_context,
+    state: *mut ffi::EvalState,
+}
+
+// SAFETY: only used behind `EVALUATOR`'s lock
+unsafe impl Send for Evaluator {}
+
+/// A value owned by the evaluator, released when dropped.
+struct OwnedValue<'a> {
+    value: *mut ffi::nix_value,
+    evaluator: &'a Evaluator,
+}
+
+impl Drop for OwnedValue<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the value was allocated by this evaluator and is only released once
+        unsafe {
+            ffi::nix_value_decref(self.evaluator.context, self.value);
+        }
+    }
+}
+
+unsafe extern "C" fn append_string(start: *const c_char, n: c_uint, user_data: *mut c_void) {
+    // SAFETY: Nix passes a string of `n` bytes, and `user_data` is the `Vec` passed to
+    // `nix_get_string` in `Evaluator::call_string`
+    unsafe {
+        let bytes = std::slice::from_raw_parts(start.cast::<u8>(), n as usize);
+        (*user_data.cast::<Vec<u8>>()).extend_from_slice(bytes);
+    }
+}
+
+impl Evaluator {
+    /// Initializes the Nix libraries and opens the default store, allowing impure evaluation
+    /// like the `nix` command line does.
+    fn new() -> Result<Self> {
+        // SAFETY: the context is checked for errors after each call and never freed, since the
+        // evaluator lives until the process exits
+        unsafe {
+            let context = ffi::nix_c_context_create();
+            if context.is_null() {
+                bail!("Failed to create a Nix C API context");
+            }
+            check(context, ffi::nix_libutil_init(context))?;
+            check(context, ffi::nix_libstore_init(context))?;
+            check(
+                context,
+                ffi::nix_setting_set(context, c"pure-eval".as_ptr(), c"false".as_ptr()),
+            )?;
+            check(context, ffi::nix_libexpr_init(context))?;
+
+            let store = ffi::nix_store_open(context, ptr::null(), ptr::null_mut());
+            check_null(context, store)?;
+            let mut lookup_path = [ptr::null()];
+            let state = ffi::nix_state_create(context, lookup_path.as_mut_ptr(), store);
+            check_null(context, state)?;
+            Ok(Self { context, state })
+        }
+    }
+
+    fn alloc_value(&self) -> Result<OwnedValue<'_>> {
+        // SAFETY: the context and state are valid for the evaluator's lifetime
+        let value = unsafe { ffi::nix_alloc_value(self.context, self.state) };
+        check_null(self.context, value)?;
+        Ok(OwnedValue {
+            value,
+            evaluator: self,
+        })
+    }
+
+    /// Evaluates `function`, calls it with the string `arg` and returns the resulting string.
+    fn call_string(&self, function: &CStr, arg: &str) -> Result<String> {
+        let arg = CString::new(arg)?;
+        let function_value = self.alloc_value()?;
+        let arg_value = self.alloc_value()?;
+        let result = self.alloc_value()?;
+        let mut bytes = Vec::<u8>::new();
+        // SAFETY: the values are valid until dropped at the end of this function, and the
+        // callback only appends to `bytes`
+        unsafe {
+            check(
+                self.context,
+                ffi::nix_expr_eval_from_string(
+                    self.context,
+                    self.state,
+                    function.as_ptr(),
+                    c".".as_ptr(),
+                    function_value.value,
+                ),
+            )?;
+            check(
+                self.context,
+                ffi::nix_init_string(self.context, arg_value.value, arg.as_ptr()),
+            )?;
+            check(
+                self.context,
+                ffi::nix_value_call(
+                    self.context,
+                    self.state,
+                    function_value.value,
+                    arg_value.value,
+                    result.value,
+                ),
+            )?;
+            check(
+                self.context,
+                ffi::nix_value_force(self.context, self.state, result.value),
+            )?;
+            check(
+                self.context,
+                ffi::nix_get_string(
+                    self.context,
+                    result.value,
+                    append_string,
+                    (&raw mut bytes).cast(),
+                ),
+            )?;
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+/// Returns the error in `context` if `err` isn't `NIX_OK`.
+///
+/// # Safety
+///
+/// `context` must be valid.
+unsafe fn check(context: *mut ffi::nix_c_context, err: ffi::nix_err) -> Result<()> {
+    if err == ffi::NIX_OK {
+        return Ok(());
+    }
+    let mut len = 0;
+    // SAFETY: the message is valid until the context is used again, and it's copied before that
+    let message = unsafe {
+        let message = ffi::nix_err_msg(ptr::null_mut(), context, &raw mut len);
+        if message.is_null() {
+            return Err(eyre!("Nix C API call failed with error code {err}"));
+        }
+        String::from_utf8_lossy(std::slice::from_raw_parts(
+            message.cast::<u8>(),
+            len as usize,
+        ))
+        .into_owned()
+    };
+    Err(eyre!(message))
+}
+
+/// Returns the error in `context` if `pointer` is null.
+fn check_null<T>(context: *mut ffi::nix_c_context, pointer: *mut T) -> Result<()> {
+    if pointer.is_null() {
+        // SAFETY: the context is valid, and -1 is `NIX_ERR_UNKNOWN`
+        unsafe { check(context, -1) }
+    } else {
+        Ok(())
+    }
+}
+
+/// Calls `function` with `arg` on the evaluator, creating it on first use.
+fn call_string(function: &CStr, arg: &str) -> Result<String> {
+    let mut evaluator = EVALUATOR.lock().unwrap_or_else(PoisonError::into_inner);
+    if evaluator.is_none() {
+        *evaluator = Some(Evaluator::new()?);
+    }
+    evaluator
+        .as_ref()
+        .ok_or_eyre("The Nix evaluator wasn't created")?
+        .call_string(function, arg)
+}
+
+/// Formats a flake reference in attribute set form, serialized as JSON, with
+/// `builtins.flakeRefToString`.
+///
+/// # Errors
+///
+/// Fails if the Nix libraries can't be initialized or Nix can't format the reference.
+pub fn flake_ref_to_string(json: &str) -> Result<String> {
+    call_string(FLAKE_REF_TO_STRING_EXPR, json)
+}
+
+/// Fetches a flake reference and returns its metadata like `nix flake metadata --json`.
+///
+/// Only the fields read into [`NixFlakeMetadata`](crate::target::NixFlakeMetadata) are
+/// included. Indirect flake references are resolved with the flake registries.
+///
+/// # Errors
+///
+/// Fails if the reference can't be parsed or resolved, the Nix libraries can't be initialized,
+/// or fetching the flake fails.
+pub fn flake_metadata(flake_ref: &str) -> Result<Vec<u8>> {
+    let original = flake_ref::parse(flake_ref)?;
+    let resolved = Registry::get()
+        .resolve(&original)
+        .ok_or_else(|| eyre!("{flake_ref} isn't in the flake registries"))?;
+    let resolved_url = flake_ref::to_string(&resolved)?;
+
+    let Value::Object(mut fetched) =
+        serde_json::from_str(&call_string(METADATA_EXPR, &resolved_url)?)?
+    else {
+        bail!("Expected the metadata to be an object");
+    };
+    let locks = fetched.remove("locks").unwrap_or_default();
+
+    // Like Nix, keep the ref only for Git, which locks the branch it fetched
+    let mut locked = resolved.clone();
+    if locked.get("type").and_then(Value::as_str) != Some("git") {
+        locked.remove("ref");
+    }
+    locked.extend(fetched.into_iter().filter(|(_, value)| !value.is_null()));
+
+    Ok(serde_json::to_vec(&serde_json::json!({
+        "locked": locked,
+        "locks": locks,
+        "resolved": resolved,
+        "resolvedUrl": resolved_url,
+    }))?)
+}
@@ -0,0 +1,64 @@
+//! Desktop notifications about outdated flakes.
+
+use std::time::{Duration, SystemTime};
+
+use color_eyre::Result;
+
+use crate::status::{OutdatedFlake, Status};
+
+/// The number of flakes listed in a notification.
+const WORST_OFFENDERS: usize = 3;
+
+/// Describes the flakes with the oldest locks, one per line.
+fn worst_offenders(outdated: &[OutdatedFlake]) -> String {
+    let mut outdated = outdated.iter().collect::<Vec<_>>();
+    // Unknown ages last
+    outdated.sort_by_key(|flake| flake.locked.last_modified.unwrap_or(u64::MAX));
+    outdated
+        .iter()
+        .take(WORST_OFFENDERS)
+        .map(|flake| {
+            flake.locked.last_modified.map_or_else(
+                || flake.directory.display().to_string(),
+                |last_modified| {
+                    let last_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified);
+                    format!(
+                        "{} locked {}",
+                        flake.directory.display(),
+                        chrono_humanize::HumanTime::from(last_modified)
+                    )
+                },
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sends a desktop notification if the number of outdated flakes reached `threshold` since the
+/// previous check, which had `previous` outdated flakes.
+pub fn notify_outdated(previous: Option<usize>, status: &Status, threshold: usize) -> Result<()> {
+    let count = status.outdated.len();
+    if count < threshold || previous.is_some_and(|previous| previous >= threshold) {
+        return Ok(());
+    }
+    let summary = format!("{count} flakes lock an outdated {}", status.input_id);
+    send(&summary, &worst_offenders(&status.outdated))
+}
+
+#[cfg(feature = "notifications")]
+fn send(summary: &str, body: &str) -> Result<()> {
+    notify_rust::Notification::new()
+        .appname(env!("CARGO_PKG_NAME"))
+        .summary(summary)
+        .body(body)
+        .show()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "notifications"))]
+fn send(_summary: &str, _body: &str) -> Result<()> {
+    color_eyre::eyre::bail!(
+        "Can't send notifications because {} was built without the `notifications` feature",
+        env!("CARGO_PKG_NAME")
+    )
+}
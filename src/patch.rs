@@ -0,0 +1,192 @@
+//! Reviewing the `flake.nix` changes `update` would propose, without prompting or writing the
+//! flakes.
+
+use std::{fmt::Write, path::Path};
+
+use color_eyre::Result;
+use fs_err as fs;
+
+use nixpkgsupd::{discovery::Flake, flake_nix::DynamicInputUrl, target::MatchTarget};
+use sha2::{Digest, Sha256};
+
+use crate::{DiffArgs, ui::Themed, update};
+
+/// Prints the change to the manifest of `flake` that `update` would apply, or writes it as a
+/// patch to `--patch-dir`.
+pub fn diff_flake(flake: &Flake, target: &MatchTarget, args: &DiffArgs) -> Result<()> {
+    let manifest = flake.project.manifest();
+    let old_contents = fs::read_to_string(flake.directory.join(manifest))?;
+    let new_contents =
+        match flake
+            .project
+            .replace_input_url(target.flake_ref_url(), &old_contents, flake.id)
+        {
+            Ok(new_contents) => new_contents,
+            Err(err) => match DynamicInputUrl::find(&err) {
+                Some(dynamic_url) => {
+                    println!("  {}", format_args!("{dynamic_url}").warning());
+                    return Ok(());
+                }
+                None => return Err(err),
+            },
+        };
+    if new_contents == old_contents {
+        println!(
+            "  {}",
+            format_args!("{manifest} already has the target, only the lock file is outdated")
+                .muted()
+        );
+        return Ok(());
+    }
+
+    let Some(patch_dir) = &args.patch_dir else {
+        update::print_diff(&old_contents, &new_contents, args.diff_context);
+        return Ok(());
+    };
+    let directory = flake.directory.to_string_lossy();
+    let name: String = directory
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    // Directories like `/a/b-c` and `/a/b/c` have the same name
+    let hash = format!("{:x}", Sha256::digest(directory.as_bytes()));
+    let name = format!("{name}-{}", &hash[..8]);
+    let path = patch_dir.join(format!("{name}.patch"));
+    fs::create_dir_all(patch_dir)?;
+    fs::write(
+        &path,
+        unified(
+            Path::new(manifest),
+            &old_contents,
+            &new_contents,
+            args.diff_context,
+        ),
+    )?;
+    println!("  {} {}", "Wrote".muted(), path.display().value());
+    Ok(())
+}
+
+/// Formats the change as a unified diff of `path`, which `git apply` and `patch -p1` accept in
+/// the directory the path is relative to.
+pub fn unified(path: &Path, old_contents: &str, new_contents: &str, context: usize) -> String {
+    // Lines keep their newline, so a last line without one differs from the same line with one
+    let old_lines: Vec<_> = old_contents.split_inclusive('\n').collect();
+    let new_lines: Vec<_> = new_contents.split_inclusive('\n').collect();
+    let lines = diff::slice(&old_lines, &new_lines);
+    let is_old = |line: &&diff::Result<&&str>| !matches!(line, diff::Result::Right(_));
+    let is_new = |line: &&diff::Result<&&str>| !matches!(line, diff::Result::Left(_));
+    let changed: Vec<_> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, diff::Result::Both(..)))
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut out = format!("--- a/{0}\n+++ b/{0}\n", path.display());
+    let mut changed = changed.iter().peekable();
+    while let Some(&first) = changed.next() {
+        let start = first.saturating_sub(context);
+        let mut end = (first + context + 1).min(lines.len());
+        // Changes whose context touches are in the same hunk
+        while let Some(&&next) = changed.peek() {
+            if next.saturating_sub(context) > end {
+                break;
+            }
+            end = (next + context + 1).min(lines.len());
+            changed.next();
+        }
+
+        let hunk = &lines[start..end];
+        let range = |before: usize, len: usize| {
+            // An empty range starts at the line before it
+            if len == 0 {
+                format!("{before},0")
+            } else {
+                format!("{},{len}", before + 1)
+            }
+        };
+        let _ = writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(
+                lines[..start].iter().filter(is_old).count(),
+                hunk.iter().filter(is_old).count()
+            ),
+            range(
+                lines[..start].iter().filter(is_new).count(),
+                hunk.iter().filter(is_new).count()
+            ),
+        );
+        for line in hunk {
+            match line {
+                diff::Result::Left(line) => write_line(&mut out, '-', line),
+                diff::Result::Both(line, _) => write_line(&mut out, ' ', line),
+                diff::Result::Right(line) => write_line(&mut out, '+', line),
+            }
+        }
+    }
+    out
+}
+
+/// Writes a line of a hunk, marking a last line without a newline like `diff` does.
+fn write_line(out: &mut String, prefix: char, line: &str) {
+    let _ = match line.strip_suffix('\n') {
+        Some(line) => writeln!(out, "{prefix}{line}"),
+        None => writeln!(out, "{prefix}{line}\n\\ No newline at end of file"),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_hunks() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nJ\n";
+        assert_eq!(
+            unified(Path::new("flake.nix"), old, new, 1),
+            "\
+--- a/flake.nix
++++ b/flake.nix
+@@ -1,3 +1,3 @@
+ a
+-b
++B
+ c
+@@ -9,2 +9,2 @@
+ i
+-j
++J
+"
+        );
+        assert!(unified(Path::new("flake.nix"), old, new, 4).contains("@@ -1,10 +1,10 @@"));
+    }
+
+    #[test]
+    fn missing_newline_at_end_of_file() {
+        assert_eq!(
+            unified(Path::new("flake.nix"), "a\nb", "a\nb\n", 1),
+            "\
+--- a/flake.nix
++++ b/flake.nix
+@@ -1,2 +1,2 @@
+ a
+-b
+\\ No newline at end of file
++b
+"
+        );
+        assert!(
+            unified(Path::new("flake.nix"), "a\nb", "a\nc", 1)
+                .ends_with("-b\n\\ No newline at end of file\n+c\n\\ No newline at end of file\n")
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Planning what `update` would do to each outdated flake without writing anything, for
+//! `update --plan`, so the plan can be reviewed and then applied with `update --apply-plan`.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use nixpkgsupd::{
+    discovery::Flake, flake_nix::DynamicInputUrl, lockfile::load_lockfile_input,
+    target::MatchTarget,
+};
+
+use crate::{
+    Cli, Outcome, UpdateArgs, is_up_to_date, patch, progress,
+    state::State,
+    ui::Themed,
+    update::{self, Session},
+    vcs::Vcs,
+};
+
+/// The steps `update` would take for each outdated flake.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub input_id: String,
+    /// The flake reference written to the manifests.
+    pub target: String,
+    /// The revision the target was at, which the lock files are updated to.
+    pub target_rev: Option<String>,
+    pub flakes: Vec<FlakePlan>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlakePlan {
+    pub directory: PathBuf,
+    /// The file name of the manifest, like `flake.nix`.
+    pub manifest: String,
+    /// [`content_hash`] of the manifest the plan was made against.
+    pub manifest_hash: String,
+    /// [`content_hash`] of the lock file the plan was made against.
+    pub lockfile_hash: String,
+    /// The manifest with the target's URL, if it changes.
+    pub new_manifest: Option<String>,
+    /// The change to the manifest as a unified diff, if it changes.
+    pub diff: Option<String>,
+    /// Where the input's URL is computed, so the manifest has to be edited manually.
+    pub manual_edit: Option<ManualEdit>,
+    /// Whether the lock file needs to be refreshed.
+    pub lock: bool,
+    /// Whether direnv would be refreshed after locking.
+    pub refresh_direnv: bool,
+    /// Whether the changes would be committed, because the flake is in a repository.
+    pub commit: bool,
+}
+
+/// 1-based position of a computed input URL. [`DynamicInputUrl`]
+#[derive(Serialize, Deserialize)]
+pub struct ManualEdit {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Returns the SHA-256 hash of `contents` in hexadecimal.
+pub fn content_hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Plans the outdated flakes and writes the plan as JSON to `path`.
+pub fn write_plan(
+    cli: &Cli,
+    target: &MatchTarget,
+    flakes: &[Flake],
+    diff_context: usize,
+    path: &Path,
+) -> Result<Outcome> {
+    let mut outcome = Outcome::default();
+    let mut plan = Plan {
+        input_id: cli.input_id.clone(),
+        target: target.flake_ref_url().to_owned(),
+        target_rev: target.locked().rev().map(str::to_owned),
+        flakes: Vec::new(),
+    };
+    let bar = progress::flakes_bar(flakes.len());
+    for flake in flakes {
+        let _span = tracing::info_span!("flake", directory = %flake.directory.display()).entered();
+        match plan_flake(cli, target, flake, diff_context)
+            .wrap_err_with(|| format!("Failed to plan flake {}", flake.directory.display()))
+        {
+            Ok(Some(flake_plan)) => {
+                outcome.outdated += 1;
+                plan.flakes.push(flake_plan);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                progress::suspend(|| eprintln!("{err:?}"));
+                outcome.failed += 1;
+            }
+        }
+        bar.inc(1);
+    }
+    drop(bar);
+
+    fs::write(path, serde_json::to_vec_pretty(&plan)?)?;
+    println!(
+        "{} {}",
+        format_args!("Wrote a plan for {} flakes to", plan.flakes.len()).good(),
+        path.display().value()
+    );
+    Ok(outcome)
+}
+
+/// Applies the plan at `path` to the discovered `flakes` it includes without prompting.
+///
+/// Flakes whose manifest or lock file changed since planning are skipped.
+pub fn apply_plan(
+    cli: &Cli,
+    target: &MatchTarget,
+    flakes: &[Flake],
+    update_args: &UpdateArgs,
+    path: &Path,
+    state: State,
+) -> Result<Outcome> {
+    let plan: Plan = serde_json::from_slice(&fs::read(path)?)
+        .wrap_err_with(|| format!("Failed to parse the plan {}", path.display()))?;
+    if plan.input_id != cli.input_id {
+        bail!(
+            "The plan is for the input {0}. Run again with --input-id {0}",
+            plan.input_id
+        );
+    }
+    if plan.target != target.flake_ref_url() {
+        bail!(
+            "The plan was made for the target {}, which differs from {}. Make a new plan",
+            plan.target,
+            target.flake_ref_url()
+        );
+    }
+    let target_rev = target.locked().rev();
+    if plan.target_rev.as_deref() != target_rev {
+        bail!(
+            "The plan was made for the target at {}, which has since moved to {}. Make a new plan",
+            plan.target_rev.as_deref().unwrap_or("an unknown revision"),
+            target_rev.unwrap_or("an unknown revision")
+        );
+    }
+
+    let mut session = Session::new(state, true);
+    let mut outcome = Outcome::default();
+    for flake_plan in &plan.flakes {
+        let Some(flake) = flakes
+            .iter()
+            .find(|flake| flake.directory == flake_plan.directory)
+        else {
+            eprintln!(
+                "{} {}",
+                flake_plan.directory.display().muted(),
+                "wasn't found, skipping it".warning()
+            );
+            continue;
+        };
+        let _span = tracing::info_span!("flake", directory = %flake.directory.display()).entered();
+        let result = apply_flake_plan(cli, target, flake, flake_plan, update_args, &mut session)
+            .wrap_err_with(|| format!("Failed to apply the plan to {}", flake.directory.display()));
+        match result {
+            Ok(applied) => {
+                outcome.outdated += usize::from(applied);
+                outcome.failed += usize::from(session.take_step_failed());
+            }
+            Err(err) => {
+                eprintln!("{err:?}");
+                outcome.failed += 1;
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// Applies the plan to the flake and returns whether it did, which it doesn't if the flake
+/// changed since planning.
+fn apply_flake_plan(
+    cli: &Cli,
+    target: &MatchTarget,
+    flake: &Flake,
+    flake_plan: &FlakePlan,
+    update_args: &UpdateArgs,
+    session: &mut Session,
+) -> Result<bool> {
+    let manifest = fs::read(flake.directory.join(&flake_plan.manifest))?;
+    let lockfile = fs::read(&flake.lockfile_path)?;
+    if content_hash(&manifest) != flake_plan.manifest_hash
+        || content_hash(&lockfile) != flake_plan.lockfile_hash
+    {
+        eprintln!(
+            "{} {}",
+            flake.directory.display().muted(),
+            "changed since the plan was made, skipping it".warning()
+        );
+        return Ok(false);
+    }
+    if let Some(manual_edit) = &flake_plan.manual_edit {
+        eprintln!(
+            "{} {}",
+            flake.directory.display().muted(),
+            format_args!(
+                "needs a manual edit of the input URL at line {}, skipping it",
+                manual_edit.line
+            )
+            .warning()
+        );
+        return Ok(false);
+    }
+    update::apply_planned(
+        flake,
+        cli,
+        target,
+        update_args,
+        session,
+        flake_plan.new_manifest.as_deref(),
+        flake_plan.lock,
+    )?;
+    Ok(true)
+}
+
+/// Returns the plan for the flake, or `None` if it's skipped.
+fn plan_flake(
+    cli: &Cli,
+    target: &MatchTarget,
+    flake: &Flake,
+    diff_context: usize,
+) -> Result<Option<FlakePlan>> {
+    if !flake.has_manifest() {
+        return Ok(None);
+    }
+    let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+    if is_up_to_date(cli, target, Some(flake), &lockfile_node)? {
+        return Ok(None);
+    }
+
+    let manifest = flake.project.manifest();
+    let manifest_path = flake.directory.join(manifest);
+    let current_manifest = fs::read_to_string(&manifest_path)?;
+    let (new_manifest, manual_edit) =
+        match flake
+            .project
+            .replace_input_url(target.flake_ref_url(), &current_manifest, flake.id)
+        {
+            Ok(new_manifest) => (
+                Some(new_manifest).filter(|new| *new != current_manifest),
+                None,
+            ),
+            Err(err) => {
+                let dynamic_url = DynamicInputUrl::find(&err).ok_or(err)?;
+                let manual_edit = ManualEdit {
+                    line: dynamic_url.line,
+                    column: dynamic_url.column,
+                };
+                (None, Some(manual_edit))
+            }
+        };
+    let lock_matches_target = target.is_up_to_date(&lockfile_node, cli.ref_match_age())?;
+
+    Ok(Some(FlakePlan {
+        directory: flake.directory.clone(),
+        manifest: manifest.to_owned(),
+        manifest_hash: content_hash(current_manifest.as_bytes()),
+        lockfile_hash: content_hash(&fs::read(&flake.lockfile_path)?),
+        diff: new_manifest.as_deref().map(|new_manifest| {
+            patch::unified(
+                Path::new(manifest),
+                &current_manifest,
+                new_manifest,
+                diff_context,
+            )
+        }),
+        lock: new_manifest.is_some() || !lock_matches_target,
+        new_manifest,
+        manual_edit,
+        refresh_direnv: flake.has_direnv_gc_roots,
+        commit: Vcs::detect(&flake.directory).is_some(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_hash() {
+        assert_eq!(
+            content_hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            content_hash(b"a"),
+            "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"
+        );
+    }
+}
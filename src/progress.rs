@@ -0,0 +1,97 @@
+//! Spinners and progress bars on standard error, so that long operations don't look hung.
+//!
+//! They're only drawn when standard error is a terminal. Logs are written above them.
+
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, LazyLock, Mutex, PoisonError},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
+use nixpkgsupd::command;
+
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows a spinner with `message` until it's dropped.
+pub fn spinner(message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let spinner = ProgressBar::new_spinner()
+        .with_message(message)
+        .with_finish(ProgressFinish::AndClear);
+    let spinner = PROGRESS.add(spinner);
+    spinner.enable_steady_tick(TICK_INTERVAL);
+    spinner
+}
+
+/// Shows the number of flakes processed out of `count` until it's dropped.
+pub fn flakes_bar(count: usize) -> ProgressBar {
+    let bar = ProgressBar::new(count as u64)
+        .with_style(
+            ProgressStyle::with_template("{bar:30} {pos}/{len} flakes")
+                .expect("the template is valid"),
+        )
+        .with_finish(ProgressFinish::AndClear);
+    PROGRESS.add(bar)
+}
+
+/// Hides the spinners and progress bars while `f` writes output.
+pub fn suspend<T>(f: impl FnOnce() -> T) -> T {
+    PROGRESS.suspend(f)
+}
+
+/// Runs a command with a spinner and returns whether it succeeded.
+///
+/// Its standard error is written above the spinner line by line as it runs, since a partial
+/// line would be mixed with the spinner.
+pub fn run_with_spinner(
+    message: impl Into<Cow<'static, str>>,
+    (program, args): (&str, Vec<&str>),
+    dir: &Path,
+) -> Result<bool> {
+    let partial_line = Arc::new(Mutex::new(Vec::new()));
+    let output = {
+        let _spinner = spinner(message);
+        let partial_line = Arc::clone(&partial_line);
+        command::output_with_retries_teeing(program, &args, Some(dir), move |chunk| {
+            let lines = complete_lines(&partial_line, chunk);
+            if !lines.is_empty() {
+                let _ = suspend(|| io::stderr().write_all(&lines));
+            }
+        })?
+    };
+    let rest = std::mem::take(&mut *partial_line.lock().unwrap_or_else(PoisonError::into_inner));
+    if !rest.is_empty() {
+        io::stderr().write_all(&rest)?;
+        eprintln!();
+    }
+    Ok(output.status.success())
+}
+
+/// Appends `chunk` to `partial_line` and takes the lines it completes.
+fn complete_lines(partial_line: &Mutex<Vec<u8>>, chunk: &[u8]) -> Vec<u8> {
+    let mut partial_line = partial_line.lock().unwrap_or_else(PoisonError::into_inner);
+    partial_line.extend_from_slice(chunk);
+    partial_line
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map(|end| partial_line.drain(..=end).collect())
+        .unwrap_or_default()
+}
+
+/// Writes logs above the spinners and progress bars.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
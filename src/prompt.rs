@@ -0,0 +1,119 @@
+//! Line editing for interactive prompts.
+
+use std::{
+    fmt::Display,
+    sync::{Mutex, PoisonError},
+};
+
+use color_eyre::{Report, Result, eyre::bail};
+use rustyline::{Behavior, Config, DefaultEditor, error::ReadlineError};
+
+use crate::ui::Themed;
+
+/// The error returned when <kbd>Ctrl</kbd>+<kbd>C</kbd> is pressed at a prompt.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Interrupted at the prompt")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Returns whether `err` was caused by <kbd>Ctrl</kbd>+<kbd>C</kbd> at a prompt.
+pub fn is_interrupted(err: &Report) -> bool {
+    err.chain().any(<dyn std::error::Error>::is::<Interrupted>)
+}
+
+/// Shared so that history persists between flakes.
+static EDITOR: Mutex<Option<DefaultEditor>> = Mutex::new(None);
+
+/// Limits the size of the history file.
+const MAX_HISTORY: usize = 1000;
+
+fn history_path() -> Option<std::path::PathBuf> {
+    crate::state::state_dir()
+        .ok()
+        .map(|dir| dir.join("history"))
+}
+
+fn new_editor() -> Result<DefaultEditor> {
+    let config = Config::builder()
+        // Keep prompts out of redirected standard output
+        .behavior(Behavior::PreferTerm)
+        .max_history_size(MAX_HISTORY)?
+        .build();
+    let mut editor = DefaultEditor::with_config(config)?;
+    if let Some(path) = history_path() {
+        // A missing history file is expected on the first run
+        let _ = editor.load_history(&path);
+    }
+    Ok(editor)
+}
+
+fn with_editor<T>(f: impl FnOnce(&mut DefaultEditor) -> Result<T>) -> Result<T> {
+    let mut editor = EDITOR.lock().unwrap_or_else(PoisonError::into_inner);
+    if editor.is_none() {
+        *editor = Some(new_editor()?);
+    }
+    editor
+        .as_mut()
+        .map_or_else(|| unreachable!("initialized above"), f)
+}
+
+fn readline(editor: &mut DefaultEditor, prompt: impl Display) -> Result<String> {
+    match editor.readline(&prompt.to_string()) {
+        Ok(line) => Ok(line),
+        Err(ReadlineError::Interrupted) => Err(Interrupted.into()),
+        Err(ReadlineError::Eof) => bail!("Unexpected end of input"),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads an answer to a question.
+pub fn read_line(prompt: impl Display) -> Result<String> {
+    with_editor(|editor| readline(editor, prompt))
+}
+
+/// Asks whether to quit after <kbd>Ctrl</kbd>+<kbd>C</kbd> at a prompt. Pressing it again also
+/// quits.
+pub fn confirm_quit() -> Result<bool> {
+    match read_line("Quit? Otherwise the flake is skipped [y,N] ".prompt()) {
+        Ok(answer) => Ok(answer.trim().eq_ignore_ascii_case("y")),
+        Err(err) if is_interrupted(&err) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Lets the user pick any of `options`, filtering them by typing a fuzzy search.
+pub fn pick<T: Display>(message: &str, options: Vec<T>) -> Result<Vec<T>> {
+    match inquire::MultiSelect::new(message, options)
+        .with_help_message("Type to filter, space to pick, → to pick all, enter to confirm")
+        .prompt()
+    {
+        Ok(picked) => Ok(picked),
+        Err(
+            inquire::InquireError::OperationInterrupted | inquire::InquireError::OperationCanceled,
+        ) => Err(Interrupted.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads a command and saves it to the persistent history.
+pub fn read_command(prompt: impl Display) -> Result<String> {
+    with_editor(|editor| {
+        let line = readline(editor, prompt)?;
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.trim())?;
+            if let Some(path) = history_path() {
+                if let Some(parent) = path.parent() {
+                    fs_err::create_dir_all(parent)?;
+                }
+                editor.save_history(&path)?;
+            }
+        }
+        Ok(line)
+    })
+}
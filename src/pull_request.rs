@@ -0,0 +1,127 @@
+//! Committing flake bumps to a separate branch and opening a pull request for them, for
+//! repositories where the main branch is protected.
+
+use std::fmt::Write;
+
+use color_eyre::{
+    Result,
+    eyre::{OptionExt, bail},
+};
+use fs_err as fs;
+
+use nixpkgsupd::{
+    command::{cmd_output, run_cmd},
+    discovery::Flake,
+    lockfile::{LockfileNode, parse_lockfile_input},
+};
+
+use crate::{
+    UpdateArgs,
+    forge::compare_url,
+    ui::Themed,
+    update::{branch_name, commit_message, git_commit_args, head_lockfile_input},
+    vcs::git_branch_exists,
+};
+
+/// Describes the change from `old` to `new` in Markdown.
+fn pull_request_body(input_id: &str, old: Option<&LockfileNode>, new: &LockfileNode) -> String {
+    fn row(body: &mut String, label: &str, node: Option<&LockfileNode>) {
+        let ref_ = node.and_then(|node| node.original.inner.ref_());
+        let rev = node.and_then(|node| node.locked.rev());
+        let _ = writeln!(
+            body,
+            "| {label} | {} | {} |",
+            ref_.map_or_else(|| "-".to_owned(), |ref_| format!("`{ref_}`")),
+            rev.map_or_else(|| "-".to_owned(), |rev| format!("`{rev}`")),
+        );
+    }
+
+    let mut body = format!("Bumps the `{input_id}` flake input.\n\n| | Ref | Rev |\n|-|-|-|\n");
+    row(&mut body, "Old", old);
+    row(&mut body, "New", Some(new));
+
+    let url = old
+        .and_then(|old| Some((old.locked.rev()?, new.locked.rev()?)))
+        .and_then(|(base, head)| compare_url(&new.locked, base, head));
+    if let Some(url) = url {
+        let _ = write!(body, "\n[Compare changes]({url})\n");
+    }
+    body
+}
+
+/// Commits `flake.nix` and `flake.lock` to a new branch, pushes it and opens a pull request with
+/// `gh` against the current branch.
+///
+/// The checkout is switched back to the current branch afterwards.
+pub fn create_pull_request(update_args: &UpdateArgs, flake: &Flake) -> Result<()> {
+    let dir = &flake.directory;
+    let files = flake.project.files();
+    let branch = branch_name(update_args, flake.id)?;
+    let base = cmd_output("git", &["branch", "--show-current"], dir)?
+        .map(|base| base.trim().to_owned())
+        .filter(|base| !base.is_empty())
+        .ok_or_eyre("Can't open a pull request from a detached HEAD")?;
+    if base == branch {
+        bail!("Already on the branch {branch}");
+    }
+    let remote = cmd_output(
+        "git",
+        &["config", "--get", &format!("branch.{base}.remote")],
+        dir,
+    )?
+    .map_or_else(|| "origin".to_owned(), |remote| remote.trim().to_owned());
+
+    let old = head_lockfile_input(dir, flake.project, flake.id);
+    let commit_msg = commit_message(update_args, dir, flake.project, flake.id, old.as_ref())?;
+    let new = parse_lockfile_input(&fs::read(dir.join(flake.project.lockfile()))?, flake.id)?;
+    let body = pull_request_body(flake.id, old.as_ref(), &new);
+
+    // An existing branch may have unmerged commits from an earlier run
+    if git_branch_exists(dir, &branch)? {
+        bail!(
+            "The branch {branch} already exists. Delete it, or choose another name with --git-branch."
+        );
+    }
+    if !run_cmd("git", &["switch", "--create", &branch], dir)? {
+        bail!("Failed to create the branch {branch}");
+    }
+    let commit_args = git_commit_args(update_args, flake.project, &commit_msg, false);
+    let commit_args = commit_args.iter().map(String::as_str).collect::<Vec<_>>();
+    let committed = run_cmd("git", &["add", "--intent-to-add", files[0], files[1]], dir)?
+        && run_cmd("git", &commit_args, dir)?;
+    if !run_cmd("git", &["switch", &base], dir)? {
+        bail!("Failed to switch back to {base}");
+    }
+    if !committed {
+        bail!("Failed to commit to the branch {branch}");
+    }
+
+    if !run_cmd("git", &["push", "--set-upstream", &remote, &branch], dir)? {
+        bail!("Failed to push the branch {branch} to {remote}");
+    }
+
+    let opened = run_cmd(
+        "gh",
+        &[
+            "pr",
+            "create",
+            "--base",
+            &base,
+            "--head",
+            &branch,
+            "--title",
+            &commit_msg,
+            "--body",
+            &body,
+        ],
+        dir,
+    );
+    if !matches!(opened, Ok(true)) {
+        eprintln!(
+            "{} {}",
+            "Failed to open a pull request with gh. Open one manually for the branch".bad(),
+            branch.highlight()
+        );
+    }
+    Ok(())
+}
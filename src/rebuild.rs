@@ -0,0 +1,214 @@
+//! The `rebuild` prompt command, which builds the packages the flake's build result links point
+//! to again, so that the links and their gcroots point to outputs of the updated inputs.
+
+use std::{collections::BTreeMap, path::Path};
+
+use color_eyre::{Result, eyre::OptionExt};
+
+use nixpkgsupd::{
+    command::{cmd_output, run_cmd},
+    discovery::Flake,
+    store,
+};
+
+use crate::ui::Themed;
+
+/// A build result link in the flake's directory and the package it's rebuilt from.
+struct BuildResult<'a> {
+    link: &'a Path,
+    /// The attribute in `packages.<system>`, if a package with the name of the link's
+    /// derivation was found.
+    attr: Option<String>,
+}
+
+/// Rebuilds the build result links of the flake with `nix build --out-link`.
+pub fn rebuild(flake: &Flake) -> Result<()> {
+    let results = build_results(flake)?;
+    if results.is_empty() {
+        eprintln!("{}", "The flake has no build results to rebuild".warning());
+    }
+    for BuildResult { link, attr } in results {
+        let Some(attr) = attr else {
+            eprintln!(
+                "{} {}",
+                link.display().value(),
+                "wasn't built from one of the flake's packages, skipping it".warning()
+            );
+            continue;
+        };
+        let installable = format!(".#{attr}");
+        let out_link = link.file_name().ok_or_eyre("Build result has no name")?;
+        let out_link = out_link.to_string_lossy();
+        eprintln!(
+            "{} {} {}",
+            "Building".muted(),
+            installable.value(),
+            format_args!("to {out_link}").muted()
+        );
+        if !run_cmd(
+            "nix",
+            &["build", &installable, "--out-link", &out_link],
+            &flake.directory,
+        )? {
+            eprintln!(
+                "{}",
+                format_args!("Failed to rebuild {}.", link.display()).bad()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the build result links of the flake with the packages they were built from, found by
+/// the name of the deriver of the store path they point to.
+fn build_results<'a>(flake: &'a Flake) -> Result<Vec<BuildResult<'a>>> {
+    let store = store::get();
+    let links: Vec<_> = flake
+        .gcroots
+        .iter()
+        .filter(|gcroot| gcroot.parent() == Some(&flake.directory))
+        .filter_map(|link| Some((link.as_path(), store.store_path(link)?)))
+        .collect();
+    if links.is_empty() {
+        return Ok(Vec::new());
+    }
+    let store_paths: Vec<_> = links.iter().map(|(_, path)| path.clone()).collect();
+    let infos = store.path_infos(&store_paths)?;
+
+    // Building the shortest link like `result` also creates the links of the other outputs like
+    // `result-dev`, so there's one link per derivation
+    let mut by_deriver = BTreeMap::<&str, &Path>::new();
+    for (link, store_path) in &links {
+        let Some(deriver) = infos
+            .get(store_path)
+            .and_then(|info| info.deriver.as_deref())
+        else {
+            continue;
+        };
+        by_deriver
+            .entry(deriver)
+            .and_modify(|shortest| {
+                if link.as_os_str().len() < shortest.as_os_str().len() {
+                    *shortest = link;
+                }
+            })
+            .or_insert(link);
+    }
+    if by_deriver.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let packages = package_names(&flake.directory)?;
+    Ok(by_deriver
+        .into_iter()
+        .map(|(deriver, link)| BuildResult {
+            link,
+            attr: derivation_name(deriver).and_then(|name| find_package(&packages, name)),
+        })
+        .collect())
+}
+
+/// Returns the names of the derivations of the flake's `packages` for the current system by
+/// their attribute names.
+fn package_names(directory: &Path) -> Result<BTreeMap<String, String>> {
+    let system = cmd_output(
+        "nix",
+        &[
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ],
+        directory,
+    )?
+    .ok_or_eyre("Failed to get the current system")?;
+    let names = cmd_output(
+        "nix",
+        &[
+            "eval",
+            "--json",
+            &format!(".#packages.{system}"),
+            "--apply",
+            "builtins.mapAttrs (_: package: package.name)",
+        ],
+        directory,
+    )?
+    .ok_or_eyre("Failed to evaluate the flake's packages")?;
+    Ok(serde_json::from_str(&names)?)
+}
+
+/// Returns the attribute of the package named like the derivation `name` regardless of their
+/// versions, preferring `default`.
+fn find_package(packages: &BTreeMap<String, String>, name: &str) -> Option<String> {
+    let name = package_name(name);
+    let matching: Vec<_> = packages
+        .iter()
+        .filter(|(_, package)| package_name(package) == name)
+        .map(|(attr, _)| attr)
+        .collect();
+    matching
+        .iter()
+        .find(|attr| **attr == "default")
+        .or_else(|| matching.first())
+        .map(|attr| (*attr).clone())
+}
+
+/// Returns the name of the derivation at `drv_path`, like `hello-2.12.1`.
+fn derivation_name(drv_path: &str) -> Option<&str> {
+    let file_name = drv_path.rsplit('/').next()?;
+    let (_hash, name) = file_name.strip_suffix(".drv")?.split_once('-')?;
+    Some(name)
+}
+
+/// Returns `name` without its version like `builtins.parseDrvName`, where the version starts at
+/// the first dash followed by something other than a letter.
+fn package_name(name: &str) -> &str {
+    name.match_indices('-')
+        .find(|(i, _)| {
+            name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_ascii_alphabetic())
+        })
+        .map_or(name, |(i, _)| &name[..i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derivation_names() {
+        assert_eq!(
+            derivation_name("/nix/store/0123456789abcdfghijklmnpqrsvwxyz-hello-2.12.1.drv"),
+            Some("hello-2.12.1")
+        );
+        assert_eq!(derivation_name("unknown-deriver"), None);
+        assert_eq!(package_name("hello-2.12.1"), "hello");
+        assert_eq!(
+            package_name("python3.12-requests-2.32.3"),
+            "python3.12-requests"
+        );
+        assert_eq!(package_name("my-app"), "my-app");
+        assert_eq!(package_name("my-app-"), "my-app-");
+    }
+
+    #[test]
+    fn prefers_default_package() {
+        let packages = BTreeMap::from([
+            ("app".to_owned(), "my-app-1.0".to_owned()),
+            ("default".to_owned(), "my-app-1.0".to_owned()),
+            ("other".to_owned(), "other-1.0".to_owned()),
+        ]);
+        assert_eq!(
+            find_package(&packages, "my-app-0.9").as_deref(),
+            Some("default")
+        );
+        assert_eq!(
+            find_package(&packages, "other-2.0").as_deref(),
+            Some("other")
+        );
+        assert_eq!(find_package(&packages, "missing-1.0"), None);
+    }
+}
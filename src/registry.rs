@@ -0,0 +1,404 @@
+//! Nix's flake registries, which resolve indirect flake references like `flake:nixpkgs`.
+//!
+//! Like in Nix, the user registry takes precedence over the system registry, which takes
+//! precedence over the global registry.
+//!
+//! <https://nix.dev/manual/nix/2.28/command-ref/new-cli/nix3-registry.html>
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{flake_ref::Attrs, lockfile::Original};
+
+const SYSTEM_REGISTRY: &str = "/etc/nix/registry.json";
+
+/// The default of Nix's `flake-registry` setting.
+const GLOBAL_REGISTRY_URL: &str = "https://channels.nixos.org/flake-registry.json";
+
+/// How long the downloaded global registry is used before downloading it again. The same as the
+/// default of Nix's `tarball-ttl` setting.
+const GLOBAL_REGISTRY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Indirect references resolving to other indirect references are followed at most this many
+/// times, like in Nix.
+const MAX_INDIRECTIONS: usize = 100;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The contents of a `registry.json`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct RegistryFile {
+    pub version: u64,
+    pub flakes: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub from: Attrs,
+    pub to: Attrs,
+    /// Whether `ref` and `rev` of the reference being resolved are ignored.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exact: bool,
+}
+
+impl RegistryFile {
+    /// Reads a registry, which is empty if the file doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be read or isn't a version 2 registry.
+    pub fn read(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Self::parse(b"");
+        }
+        Self::parse(&fs::read(path)?)
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Parses the contents of a registry, which is empty if `contents` is.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `contents` isn't a version 2 registry.
+    pub fn parse(contents: &[u8]) -> Result<Self> {
+        if contents.is_empty() {
+            return Ok(Self {
+                version: 2,
+                flakes: Vec::new(),
+            });
+        }
+        let registry: Self = serde_json::from_slice(contents)?;
+        if registry.version != 2 {
+            bail!("Unsupported registry version {}", registry.version);
+        }
+        Ok(registry)
+    }
+
+    /// Writes the registry to `path`, creating its directory.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file or its directory can't be written.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = serde_json::to_string_pretty(self)?;
+        contents.push('\n');
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// Returns the attribute set form of the indirect flake reference `flake:<id>/<ref>/<rev>`.
+#[must_use]
+pub fn indirect(id: &str, ref_: Option<&str>, rev: Option<&str>) -> Attrs {
+    let mut attrs = Attrs::new();
+    attrs.insert("type".to_owned(), "indirect".into());
+    attrs.insert("id".to_owned(), id.into());
+    if let Some(ref_) = ref_ {
+        attrs.insert("ref".to_owned(), ref_.into());
+    }
+    if let Some(rev) = rev {
+        attrs.insert("rev".to_owned(), rev.into());
+    }
+    attrs
+}
+
+/// Returns the ref of `original`, looking up indirect references in the registries.
+///
+/// An indirect input without a ref like `nixpkgs` is then known to follow `nixpkgs-unstable`.
+pub fn resolved_ref(original: &Original) -> Option<Cow<'_, str>> {
+    let Original::Indirect { id, rev, ref_ } = original else {
+        return original.ref_().map(Cow::Borrowed);
+    };
+    let resolved = Registry::get().resolve(&indirect(id, ref_.as_deref(), rev.as_deref()))?;
+    resolved
+        .get("ref")
+        .and_then(Value::as_str)
+        .map(|ref_| Cow::Owned(ref_.to_owned()))
+}
+
+/// Pins `id` in the user registry to the locked flake reference `to`, replacing an existing entry
+/// for it, like `nix registry pin`.
+///
+/// # Errors
+///
+/// Fails if the user registry can't be found, read or written.
+pub fn pin(id: &str, to: Attrs) -> Result<()> {
+    let path = user_registry_path()?;
+    let mut registry = RegistryFile::read(&path)?;
+    let from = indirect(id, None, None);
+    registry.flakes.retain(|entry| entry.from != from);
+    registry.flakes.push(Entry {
+        from,
+        to,
+        exact: true,
+    });
+    registry.write(&path)
+}
+
+/// Removes the entries for `id` from the user registry and returns whether there were any.
+///
+/// # Errors
+///
+/// Fails like [`pin`].
+pub fn unpin(id: &str) -> Result<bool> {
+    let path = user_registry_path()?;
+    let mut registry = RegistryFile::read(&path)?;
+    let from = indirect(id, None, None);
+    let len = registry.flakes.len();
+    registry.flakes.retain(|entry| entry.from != from);
+    if registry.flakes.len() == len {
+        return Ok(false);
+    }
+    registry.write(&path)?;
+    Ok(true)
+}
+
+/// Returns the path of the user registry, `$XDG_CONFIG_HOME/nix/registry.json`.
+///
+/// # Errors
+///
+/// Fails if neither `XDG_CONFIG_HOME` nor `HOME` is set.
+pub fn user_registry_path() -> Result<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok_or_eyre("Neither XDG_CONFIG_HOME nor HOME is set")?;
+    Ok(config_home.join("nix/registry.json"))
+}
+
+/// Returns the path of the global registry saved by [`refresh_global_registry`].
+fn global_registry_path() -> Result<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok_or_eyre("Neither XDG_CACHE_HOME nor HOME is set")?;
+    Ok(cache_home
+        .join(env!("CARGO_PKG_NAME"))
+        .join("flake-registry.json"))
+}
+
+/// Downloads the global registry if the saved copy is missing or older than
+/// [`GLOBAL_REGISTRY_TTL`]. An outdated copy is kept with a warning if the download fails.
+///
+/// Call this before resolving anything, since [`Registry`] only reads the saved copy.
+///
+/// # Errors
+///
+/// Fails if the download fails and there's no saved copy.
+pub fn refresh_global_registry() -> Result<()> {
+    let path = global_registry_path()?;
+    let is_fresh = fs::metadata(&path)
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < GLOBAL_REGISTRY_TTL);
+    if is_fresh {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    let status = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(&tmp_path)
+        .arg(GLOBAL_REGISTRY_URL)
+        .stderr(Stdio::inherit())
+        .status()?;
+    if status.success() {
+        fs::rename(&tmp_path, &path)?;
+    } else if path.exists() {
+        // An outdated registry is better than none when offline
+        tracing::warn!("Failed to download the global flake registry, using an outdated copy");
+    } else {
+        bail!("Failed to download the global flake registry");
+    }
+    Ok(())
+}
+
+/// The user, system and global registries merged in order of precedence.
+#[derive(Default)]
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    /// Loads the registries, skipping ones that fail to load with a warning.
+    ///
+    /// The global registry is only read from the copy saved by [`refresh_global_registry`], so
+    /// resolving a reference never downloads anything.
+    #[must_use]
+    pub fn load() -> Self {
+        let user = user_registry_path().and_then(|path| RegistryFile::read(&path));
+        let system = RegistryFile::read(Path::new(SYSTEM_REGISTRY));
+        Self::from_files([user, system])
+    }
+
+    /// Merges the user and system registries of another machine with the global registry saved
+    /// by [`refresh_global_registry`].
+    #[must_use]
+    pub fn with_registries(user: RegistryFile, system: RegistryFile) -> Self {
+        Self::from_files([Ok(user), Ok(system)])
+    }
+
+    fn from_files(files: [Result<RegistryFile>; 2]) -> Self {
+        let global = global_registry_path().and_then(|path| RegistryFile::read(&path));
+        let mut entries = Vec::new();
+        for file in files.into_iter().chain([global]) {
+            match file {
+                Ok(registry) => entries.extend(registry.flakes),
+                Err(err) => tracing::warn!("Failed to load a flake registry: {err}"),
+            }
+        }
+        Self { entries }
+    }
+
+    /// Uses these registries for the rest of the process instead of loading the local ones on
+    /// first use.
+    pub fn init(self) {
+        // Only the first call matters
+        let _ = REGISTRY.set(self);
+    }
+
+    /// Returns the registries, loading them on first use.
+    pub fn get() -> &'static Self {
+        REGISTRY.get_or_init(Self::load)
+    }
+
+    /// Resolves an indirect flake reference in attribute set form like Nix does.
+    ///
+    /// Returns `None` if no registry has an entry for it. Other references are returned as is.
+    pub fn resolve(&self, flake_ref: &Attrs) -> Option<Attrs> {
+        let mut flake_ref = flake_ref.clone();
+        for _ in 0..MAX_INDIRECTIONS {
+            if flake_ref.get("type").and_then(Value::as_str) != Some("indirect") {
+                return Some(flake_ref);
+            }
+            let entry = self.entries.iter().find(|entry| {
+                entry
+                    .from
+                    .iter()
+                    .all(|(key, value)| flake_ref.get(key) == Some(value))
+            })?;
+            let mut resolved = entry.to.clone();
+            if !entry.exact {
+                for key in ["ref", "rev"] {
+                    if let Some(value) = flake_ref.get(key) {
+                        resolved.insert(key.to_owned(), value.clone());
+                    }
+                }
+            }
+            flake_ref = resolved;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn attrs(value: Value) -> Attrs {
+        let Value::Object(attrs) = value else {
+            panic!("not an object: {value}");
+        };
+        attrs
+    }
+
+    fn registry() -> Registry {
+        let user: RegistryFile = serde_json::from_value(json!({
+            "version": 2,
+            "flakes": [
+                {
+                    "from": { "type": "indirect", "id": "nixpkgs" },
+                    "to": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "0123456789abcdef0123456789abcdef01234567" },
+                    "exact": true
+                },
+                {
+                    "from": { "type": "indirect", "id": "mine" },
+                    "to": { "type": "indirect", "id": "templates" }
+                }
+            ]
+        }))
+        .unwrap();
+        let global: RegistryFile = serde_json::from_value(json!({
+            "version": 2,
+            "flakes": [
+                {
+                    "from": { "type": "indirect", "id": "nixpkgs" },
+                    "to": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "ref": "nixpkgs-unstable" }
+                },
+                {
+                    "from": { "type": "indirect", "id": "templates" },
+                    "to": { "type": "github", "owner": "NixOS", "repo": "templates" }
+                }
+            ]
+        }))
+        .unwrap();
+        Registry {
+            entries: user.flakes.into_iter().chain(global.flakes).collect(),
+        }
+    }
+
+    #[test]
+    fn user_registry_takes_precedence() {
+        let resolved = registry()
+            .resolve(&attrs(
+                json!({ "type": "indirect", "id": "nixpkgs", "ref": "nixos-unstable" }),
+            ))
+            .unwrap();
+        // The user's entry is exact, so the ref isn't applied
+        assert_eq!(
+            resolved,
+            attrs(
+                json!({ "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "0123456789abcdef0123456789abcdef01234567" })
+            )
+        );
+    }
+
+    #[test]
+    fn chained_and_missing() {
+        let registry = registry();
+        assert_eq!(
+            registry
+                .resolve(&attrs(
+                    json!({ "type": "indirect", "id": "mine", "ref": "main" })
+                ))
+                .unwrap(),
+            attrs(
+                json!({ "type": "github", "owner": "NixOS", "repo": "templates", "ref": "main" })
+            )
+        );
+        assert_eq!(
+            registry.resolve(&attrs(json!({ "type": "indirect", "id": "unknown" }))),
+            None
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Listing the flakes of another machine over SSH with `--host`.
+//!
+//! Only `ssh` and a POSIX shell are needed on the remote machine. Its gcroots, lock files, store
+//! directory and user and system flake registries are read with shell commands, and everything
+//! else happens locally. Updating the flakes isn't supported, since that needs Nix, direnv and
+//! the editor on the machine.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use iddqd::IdHashMap;
+
+use nixpkgsupd::{
+    discovery::{ProjectKind, add_gcroot, gcroot_owner_by_path},
+    lockfile::parse_lockfile_input,
+    registry::{Registry, RegistryFile},
+    sigint_guard, store,
+    target::MatchTarget,
+};
+
+use crate::{Cli, Outcome, explain, is_up_to_date, print_flake_info, print_non_flake_input};
+
+/// Prints the store and state directories, then the targets of the automatic gcroots that
+/// exist, each followed by NUL.
+const LIST_GCROOTS: &str = r#"state_dir=${NIX_STATE_DIR:-/nix/var/nix}
+printf '%s\0%s\0' "${NIX_STORE_DIR:-/nix/store}" "$state_dir"
+for link in "$state_dir"/gcroots/auto/*; do
+    target=$(readlink "$link") && [ -e "$target" ] && printf '%s\0' "$target"
+done
+exit 0"#;
+
+/// Prints the user and system flake registries, each followed by NUL. A missing registry is
+/// printed as nothing.
+const READ_REGISTRIES: &str = r#"for file in "${XDG_CONFIG_HOME:-$HOME/.config}/nix/registry.json" /etc/nix/registry.json; do
+    if [ -f "$file" ]; then
+        cat "$file"
+    fi
+    printf '\0'
+done"#;
+
+/// Prints the directory, name and contents of the first lock file in each directory given as an
+/// argument, each followed by NUL.
+const READ_LOCKFILES: &str = r#"for dir in "$@"; do
+    for file in flake.lock devenv.lock; do
+        if [ -f "$dir/$file" ]; then
+            printf '%s\0%s\0' "$dir" "$file" && cat "$dir/$file" && printf '\0'
+            break
+        fi
+    done
+done"#;
+
+/// Quotes `arg` for a POSIX shell. The quoting also works in fish.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Runs `script` with `sh` on `host` and returns its standard output.
+fn run_script(host: &str, script: &str, args: &[&str]) -> Result<Vec<u8>> {
+    let _span = tracing::debug_span!("ssh", host, ?args).entered();
+    // ssh joins its arguments with spaces for the remote user's login shell to parse
+    let mut command = format!("sh -c {} sh", shell_quote(script));
+    for arg in args {
+        command.push(' ');
+        command.push_str(&shell_quote(arg));
+    }
+    let output = sigint_guard::output_interactive(
+        Command::new("ssh")
+            .args([host, "--", &command])
+            .stderr(Stdio::inherit()),
+    )?;
+    tracing::debug!(status = %output.status, "ssh exited");
+    if !output.status.success() {
+        bail!("ssh {host} failed with {}", output.status);
+    }
+    Ok(output.stdout)
+}
+
+/// Parses the output of [`LIST_GCROOTS`] into the store and state directories and the gcroots.
+fn parse_gcroots(output: &[u8]) -> Result<(PathBuf, PathBuf, Vec<PathBuf>)> {
+    let output = output.strip_suffix(b"\0").unwrap_or(output);
+    let mut fields = output
+        .split(|&b| b == 0)
+        .map(|field| String::from_utf8(field.to_owned()).map(PathBuf::from));
+    let (Some(store_dir), Some(state_dir)) = (fields.next(), fields.next()) else {
+        bail!("Truncated gcroot listing");
+    };
+    let gcroots = fields.filter_map(Result::ok).collect();
+    Ok((store_dir?, state_dir?, gcroots))
+}
+
+/// Uses the user and system flake registries of `host` to resolve indirect flake references
+/// instead of the local ones, like Nix on the machine does.
+///
+/// Call this before resolving anything, since the registries are only loaded once.
+pub fn load_registries(host: &str) -> Result<()> {
+    let output = run_script(host, READ_REGISTRIES, &[])
+        .wrap_err("Failed to read the flake registries of the remote host")?;
+    let mut files = output.split(|&b| b == 0);
+    let (Some(user), Some(system)) = (files.next(), files.next()) else {
+        bail!("Truncated registry listing");
+    };
+    Registry::with_registries(
+        RegistryFile::parse(user)
+            .wrap_err("Failed to parse the user registry of the remote host")?,
+        RegistryFile::parse(system)
+            .wrap_err("Failed to parse the system registry of the remote host")?,
+    )
+    .init();
+    Ok(())
+}
+
+/// Parses the output of [`READ_LOCKFILES`] into the project kind and lock file of each directory.
+fn parse_lockfiles(output: &[u8]) -> Result<HashMap<PathBuf, (ProjectKind, Vec<u8>)>> {
+    let output = output.strip_suffix(b"\0").unwrap_or(output);
+    let mut fields = output.split(|&b| b == 0);
+    let mut lockfiles = HashMap::new();
+    while let Some(dir) = fields.next().filter(|dir| !dir.is_empty()) {
+        let (Some(file), Some(contents)) = (fields.next(), fields.next()) else {
+            bail!("Truncated lock file listing");
+        };
+        let project = if file == ProjectKind::Devenv.lockfile().as_bytes() {
+            ProjectKind::Devenv
+        } else {
+            ProjectKind::Flake
+        };
+        let dir = PathBuf::from(String::from_utf8(dir.to_owned())?);
+        lockfiles.insert(dir, (project, contents.to_owned()));
+    }
+    Ok(lockfiles)
+}
+
+/// Lists the flakes on `host` whose input doesn't match the target.
+///
+/// lorri's gcroots and `.envrc` files using a flake in another directory aren't followed, since
+/// that would need more round trips. See [`gcroot_owner_by_path`].
+pub fn list_flakes(host: &str, cli: &Cli, target: &MatchTarget) -> Result<Outcome> {
+    let extra_result_names = cli.config.gcroot_patterns()?;
+    let (store_dir, state_dir, gcroots) = parse_gcroots(
+        &run_script(host, LIST_GCROOTS, &[])
+            .wrap_err("Failed to list the gcroots of the remote host")?,
+    )?;
+    // Flakes in the machine's store are told apart by its store directory
+    store::init_remote(store_dir, state_dir);
+    let owned_gcroots = gcroots
+        .into_iter()
+        .filter_map(|gcroot| {
+            let (directory, kind) = gcroot_owner_by_path(&gcroot, &extra_result_names)?;
+            Some((gcroot, directory, kind))
+        })
+        .collect::<Vec<_>>();
+
+    let mut directories = owned_gcroots
+        .iter()
+        .filter_map(|(_, directory, _)| directory.to_str())
+        .collect::<Vec<_>>();
+    directories.sort_unstable();
+    directories.dedup();
+    let lockfiles = parse_lockfiles(
+        &run_script(host, READ_LOCKFILES, &directories)
+            .wrap_err("Failed to read the lock files of the remote host")?,
+    )?;
+
+    let mut flakes = IdHashMap::new();
+    for (gcroot, directory, kind) in owned_gcroots {
+        add_gcroot(
+            &mut flakes,
+            &cli.input_id,
+            gcroot,
+            &directory,
+            kind,
+            |dir| lockfiles.get(dir).map(|(project, _)| *project),
+        );
+    }
+
+    let mut outcome = Outcome::default();
+    for flake in &flakes {
+        let Some((_, contents)) = lockfiles.get(&flake.directory) else {
+            continue;
+        };
+        let _span = tracing::info_span!("flake", directory = %flake.directory.display()).entered();
+        let result = parse_lockfile_input(contents, flake.id)
+            .and_then(|lockfile_node| {
+                if !lockfile_node.flake {
+                    print_non_flake_input(flake, cli);
+                    return Ok(false);
+                }
+                if cli.explain {
+                    explain::print_checks(flake, cli, target, &lockfile_node)?;
+                }
+                let outdated = !is_up_to_date(cli, target, None, &lockfile_node)?;
+                if outdated {
+                    // The state of the other machine isn't read
+                    print_flake_info(flake, cli, target, &lockfile_node, &[], None)?;
+                }
+                Ok(outdated)
+            })
+            .wrap_err_with(|| format!("Failed to process flake {}", flake.directory.display()));
+        match result {
+            Ok(outdated) => outcome.outdated += usize::from(outdated),
+            Err(err) => {
+                eprintln!("{err:?}");
+                outcome.failed += 1;
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn quote_and_parse() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+
+        let lockfiles = parse_lockfiles(
+            b"/home/a/x\0flake.lock\0{\"version\":7}\n\0/home/a/y\0devenv.lock\0{}\0",
+        )
+        .unwrap();
+        assert_eq!(lockfiles.len(), 2);
+        assert_eq!(lockfiles[Path::new("/home/a/x")].0, ProjectKind::Flake);
+        assert_eq!(lockfiles[Path::new("/home/a/x")].1, b"{\"version\":7}\n");
+        assert_eq!(lockfiles[Path::new("/home/a/y")].0, ProjectKind::Devenv);
+        assert!(parse_lockfiles(b"/home/a/x\0flake.lock\0").is_err());
+
+        let (store_dir, state_dir, gcroots) =
+            parse_gcroots(b"/nix/store\0/nix/var/nix\0/home/a/x/result\0").unwrap();
+        assert_eq!(store_dir, Path::new("/nix/store"));
+        assert_eq!(state_dir, Path::new("/nix/var/nix"));
+        assert_eq!(gcroots, [Path::new("/home/a/x/result")]);
+        assert!(parse_gcroots(b"/nix/store\0").is_err());
+    }
+}
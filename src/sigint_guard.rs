@@ -1,36 +1,447 @@
-use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+//! Running child processes so that signals meant for them don't leave things half-done.
+//!
+//! Children run in their own process group, which is given the terminal while it runs. Then
+//! <kbd>Ctrl</kbd>+<kbd>C</kbd> only interrupts the child, `SIGTERM` and `SIGHUP` are forwarded
+//! to the child before exiting, and the terminal's mode is restored even if an editor or shell
+//! is killed.
 
-const extern "C" fn empty_handler(_signal: std::ffi::c_int) {}
+use std::{
+    ffi::c_int,
+    io::{self, IsTerminal, Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::process::{CommandExt, ExitStatusExt},
+    },
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-/// Disables the effects of <kbd>Ctrl</kbd>+<kbd>C</kbd> on the current process.
-pub struct SigintGuard {
-    old_action: SigAction,
-}
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, killpg, raise, sigaction},
+        termios::{SetArg, Termios, tcgetattr, tcsetattr},
+        wait::{WaitPidFlag, WaitStatus, waitpid},
+    },
+    unistd::{Pid, getpgrp, tcgetpgrp, tcsetpgrp},
+};
+
+/// Signals forwarded to the running child. The terminal only sends `SIGINT` to the child while
+/// it has the terminal, but it can still be sent to this process otherwise.
+const FORWARDED_SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// The process group of the running child, or 0 when none is running.
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
 
-impl SigintGuard {
-    pub fn new() -> Self {
-        // SAFETY: async-signal-safe handler and only one thread here
+/// A signal other than `SIGINT` received while the child ran, which exits after it.
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: c_int) {
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        // SAFETY: kill is async-signal-safe
         unsafe {
-            let old_action = sigaction(
-                Signal::SIGINT,
-                &SigAction::new(
-                    SigHandler::Handler(empty_handler),
-                    SaFlags::SA_RESTART,
-                    SigSet::empty(), // TODO: pass SIGINT or no?
-                ),
-            )
-            .unwrap();
+            nix::libc::kill(-pgid, signal);
+        }
+    }
+    if signal != Signal::SIGINT as c_int {
+        PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+    }
+}
+
+/// The terminal on standard input while this process is in its foreground process group.
+struct Terminal {
+    pgrp: Pid,
+    termios: Termios,
+}
+
+impl Terminal {
+    fn foreground() -> Option<Self> {
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            return None;
+        }
+        let pgrp = getpgrp();
+        if tcgetpgrp(&stdin).ok()? != pgrp {
+            return None;
+        }
+        let termios = tcgetattr(&stdin).ok()?;
+        Some(Self { pgrp, termios })
+    }
+
+    /// Gives the terminal to the process group `pgid`.
+    fn give(pgid: Pid) {
+        let _ = tcsetpgrp(io::stdin(), pgid);
+    }
+
+    /// Takes the terminal back and restores the mode it had before the child ran.
+    fn restore(&self) {
+        let _ = tcsetpgrp(io::stdin(), self.pgrp);
+        let _ = tcsetattr(io::stdin(), SetArg::TCSADRAIN, &self.termios);
+    }
+}
+
+/// Forwards signals to the child and restores the terminal and signal handlers when dropped.
+struct SignalGuard {
+    old_actions: Vec<(Signal, SigAction)>,
+    terminal: Option<Terminal>,
+}
 
-            Self { old_action }
+impl SignalGuard {
+    fn new() -> io::Result<Self> {
+        let forward = SigAction::new(
+            SigHandler::Handler(forward_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // Taking the terminal back from the child's process group would stop this process
+        let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+        let mut old_actions = Vec::new();
+        for (signal, action) in FORWARDED_SIGNALS
+            .into_iter()
+            .map(|signal| (signal, &forward))
+            .chain([(Signal::SIGTTOU, &ignore)])
+        {
+            // SAFETY: the handler is async-signal-safe
+            old_actions.push((signal, unsafe { sigaction(signal, action) }?));
         }
+        Ok(Self {
+            old_actions,
+            terminal: Terminal::foreground(),
+        })
     }
 }
 
-impl Drop for SigintGuard {
+impl Drop for SignalGuard {
     fn drop(&mut self) {
-        // SAFETY: async-signal-safe handler and only one thread here
+        if let Some(terminal) = &self.terminal {
+            terminal.restore();
+        }
+        for (signal, action) in self.old_actions.iter().rev() {
+            // SAFETY: restores the previous handler
+            let _ = unsafe { sigaction(*signal, action) };
+        }
+    }
+}
+
+/// Waits for `child`, suspending this process too if it's suspended with
+/// <kbd>Ctrl</kbd>+<kbd>Z</kbd> so that the shell's job control still works.
+fn wait(child: &Child, terminal: Option<&Terminal>) -> io::Result<ExitStatus> {
+    let pid = Pid::from_raw(i32::try_from(child.id()).map_err(io::Error::other)?);
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WUNTRACED)) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(ExitStatus::from_raw(code << 8)),
+            Ok(WaitStatus::Signaled(_, signal, core_dumped)) => {
+                let core_dumped = if core_dumped { 0x80 } else { 0 };
+                return Ok(ExitStatus::from_raw(signal as i32 | core_dumped));
+            }
+            Ok(WaitStatus::Stopped(_, Signal::SIGTTIN | Signal::SIGTTOU)) if terminal.is_some() => {
+                // The child accessed the terminal before it was given to it
+                Terminal::give(pid);
+                killpg(pid, Signal::SIGCONT)?;
+            }
+            // Ctrl+Z, or the child accessing the terminal while this process is in the
+            // background, where only the shell can resume it by resuming this process
+            Ok(WaitStatus::Stopped(..)) => {
+                if let Some(terminal) = terminal {
+                    terminal.restore();
+                }
+                // Continues here once the shell resumes this process
+                raise(Signal::SIGSTOP)?;
+                if terminal.is_some() {
+                    Terminal::give(pid);
+                }
+                killpg(pid, Signal::SIGCONT)?;
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Runs `command` in its own process group and calls `wait_child` to wait for it.
+fn run<T>(
+    command: &mut Command,
+    wait_child: impl FnOnce(&mut Child, Option<&Terminal>) -> io::Result<T>,
+) -> io::Result<T> {
+    let guard = SignalGuard::new()?;
+    command.process_group(0);
+    if guard.terminal.is_some() {
+        // Standard input of the child may be redirected, so the terminal is given through this
+        // process's. Closed on exec.
+        let terminal = io::stdin().as_fd().try_clone_to_owned()?;
+        // SAFETY: only calls async-signal-safe functions
         unsafe {
-            sigaction(Signal::SIGINT, &self.old_action).unwrap();
+            command.pre_exec(move || {
+                // Given before exec, so the child can't read the terminal before it has it. The
+                // child is already in its own process group, which ignores SIGTTOU like this
+                // process until it's reset.
+                nix::libc::tcsetpgrp(terminal.as_raw_fd(), nix::libc::getpid());
+                nix::libc::signal(nix::libc::SIGTTOU, nix::libc::SIG_DFL);
+                Ok(())
+            });
+        }
+    }
+    let mut child = command.spawn()?;
+    let pgid = i32::try_from(child.id()).map_err(io::Error::other)?;
+    CHILD_PGID.store(pgid, Ordering::SeqCst);
+    // A signal received before the child had a process group wasn't forwarded
+    let pending = PENDING_SIGNAL.load(Ordering::SeqCst);
+    if pending != 0 {
+        // SAFETY: kill has no memory safety requirements
+        unsafe {
+            nix::libc::kill(-pgid, pending);
+        }
+    }
+
+    let result = wait_child(&mut child, guard.terminal.as_ref());
+    CHILD_PGID.store(0, Ordering::SeqCst);
+    drop(guard);
+
+    // Exit like the signal would have if the child hadn't been running
+    let pending = PENDING_SIGNAL.swap(0, Ordering::SeqCst);
+    if let Ok(signal) = Signal::try_from(pending) {
+        raise(signal)?;
+    }
+    result
+}
+
+/// Kills a process group unless stopped before the timeout.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(pgid: Pid, timeout: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || match stopped.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = killpg(pgid, Signal::SIGKILL);
+                true
+            }
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => false,
+        });
+        Self { stop, thread }
+    }
+
+    /// Stops the watchdog and returns whether it killed the process group.
+    fn stop(self) -> bool {
+        let _ = self.stop.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+/// Runs `command` like [`Command::status`] with signals handled as described in the module
+/// documentation.
+///
+/// # Errors
+///
+/// Fails if the command can't be started or waited for.
+pub fn status(command: &mut Command) -> io::Result<ExitStatus> {
+    run(command, |child, terminal| wait(child, terminal))
+}
+
+/// Runs `command` like [`status`], but with `input` written to its standard input.
+///
+/// # Errors
+///
+/// Fails like [`status`], or if writing the input fails.
+pub fn status_with_input(command: &mut Command, input: Vec<u8>) -> io::Result<ExitStatus> {
+    command.stdin(Stdio::piped());
+    run(command, |child, terminal| {
+        let stdin = child.stdin.take();
+        // Written from another thread so a child writing a lot before reading can't deadlock
+        let writer = thread::spawn(move || -> io::Result<()> {
+            if let Some(mut stdin) = stdin {
+                stdin.write_all(&input)?;
+            }
+            Ok(())
+        });
+        let status = wait(child, terminal)?;
+        writer
+            .join()
+            .map_err(|_| io::Error::other("Writing the input of a command panicked"))??;
+        Ok(status)
+    })
+}
+
+/// Runs `command` like [`Command::output`] with signals handled as described in the module
+/// documentation.
+///
+/// Like with [`Command::output`], standard input is null, but standard error is inherited unless
+/// set.
+///
+/// # Errors
+///
+/// Fails if the command can't be started or its output can't be read.
+pub fn output(command: &mut Command) -> io::Result<Output> {
+    output_with_timeout(command, None)
+}
+
+/// Runs `command` like [`output`], but with standard input inherited, for commands that may ask
+/// the user something, like `ssh`.
+///
+/// # Errors
+///
+/// Fails like [`output`].
+pub fn output_interactive(command: &mut Command) -> io::Result<Output> {
+    output_inner(command.stdin(Stdio::inherit()), None, None::<fn(&[u8])>)
+}
+
+/// Runs `command` like [`output`], but kills it and returns an error of kind
+/// [`io::ErrorKind::TimedOut`] if it runs longer than `timeout`.
+///
+/// # Errors
+///
+/// Fails like [`output`], or once the timeout runs out.
+pub fn output_with_timeout(command: &mut Command, timeout: Option<Duration>) -> io::Result<Output> {
+    output_inner(command.stdin(Stdio::null()), timeout, None::<fn(&[u8])>)
+}
+
+/// Runs `command` like [`output_with_timeout`], but passes its standard error to `tee` as it's
+/// written, so progress and prompts still reach the user.
+///
+/// Standard input is inherited to answer them, like Nix's question whether to trust a flake's
+/// `nixConfig`. Only the last line of standard error is kept in the returned [`Output`].
+///
+/// # Errors
+///
+/// Fails like [`output_interactive`].
+pub fn output_teeing_stderr(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    tee: impl FnMut(&[u8]) + Send + 'static,
+) -> io::Result<Output> {
+    command.stdin(Stdio::inherit()).stderr(Stdio::piped());
+    output_inner(command, timeout, Some(tee))
+}
+
+fn output_inner(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    tee: Option<impl FnMut(&[u8]) + Send + 'static>,
+) -> io::Result<Output> {
+    fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<io::Result<Vec<u8>>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buf)?;
+            }
+            Ok(buf)
+        })
+    }
+    /// Passes everything read to `tee` and returns the last line.
+    fn tee_last_line(
+        pipe: Option<impl Read + Send + 'static>,
+        mut tee: impl FnMut(&[u8]) + Send + 'static,
+    ) -> JoinHandle<io::Result<Vec<u8>>> {
+        thread::spawn(move || {
+            let mut last_line = Vec::new();
+            let Some(mut pipe) = pipe else {
+                return Ok(last_line);
+            };
+            let mut buf = [0; 8192];
+            loop {
+                let read = match pipe.read(&mut buf) {
+                    Ok(0) => return Ok(last_line),
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                tee(&buf[..read]);
+                last_line.extend_from_slice(&buf[..read]);
+                let line = last_line.strip_suffix(b"\n").unwrap_or(&last_line);
+                if let Some(end) = line.iter().rposition(|&byte| byte == b'\n') {
+                    last_line.drain(..=end);
+                }
+            }
+        })
+    }
+    fn join(reader: JoinHandle<io::Result<Vec<u8>>>) -> io::Result<Vec<u8>> {
+        reader
+            .join()
+            .map_err(|_| io::Error::other("Reading the output of a command panicked"))?
+    }
+
+    command.stdout(Stdio::piped());
+    run(command, |child, terminal| {
+        let pgid = Pid::from_raw(i32::try_from(child.id()).map_err(io::Error::other)?);
+        let watchdog = timeout.map(|timeout| Watchdog::start(pgid, timeout));
+        let stdout = read_to_end(child.stdout.take());
+        let stderr = match tee {
+            Some(tee) => tee_last_line(child.stderr.take(), tee),
+            None => read_to_end(child.stderr.take()),
+        };
+        let status = wait(child, terminal);
+        if watchdog.is_some_and(Watchdog::stop) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The command took too long and was killed",
+            ));
         }
+        let status = status?;
+        Ok(Output {
+            status,
+            stdout: join(stdout)?,
+            stderr: join(stderr)?,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_status_and_output() {
+        let exited = status(Command::new("sh").args(["-c", "exit 3"])).unwrap();
+        assert_eq!(exited.code(), Some(3));
+
+        let killed = status(Command::new("sh").args(["-c", "kill -KILL $$"])).unwrap();
+        assert_eq!(killed.signal(), Some(Signal::SIGKILL as i32));
+
+        let output = output(
+            Command::new("sh")
+                .args(["-c", "echo out; echo err >&2"])
+                .stderr(Stdio::piped()),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        // Standard input is null like with Command::output, so this doesn't wait for input
+        let read = super::output(&mut Command::new("cat")).unwrap();
+        assert!(read.stdout.is_empty());
+
+        let timed_out = output_with_timeout(
+            Command::new("sh").args(["-c", "sleep 10"]),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap_err();
+        assert_eq!(timed_out.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn teed_stderr() {
+        let (sender, receiver) = mpsc::channel();
+        let output = output_teeing_stderr(
+            Command::new("sh").args(["-c", "echo first >&2; echo last >&2; echo out"]),
+            None,
+            move |chunk| sender.send(chunk.to_owned()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"last\n");
+        assert_eq!(
+            receiver.iter().flatten().collect::<Vec<_>>(),
+            b"first\nlast\n"
+        );
     }
 }
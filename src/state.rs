@@ -0,0 +1,121 @@
+//! State kept between runs in `$XDG_STATE_HOME/nixpkgsupd/state.json`.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt},
+};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    /// Keyed by the flake's directory.
+    #[serde(default)]
+    flakes: BTreeMap<PathBuf, FlakeState>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct FlakeState {
+    /// Seconds since the Unix epoch until which the flake is hidden.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    snoozed_until: Option<u64>,
+    /// Seconds since the Unix epoch when the flake was last locked by `update`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_updated: Option<u64>,
+}
+
+/// Returns `$XDG_STATE_HOME/nixpkgsupd`.
+pub fn state_dir() -> Result<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .ok_or_eyre("Neither XDG_STATE_HOME nor HOME is set")?;
+    Ok(state_home.join(env!("CARGO_PKG_NAME")))
+}
+
+impl State {
+    fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("state.json"))
+    }
+
+    /// Loads the state, which is empty if it hasn't been saved before.
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read(&path)?;
+        serde_json::from_slice(&contents).wrap_err("Failed to parse state file")
+    }
+
+    /// Loads the state like [`State::load`], but falls back to the empty state with a warning if
+    /// the file is unreadable or corrupt, for commands that only read it.
+    pub fn load_or_default() -> Self {
+        Self::load().unwrap_or_else(|err| {
+            tracing::warn!("Ignoring the saved state: {err:?}");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replace atomically so that concurrent runs never see a partial file
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Hides the flake in `directory` until `duration` from now.
+    pub fn snooze(&mut self, directory: &Path, duration: Duration) {
+        let until = SystemTime::now()
+            .checked_add(duration)
+            .and_then(|until| until.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(u64::MAX, |until| until.as_secs());
+        self.flakes
+            .entry(directory.to_owned())
+            .or_default()
+            .snoozed_until = Some(until);
+    }
+
+    /// Records that the flake in `directory` was just locked by `update`.
+    pub fn record_update(&mut self, directory: &Path) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.flakes
+            .entry(directory.to_owned())
+            .or_default()
+            .last_updated = Some(now);
+    }
+
+    /// Returns when the flake in `directory` was last locked by `update`.
+    pub fn last_updated(&self, directory: &Path) -> Option<SystemTime> {
+        let last_updated = self.flakes.get(directory)?.last_updated?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(last_updated))
+    }
+
+    pub fn is_snoozed(&self, directory: &Path) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.flakes
+            .get(directory)
+            .and_then(|flake| flake.snoozed_until)
+            .is_some_and(|until| now < until)
+    }
+}
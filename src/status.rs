@@ -0,0 +1,301 @@
+//! Checking every flake without prompting and recording which are outdated, for `watch`.
+//!
+//! The status is written to `$XDG_STATE_HOME/nixpkgsupd/status.json` after every check so that
+//! other programs like status bars can read it without scanning. It can also be written as
+//! Prometheus metrics for `node_exporter`.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::{Result, eyre::Context};
+use fs_err as fs;
+use serde::Serialize;
+
+use nixpkgsupd::{
+    discovery::{Flake, discover_flakes},
+    lockfile::{Locked, Original, load_lockfile_input},
+    target::MatchTarget,
+};
+
+use crate::{Cli, WatchArgs, is_up_to_date, notification, resolve_target, state, ui::Themed};
+
+/// The result of checking every flake.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// Seconds since the Unix epoch.
+    pub checked_at: u64,
+    pub input_id: String,
+    pub target: LockedInput,
+    /// The number of flakes checked, excluding snoozed ones.
+    pub flakes: usize,
+    pub outdated_count: usize,
+    /// Seconds from the oldest `lastModified` of the outdated flakes to `checked_at`.
+    pub oldest_lock_age: Option<u64>,
+    pub outdated: Vec<OutdatedFlake>,
+    /// Flakes that couldn't be checked.
+    pub errors: Vec<FlakeError>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedInput {
+    #[serde(rename = "ref")]
+    pub ref_: Option<String>,
+    pub rev: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub last_modified: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct OutdatedFlake {
+    pub directory: PathBuf,
+    pub locked: LockedInput,
+}
+
+#[derive(Serialize)]
+pub struct FlakeError {
+    pub directory: PathBuf,
+    pub message: String,
+}
+
+impl LockedInput {
+    fn new(original: &Original, locked: &Locked) -> Self {
+        Self {
+            ref_: original.ref_().map(str::to_owned),
+            rev: locked.rev().map(str::to_owned),
+            last_modified: locked.last_modified(),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns the locked input of the flake if it doesn't match the target.
+fn check_flake(cli: &Cli, target: &MatchTarget, flake: &Flake) -> Result<Option<OutdatedFlake>> {
+    let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+    if is_up_to_date(cli, target, Some(flake), &lockfile_node)? {
+        return Ok(None);
+    }
+    Ok(Some(OutdatedFlake {
+        directory: flake.directory.clone(),
+        locked: LockedInput::new(&lockfile_node.original.inner, &lockfile_node.locked),
+    }))
+}
+
+/// Resolves the target and checks every flake against it.
+///
+/// `nix flake metadata` caches the target for Nix's `tarball-ttl`, so frequent checks don't
+/// fetch it every time.
+pub fn check(cli: &Cli) -> Result<Status> {
+    let target = resolve_target(cli)?;
+    let flakes = discover_flakes(&cli.input_id, &cli.config)?;
+    let state = state::State::load_or_default();
+
+    let mut status = Status {
+        checked_at: unix_now(),
+        input_id: cli.input_id.clone(),
+        target: LockedInput::new(target.original(), target.locked()),
+        flakes: 0,
+        outdated_count: 0,
+        oldest_lock_age: None,
+        outdated: Vec::new(),
+        errors: Vec::new(),
+    };
+    for flake in &flakes {
+        if !cli.include_snoozed && state.is_snoozed(&flake.directory) {
+            continue;
+        }
+        status.flakes += 1;
+        let _span = tracing::info_span!("flake", directory = %flake.directory.display()).entered();
+        match check_flake(cli, &target, flake) {
+            Ok(Some(outdated)) => status.outdated.push(outdated),
+            Ok(None) => {}
+            Err(err) => status.errors.push(FlakeError {
+                directory: flake.directory.clone(),
+                message: format!("{err:#}"),
+            }),
+        }
+    }
+    status.outdated_count = status.outdated.len();
+    status.oldest_lock_age = status
+        .outdated
+        .iter()
+        .filter_map(|flake| flake.locked.last_modified)
+        .min()
+        .map(|oldest| status.checked_at.saturating_sub(oldest));
+    Ok(status)
+}
+
+impl Status {
+    fn path() -> Result<PathBuf> {
+        Ok(state::state_dir()?.join("status.json"))
+    }
+
+    /// Writes the status to `$XDG_STATE_HOME/nixpkgsupd/status.json`.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replace atomically so that readers never see a partial file
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Formats the status in Prometheus' text format, for `node_exporter`'s textfile collector.
+    fn prometheus_metrics(&self) -> String {
+        let input = self
+            .input_id
+            .replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n");
+        let mut metrics = String::new();
+        let mut metric = |name: &str, help: &str, value: Option<u64>| {
+            if let Some(value) = value {
+                let _ = write!(
+                    metrics,
+                    "# HELP {name} {help}\n# TYPE {name} gauge\n{name}{{input=\"{input}\"}} {value}\n"
+                );
+            }
+        };
+        metric(
+            "nixpkgsupd_flakes",
+            "Flakes checked, excluding snoozed ones.",
+            Some(self.flakes as u64),
+        );
+        metric(
+            "nixpkgsupd_outdated_flakes",
+            "Flakes whose input doesn't match the target.",
+            Some(self.outdated_count as u64),
+        );
+        metric(
+            "nixpkgsupd_failed_flakes",
+            "Flakes that couldn't be checked.",
+            Some(self.errors.len() as u64),
+        );
+        metric(
+            "nixpkgsupd_oldest_lock_age_seconds",
+            "Age of the oldest lock of the outdated flakes.",
+            self.oldest_lock_age,
+        );
+        metric(
+            "nixpkgsupd_last_check_timestamp_seconds",
+            "When the flakes were last checked.",
+            Some(self.checked_at),
+        );
+        metrics
+    }
+
+    /// Writes the metrics to `path`, which should end in `.prom` for `node_exporter`.
+    fn save_prometheus_metrics(&self, path: &Path) -> Result<()> {
+        // Replace atomically so that node_exporter never reads a partial file
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.prometheus_metrics())?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Returns the number of outdated flakes in the saved status, if there is one.
+fn saved_outdated_count() -> Option<usize> {
+    let contents = fs::read(Status::path().ok()?).ok()?;
+    let status: serde_json::Value = serde_json::from_slice(&contents).ok()?;
+    Some(status.get("outdated")?.as_array()?.len())
+}
+
+/// Checks every flake, saves the status and metrics and notifies when the outdated flakes reach
+/// `notify_threshold`.
+///
+/// Returns the number of outdated flakes.
+fn check_and_save(
+    cli: &Cli,
+    previous: Option<usize>,
+    notify_threshold: Option<usize>,
+    prometheus_textfile: Option<&Path>,
+) -> Result<usize> {
+    let status = check(cli)?;
+    status.save()?;
+    if let Some(path) = prometheus_textfile {
+        status
+            .save_prometheus_metrics(path)
+            .wrap_err("Failed to write Prometheus metrics")?;
+    }
+    eprintln!(
+        "{} {} {}",
+        format_args!("Checked {} flakes:", status.flakes).muted(),
+        format_args!("{} outdated", status.outdated.len()).value(),
+        format_args!("{} failed", status.errors.len()).muted(),
+    );
+    if let Some(threshold) = notify_threshold {
+        notification::notify_outdated(previous, &status, threshold)
+            .wrap_err("Failed to send a notification")?;
+    }
+    Ok(status.outdated.len())
+}
+
+/// Checks every flake every `--interval` until interrupted, keeping the status file up to date.
+pub fn watch(cli: &Cli, watch_args: &WatchArgs) -> Result<()> {
+    let mut previous = saved_outdated_count();
+    loop {
+        match check_and_save(
+            cli,
+            previous,
+            watch_args.notify_threshold,
+            watch_args.prometheus_textfile.as_deref(),
+        ) {
+            Ok(count) => previous = Some(count),
+            Err(err) => eprintln!("{:?}", err.wrap_err("Failed to check flakes")),
+        }
+        if watch_args.once {
+            return Ok(());
+        }
+        std::thread::sleep(watch_args.interval);
+    }
+}
+
+/// Checks every flake once and notifies when the outdated flakes reached `threshold` since the
+/// previous check.
+pub fn notify(cli: &Cli, threshold: usize) -> Result<()> {
+    check_and_save(cli, saved_outdated_count(), Some(threshold), None).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_metrics() {
+        let status = Status {
+            checked_at: 1_700_000_000,
+            input_id: "nixpkgs".to_owned(),
+            target: LockedInput {
+                ref_: Some("nixos-unstable".to_owned()),
+                rev: None,
+                last_modified: None,
+            },
+            flakes: 4,
+            outdated_count: 1,
+            oldest_lock_age: None,
+            outdated: Vec::new(),
+            errors: Vec::new(),
+        };
+        let metrics = status.prometheus_metrics();
+        assert!(metrics.contains(
+            "# TYPE nixpkgsupd_outdated_flakes gauge\nnixpkgsupd_outdated_flakes{input=\"nixpkgs\"} 1\n"
+        ));
+        assert!(metrics.contains("nixpkgsupd_flakes{input=\"nixpkgs\"} 4\n"));
+        assert!(!metrics.contains("oldest_lock_age"));
+    }
+}
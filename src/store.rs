@@ -0,0 +1,369 @@
+//! Locations of the Nix store and state directories, which can be moved with `--store`,
+//! `NIX_STORE_DIR` and `NIX_STATE_DIR`.
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use fs_err as fs;
+use serde::Deserialize;
+
+use crate::sigint_guard;
+
+static STORE: OnceLock<Store> = OnceLock::new();
+
+/// Symbolic links followed at most when checking whether a gcroot exists, like Linux's limit.
+const MAX_SYMLINKS: usize = 40;
+
+pub struct Store {
+    /// Root of a chroot store like `~/my-nix`, whose store paths are below it.
+    root: Option<PathBuf>,
+    /// The store directory store paths start with, `/nix/store` by default.
+    dir: PathBuf,
+    /// `/nix/var/nix` by default.
+    state_dir: PathBuf,
+}
+
+impl Store {
+    fn from_env(root: Option<PathBuf>) -> Self {
+        let env_dir = |var: &str, default: &str| {
+            std::env::var_os(var)
+                .filter(|dir| !dir.is_empty())
+                .map_or_else(|| PathBuf::from(default), PathBuf::from)
+        };
+        Self {
+            root,
+            dir: env_dir("NIX_STORE_DIR", "/nix/store"),
+            state_dir: env_dir("NIX_STATE_DIR", "/nix/var/nix"),
+        }
+    }
+
+    /// Returns where `path` in the store or state directory actually is.
+    fn real_path(&self, path: &Path) -> PathBuf {
+        match (&self.root, path.strip_prefix("/")) {
+            (Some(root), Ok(relative)) => root.join(relative),
+            _ => path.to_owned(),
+        }
+    }
+
+    /// Returns the directory of the automatic garbage collector roots.
+    #[must_use]
+    pub fn gcroots_dir(&self) -> PathBuf {
+        self.real_path(&self.state_dir.join("gcroots/auto"))
+    }
+
+    /// Returns the directory of system profiles like `system`.
+    #[must_use]
+    pub fn profiles_dir(&self) -> PathBuf {
+        self.real_path(&self.state_dir.join("profiles"))
+    }
+
+    /// Returns whether the store path `gcroot` points to exists.
+    ///
+    /// In a chroot store, the symbolic links are followed manually until a store path so it's
+    /// looked up below the root.
+    #[must_use]
+    pub fn gcroot_exists(&self, gcroot: &Path) -> bool {
+        if self.root.is_none() {
+            return gcroot.exists();
+        }
+        let mut path = gcroot.to_owned();
+        for _ in 0..MAX_SYMLINKS {
+            if path.starts_with(&self.dir) {
+                return self.real_path(&path).exists();
+            }
+            let Ok(target) = fs::read_link(&path) else {
+                return path.exists();
+            };
+            path = match path.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            };
+        }
+        false
+    }
+
+    /// Returns whether `path` is inside the store directory.
+    #[must_use]
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir) || path.starts_with(self.real_path(&self.dir))
+    }
+
+    /// Returns the store path `gcroot` points to, following the symbolic links until the store
+    /// directory.
+    #[must_use]
+    pub fn store_path(&self, gcroot: &Path) -> Option<PathBuf> {
+        let mut path = gcroot.to_owned();
+        for _ in 0..MAX_SYMLINKS {
+            if let Ok(rest) = path.strip_prefix(&self.dir) {
+                // Only the top-level store path, not a file inside it
+                let Some(Component::Normal(name)) = rest.components().next() else {
+                    return None;
+                };
+                return Some(self.dir.join(name));
+            }
+            let target = fs::read_link(&path).ok()?;
+            path = match path.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            };
+        }
+        None
+    }
+
+    /// Asks the Nix daemon or the local store database about `store_paths` with
+    /// `nix path-info`.
+    ///
+    /// Paths missing from the store directory aren't queried and are left out like invalid
+    /// ones.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `nix path-info` fails or its output can't be parsed.
+    pub fn path_infos(&self, store_paths: &[PathBuf]) -> Result<HashMap<PathBuf, PathInfo>> {
+        self.query_path_infos(store_paths, false)
+    }
+
+    /// Returns the total size of `store_paths` and their dependencies in bytes, counting paths
+    /// shared between the closures once.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`Store::path_infos`].
+    pub fn closure_size(&self, store_paths: &[PathBuf]) -> Result<u64> {
+        Ok(self
+            .query_path_infos(store_paths, true)?
+            .values()
+            .filter_map(|info| info.nar_size)
+            .sum())
+    }
+
+    /// Returns the size in bytes of the store paths only `gcroots` keep alive, which become
+    /// collectible once the gcroots are deleted.
+    ///
+    /// Compares the closures of the gcroots to the closures of the other roots listed by
+    /// `nix-store --gc --print-roots`. Roots only root can see aren't listed to other users, so
+    /// it may be an overestimate.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the roots or the closures can't be queried.
+    pub fn reclaimable_size(&self, gcroots: &[PathBuf]) -> Result<u64> {
+        let deleted: Vec<_> = gcroots
+            .iter()
+            .filter_map(|gcroot| self.store_path(gcroot))
+            .collect();
+        let kept: Vec<_> = self
+            .roots()?
+            .into_iter()
+            .filter(|(link, _)| !gcroots.contains(link))
+            .map(|(_, store_path)| store_path)
+            .collect();
+        let kept = self.query_path_infos(&kept, true)?;
+        Ok(self
+            .query_path_infos(&deleted, true)?
+            .into_iter()
+            .filter(|(path, _)| !kept.contains_key(path))
+            .filter_map(|(_, info)| info.nar_size)
+            .sum())
+    }
+
+    /// Lists the garbage collector roots and the store paths they point to with
+    /// `nix-store --gc --print-roots`.
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let _span = tracing::debug_span!("print_roots").entered();
+        let output = sigint_guard::output(
+            Command::new("nix-store")
+                .args(["--gc", "--print-roots"])
+                .stderr(Stdio::inherit()),
+        )?;
+        tracing::debug!(status = %output.status, "nix-store exited");
+        if !output.status.success() {
+            bail!(
+                "`nix-store --gc --print-roots` failed with {}",
+                output.status
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .filter_map(|line| line.rsplit_once(" -> "))
+            .filter(|(_, store_path)| self.contains(Path::new(store_path)))
+            .map(|(link, store_path)| (PathBuf::from(link), PathBuf::from(store_path)))
+            .collect())
+    }
+
+    /// Runs `nix path-info`, with `--recursive` to also include the dependencies instead of
+    /// computing the closure sizes.
+    fn query_path_infos(
+        &self,
+        store_paths: &[PathBuf],
+        recursive: bool,
+    ) -> Result<HashMap<PathBuf, PathInfo>> {
+        let present: Vec<_> = store_paths
+            .iter()
+            .filter(|path| self.real_path(path).exists())
+            .collect();
+        if present.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let _span = tracing::debug_span!("path_info", count = present.len(), recursive).entered();
+        let output = sigint_guard::output(
+            Command::new("nix")
+                .args(["path-info", "--json"])
+                .arg(if recursive {
+                    "--recursive"
+                } else {
+                    "--closure-size"
+                })
+                .arg("--")
+                .args(present)
+                .stderr(Stdio::inherit()),
+        )?;
+        tracing::debug!(status = %output.status, "nix path-info exited");
+        if !output.status.success() {
+            bail!("`nix path-info` failed with {}", output.status);
+        }
+
+        let infos = match serde_json::from_slice(&output.stdout)
+            .wrap_err("Failed to parse the output of `nix path-info`")?
+        {
+            PathInfoOutput::ByPath(infos) => infos
+                .into_iter()
+                .filter_map(|(path, info)| Some((PathBuf::from(path), info?)))
+                .collect(),
+            PathInfoOutput::List(infos) => infos
+                .into_iter()
+                .filter(|info| info.valid)
+                .map(|info| (PathBuf::from(info.path), info.info))
+                .collect(),
+        };
+        Ok(infos)
+    }
+}
+
+/// What `nix path-info` reports about a valid store path.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PathInfo {
+    /// The total size of the path and its dependencies in bytes.
+    pub closure_size: Option<u64>,
+    /// The size of the path itself in bytes.
+    pub nar_size: Option<u64>,
+    /// The derivation that built the path, if known.
+    pub deriver: Option<String>,
+}
+
+/// `nix path-info --json` output, which is keyed by path with `null` for invalid paths since
+/// Nix 2.19.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathInfoOutput {
+    ByPath(HashMap<String, Option<PathInfo>>),
+    List(Vec<ListedPathInfo>),
+}
+
+#[derive(Deserialize)]
+struct ListedPathInfo {
+    path: String,
+    #[serde(default = "default_valid")]
+    valid: bool,
+    #[serde(flatten)]
+    info: PathInfo,
+}
+
+const fn default_valid() -> bool {
+    true
+}
+
+/// Sets the root of the chroot store used for the rest of the process, also for the `nix`
+/// commands run.
+pub fn init(root: Option<PathBuf>) {
+    if let Some(root) = &root {
+        // SAFETY: called at startup before any other threads exist
+        unsafe {
+            std::env::set_var("NIX_REMOTE", root);
+        }
+    }
+    // Only the first call matters
+    let _ = STORE.set(Store::from_env(root));
+}
+
+/// Uses the store and state directories of another machine for the rest of the process, for
+/// `--host`. Only paths are compared against them.
+pub fn init_remote(dir: PathBuf, state_dir: PathBuf) {
+    // Only the first call matters
+    let _ = STORE.set(Store {
+        root: None,
+        dir,
+        state_dir,
+    });
+}
+
+pub fn get() -> &'static Store {
+    STORE.get_or_init(|| Store::from_env(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chroot_store_gcroot() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let project = root.join("project");
+        fs::create_dir_all(root.join("nix/store/abc-source")).unwrap();
+        fs::create_dir_all(&project).unwrap();
+        std::os::unix::fs::symlink("/nix/store/abc-source", project.join("result")).unwrap();
+        std::os::unix::fs::symlink("/nix/store/def-gone", project.join("result-old")).unwrap();
+
+        let store = Store {
+            root: Some(root.to_owned()),
+            dir: PathBuf::from("/nix/store"),
+            state_dir: PathBuf::from("/nix/var/nix"),
+        };
+        assert!(store.gcroot_exists(&project.join("result")));
+        assert!(!store.gcroot_exists(&project.join("result-old")));
+        assert_eq!(
+            store.store_path(&project.join("result-old")),
+            Some(PathBuf::from("/nix/store/def-gone"))
+        );
+        assert_eq!(store.gcroots_dir(), root.join("nix/var/nix/gcroots/auto"));
+    }
+
+    #[test]
+    fn path_info_output() {
+        let by_path: PathInfoOutput = serde_json::from_str(
+            r#"{"/nix/store/abc-source":{"closureSize":2048,"narSize":1024},"/nix/store/def-gone":null}"#,
+        )
+        .unwrap();
+        let PathInfoOutput::ByPath(infos) = by_path else {
+            panic!("Expected output keyed by path");
+        };
+        assert_eq!(
+            infos["/nix/store/abc-source"]
+                .as_ref()
+                .unwrap()
+                .closure_size,
+            Some(2048)
+        );
+        assert!(infos["/nix/store/def-gone"].is_none());
+
+        let list: PathInfoOutput = serde_json::from_str(
+            r#"[{"path":"/nix/store/abc-source","closureSize":2048},{"path":"/nix/store/def-gone","valid":false}]"#,
+        )
+        .unwrap();
+        let PathInfoOutput::List(infos) = list else {
+            panic!("Expected a list");
+        };
+        assert!(infos[0].valid && !infos[1].valid);
+    }
+}
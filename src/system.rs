@@ -0,0 +1,85 @@
+//! Flakes of system configurations, which only take effect after switching to the new
+//! configuration.
+
+use std::path::{Path, PathBuf};
+
+use fs_err as fs;
+use serde::Deserialize;
+
+/// A system configuration tool building from a flake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemKind {
+    #[serde(rename = "nixos")]
+    #[strum(serialize = "NixOS")]
+    NixOS,
+    #[strum(serialize = "nix-darwin")]
+    Darwin,
+}
+
+impl SystemKind {
+    /// Returns the program and arguments switching to the configuration of the flake in the
+    /// current directory.
+    #[must_use]
+    pub const fn switch_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            Self::NixOS => ("sudo", &["nixos-rebuild", "switch", "--flake", "."]),
+            // Activation requires root since nix-darwin 25.05
+            Self::Darwin => ("sudo", &["darwin-rebuild", "switch", "--flake", "."]),
+        }
+    }
+}
+
+/// Returns the directory of the flake `nixos-rebuild` uses by default when running on NixOS.
+///
+/// `/etc/nixos` is often a symlink to a checkout elsewhere, so the path is canonicalized.
+#[must_use]
+pub fn nixos_flake_directory() -> Option<PathBuf> {
+    if !Path::new("/etc/NIXOS").exists() {
+        return None;
+    }
+    let flake_nix = fs::canonicalize("/etc/nixos/flake.nix").ok()?;
+    Some(flake_nix.parent()?.to_owned())
+}
+
+/// Returns the directory of the flake `darwin-rebuild` uses by default when nix-darwin manages
+/// this Mac, which is detected from its system profile.
+#[must_use]
+pub fn darwin_flake_directory() -> Option<PathBuf> {
+    if !cfg!(target_os = "macos") || !crate::store::get().profiles_dir().join("system").exists() {
+        return None;
+    }
+    let flake_nix = fs::canonicalize("/etc/nix-darwin/flake.nix").ok()?;
+    Some(flake_nix.parent()?.to_owned())
+}
+
+/// Returns the directory of the flake `home-manager switch` uses by default when home-manager is
+/// installed standalone, which is detected from its profile's generation links.
+pub fn home_manager_flake_directory() -> Option<PathBuf> {
+    let home = PathBuf::from(std::env::var_os("HOME")?);
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| home.join(".local/state"), PathBuf::from);
+    let mut profiles = vec![state_home.join("nix/profiles/home-manager")];
+    if let Some(user) = std::env::var_os("USER") {
+        profiles.push(
+            crate::store::get()
+                .profiles_dir()
+                .join("per-user")
+                .join(user)
+                .join("home-manager"),
+        );
+    }
+    if !profiles.iter().any(|profile| profile.exists()) {
+        return None;
+    }
+
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| home.join(".config"), PathBuf::from);
+    // `nixpkgs` is the location used before home-manager 23.05
+    ["home-manager", "nixpkgs"].into_iter().find_map(|dir| {
+        let flake_nix = fs::canonicalize(config_home.join(dir).join("flake.nix")).ok()?;
+        Some(flake_nix.parent()?.to_owned())
+    })
+}
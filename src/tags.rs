@@ -0,0 +1,195 @@
+//! Targets picking the newest release tag of a repository, like `github:owner/repo/v*` or a
+//! semver requirement, for inputs that are released with tags.
+
+use color_eyre::{
+    Result,
+    eyre::{OptionExt, bail, eyre},
+};
+use regex::{Regex, RegexBuilder};
+use semver::{Version, VersionReq};
+use serde_json::Value;
+
+use crate::{command, flake_ref};
+
+/// Resolves a target whose ref has a `*` wildcard or that has a semver `requirement` to the
+/// newest matching tag of its repository.
+///
+/// Returns `None` if the target doesn't need it. Tags are only considered if they're versions
+/// after a prefix like `v`, and pre-releases only if `requirement` allows them.
+///
+/// # Errors
+///
+/// Fails for targets of flake inputs, if the tags can't be listed, or if none of them match.
+pub fn resolve_tag_target(
+    target: &str,
+    requirement: Option<&VersionReq>,
+) -> Result<Option<String>> {
+    if !target.contains('*') && requirement.is_none() {
+        return Ok(None);
+    }
+    if target.contains('#') {
+        bail!("Tags can't be picked for targets of flake inputs");
+    }
+    let mut attrs = flake_ref::parse(target)?;
+    let pattern = attrs
+        .get("ref")
+        .and_then(Value::as_str)
+        .filter(|ref_| ref_.contains('*'))
+        .map(str::to_owned);
+
+    let url = git_url(&attrs)?;
+    let tags = list_tags(&url)?;
+    let tag = newest_tag(&tags, pattern.as_deref(), requirement).ok_or_else(|| {
+        eyre!(
+            "No tag of {url} matches the target among {} tags",
+            tags.len()
+        )
+    })?;
+    tracing::info!(tag, "Picked the newest matching tag");
+
+    attrs.remove("rev");
+    attrs.insert("ref".to_owned(), tag.into());
+    Ok(Some(flake_ref::to_string(&attrs)?))
+}
+
+/// Returns the URL `git ls-remote` can list the tags of the flake reference from.
+fn git_url(attrs: &flake_ref::Attrs) -> Result<String> {
+    let get = |key: &str| attrs.get(key).and_then(Value::as_str);
+    let type_ = get("type").ok_or_eyre("The flake reference has no type")?;
+    let owner_repo = || -> Result<_> {
+        Ok((
+            get("owner").ok_or_eyre("The flake reference has no owner")?,
+            get("repo").ok_or_eyre("The flake reference has no repo")?,
+        ))
+    };
+    Ok(match type_ {
+        "github" => {
+            let (owner, repo) = owner_repo()?;
+            format!(
+                "https://{}/{owner}/{repo}.git",
+                get("host").unwrap_or("github.com")
+            )
+        }
+        "gitlab" => {
+            let (owner, repo) = owner_repo()?;
+            format!(
+                "https://{}/{owner}/{repo}.git",
+                get("host").unwrap_or("gitlab.com")
+            )
+        }
+        "sourcehut" => {
+            let (owner, repo) = owner_repo()?;
+            format!(
+                "https://{}/{owner}/{repo}",
+                get("host").unwrap_or("git.sr.ht")
+            )
+        }
+        "git" => get("url")
+            .ok_or_eyre("The flake reference has no URL")?
+            .to_owned(),
+        _ => bail!("Tags can only be listed for GitHub, GitLab, sourcehut and Git repositories"),
+    })
+}
+
+fn list_tags(url: &str) -> Result<Vec<String>> {
+    let output =
+        command::output_with_retries("git", &["ls-remote", "--tags", "--refs", "--", url], None)?;
+    if !output.status.success() {
+        bail!(
+            "Failed to list the tags of {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once("\trefs/tags/"))
+        .map(|(_, tag)| tag.to_owned())
+        .collect())
+}
+
+/// Returns a regular expression matching all of a string against `pattern`, where `*` matches
+/// anything.
+///
+/// # Panics
+///
+/// Never, since the rest of `pattern` is escaped.
+#[must_use]
+pub fn wildcard(pattern: &str) -> Regex {
+    wildcard_regex(pattern, false)
+}
+
+/// Like [`wildcard`], but ignoring case, like forges do for the owner and repository of
+/// `github:NixOS/nixpkgs`.
+#[must_use]
+pub fn wildcard_ignoring_case(pattern: &str) -> Regex {
+    wildcard_regex(pattern, true)
+}
+
+fn wildcard_regex(pattern: &str, case_insensitive: bool) -> Regex {
+    let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+    RegexBuilder::new(&format!("^{}$", parts.join(".*")))
+        .case_insensitive(case_insensitive)
+        .build()
+        .expect("escaped pattern is valid")
+}
+
+/// Returns the tag with the highest version matching the `*` wildcard `pattern` and
+/// `requirement`.
+fn newest_tag<'a>(
+    tags: &'a [String],
+    pattern: Option<&str>,
+    requirement: Option<&VersionReq>,
+) -> Option<&'a str> {
+    let pattern = pattern.map(wildcard);
+    tags.iter()
+        .filter(|tag| pattern.as_ref().is_none_or(|pattern| pattern.is_match(tag)))
+        .filter_map(|tag| Some((parse_version(tag)?, tag.as_str())))
+        .filter(|(version, _)| {
+            requirement.map_or(version.pre.is_empty(), |requirement| {
+                requirement.matches(version)
+            })
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, tag)| tag)
+}
+
+/// Parses a tag like `v1.4`, `1.4.2` or `release-2.0.0-rc.1` as a version.
+fn parse_version(tag: &str) -> Option<Version> {
+    let version = tag.trim_start_matches(|c: char| !c.is_ascii_digit());
+    let core_len = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(core_len);
+    let padding = match core.split('.').count() {
+        1 => ".0.0",
+        2 => ".0",
+        _ => "",
+    };
+    Version::parse(&format!("{core}{padding}{rest}")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_newest_matching_tag() {
+        let tags: Vec<_> = [
+            "v1.3.0",
+            "v1.4",
+            "v1.10.1",
+            "v2.0.0-rc.1",
+            "v2.0.0",
+            "nightly",
+        ]
+        .map(str::to_owned)
+        .into();
+        assert_eq!(newest_tag(&tags, Some("v*"), None), Some("v2.0.0"));
+        assert_eq!(newest_tag(&tags, Some("v1.*"), None), Some("v1.10.1"));
+        let requirement = VersionReq::parse(">=1.4, <2").unwrap();
+        assert_eq!(newest_tag(&tags, None, Some(&requirement)), Some("v1.10.1"));
+        assert_eq!(
+            newest_tag(&tags, Some("v1.4*"), Some(&requirement)),
+            Some("v1.4")
+        );
+        assert_eq!(newest_tag(&tags, Some("release-*"), None), None);
+    }
+}
@@ -0,0 +1,797 @@
+//! Matching the locked inputs of flakes against the target flake reference.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::{
+    Result, Section, SectionExt,
+    eyre::{Context, OptionExt, bail, eyre},
+};
+use fs_err as fs;
+use serde::Deserialize;
+
+use crate::{
+    command, flake_ref,
+    lockfile::{self, LockGraph, Locked, LockfileNode, Original, TransitiveInput},
+    registry,
+};
+
+/// `nix flake metadata --json` output
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NixFlakeMetadata {
+    //description: Option<String>,
+    //fingerprint: String,
+    // lastModified = locked.lastModified?
+    pub locked: Locked,
+    pub locks: lockfile::Lockfile,
+    //original: Original,
+    //original_url: String,
+    /// Equal to `original` except when `original` is indirect.
+    pub resolved: Original,
+    pub resolved_url: String,
+    // unused: url: String,
+}
+
+/// The locked version flakes are compared against.
+pub enum MatchTarget {
+    /// Target a flake's flake ref
+    FlakeMetadata(NixFlakeMetadata),
+    /// Target a flake's input's flake ref
+    FlakeInput {
+        input: LockfileNode,
+        flake_ref_url: String,
+    },
+    /// Target a commit, whose metadata is only fetched once it's needed. [`target_rev`]
+    Rev {
+        input: LockfileNode,
+        flake_ref_url: String,
+        /// The fetched `locked` key, or `None` if fetching it failed.
+        metadata: OnceLock<Option<Locked>>,
+    },
+}
+
+impl MatchTarget {
+    /// Returns the `locked` key.
+    #[must_use]
+    pub fn locked(&self) -> &Locked {
+        match self {
+            Self::FlakeMetadata(metadata) => &metadata.locked,
+            Self::FlakeInput { input, .. } => &input.locked,
+            Self::Rev {
+                input, metadata, ..
+            } => metadata
+                .get()
+                .and_then(Option::as_ref)
+                .unwrap_or(&input.locked),
+        }
+    }
+    /// Returns the `original` key.
+    #[must_use]
+    pub const fn original(&self) -> &Original {
+        match self {
+            Self::FlakeMetadata(metadata) => &metadata.resolved,
+            Self::FlakeInput { input, .. } | Self::Rev { input, .. } => &input.original.inner,
+        }
+    }
+    /// Returns the URL-like flake ref with `indirect` flakes resolved for [`MatchTarget::FlakeMetadata`].
+    #[must_use]
+    pub fn flake_ref_url(&self) -> &str {
+        match self {
+            Self::FlakeMetadata(metadata) => &metadata.resolved_url,
+            Self::FlakeInput { flake_ref_url, .. } | Self::Rev { flake_ref_url, .. } => {
+                flake_ref_url
+            }
+        }
+    }
+    /// Fetches the metadata of a [`MatchTarget::Rev`] the first time it's called.
+    fn fetch_rev_metadata(&self) {
+        let Self::Rev {
+            flake_ref_url,
+            metadata,
+            ..
+        } = self
+        else {
+            return;
+        };
+        metadata.get_or_init(|| match get_flake_ref_metadata(flake_ref_url, None) {
+            Ok(fetched) => Some(fetched.locked),
+            Err(err) => {
+                tracing::warn!("Failed to get metadata of {flake_ref_url}: {err:?}");
+                None
+            }
+        });
+    }
+    /// Indirect references are compared by what they resolve to in the flake registries.
+    #[must_use]
+    pub fn matches_ref(&self, lockfile_node: &LockfileNode) -> bool {
+        registry::resolved_ref(&lockfile_node.original.inner)
+            .is_some_and(|ref_| Some(ref_) == registry::resolved_ref(self.original()))
+    }
+    #[must_use]
+    pub fn matches_rev(&self, lockfile_node: &LockfileNode) -> bool {
+        lockfile_node
+            .locked
+            .rev()
+            .is_some_and(|rev| Some(rev) == self.locked().rev())
+    }
+    /// Tarball URLs embedding a revision are compared by it, like a GitHub archive and a `FlakeHub`
+    /// URL of the same commit.
+    #[must_use]
+    pub fn matches_url(&self, lockfile_node: &LockfileNode) -> bool {
+        let locked = &lockfile_node.locked;
+        let (Some(url), Some(target_url)) = (locked.url_no_git(), self.locked().url_no_git())
+        else {
+            return false;
+        };
+        match (locked.url_rev(), self.locked().url_rev()) {
+            (Some(rev), Some(target_rev)) => rev == target_rev,
+            _ => url == target_url,
+        }
+    }
+    /// The same branch of a Git repository matches however its URL is written, like
+    /// `git+ssh://git@example.com/repo.git` and `git+https://example.com/repo`, and however its
+    /// ref is, like `main` and `refs/heads/main`. Like the ref, it only counts within
+    /// `ref_match_age`.
+    #[must_use]
+    pub fn matches_git_url(&self, lockfile_node: &LockfileNode) -> bool {
+        fn branch(locked: &Locked) -> Option<&str> {
+            let ref_ = locked.git_ref()?;
+            Some(ref_.strip_prefix("refs/heads/").unwrap_or(ref_))
+        }
+        let locked = &lockfile_node.locked;
+        locked
+            .git_url()
+            .is_some_and(|url| Some(url) == self.locked().git_url())
+            && branch(locked) == branch(self.locked())
+    }
+    /// Same contents match even when fetched from different URLs, like tarball mirrors.
+    #[must_use]
+    pub fn matches_nar_hash(&self, lockfile_node: &LockfileNode) -> bool {
+        lockfile_node
+            .locked
+            .nar_hash()
+            .is_some_and(|hash| Some(hash) == self.locked().nar_hash())
+    }
+    /// A `path:` input whose own lock file can't be followed matches if it was modified no
+    /// earlier than the target.
+    #[must_use]
+    pub fn matches_path_last_modified(&self, lockfile_node: &LockfileNode) -> bool {
+        matches!(lockfile_node.locked, Locked::Path { .. })
+            && lockfile_node
+                .locked
+                .last_modified()
+                .zip(self.locked().last_modified())
+                .is_some_and(|(last_modified, target_last_modified)| {
+                    last_modified >= target_last_modified
+                })
+    }
+
+    /// Returns the directory and the input the `path:` input `lockfile_node` of the flake in
+    /// `directory` is compared by instead, the one locking the target's repository in the path's
+    /// own lock file. See [`lockfile::follow_path_input`].
+    #[must_use]
+    pub fn follow_path_input(
+        &self,
+        directory: &Path,
+        input_id: &str,
+        lockfile_node: &LockfileNode,
+    ) -> Option<(PathBuf, LockfileNode)> {
+        lockfile::follow_path_input(
+            directory,
+            lockfile_node,
+            input_id,
+            self.locked().repository_key().as_deref(),
+        )
+    }
+
+    /// Returns which checks of the locked input against the target passed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the locked `lastModified` is out of range.
+    pub fn checks(&self, lockfile_node: &LockfileNode, ref_match_age: Duration) -> Result<Checks> {
+        // Only the `narHash` of a commit target can match an input locked without a rev, like a
+        // tarball
+        if lockfile_node.locked.rev().is_none() && lockfile_node.locked.nar_hash().is_some() {
+            self.fetch_rev_metadata();
+        }
+        Ok(Checks {
+            ref_: self.matches_ref(lockfile_node),
+            git_url: self.matches_git_url(lockfile_node),
+            timestamp: lockfile_node
+                .locked
+                .last_modified()
+                .map(|ts| timestamp_matches(ts, ref_match_age))
+                .transpose()?
+                .is_some_and(|x| x.1),
+            rev: self.matches_rev(lockfile_node),
+            url: self.matches_url(lockfile_node),
+            nar_hash: self.matches_nar_hash(lockfile_node),
+            path_last_modified: self.matches_path_last_modified(lockfile_node),
+        })
+    }
+
+    /// Returns whether the locked input matches the target, so the flake is skipped.
+    ///
+    /// Only matching the ref is enough if the input was last modified less than `ref_match_age`
+    /// ago. See [`Checks::matches`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`MatchTarget::checks`].
+    pub fn is_up_to_date(
+        &self,
+        lockfile_node: &LockfileNode,
+        ref_match_age: Duration,
+    ) -> Result<bool> {
+        Ok(self.checks(lockfile_node, ref_match_age)?.matches())
+    }
+
+    /// Returns whether a flake with the locked input is skipped: if it matches the target, or if
+    /// `mismatches` are given and it doesn't differ from the target in any of those ways.
+    /// Non-flake inputs are always skipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`MatchTarget::checks`].
+    pub fn is_skipped(
+        &self,
+        lockfile_node: &LockfileNode,
+        ref_match_age: Duration,
+        mismatches: &[Mismatch],
+    ) -> Result<bool> {
+        if !lockfile_node.flake || self.is_up_to_date(lockfile_node, ref_match_age)? {
+            return Ok(true);
+        }
+        for mismatch in mismatches {
+            if mismatch.applies(self, lockfile_node, ref_match_age)? {
+                return Ok(false);
+            }
+        }
+        Ok(!mismatches.is_empty())
+    }
+
+    /// Returns the other lock nodes of the repository of the input `input_id` in `graph` that
+    /// aren't skipped by [`MatchTarget::is_skipped`], like the `nixpkgs` of home-manager.
+    ///
+    /// Only nodes following the same ref as the target are returned. Others, like the `nixpkgs`
+    /// of a home-manager release, can't be updated to it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a transitive node can't be decoded.
+    pub fn outdated_transitive_inputs(
+        &self,
+        graph: &LockGraph,
+        input_id: &str,
+        ref_match_age: Duration,
+        mismatches: &[Mismatch],
+    ) -> Result<Vec<(TransitiveInput, LockfileNode)>> {
+        let mut outdated = Vec::new();
+        for input in graph.transitive_duplicates(input_id) {
+            let node = graph.nodes[&input.node_id].decode()?;
+            if registry::resolved_ref(&node.original.inner)
+                != registry::resolved_ref(self.original())
+            {
+                continue;
+            }
+            if !self.is_skipped(&node, ref_match_age, mismatches)? {
+                outdated.push((input, node));
+            }
+        }
+        Ok(outdated)
+    }
+}
+
+/// How a locked input can differ from the target, to only count some differences as outdated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mismatch {
+    /// The original ref differs, like another branch
+    Ref,
+    /// The locked revision differs
+    Rev,
+    /// The locked tarball URL differs
+    Url,
+    /// The ref matches, but the input was last modified longer than `--ref-match-age` ago
+    Timestamp,
+}
+
+impl Mismatch {
+    /// Returns whether the locked input differs from the target this way.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`MatchTarget::checks`].
+    pub fn applies(
+        self,
+        target: &MatchTarget,
+        lockfile_node: &LockfileNode,
+        ref_match_age: Duration,
+    ) -> Result<bool> {
+        let checks = target.checks(lockfile_node, ref_match_age)?;
+        let locked = &lockfile_node.locked;
+        Ok(match self {
+            Self::Ref => !checks.ref_ && !checks.git_url,
+            Self::Rev => locked.rev().is_some() && !checks.rev,
+            Self::Url => locked.url_no_git().is_some() && !checks.url,
+            Self::Timestamp => (checks.ref_ || checks.git_url) && !checks.timestamp,
+        })
+    }
+}
+
+/// Which checks of a locked input against the target passed, from [`MatchTarget::checks`].
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "These are independent checks"
+)]
+#[derive(Clone, Copy, Debug)]
+pub struct Checks {
+    /// [`MatchTarget::matches_ref`]
+    pub ref_: bool,
+    /// [`MatchTarget::matches_git_url`]
+    pub git_url: bool,
+    /// Whether the input was last modified within `ref_match_age`, which only counts with the
+    /// ref or the Git URL.
+    pub timestamp: bool,
+    /// [`MatchTarget::matches_rev`]
+    pub rev: bool,
+    /// [`MatchTarget::matches_url`]
+    pub url: bool,
+    /// [`MatchTarget::matches_nar_hash`]
+    pub nar_hash: bool,
+    /// [`MatchTarget::matches_path_last_modified`]
+    pub path_last_modified: bool,
+}
+
+impl Checks {
+    /// Returns whether the input matches the target: by the ref or Git URL if it was also last
+    /// modified recently enough, or by any of the other checks.
+    #[must_use]
+    pub const fn matches(&self) -> bool {
+        ((self.ref_ || self.git_url) && self.timestamp)
+            || self.rev
+            || self.url
+            || self.nar_hash
+            || self.path_last_modified
+    }
+}
+
+/// Returns when the input was last modified and whether that's less than `ref_match_age` ago.
+///
+/// Complementary to [`MatchTarget::matches_ref`].
+///
+/// # Errors
+///
+/// Fails if `last_modified` is out of range.
+pub fn timestamp_matches(
+    last_modified: u64,
+    ref_match_age: Duration,
+) -> Result<(SystemTime, bool)> {
+    let last_modified = SystemTime::UNIX_EPOCH
+        .checked_add(Duration::from_secs(last_modified))
+        .ok_or_eyre("Invalid last_modified")?;
+    // Timestamps in the future count as just modified
+    let elapsed = last_modified.elapsed().unwrap_or_default();
+
+    Ok((last_modified, elapsed < ref_match_age))
+}
+
+/// Metadata of target flake references saved by previous runs, for when fetching it fails.
+pub struct MetadataCache {
+    pub dir: PathBuf,
+    /// Whether to use the saved metadata when fetching it fails. Otherwise it's only saved.
+    pub fall_back: bool,
+}
+
+impl MetadataCache {
+    /// Returns the file for `flake_ref`, or `None` for paths which depend on the working
+    /// directory and don't need the network anyway.
+    fn path(&self, flake_ref: &str) -> Option<PathBuf> {
+        if flake_ref.starts_with(['.', '/']) || flake_ref.starts_with("path:") {
+            return None;
+        }
+        let name: String = flake_ref
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '-' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Some(self.dir.join(format!("{name}.json")))
+    }
+
+    fn save(&self, flake_ref: &str, metadata: &[u8]) -> Result<()> {
+        if let Some(path) = self.path(flake_ref) {
+            fs::create_dir_all(&self.dir)?;
+            fs::write(path, metadata)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the saved metadata if falling back is enabled and it exists.
+    fn load(&self, flake_ref: &str) -> Result<Option<Vec<u8>>> {
+        let Some(path) = self.path(flake_ref).filter(|_| self.fall_back) else {
+            return Ok(None);
+        };
+        if !path.exists() {
+            return Ok(None);
+        }
+        let age = fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        tracing::warn!(
+            "Using the metadata of {flake_ref} saved {} ago",
+            humantime::format_duration(Duration::from_secs(age.as_secs()))
+        );
+        Ok(Some(fs::read(path)?))
+    }
+}
+
+/// Fetches the metadata of the target flake reference.
+///
+/// Use a hash symbol to target an input of a flake, like `./my-nixos-config#nixpkgs`.
+///
+/// With a `cache`, the fetched metadata is saved there, and the saved metadata is used if
+/// fetching fails and [`MetadataCache::fall_back`] is set.
+///
+/// # Errors
+///
+/// Fails if the target can't be parsed or its metadata can't be fetched, or if the input isn't
+/// in the target flake's lock file.
+pub fn resolve_target(target: &str, cache: Option<&MetadataCache>) -> Result<MatchTarget> {
+    let Some((flake_ref, input_id)) = target.rsplit_once('#') else {
+        return Ok(MatchTarget::FlakeMetadata(
+            get_flake_ref_metadata(target, cache)
+                .wrap_err("Failed to get metadata of flake reference")?,
+        ));
+    };
+    let metadata = get_flake_ref_metadata(flake_ref, cache)
+        .wrap_err("Failed to get metadata of flake reference")?;
+    let input = metadata
+        .locks
+        .extract_input(input_id)
+        .wrap_err("Failed to extract input of flake reference")?;
+    Ok(MatchTarget::FlakeInput {
+        flake_ref_url: get_flake_ref_url(&input)
+            .wrap_err("Failed to convert flake reference to URL-like format")?,
+        input,
+    })
+}
+
+/// Targets the input `input_id` locked in the lockfile at `path`, or in the `flake.lock` of the
+/// directory `path`, without fetching anything.
+///
+/// # Errors
+///
+/// Fails if the lock file can't be read or has no input `input_id`.
+pub fn target_from_lockfile(path: &Path, input_id: &str) -> Result<MatchTarget> {
+    let path = if path.is_dir() {
+        path.join("flake.lock")
+    } else {
+        path.to_owned()
+    };
+    let input = lockfile::load_lockfile_input(&path, input_id)
+        .wrap_err_with(|| format!("Failed to read the target from {}", path.display()))?;
+    Ok(MatchTarget::FlakeInput {
+        flake_ref_url: get_flake_ref_url(&input)
+            .wrap_err("Failed to convert flake reference to URL-like format")?,
+        input,
+    })
+}
+
+/// Returns whether `target` is a full commit hash rather than a flake reference.
+#[must_use]
+pub fn is_rev(target: &str) -> bool {
+    target.len() == 40 && target.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Targets the commit `rev` of the repository `repo`, like `github:NixOS/nixpkgs`, without
+/// fetching anything yet.
+///
+/// Flakes are matched by their locked rev. The target's metadata is only fetched once an input
+/// locked without a rev, like a tarball, is compared to it, so it can match by `narHash`.
+///
+/// # Errors
+///
+/// Fails if `repo` isn't a valid flake reference.
+pub fn target_rev(repo: &str, rev: &str) -> Result<MatchTarget> {
+    let mut attrs = flake_ref::parse(repo).wrap_err("Failed to parse the target repository")?;
+    attrs.remove("ref");
+    attrs.insert("rev".to_owned(), rev.into());
+    let input = serde_json::from_value(serde_json::json!({
+        "locked": attrs,
+        "original": attrs,
+    }))
+    .wrap_err("Failed to pin the target repository to the rev")?;
+    Ok(MatchTarget::Rev {
+        flake_ref_url: flake_ref::to_string(&attrs)?,
+        input,
+        metadata: OnceLock::new(),
+    })
+}
+
+fn get_flake_ref_metadata(
+    flake_ref: &str,
+    cache: Option<&MetadataCache>,
+) -> Result<NixFlakeMetadata> {
+    let _span = tracing::debug_span!("flake_metadata", flake_ref).entered();
+    let fetched = fetch_flake_ref_metadata(flake_ref);
+    let json = match (fetched, cache) {
+        (Ok(json), Some(cache)) => {
+            if let Err(err) = cache.save(flake_ref, &json) {
+                tracing::warn!("Failed to save the metadata of {flake_ref}: {err:?}");
+            }
+            json
+        }
+        (Ok(json), None) => json,
+        (Err(err), Some(cache)) => match cache.load(flake_ref) {
+            Ok(Some(json)) => json,
+            Ok(None) => return Err(err),
+            Err(cache_err) => {
+                return Err(
+                    err.wrap_err(format!("Also failed to load saved metadata: {cache_err}"))
+                );
+            }
+        },
+        (Err(err), None) => return Err(err),
+    };
+
+    serde_json::from_slice(&json).wrap_err("Failed to parse output")
+}
+
+/// Runs `nix flake metadata` and returns its JSON output.
+fn fetch_flake_ref_metadata(flake_ref: &str) -> Result<Vec<u8>> {
+    #[cfg(feature = "nix-c-api")]
+    match crate::nix_c::flake_metadata(flake_ref) {
+        Ok(json) => return Ok(json),
+        Err(err) => tracing::debug!("Falling back to `nix flake metadata`: {err:?}"),
+    }
+
+    let args = ["flake", "metadata", "--json", "--", flake_ref];
+    let output = command::output_with_retries("nix", &args, None)?;
+    if output.status.success() {
+        // Warnings, like about a dirty Git tree
+        io::stderr().write_all(&output.stderr)?;
+        return Ok(output.stdout);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut report = eyre!("`nix {}` failed with {}", args.join(" "), output.status)
+        .with_section(|| stderr.trim().to_owned().header("Nix output:"));
+    if let Some(hint) = metadata_failure_hint(&stderr) {
+        report = report.suggestion(hint);
+    }
+    Err(report)
+}
+
+/// Guesses the cause of `nix flake metadata` failing from its standard error.
+fn metadata_failure_hint(stderr: &str) -> Option<&'static str> {
+    if stderr.contains("experimental Nix feature") {
+        Some("Enable flakes by adding `experimental-features = nix-command flakes` to nix.conf")
+    } else if command::is_transient(stderr) {
+        Some("Check the network connection, or use the metadata saved by a previous run")
+    } else if [
+        "does not exist",
+        "cannot find flake",
+        "HTTP error 404",
+        "unable to find",
+    ]
+    .iter()
+    .any(|message| stderr.contains(message))
+    {
+        Some("Check the spelling of the flake reference and that its branch or tag exists")
+    } else {
+        None
+    }
+}
+
+fn get_flake_ref_url(input: &LockfileNode) -> Result<String> {
+    let attrs = serde_json::to_value(&input.original)?;
+    if let Some(url) = attrs
+        .as_object()
+        .and_then(|attrs| flake_ref::to_string(attrs).ok())
+    {
+        return Ok(url);
+    }
+
+    // Fall back to Nix for flake reference types not supported natively
+    let json = serde_json::to_string(&input.original)?;
+    #[cfg(feature = "nix-c-api")]
+    match crate::nix_c::flake_ref_to_string(&json) {
+        Ok(url) => return Ok(url),
+        Err(err) => tracing::debug!("Falling back to `nix-instantiate`: {err:?}"),
+    }
+    let output = {
+        // `--argstr` doesn't work at all with `nix eval`
+        Command::new("nix-instantiate")
+            .args([
+                "--eval",
+                "--expr",
+                "{ json }: builtins.flakeRefToString (builtins.fromJSON json)",
+                "--raw",
+                "--argstr",
+                "json",
+                &json,
+            ])
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()?
+    };
+
+    if !output.status.success() {
+        bail!("Command failed with {}", output.status);
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tarball_node(url: &str) -> LockfileNode {
+        serde_json::from_value(serde_json::json!({
+            "locked": { "type": "tarball", "url": url },
+            "original": { "type": "tarball", "url": url },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tarball_revs_match() {
+        let rev = "6e987485eb2c77e5dcc5af4e3c70843711ef9251";
+        let target = MatchTarget::FlakeInput {
+            input: tarball_node(&format!(
+                "https://github.com/NixOS/nixpkgs/archive/{rev}.tar.gz"
+            )),
+            flake_ref_url: String::new(),
+        };
+        let flakehub = tarball_node(&format!(
+            "https://api.flakehub.com/f/pinned/NixOS/nixpkgs/0.1.826543+rev-{rev}/0198/source.tar.gz"
+        ));
+        assert!(target.matches_url(&flakehub));
+        assert!(target.matches_rev(&flakehub));
+        let other = tarball_node(
+            "https://github.com/NixOS/nixpkgs/archive/1f08a4df998e21f4e8be8fb6fbf61d11a1a5076a.tar.gz",
+        );
+        assert!(!target.matches_url(&other));
+        assert!(!target.matches_rev(&other));
+    }
+
+    #[test]
+    fn tarball_nar_hashes_match() {
+        let node = |url: &str, nar_hash: &str| -> LockfileNode {
+            serde_json::from_value(serde_json::json!({
+                "locked": { "type": "tarball", "url": url, "narHash": nar_hash },
+                "original": { "type": "tarball", "url": url },
+            }))
+            .unwrap()
+        };
+        let nar_hash = "sha256-RKwfXA4OZROjBTQAl9WOZQFm7L8Bo93FQwSJpAiSRvo=";
+        let target = MatchTarget::FlakeInput {
+            input: node("https://example.com/nixexprs.tar.xz", nar_hash),
+            flake_ref_url: String::new(),
+        };
+        let mirror = node("https://mirror.example.org/nixpkgs.tar.xz", nar_hash);
+        assert!(!target.matches_url(&mirror));
+        assert!(target.matches_nar_hash(&mirror));
+        assert!(target.is_up_to_date(&mirror, Duration::ZERO).unwrap());
+        let other = node(
+            "https://mirror.example.org/nixpkgs.tar.xz",
+            "sha256-JHQbm+OcGp32wAsXTE/FLYGNpb+4GLi5oTvCxwSoBOA=",
+        );
+        assert!(!target.matches_nar_hash(&other));
+        assert!(!target.is_up_to_date(&other, Duration::ZERO).unwrap());
+    }
+
+    #[test]
+    fn path_inputs() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/path-input");
+        let target = target_from_lockfile(&directory.join("common/flake.lock"), "pkgs").unwrap();
+        let node = lockfile::load_lockfile_input(&directory.join("flake.lock"), "nixpkgs").unwrap();
+        let (_, followed) = target
+            .follow_path_input(&directory, "nixpkgs", &node)
+            .unwrap();
+        assert!(target.is_up_to_date(&followed, Duration::ZERO).unwrap());
+
+        // Without its own lock file, the path is compared by when it was modified
+        let path_node = |last_modified: u64| -> LockfileNode {
+            serde_json::from_value(serde_json::json!({
+                "locked": { "type": "path", "path": "/tmp/nixpkgs", "lastModified": last_modified },
+                "original": { "type": "path", "path": "/tmp/nixpkgs" },
+            }))
+            .unwrap()
+        };
+        let last_modified = target.locked().last_modified().unwrap();
+        assert!(target.matches_path_last_modified(&path_node(last_modified + 1)));
+        assert!(!target.matches_path_last_modified(&path_node(last_modified - 1)));
+    }
+
+    #[test]
+    fn bare_rev_target() {
+        let rev = "1f08a4df998e21f4e8be8fb6fbf61d11a1a5076a";
+        assert!(is_rev(rev));
+        assert!(!is_rev("nixos-unstable"));
+        let target = target_rev("github:NixOS/nixpkgs/nixos-unstable", rev).unwrap();
+        assert_eq!(target.locked().rev(), Some(rev));
+        assert_eq!(target.original().ref_(), None);
+        assert_eq!(
+            target.flake_ref_url(),
+            format!("github:NixOS/nixpkgs/{rev}")
+        );
+        let target = target_rev("git+https://example.com/repo", rev).unwrap();
+        assert_eq!(target.locked().rev(), Some(rev));
+    }
+
+    #[test]
+    fn transitive_inputs_of_the_target_ref() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lockfiles/duplicates.lock");
+        let graph = lockfile::load_lockfile_graph(&path).unwrap();
+        let unstable = target_from_lockfile(&path, "nixpkgs").unwrap();
+        let outdated = unstable
+            .outdated_transitive_inputs(&graph, "nixpkgs", Duration::ZERO, &[])
+            .unwrap();
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].0.input_path(), "home-manager/nixpkgs");
+
+        let release = MatchTarget::FlakeInput {
+            input: serde_json::from_value(serde_json::json!({
+                "locked": {
+                    "type": "github",
+                    "owner": "NixOS",
+                    "repo": "nixpkgs",
+                    "rev": "1f08a4df998e21f4e8be8fb6fbf61d11a1a5076a",
+                },
+                "original": {
+                    "type": "github",
+                    "owner": "NixOS",
+                    "repo": "nixpkgs",
+                    "ref": "nixos-25.05",
+                },
+            }))
+            .unwrap(),
+            flake_ref_url: String::new(),
+        };
+        assert!(
+            release
+                .outdated_transitive_inputs(&graph, "nixpkgs", Duration::ZERO, &[])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn git_refs_match_with_or_without_prefix() {
+        let git_node = |url: &str, ref_: &str| -> LockfileNode {
+            serde_json::from_value(serde_json::json!({
+                "locked": {
+                    "type": "git",
+                    "url": url,
+                    "ref": ref_,
+                    "rev": "6e987485eb2c77e5dcc5af4e3c70843711ef9251",
+                },
+                "original": { "type": "git", "url": url },
+            }))
+            .unwrap()
+        };
+        let target = MatchTarget::FlakeInput {
+            input: git_node("https://example.com/repo.git", "refs/heads/main"),
+            flake_ref_url: String::new(),
+        };
+        assert!(target.matches_git_url(&git_node("ssh://git@example.com/repo", "main")));
+        assert!(target.matches_git_url(&git_node("https://example.com/repo", "refs/heads/main")));
+        assert!(!target.matches_git_url(&git_node("https://example.com/repo", "develop")));
+        assert!(!target.matches_git_url(&git_node("https://example.com/other", "main")));
+    }
+}
@@ -0,0 +1,139 @@
+//! Semantic output styles.
+//!
+//! Call sites describe what a piece of text is (a warning, a command, ...) and the active
+//! [`Theme`] decides what it looks like.
+
+use std::{io::IsTerminal, sync::OnceLock};
+
+use owo_colors::{Style, Styled, colors::xterm};
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Color theme for terminal output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Theme {
+    /// Colors suited for dark terminal backgrounds.
+    Dark,
+    /// Colors suited for light terminal backgrounds.
+    Light,
+    /// No colors or text attributes.
+    None,
+}
+
+impl Theme {
+    /// Picks [`Theme::Dark`] for terminals and [`Theme::None`] otherwise or when `NO_COLOR` is
+    /// set.
+    pub fn detect() -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        if !no_color && std::io::stdout().is_terminal() && std::io::stderr().is_terminal() {
+            Self::Dark
+        } else {
+            Self::None
+        }
+    }
+
+    fn style(self, role: Role) -> Style {
+        let style = Style::new();
+        match self {
+            Self::Dark => match role {
+                Role::Muted => style.fg::<xterm::Gray>(),
+                Role::Good => style.green(),
+                Role::Bad => style.red(),
+                Role::Warning => style.yellow(),
+                Role::Notice => style.yellow().bold(),
+                Role::Prompt => style.blue(),
+                Role::Command | Role::Value => style.cyan(),
+                Role::Highlight => style.cyan().bold(),
+                Role::Heading => style.bold(),
+            },
+            Self::Light => match role {
+                Role::Muted => style.fg::<xterm::DarkGray>(),
+                Role::Good => style.green(),
+                Role::Bad => style.red(),
+                Role::Warning => style.magenta(),
+                Role::Notice => style.magenta().bold(),
+                Role::Prompt | Role::Command | Role::Value => style.blue(),
+                Role::Highlight => style.blue().bold(),
+                Role::Heading => style.bold(),
+            },
+            Self::None => style,
+        }
+    }
+}
+
+/// Sets the theme used for the rest of the process.
+pub fn init(theme: Theme) {
+    // Only the first call matters
+    let _ = THEME.set(theme);
+}
+
+fn theme() -> Theme {
+    THEME.get().copied().unwrap_or(Theme::Dark)
+}
+
+/// Returns whether output is styled at all.
+pub fn is_styled() -> bool {
+    theme() != Theme::None
+}
+
+/// What a piece of text means to the user.
+#[derive(Clone, Copy, Debug)]
+pub enum Role {
+    /// Paths, labels and other secondary text.
+    Muted,
+    /// Something matching the target or succeeding.
+    Good,
+    /// Something mismatching the target or failing.
+    Bad,
+    Warning,
+    /// A warning that must not be missed.
+    Notice,
+    /// Questions waiting for input.
+    Prompt,
+    /// Prompt commands and command-line flags.
+    Command,
+    /// Timestamps, IDs and other data.
+    Value,
+    /// Data the user is asked to confirm.
+    Highlight,
+    Heading,
+}
+
+/// Styles any displayable value according to the active [`Theme`].
+pub trait Themed {
+    fn styled(&self, role: Role) -> Styled<&Self> {
+        theme().style(role).style(self)
+    }
+    fn muted(&self) -> Styled<&Self> {
+        self.styled(Role::Muted)
+    }
+    fn good(&self) -> Styled<&Self> {
+        self.styled(Role::Good)
+    }
+    fn bad(&self) -> Styled<&Self> {
+        self.styled(Role::Bad)
+    }
+    fn warning(&self) -> Styled<&Self> {
+        self.styled(Role::Warning)
+    }
+    fn notice(&self) -> Styled<&Self> {
+        self.styled(Role::Notice)
+    }
+    fn prompt(&self) -> Styled<&Self> {
+        self.styled(Role::Prompt)
+    }
+    fn command(&self) -> Styled<&Self> {
+        self.styled(Role::Command)
+    }
+    fn value(&self) -> Styled<&Self> {
+        self.styled(Role::Value)
+    }
+    fn highlight(&self) -> Styled<&Self> {
+        self.styled(Role::Highlight)
+    }
+    fn heading(&self) -> Styled<&Self> {
+        self.styled(Role::Heading)
+    }
+}
+
+impl<T: std::fmt::Display + ?Sized> Themed for T {}
@@ -1,9 +1,11 @@
 use std::{
-    io::{Write, stderr, stdin},
+    borrow::Cow,
+    collections::HashMap,
     ops::ControlFlow,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
     str::FromStr,
+    time::{Duration, SystemTime},
 };
 
 use color_eyre::{
@@ -11,107 +13,144 @@ use color_eyre::{
     eyre::{Context, OptionExt, bail},
 };
 use fs_err as fs;
-use owo_colors::{OwoColorize, colors::xterm};
+use indicatif::HumanBytes;
+use nix::unistd::{AccessFlags, access};
 
-use crate::{
-    Flake, UpdateArgs, flake_nix::print_diff, flake_nix::replace_flake_input_url,
-    lockfile::load_lockfile_input, print_flake_info,
+use nixpkgsupd::{
+    channels,
+    command::{cmd_output, run_cmd},
+    discovery::{self, Flake, ProjectKind},
+    envrc::{self, DirenvSetup, NixLoader},
+    flake_nix::DynamicInputUrl,
+    lockfile::{Locked, LockfileNode, load_lockfile_input, parse_lockfile_input},
+    registry, sigint_guard, store,
+    target::MatchTarget,
 };
 
-/// Runs the given command and returns whether it was successful.
-pub fn run_cmd(program: &str, args: &[&str], dir: &Path) -> Result<bool> {
-    let _guard = crate::sigint_guard::SigintGuard::new();
-
-    Ok(Command::new(program)
-        .args(args)
-        .current_dir(dir)
-        .status()?
-        .success())
-}
+use crate::{
+    UpdateArgs, editor, elevate, end_of_life_release,
+    hooks::{HookRevs, run_hook},
+    is_up_to_date, outdated_transitive_inputs, print_flake_info, print_missing_manifest,
+    prompt::{read_command, read_line},
+    state::State,
+    ui::Themed,
+    vcs::{self, GitCheckout, Vcs, git_checkout, in_git_repo},
+};
 
 pub fn update_flake(
     flake: &Flake,
     cli: &crate::Cli,
-    target: &crate::MatchTarget,
+    target: &MatchTarget,
     flake_index: usize,
     flakes_count: usize,
     update_args: &UpdateArgs,
+    session: &mut Session,
 ) -> Result<()> {
-    let flake_nix = flake.directory.join("flake.nix");
+    let ControlFlow::Continue(elevate) = check_writable(flake, update_args)? else {
+        return Ok(());
+    };
+
+    let flake_nix = flake.directory.join(flake.project.manifest());
     if !flake_nix.exists() {
-        bail!("flake.nix does not exist")
+        bail!("{} does not exist", flake.project.manifest())
     }
 
-    let target_flake_ref = target.flake_ref_url();
+    run_pre_hook(flake, update_args)?;
 
     loop {
         println!();
-        let lockfile_node = load_lockfile_input(&flake.lockfile_path, cli)?;
-        let lock_matches_target = print_flake_info(flake, cli, target, &lockfile_node)?;
+        let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+        let lock_matches_target = print_flake_info(
+            flake,
+            cli,
+            target,
+            &lockfile_node,
+            &outdated_transitive_inputs(flake, cli, target)?,
+            session.state.last_updated(&flake.directory),
+        )?;
 
         let current_flake_nix = fs::read_to_string(&flake_nix)?;
 
-        let new_flake_nix =
-            replace_flake_input_url(target_flake_ref, &current_flake_nix, flake.id)?;
+        let (new_flake_nix, dynamic_url) =
+            propose_flake_nix(flake, target.flake_ref_url(), &current_flake_nix)?;
 
-        print_diff(&current_flake_nix, &new_flake_nix, update_args);
-
-        let escaped_flake_id = regex::escape(flake.id);
-        let regex = regex::Regex::new(&format!(
-            r"#[ \t\n\r]*(inputs\.)?{escaped_flake_id}(\.url)?[ \t\n\r]*="
-        ))?;
-        if regex.is_match(&current_flake_nix) {
-            eprintln!(
-                "{} {} {}",
-                "Found a comment defining the input. Use".yellow(),
-                PromptCommand::LaunchEditor.cyan(),
-                "to remove it before applying the diff.".yellow()
-            );
-        }
+        print_diff(&current_flake_nix, &new_flake_nix, update_args.diff_context);
 
         let changes_exist = new_flake_nix != current_flake_nix;
+        let end_of_life = end_of_life_release(&lockfile_node)?.is_some();
 
-        if !changes_exist && !lock_matches_target {
-            eprintln!("{} {} {} {} {}", "The `flake.nix` is up to date but the locked version doesn't match the target. Try".yellow(), PromptCommand::Lock.cyan(), "or".yellow(), PromptCommand::RefreshDirenv.cyan(), "to update the lockfile".yellow());
+        if session.batch && matches!(lockfile_node.locked, Locked::Path { .. }) {
+            eprintln!("{}", "Skipping the path input in batch mode".warning());
+            break;
         }
+        if let Some(dynamic_url) = dynamic_url {
+            print_dynamic_url_hint(dynamic_url);
+            if session.batch {
+                eprintln!("{}", "Skipping the flake in batch mode".warning());
+                break;
+            }
+        } else {
+            print_hints(
+                flake,
+                &current_flake_nix,
+                changes_exist,
+                lock_matches_target,
+                end_of_life,
+            )?;
+        }
+
+        let ctx = PromptContext {
+            cli,
+            update_args,
+            flake,
+            target,
+            lockfile_node: &lockfile_node,
+            flake_nix: &flake_nix,
+            new_flake_nix: &new_flake_nix,
+            elevate,
+        };
 
-        if lock_matches_target {
-            eprintln!("{} {} {} {} {}", "The locked version matches the target but the gcroots may not be up to date. You can try".yellow(), PromptCommand::DeleteGcroots.cyan(), "or".yellow(), PromptCommand::RefreshDirenv.cyan(), "to clean up the gcroots.".yellow());
+        if session.batch {
+            apply_all(&ctx, session, changes_exist, lock_matches_target)?;
+            break;
         }
 
-        eprint!(
-            "{}",
-            format_args!(
-                "({}/{}) [{}{},{},{},{},{},{},{},{}?] ",
-                flake_index + 1,
-                flakes_count,
-                changes_exist.then_some("a,").unwrap_or_default(),
-                PromptCommand::NextFlake,
-                PromptCommand::LaunchEditor,
-                PromptCommand::LaunchShell,
-                PromptCommand::RunNixFlakeUpdate,
-                PromptCommand::DeleteGcroots,
-                PromptCommand::Lock,
-                PromptCommand::RefreshDirenv,
-                flake.in_git_repo().then_some("commit,").unwrap_or_default(),
-            )
-            .blue()
-        );
+        let default_cmd = default_command(update_args.default_action, changes_exist);
 
-        let cmd_string = read_line()?;
-        let cmd_string = cmd_string.trim();
+        let available = available_commands(flake, &lockfile_node, changes_exist, end_of_life);
+        let line = read_prompt_line(
+            &available,
+            default_cmd,
+            update_args.menu,
+            flake_index,
+            flakes_count,
+        )?;
+        let line = line.trim();
+        let (cmd_string, arg) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(cmd, arg)| (cmd, arg.trim()));
 
-        let cmd = PromptCommand::from_str(cmd_string).unwrap_or_else(|_| {
+        let cmd = match default_cmd {
+            Some(default_cmd) if cmd_string.is_empty() => Some(default_cmd),
+            _ => PromptCommand::from_str(cmd_string).ok().or_else(|| {
+                // Menu numbers are 1-based indices into the available commands
+                cmd_string
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| available.get(n.checked_sub(1)?).copied())
+            }),
+        };
+        let cmd = cmd.unwrap_or_else(|| {
             if !cmd_string.is_empty() {
                 eprintln!(
                     "{}",
-                    format_args!("Unknown command: {}", cmd_string.red()).red()
+                    format_args!("Unknown command: {}", cmd_string.bad()).bad()
                 );
             }
             PromptCommand::PrintHelp
         });
 
-        let flow = execute_prompt_cmd(update_args, flake, &flake_nix, &new_flake_nix, cmd)?;
+        let flow = execute_prompt_cmd(&ctx, session, cmd, arg)?;
 
         match flow {
             ControlFlow::Break(()) => break,
@@ -122,58 +161,610 @@ pub fn update_flake(
     Ok(())
 }
 
-#[expect(clippy::too_many_lines, reason = "Really can't shorten this any more")]
-fn execute_prompt_cmd(
+/// Applies `new_manifest`, if it changes, and locks if `lock` is set without prompting, for
+/// `--apply-plan`.
+pub fn apply_planned(
+    flake: &Flake,
+    cli: &crate::Cli,
+    target: &MatchTarget,
     update_args: &UpdateArgs,
+    session: &mut Session,
+    new_manifest: Option<&str>,
+    lock: bool,
+) -> Result<()> {
+    let ControlFlow::Continue(elevate) = check_writable(flake, update_args)? else {
+        return Ok(());
+    };
+    run_pre_hook(flake, update_args)?;
+
+    println!();
+    let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+    print_flake_info(
+        flake,
+        cli,
+        target,
+        &lockfile_node,
+        &outdated_transitive_inputs(flake, cli, target)?,
+        session.state.last_updated(&flake.directory),
+    )?;
+    let flake_nix = flake.directory.join(flake.project.manifest());
+    let current_flake_nix = fs::read_to_string(&flake_nix)?;
+    let new_flake_nix = new_manifest.unwrap_or(&current_flake_nix);
+    print_diff(&current_flake_nix, new_flake_nix, update_args.diff_context);
+
+    let ctx = PromptContext {
+        cli,
+        update_args,
+        flake,
+        target,
+        lockfile_node: &lockfile_node,
+        flake_nix: &flake_nix,
+        new_flake_nix,
+        elevate,
+    };
+    apply_all(&ctx, session, new_manifest.is_some(), !lock)
+}
+
+/// Returns the manifest with the input's URL replaced by the target, or the current manifest and
+/// where the URL is if it's computed and has to be edited manually.
+fn propose_flake_nix(
     flake: &Flake,
-    flake_nix: &PathBuf,
-    new_flake_nix: &str,
+    target_flake_ref: &str,
+    current_flake_nix: &str,
+) -> Result<(String, Option<DynamicInputUrl>)> {
+    match flake
+        .project
+        .replace_input_url(target_flake_ref, current_flake_nix, flake.id)
+    {
+        Ok(new_flake_nix) => Ok((new_flake_nix, None)),
+        Err(err) => {
+            let dynamic_url = DynamicInputUrl::find(&err).ok_or(err)?;
+            Ok((current_flake_nix.to_owned(), Some(dynamic_url)))
+        }
+    }
+}
+
+/// Suggests editing a computed input URL manually.
+fn print_dynamic_url_hint(dynamic_url: DynamicInputUrl) {
+    eprintln!(
+        "{} {} {}",
+        format_args!("{dynamic_url}. Use").warning(),
+        PromptCommand::LaunchEditor.command(),
+        "to change it to the target.".warning()
+    );
+}
+
+/// Returns the prompt commands that apply to the flake.
+fn available_commands(
+    flake: &Flake,
+    lockfile_node: &LockfileNode,
+    changes_exist: bool,
+    end_of_life: bool,
+) -> Vec<PromptCommand> {
+    PromptCommand::ALL
+        .iter()
+        .copied()
+        .filter(|cmd| match cmd {
+            PromptCommand::ApplyDiff => changes_exist,
+            PromptCommand::Commit => Vcs::detect(&flake.directory).is_some(),
+            PromptCommand::PullRequest => in_git_repo(&flake.directory),
+            PromptCommand::Rebuild => flake.has_build_result && flake.project == ProjectKind::Flake,
+            PromptCommand::Switch => flake.system.is_some(),
+            PromptCommand::HomeManagerSwitch => flake.home_manager,
+            PromptCommand::Deepen => lockfile_node.locked.is_shallow(),
+            PromptCommand::Retarget => end_of_life,
+            PromptCommand::Transitive => flake.project == ProjectKind::Flake,
+            _ => true,
+        })
+        .collect()
+}
+
+/// Runs `--pre-hook` if given, failing if the hook fails.
+fn run_pre_hook(flake: &Flake, update_args: &UpdateArgs) -> Result<()> {
+    let Some(pre_hook) = &update_args.pre_hook else {
+        return Ok(());
+    };
+    if !update_args.allow_write {
+        eprintln!("{}", "Dry run, not running the pre-hook".warning());
+        return Ok(());
+    }
+    let lockfile_node = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+    let revs = HookRevs {
+        old: lockfile_node.locked.rev(),
+        new: None,
+    };
+    if !run_hook("pre-hook", pre_hook, flake, &revs)? {
+        bail!("Skipping the flake because the pre-hook failed");
+    }
+    Ok(())
+}
+
+/// Prints warnings about the state of the flake and suggests prompt commands.
+fn print_hints(
+    flake: &Flake,
+    current_flake_nix: &str,
+    changes_exist: bool,
+    lock_matches_target: bool,
+    end_of_life: bool,
+) -> Result<()> {
+    let escaped_flake_id = regex::escape(flake.id);
+    let regex = regex::Regex::new(&format!(
+        r"#[ \t\n\r]*(inputs\.)?{escaped_flake_id}(\.url)?[ \t\n\r]*="
+    ))?;
+    if regex.is_match(current_flake_nix) {
+        eprintln!(
+            "{} {} {}",
+            "Found a comment defining the input. Use".warning(),
+            PromptCommand::LaunchEditor.command(),
+            "to remove it before applying the diff.".warning()
+        );
+    }
+
+    if !changes_exist && !lock_matches_target {
+        eprintln!(
+            "{} {} {} {} {}",
+            "The `flake.nix` is up to date but the locked version doesn't match the target. Try"
+                .warning(),
+            PromptCommand::Lock.command(),
+            "or".warning(),
+            PromptCommand::RefreshDirenv.command(),
+            "to update the lockfile".warning()
+        );
+    }
+
+    if lock_matches_target {
+        eprintln!("{} {} {} {} {}", "The locked version matches the target but the gcroots may not be up to date. You can try".warning(), PromptCommand::DeleteGcroots.command(), "or".warning(), PromptCommand::RefreshDirenv.command(), "to clean up the gcroots.".warning());
+    }
+
+    if end_of_life {
+        eprintln!(
+            "{} {} {}",
+            "The input follows a NixOS release past its end of life. Use".warning(),
+            PromptCommand::Retarget.command(),
+            "to move it to the newest release branch.".warning()
+        );
+    }
+
+    if flake.has_direnv_gc_roots
+        && envrc::provenance(flake.envrc_directory(), &flake.lockfile_path, flake.id).diverges()
+    {
+        eprintln!(
+            "{} {} {}",
+            "The direnv environment doesn't reflect flake.lock, so locking alone won't change it. Check `.envrc` for `--override-input` or use".warning(),
+            PromptCommand::RefreshDirenv.command(),
+            "to rebuild the cache.".warning()
+        );
+    }
+
+    Ok(())
+}
+
+/// Applies the diff and locks without prompting.
+fn apply_all(
+    ctx: &PromptContext,
+    session: &mut Session,
+    changes_exist: bool,
+    lock_matches_target: bool,
+) -> Result<()> {
+    // The worktree flow also locks and commits
+    let applying_in_worktree =
+        changes_exist && ctx.update_args.worktree && in_git_repo(&ctx.flake.directory);
+
+    // Failures are reported by the commands themselves
+    if changes_exist {
+        let _ = execute_prompt_cmd(ctx, session, PromptCommand::ApplyDiff, "")?;
+    }
+    if !applying_in_worktree && (changes_exist || !lock_matches_target) {
+        let _ = execute_prompt_cmd(ctx, session, PromptCommand::Lock, "")?;
+    } else if ctx.cli.transitive && !applying_in_worktree {
+        let _ = execute_prompt_cmd(ctx, session, PromptCommand::Transitive, "")?;
+    }
+    Ok(())
+}
+
+/// Returns the command run when the prompt is answered with an empty line.
+const fn default_command(action: DefaultAction, changes_exist: bool) -> Option<PromptCommand> {
+    match action {
+        DefaultAction::Auto if changes_exist => Some(PromptCommand::ApplyDiff),
+        DefaultAction::Auto => Some(PromptCommand::NextFlake),
+        DefaultAction::None => None,
+        DefaultAction::Command(cmd) => Some(cmd),
+    }
+}
+
+/// Updates the outdated other nodes of the input's repository with
+/// `nix flake update <input path>` and returns whether any were.
+fn update_transitive(ctx: &PromptContext) -> Result<bool> {
+    let outdated = outdated_transitive_inputs(ctx.flake, ctx.cli, ctx.target)?;
+    if outdated.is_empty() {
+        return Ok(false);
+    }
+    let input_paths: Vec<_> = outdated
+        .iter()
+        .map(|(input, _)| input.input_path())
+        .collect();
+    let mut args = vec!["flake", "update"];
+    args.extend(input_paths.iter().map(String::as_str));
+    if !elevate::run(
+        ctx.elevate,
+        "Updating transitive inputs",
+        ("nix", args),
+        &ctx.flake.directory,
+    )? {
+        eprintln!(
+            "{}",
+            format_args!("Failed to update {}.", input_paths.join(", ")).bad()
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Reads a line from the prompt listing the `available` commands in the given `menu` style.
+fn read_prompt_line(
+    available: &[PromptCommand],
+    default_cmd: Option<PromptCommand>,
+    menu: MenuStyle,
+    flake_index: usize,
+    flakes_count: usize,
+) -> Result<String> {
+    let position = format!("({}/{})", flake_index + 1, flakes_count);
+    match menu {
+        MenuStyle::Mnemonic => read_command(
+            format_args!(
+                "{position} [{}] ",
+                available
+                    .iter()
+                    .map(|cmd| cmd.prompt_label(default_cmd == Some(*cmd)))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .prompt(),
+        ),
+        MenuStyle::Numbers => {
+            for (i, cmd) in available.iter().enumerate() {
+                let marker = if default_cmd == Some(*cmd) { "*" } else { " " };
+                eprintln!(
+                    "{:>3}{marker} {:<8} {}",
+                    i + 1,
+                    cmd.command(),
+                    cmd.description().muted()
+                );
+            }
+            read_command(format_args!("{position} [1-{}] ", available.len()).prompt())
+        }
+    }
+}
+
+/// How the prompt presents the available commands.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum MenuStyle {
+    /// A single line of mnemonic commands like `a,n,dg`.
+    Mnemonic,
+    /// A numbered list of commands, one per line. Mnemonic commands are still accepted.
+    Numbers,
+}
+
+/// What pressing Enter at the prompt does.
+#[derive(Clone, Copy)]
+pub enum DefaultAction {
+    /// [`PromptCommand::ApplyDiff`] when there are changes, otherwise
+    /// [`PromptCommand::NextFlake`].
+    Auto,
+    /// Prints help.
+    None,
+    Command(PromptCommand),
+}
+
+impl FromStr for DefaultAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "none" => Ok(Self::None),
+            _ => PromptCommand::from_str(s)
+                .map(Self::Command)
+                .map_err(|_| format!("expected `auto`, `none` or a prompt command, got `{s}`")),
+        }
+    }
+}
+
+/// State kept between flakes during one `update` run.
+pub struct Session {
+    state: State,
+    /// Whether to apply the diff and lock every remaining flake without prompting.
+    batch: bool,
+    /// The last answer to [`refresh_direnv`]'s question.
+    refresh_direnv: Option<Answer>,
+    /// The last answer to [`commit_changes`]'s question.
+    commit: Option<Answer>,
+    /// The last answer to [`git_push`]'s question.
+    push: Option<Answer>,
+    /// Whether a step that doesn't stop the flake failed, like reloading direnv.
+    step_failed: bool,
+}
+
+/// An answer to a yes/no question. `Y!` and `N!` answer it for the rest of the session.
+#[derive(Clone, Copy)]
+struct Answer {
+    yes: bool,
+    remember: bool,
+}
+
+impl Answer {
+    fn parse(line: &str) -> Self {
+        match line.trim() {
+            "Y!" | "y!" => Self {
+                yes: true,
+                remember: true,
+            },
+            "N!" | "n!" => Self {
+                yes: false,
+                remember: true,
+            },
+            line => Self {
+                yes: line == "y",
+                remember: false,
+            },
+        }
+    }
+}
+
+/// Returns the remembered answer in `slot`, or asks with `ask` and stores the answer there.
+///
+/// In batch mode the last answer is reused, defaulting to no.
+fn remembered_answer(
+    slot: &mut Option<Answer>,
+    batch: bool,
+    ask: impl FnOnce() -> Result<String>,
+) -> Result<bool> {
+    match *slot {
+        Some(answer) if answer.remember || batch => Ok(answer.yes),
+        None if batch => Ok(false),
+        _ => {
+            let answer = Answer::parse(&ask()?);
+            *slot = Some(answer);
+            Ok(answer.yes)
+        }
+    }
+}
+
+impl Session {
+    /// Creates a session. `batch` starts in the mode entered with [`PromptCommand::ApplyAll`].
+    pub const fn new(state: State, batch: bool) -> Self {
+        Self {
+            state,
+            batch,
+            refresh_direnv: None,
+            commit: None,
+            push: None,
+            step_failed: false,
+        }
+    }
+
+    /// Returns the state kept between runs.
+    pub const fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Returns whether a step failed since the last call, for counting the flake as failed.
+    pub const fn take_step_failed(&mut self) -> bool {
+        std::mem::replace(&mut self.step_failed, false)
+    }
+}
+
+/// Everything prompt commands need to know about the flake being updated.
+struct PromptContext<'a> {
+    cli: &'a crate::Cli,
+    update_args: &'a UpdateArgs,
+    flake: &'a Flake<'a>,
+    target: &'a MatchTarget,
+    lockfile_node: &'a LockfileNode,
+    flake_nix: &'a Path,
+    new_flake_nix: &'a str,
+    /// The program running the steps modifying the flake if the user can't write it.
+    elevate: Option<&'a str>,
+}
+
+/// Updates the other nodes of the input's repository with `--transitive` and runs
+/// [`after_update`] after the flake was successfully locked.
+fn after_lock(ctx: &PromptContext, session: &mut Session) -> Result<()> {
+    if ctx.cli.transitive {
+        update_transitive(ctx)?;
+    }
+    after_update(ctx, session)
+}
+
+/// Runs the flake's post-update commands, refreshes direnv, commits and runs the post-hook after
+/// the lock file was updated.
+fn after_update(ctx: &PromptContext, session: &mut Session) -> Result<()> {
+    record_update(session, ctx.flake);
+    let new = load_lockfile_input(&ctx.flake.lockfile_path, ctx.flake.id)?;
+    let revs = HookRevs {
+        old: ctx.lockfile_node.locked.rev(),
+        new: new.locked.rev(),
+    };
+
+    let post_update = ctx
+        .cli
+        .config
+        .flake(&ctx.flake.directory)
+        .map_or(&[][..], |config| &config.post_update);
+    for command in post_update {
+        if !run_hook("post-update command", command, ctx.flake, &revs)? {
+            eprintln!(
+                "{}",
+                "Skipping the remaining post-update commands".warning()
+            );
+            break;
+        }
+    }
+
+    if ctx.flake.has_direnv_gc_roots {
+        refresh_direnv(ctx.update_args, session, ctx.flake)?;
+    }
+    if Vcs::detect(&ctx.flake.directory).is_some() {
+        commit_changes(ctx.update_args, session, ctx.flake)?;
+    }
+    run_post_hook(ctx.update_args, ctx.flake, &revs)?;
+
+    if let Some(system) = ctx.flake.system {
+        eprintln!(
+            "{} {}",
+            format_args!("The {system} configuration takes effect after").notice(),
+            PromptCommand::Switch.command(),
+        );
+    }
+    if ctx.flake.home_manager {
+        eprintln!(
+            "{} {}",
+            "The home-manager configuration takes effect after".notice(),
+            PromptCommand::HomeManagerSwitch.command(),
+        );
+    }
+    Ok(())
+}
+
+/// Saves when the flake was locked, shown by `list`. Failing to save only warns, since the
+/// update itself already succeeded.
+fn record_update(session: &mut Session, flake: &Flake) {
+    session.state.record_update(&flake.directory);
+    if let Err(err) = session.state.save() {
+        tracing::warn!("Failed to save state: {err:?}");
+    }
+}
+
+/// Runs `--post-hook` if given.
+fn run_post_hook(update_args: &UpdateArgs, flake: &Flake, revs: &HookRevs) -> Result<()> {
+    if let Some(post_hook) = &update_args.post_hook {
+        run_hook("post-hook", post_hook, flake, revs)?;
+    }
+    Ok(())
+}
+
+/// Executes a prompt command. `arg` is the rest of the line after the command.
+#[expect(clippy::too_many_lines, reason = "Really can't shorten this any more")]
+fn execute_prompt_cmd(
+    ctx: &PromptContext,
+    session: &mut Session,
     cmd: PromptCommand,
+    arg: &str,
 ) -> Result<ControlFlow<()>> {
+    let PromptContext {
+        update_args,
+        flake,
+        target,
+        lockfile_node,
+        flake_nix,
+        new_flake_nix,
+        elevate,
+        ..
+    } = *ctx;
+
     let check_dry_run_here = matches!(
         cmd,
         PromptCommand::ApplyDiff
+            | PromptCommand::Deepen
+            | PromptCommand::Retarget
+            | PromptCommand::Transitive
             | PromptCommand::RunNixFlakeUpdate
             | PromptCommand::DeleteGcroots
             | PromptCommand::Lock
+            | PromptCommand::PullRequest
+            | PromptCommand::Rebuild
+            | PromptCommand::Switch
+            | PromptCommand::HomeManagerSwitch
     );
     if check_dry_run_here && !update_args.allow_write {
-        eprintln!("{}", "Dry run, not modifying files".yellow());
+        eprintln!("{}", "Dry run, not modifying files".warning());
         return Ok(ControlFlow::Continue(()));
     }
 
     match cmd {
+        PromptCommand::ApplyAll => {
+            eprintln!(
+                "{}",
+                "Applying to this and every remaining flake without prompting".good()
+            );
+            session.batch = true;
+        }
+        PromptCommand::ApplyDiff if update_args.worktree && in_git_repo(&flake.directory) => {
+            let merged = crate::worktree::update_in_worktree(flake, new_flake_nix, update_args)?;
+            if merged {
+                record_update(session, flake);
+                if flake.has_direnv_gc_roots {
+                    refresh_direnv(update_args, session, flake)?;
+                }
+                let new = load_lockfile_input(&flake.lockfile_path, flake.id)?;
+                let revs = HookRevs {
+                    old: lockfile_node.locked.rev(),
+                    new: new.locked.rev(),
+                };
+                run_post_hook(update_args, flake, &revs)?;
+            }
+        }
         PromptCommand::ApplyDiff => {
-            fs::write(flake_nix, new_flake_nix)?;
+            elevate::write(elevate, flake_nix, new_flake_nix)?;
 
             eprintln!(
                 "{} {} {}",
-                "You should execute one of the following:".yellow(),
-                PromptCommand::Lock.cyan(),
-                PromptCommand::RefreshDirenv.cyan(),
+                "You should execute one of the following:".warning(),
+                PromptCommand::Lock.command(),
+                PromptCommand::RefreshDirenv.command(),
             );
         }
         PromptCommand::NextFlake => {
-            eprintln!("{}", "Going to the next flake".green());
+            eprintln!("{}", "Going to the next flake".good());
+            return Ok(ControlFlow::Break(()));
+        }
+        PromptCommand::Snooze => {
+            let duration = match humantime::parse_duration(arg) {
+                Ok(duration) => duration,
+                Err(err) => {
+                    eprintln!(
+                        "{} {}",
+                        format_args!("Invalid duration {arg:?}:").bad(),
+                        err.bad()
+                    );
+                    return Ok(ControlFlow::Continue(()));
+                }
+            };
+            session.state.snooze(&flake.directory, duration);
+            session.state.save().wrap_err("Failed to save state")?;
+            eprintln!(
+                "{}",
+                format_args!(
+                    "Snoozed for {}, going to the next flake",
+                    humantime::format_duration(duration)
+                )
+                .good()
+            );
             return Ok(ControlFlow::Break(()));
         }
         PromptCommand::LaunchEditor => {
-            let status = Command::new(
-                std::env::var_os("EDITOR").ok_or_eyre("EDITOR environment variable missing")?,
-            )
-            .current_dir(&flake.directory)
-            .arg(flake_nix)
-            .status()?;
+            // Only `flake.nix` is parsed to find the input
+            let position = match flake.project {
+                ProjectKind::Flake => nixpkgsupd::flake_nix::input_url_position(
+                    &fs::read_to_string(flake_nix)?,
+                    flake.id,
+                ),
+                ProjectKind::Devenv => None,
+            };
+            let status = sigint_guard::status(
+                editor::command(update_args.editor.as_deref(), flake_nix, position)?
+                    .current_dir(&flake.directory),
+            )?;
 
             if !status.success() {
-                eprintln!("{}", "Editor exited with nonzero exit code".red());
+                eprintln!("{}", "Editor exited with nonzero exit code".bad());
             }
 
             eprintln!(
                 "{} {} {}",
-                "You have been returned to the prompt. Select".green(),
-                PromptCommand::Lock.cyan(),
-                "or similar if you have applied edits manually.".green()
+                "You have been returned to the prompt. Select".good(),
+                PromptCommand::Lock.command(),
+                "or similar if you have applied edits manually.".good()
             );
         }
         PromptCommand::LaunchShell => {
@@ -191,86 +782,226 @@ fn execute_prompt_cmd(
                 cmd.env("PROMPTEXTRA", PROMPTEXTRA_ADDITION);
             }
 
-            let status = cmd.current_dir(&flake.directory).status()?;
+            let status = sigint_guard::status(cmd.current_dir(&flake.directory))?;
 
             if !status.success() {
-                eprintln!("{}", "Shell exited with nonzero exit code".red());
+                eprintln!("{}", "Shell exited with nonzero exit code".bad());
             }
 
             eprintln!(
                 "{} {} {}",
-                "You have been returned to the prompt. Select".green(),
-                PromptCommand::Lock.cyan(),
-                "or similar if you have applied edits manually.".green()
+                "You have been returned to the prompt. Select".good(),
+                PromptCommand::Lock.command(),
+                "or similar if you have applied edits manually.".good()
             );
         }
+        PromptCommand::LaunchDevShell => {
+            let status = sigint_guard::status(
+                Command::new("nix")
+                    .arg("develop")
+                    .current_dir(&flake.directory),
+            )?;
+
+            if !status.success() {
+                eprintln!(
+                    "{}",
+                    "Development shell exited with nonzero exit code".bad()
+                );
+            }
+
+            eprintln!(
+                "{} {} {}",
+                "You have been returned to the prompt. Select".good(),
+                PromptCommand::Commit.command(),
+                "if the project still works with the update.".good()
+            );
+        }
+        PromptCommand::ShowOutputs => {
+            if !run_cmd("nix", &["flake", "show"], &flake.directory)? {
+                eprintln!("{}", "Failed to show flake outputs.".bad());
+            }
+        }
+        PromptCommand::LockDelta => print_lock_delta(target, lockfile_node),
+        PromptCommand::Changelog => print_changelog(target, lockfile_node),
+        PromptCommand::OpenCompare => {
+            let url = lockfile_node
+                .locked
+                .rev()
+                .zip(target.locked().rev())
+                .and_then(|(locked_rev, target_rev)| {
+                    crate::forge::compare_url(&lockfile_node.locked, locked_rev, target_rev)
+                });
+            let Some(url) = url else {
+                eprintln!(
+                    "{}",
+                    "Only GitHub, GitLab and Sourcehut inputs with a rev can be opened".bad()
+                );
+                return Ok(ControlFlow::Continue(()));
+            };
+
+            eprintln!("Opening {}", url.value());
+            let opener = if cfg!(target_os = "macos") {
+                "open"
+            } else {
+                "xdg-open"
+            };
+            if !run_cmd(opener, &[&url], &flake.directory)? {
+                eprintln!("{}", "Failed to open the browser.".bad());
+            }
+        }
         PromptCommand::RunNixFlakeUpdate => {
-            if !run_cmd("nix", &["flake", "update", flake.id], &flake.directory)? {
+            if !elevate::run(
+                elevate,
+                "Updating the input",
+                flake.project.update_input_command(flake.id),
+                &flake.directory,
+            )? {
                 eprintln!(
                     "{}",
-                    "Failed to update indirect input. Try another method.".red()
+                    "Failed to update indirect input. Try another method.".bad()
                 );
                 return Ok(ControlFlow::Continue(()));
             }
 
-            if flake.has_direnv_gc_roots {
-                refresh_direnv(update_args, flake)?;
+            after_lock(ctx, session)?;
+        }
+        PromptCommand::Deepen => {
+            let mut attrs = serde_json::to_value(&lockfile_node.original)?;
+            let attrs = attrs
+                .as_object_mut()
+                .ok_or_eyre("Original flake reference is not an object")?;
+            if attrs.remove("shallow").is_none() {
+                eprintln!("{}", "The input isn't declared shallow".warning());
+                return Ok(ControlFlow::Continue(()));
             }
-            if flake.in_git_repo() {
-                git_commit_changes(update_args, flake)?;
+            let flake_ref = nixpkgsupd::flake_ref::to_string(attrs)?;
+
+            let current_flake_nix = fs::read_to_string(flake_nix)?;
+            let deep_flake_nix =
+                flake
+                    .project
+                    .replace_input_url(&flake_ref, &current_flake_nix, flake.id)?;
+            print_diff(
+                &current_flake_nix,
+                &deep_flake_nix,
+                update_args.diff_context,
+            );
+            elevate::write(elevate, flake_nix, &deep_flake_nix)?;
+
+            if !lock(flake.project, &flake.directory, flake.id, elevate)? {
+                eprintln!("Failed to recreate lockfile. Try manually editing flake.nix.");
+            }
+        }
+        PromptCommand::Retarget => retarget(ctx)?,
+        PromptCommand::Transitive => {
+            if update_transitive(ctx)? {
+                after_update(ctx, session)?;
+            } else {
+                eprintln!(
+                    "{}",
+                    "No other nodes of the input's repository are outdated".good()
+                );
             }
         }
         PromptCommand::DeleteGcroots => {
-            eprintln!("Deleting garbage collector root.");
-            for gcroot in &flake.gcroots {
-                fs::remove_file(gcroot).wrap_err("Failed to remove garbage collector root")?;
+            print_gcroots(flake);
+            print_reclaimable(&flake.gcroots);
+            let answer = read_line("Delete the gcroots? [y,N] ".prompt())?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return Ok(ControlFlow::Continue(()));
             }
+            let profile_links = ask_profile_links(&[flake])?;
+            eprintln!("Deleting garbage collector root.");
+            remove_gcroots(
+                gcroots_elevate(flake, ctx.update_args),
+                flake,
+                profile_links,
+            )?;
         }
         PromptCommand::Lock => {
-            if !run_cmd("nix", &["flake", "lock"], &flake.directory)? {
+            if !lock(flake.project, &flake.directory, flake.id, elevate)? {
                 eprintln!("Failed to recreate lockfile. Try manually editing flake.nix.");
                 return Ok(ControlFlow::Continue(()));
             }
 
-            if flake.has_direnv_gc_roots {
-                refresh_direnv(update_args, flake)?;
-            }
-            if flake.in_git_repo() {
-                git_commit_changes(update_args, flake)?;
-            }
+            after_lock(ctx, session)?;
         }
         PromptCommand::RefreshDirenv => {
-            refresh_direnv(update_args, flake)?;
+            refresh_direnv(update_args, session, flake)?;
         }
         PromptCommand::Commit => {
-            git_commit_changes(update_args, flake)?;
+            commit_changes(update_args, session, flake)?;
         }
-        PromptCommand::PrintHelp => {
-            for cmd in PromptCommand::ALL {
+        PromptCommand::PullRequest => {
+            crate::pull_request::create_pull_request(update_args, flake)?;
+        }
+        PromptCommand::Rebuild => crate::rebuild::rebuild(flake)?,
+        PromptCommand::Switch => {
+            let Some(system) = flake.system else {
+                eprintln!("{}", "The flake isn't a system configuration".bad());
+                return Ok(ControlFlow::Continue(()));
+            };
+            let (program, args) = system.switch_command();
+            if !run_cmd(program, args, &flake.directory)? {
                 eprintln!(
-                    "{:<6} {} {}",
-                    cmd.cyan(),
-                    "-".fg::<xterm::Gray>(),
-                    cmd.description()
+                    "{}",
+                    format_args!("Failed to switch to the {system} configuration.").bad()
                 );
             }
         }
+        PromptCommand::HomeManagerSwitch => {
+            if !run_cmd(
+                "home-manager",
+                &["switch", "--flake", "."],
+                &flake.directory,
+            )? {
+                eprintln!(
+                    "{}",
+                    "Failed to switch to the home-manager configuration.".bad()
+                );
+            }
+        }
+        PromptCommand::PrintHelp => {
+            for cmd in PromptCommand::ALL {
+                eprintln!("{:<6} {} {}", cmd.command(), "-".muted(), cmd.description());
+            }
+        }
     }
     Ok(ControlFlow::Continue(()))
 }
 
-#[derive(Clone, Copy, strum::EnumString, strum::Display)]
-enum PromptCommand {
+#[derive(Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+pub enum PromptCommand {
     #[strum(serialize = "a")]
     ApplyDiff,
+    #[strum(serialize = "A")]
+    ApplyAll,
     #[strum(serialize = "n")]
     NextFlake,
+    #[strum(serialize = "s")]
+    Snooze,
     #[strum(serialize = "e")]
     LaunchEditor,
     #[strum(serialize = "sh")]
     LaunchShell,
+    #[strum(serialize = "develop")]
+    LaunchDevShell,
+    #[strum(serialize = "show")]
+    ShowOutputs,
+    #[strum(serialize = "dl")]
+    LockDelta,
+    #[strum(serialize = "log")]
+    Changelog,
+    #[strum(serialize = "open")]
+    OpenCompare,
     #[strum(serialize = "up")]
     RunNixFlakeUpdate,
+    #[strum(serialize = "deepen")]
+    Deepen,
+    #[strum(serialize = "retarget")]
+    Retarget,
+    #[strum(serialize = "transitive")]
+    Transitive,
     #[strum(serialize = "dg")]
     DeleteGcroots,
     #[strum(serialize = "lock")]
@@ -279,108 +1010,1168 @@ enum PromptCommand {
     RefreshDirenv,
     #[strum(serialize = "commit")]
     Commit,
+    #[strum(serialize = "pr")]
+    PullRequest,
+    #[strum(serialize = "rebuild")]
+    Rebuild,
+    #[strum(serialize = "switch")]
+    Switch,
+    #[strum(serialize = "hm")]
+    HomeManagerSwitch,
     #[strum(serialize = "?")]
     PrintHelp,
 }
 impl PromptCommand {
     const ALL: &[Self] = &[
         Self::ApplyDiff,
+        Self::ApplyAll,
         Self::NextFlake,
+        Self::Snooze,
         Self::LaunchEditor,
         Self::LaunchShell,
+        Self::LaunchDevShell,
+        Self::ShowOutputs,
+        Self::LockDelta,
+        Self::Changelog,
+        Self::OpenCompare,
         Self::RunNixFlakeUpdate,
+        Self::Deepen,
+        Self::Retarget,
+        Self::Transitive,
         Self::DeleteGcroots,
         Self::Lock,
         Self::RefreshDirenv,
         Self::Commit,
+        Self::PullRequest,
+        Self::Rebuild,
+        Self::Switch,
+        Self::HomeManagerSwitch,
         Self::PrintHelp,
     ];
+    /// Returns the command as shown in the prompt. The default is capitalized, or marked with
+    /// an asterisk if the capitalized form is another command.
+    fn prompt_label(self, is_default: bool) -> String {
+        let label = self.to_string();
+        if !is_default {
+            return label;
+        }
+        let capitalized = label.to_uppercase();
+        if capitalized != label && Self::from_str(&capitalized).is_err() {
+            capitalized
+        } else {
+            format!("{label}*")
+        }
+    }
     const fn description(self) -> &'static str {
         match self {
             Self::ApplyDiff => "Applies the change",
+            Self::ApplyAll => {
+                "Applies and locks this and every remaining flake without prompting, reusing earlier answers"
+            }
             Self::NextFlake => "Proceeds to the next flake",
-            Self::LaunchEditor => "Edits `flake.nix` using `$EDITOR`",
+            Self::Snooze => {
+                "Skips the flake and hides it until the given duration passes, e.g. `s 2w`"
+            }
+            Self::LaunchEditor => "Edits `flake.nix` using `--editor`, `$VISUAL` or `$EDITOR`",
             Self::LaunchShell => "Launches `$SHELL` in the flake's directory",
+            Self::LaunchDevShell => "Launches `nix develop` in the flake's directory",
+            Self::ShowOutputs => "Runs `nix flake show` to list the flake's outputs",
+            Self::LockDelta => "Compares the locked version to the target",
+            Self::Changelog => "Lists the commits between the locked version and the target",
+            Self::OpenCompare => "Opens the changes up to the target in a web browser",
             Self::RunNixFlakeUpdate => "Runs `nix flake update <input id>",
+            Self::Deepen => "Removes `shallow=1` from the input and relocks it with history",
+            Self::Retarget => {
+                "Moves an input following an end-of-life NixOS release branch to the newest release"
+            }
+            Self::Transitive => {
+                "Updates the outdated other nodes of the input's repository, like `nixpkgs_2` of another input"
+            }
             Self::DeleteGcroots => "Deletes garbage collector roots like build results and direnv",
             Self::Lock => "Runs `nix flake lock`",
             Self::RefreshDirenv => "Refreshes direnv",
-            Self::Commit => "Makes a Git commit with `flake.nix` and `flake.lock`",
+            Self::Commit => "Commits `flake.nix` and `flake.lock` with Git, Mercurial or Sapling",
+            Self::PullRequest => {
+                "Commits `flake.nix` and `flake.lock` to a new branch, pushes it and opens a pull request with `gh`"
+            }
+            Self::Rebuild => {
+                "Runs `nix build` again for the packages the `result` links point to, to point them to the new outputs"
+            }
+            Self::Switch => {
+                "Switches to the system configuration with `nixos-rebuild` or `darwin-rebuild`"
+            }
+            Self::HomeManagerSwitch => "Runs `home-manager switch --flake .`",
             Self::PrintHelp => "Prints help",
         }
     }
 }
 
-fn refresh_direnv(update_args: &UpdateArgs, flake: &Flake) -> Result<()> {
-    eprint!("{}", "Refresh direnv? [y,N] ".blue());
-    let buf = read_line()?;
-    if buf.trim() == "y" {
+/// Replaces the end-of-life NixOS release branch of the input with the newest release's and
+/// relocks it.
+fn retarget(ctx: &PromptContext) -> Result<()> {
+    let PromptContext {
+        update_args,
+        flake,
+        lockfile_node,
+        flake_nix,
+        elevate,
+        ..
+    } = *ctx;
+    let Some(release) = end_of_life_release(lockfile_node)? else {
+        eprintln!(
+            "{}",
+            "The input doesn't follow an end-of-life NixOS release".warning()
+        );
+        return Ok(());
+    };
+    let stable = channels::stable()?;
+
+    let mut attrs = serde_json::to_value(&lockfile_node.original)?;
+    let attrs = attrs
+        .as_object_mut()
+        .ok_or_eyre("Original flake reference is not an object")?;
+    attrs.remove("rev");
+    let ref_ = registry::resolved_ref(&lockfile_node.original.inner)
+        .ok_or_eyre("The input has no ref")?
+        .into_owned();
+    attrs.insert(
+        "ref".to_owned(),
+        release.retarget_branch(&ref_, stable).into(),
+    );
+    let flake_ref = nixpkgsupd::flake_ref::to_string(attrs)?;
+
+    let current_flake_nix = fs::read_to_string(flake_nix)?;
+    let new_flake_nix =
+        flake
+            .project
+            .replace_input_url(&flake_ref, &current_flake_nix, flake.id)?;
+    print_diff(&current_flake_nix, &new_flake_nix, update_args.diff_context);
+    elevate::write(elevate, flake_nix, &new_flake_nix)?;
+
+    if !lock(flake.project, &flake.directory, flake.id, elevate)? {
+        eprintln!("Failed to recreate lockfile. Try manually editing flake.nix.");
+    }
+    Ok(())
+}
+
+/// Prints the locked `ref`, `rev` and `lastModified` next to the target's.
+fn print_lock_delta(target: &MatchTarget, lockfile_node: &LockfileNode) {
+    fn row(label: &str, locked: Option<&str>, target: Option<&str>) {
+        let locked = locked.unwrap_or("-");
+        let target = target.unwrap_or("-");
+        if locked == target {
+            eprintln!("{:<8} {:<42} {}", label.muted(), locked, target);
+        } else {
+            eprintln!(
+                "{:<8} {:<42} {}",
+                label.muted(),
+                locked.bad(),
+                target.good()
+            );
+        }
+    }
+    fn format_timestamp(last_modified: Option<u64>) -> Option<String> {
+        let last_modified = SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified?);
+        Some(humantime::format_rfc3339_seconds(last_modified).to_string())
+    }
+
+    eprintln!(
+        "{:<8} {:<42} {}",
+        "",
+        "locked".heading(),
+        "target".heading()
+    );
+    row(
+        "ref",
+        registry::resolved_ref(&lockfile_node.original.inner).as_deref(),
+        registry::resolved_ref(target.original()).as_deref(),
+    );
+    row("rev", lockfile_node.locked.rev(), target.locked().rev());
+    row(
+        "url",
+        lockfile_node.locked.url_no_git(),
+        target.locked().url_no_git(),
+    );
+    row(
+        "updated",
+        format_timestamp(lockfile_node.locked.last_modified()).as_deref(),
+        format_timestamp(target.locked().last_modified()).as_deref(),
+    );
+
+    if let (Some(locked), Some(target)) = (
+        lockfile_node.locked.last_modified(),
+        target.locked().last_modified(),
+    ) {
+        let days = target.abs_diff(locked) / (60 * 60 * 24);
+        if locked < target {
+            eprintln!(
+                "{}",
+                format_args!("Behind the target by {days} days").warning()
+            );
+        } else {
+            eprintln!(
+                "{}",
+                format_args!("Ahead of the target by {days} days").good()
+            );
+        }
+    }
+}
+
+/// Prints the commits between the locked and target `rev` using the forge's API, warning if they
+/// can't be fetched.
+fn print_changelog(target: &MatchTarget, lockfile_node: &LockfileNode) {
+    /// Keeps the list from scrolling the diff out of view.
+    const MAX_TITLES: usize = 20;
+
+    if lockfile_node.locked.is_shallow() {
+        eprintln!(
+            "{} {} {}",
+            "The input is a shallow Git clone without history. Use".warning(),
+            PromptCommand::Deepen.command(),
+            "to lock it with history.".warning()
+        );
+        return;
+    }
+
+    let (Some(locked_rev), Some(target_rev)) = (lockfile_node.locked.rev(), target.locked().rev())
+    else {
+        eprintln!("{}", "The locked version or the target has no rev".bad());
+        return;
+    };
+
+    let comparison = match crate::forge::compare(&lockfile_node.locked, locked_rev, target_rev) {
+        Ok(comparison) => comparison,
+        Err(err) => {
+            tracing::warn!("Failed to compare revisions: {err:?}");
+            return;
+        }
+    };
+
+    // Newest first
+    let shown = comparison.titles.iter().rev().take(MAX_TITLES);
+    let rest = comparison.total_commits.saturating_sub(shown.len());
+    for title in shown {
+        eprintln!("{} {title}", "*".muted());
+    }
+    if rest > 0 {
+        eprintln!("{}", format_args!("... and {rest} more").muted());
+    }
+    eprintln!(
+        "{}",
+        format_args!(
+            "{} commits between the locked version and the target",
+            comparison.total_commits
+        )
+        .value()
+    );
+}
+
+fn refresh_direnv(update_args: &UpdateArgs, session: &mut Session, flake: &Flake) -> Result<()> {
+    let answer = update_args.always_direnv
+        || remembered_answer(&mut session.refresh_direnv, session.batch, || {
+            read_line("Refresh direnv? [y,N,Y!,N!] ".prompt())
+        })?;
+    if answer {
         if update_args.allow_write {
-            if !run_cmd("direnv", &["exec", ".", "true"], &flake.directory)? {
-                // FIXME: This never even happens...
-                // `direnv: nix-direnv: Evaluating current devShell failed. Falling back to previous environment!` and exit code 0
-                eprintln!("{}", "Failed to reload direnv.".red());
+            // The flake was updated anyway, so committing still follows
+            if !reload_direnv(flake.envrc_directory())? {
+                session.step_failed = true;
             }
         } else {
-            eprintln!("{}", "Dry run, not modifying files".yellow());
+            eprintln!("{}", "Dry run, not modifying files".warning());
+        }
+    }
+    Ok(())
+}
+
+/// What nix-direnv prints when it fails to build the new environment and keeps the previous one.
+/// It still exits successfully.
+const NIX_DIRENV_FALLBACK_MESSAGES: &[&str] = &[
+    "Evaluating current devShell failed",
+    "Falling back to previous environment",
+];
+
+/// Prints the store paths the flake's gcroots keep alive and their closure sizes.
+fn print_gcroots(flake: &Flake) {
+    let store = store::get();
+    let store_paths: Vec<_> = flake
+        .gcroots
+        .iter()
+        .map(|gcroot| store.store_path(gcroot))
+        .collect();
+    let infos = store
+        .path_infos(&store_paths.iter().flatten().cloned().collect::<Vec<_>>())
+        .unwrap_or_else(|err| {
+            tracing::warn!("Failed to query the store paths of the gcroots: {err:?}");
+            HashMap::new()
+        });
+    for (gcroot, store_path) in flake.gcroots.iter().zip(&store_paths) {
+        let info = store_path.as_ref().and_then(|path| infos.get(path));
+        match info {
+            Some(info) => eprintln!(
+                "  {} {}",
+                gcroot.display().value(),
+                info.closure_size
+                    .map(|size| format!("keeps {} alive", HumanBytes(size)))
+                    .unwrap_or_default()
+                    .muted(),
+            ),
+            None => eprintln!(
+                "  {} {}",
+                gcroot.display().value(),
+                "no longer points to a valid store path".warning()
+            ),
+        }
+    }
+}
+
+/// Removes the flake's gcroots, and the profile links pointing to them, which would dangle, if
+/// `profile_links` is set.
+fn remove_gcroots(elevate: Option<&str>, flake: &Flake, profile_links: bool) -> Result<()> {
+    for gcroot in &flake.gcroots {
+        let links = if profile_links {
+            discovery::profile_links(gcroot)
+        } else {
+            Vec::new()
+        };
+        elevate::remove_file(elevate, gcroot)
+            .wrap_err("Failed to remove garbage collector root")?;
+        for link in links {
+            tracing::debug!(link = %link.display(), "Removing profile link");
+            elevate::remove_file(elevate, &link).wrap_err("Failed to remove profile link")?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the profile links pointing to the gcroots of `flakes` and asks whether to delete them
+/// too, since they dangle once the gcroots are gone. Returns `false` if there are none.
+fn ask_profile_links(flakes: &[&Flake]) -> Result<bool> {
+    if !print_profile_links(flakes) {
+        return Ok(false);
+    }
+    let answer = read_line("Delete the profile links too? [Y,n] ".prompt())?;
+    Ok(!answer.trim().eq_ignore_ascii_case("n"))
+}
+
+/// Prints the profile links pointing to the gcroots of `flakes` and returns whether there are
+/// any.
+fn print_profile_links(flakes: &[&Flake]) -> bool {
+    let links: Vec<_> = flakes
+        .iter()
+        .flat_map(|flake| &flake.gcroots)
+        .flat_map(|gcroot| discovery::profile_links(gcroot))
+        .collect();
+    if links.is_empty() {
+        return false;
+    }
+    eprintln!("{}", "Profile links pointing to them:".heading());
+    for link in &links {
+        eprintln!("  {}", link.display().value());
+    }
+    true
+}
+
+/// Returns the program to delete the flake's gcroots with if the user can't remove them, like
+/// the root-owned `result` link of `/etc/nixos`.
+fn gcroots_elevate<'a>(flake: &Flake, update_args: &'a UpdateArgs) -> Option<&'a str> {
+    let removable = flake.gcroots.iter().all(|gcroot| {
+        gcroot
+            .parent()
+            .is_some_and(|parent| access(parent, AccessFlags::W_OK).is_ok())
+    });
+    (!removable).then(|| {
+        update_args
+            .elevate_with
+            .as_deref()
+            .unwrap_or(elevate::DEFAULT_PROGRAM)
+    })
+}
+
+/// Prints how much space becomes collectible once `gcroots` are deleted.
+fn print_reclaimable(gcroots: &[PathBuf]) {
+    match store::get().reclaimable_size(gcroots) {
+        Ok(size) => eprintln!(
+            "  {}",
+            format_args!(
+                "About {} becomes collectible, e.g. with `nix store gc`",
+                HumanBytes(size)
+            )
+            .muted()
+        ),
+        Err(err) => tracing::warn!("Failed to estimate the reclaimable space: {err:?}"),
+    }
+}
+
+/// Locks changed inputs in `directory` and returns whether it succeeded.
+///
+/// `elevate` is the program to run the command with if the user can't write the flake.
+pub fn lock(
+    project: ProjectKind,
+    directory: &Path,
+    input_id: &str,
+    elevate: Option<&str>,
+) -> Result<bool> {
+    elevate::run(
+        elevate,
+        "Locking",
+        project.lock_command(input_id),
+        directory,
+    )
+}
+
+/// Deletes the gcroots of every flake already matching the target after confirming once, for
+/// `--clean-matching`.
+pub fn clean_matching(
+    cli: &crate::Cli,
+    target: &MatchTarget,
+    flakes: &[Flake],
+    update_args: &UpdateArgs,
+) -> Result<()> {
+    let mut matching = Vec::new();
+    for flake in flakes {
+        if flake.gcroots.is_empty() || !flake.has_manifest() {
+            continue;
+        }
+        let lockfile_node = match load_lockfile_input(&flake.lockfile_path, flake.id) {
+            Ok(lockfile_node) => lockfile_node,
+            Err(err) => {
+                tracing::warn!(directory = %flake.directory.display(), "Failed to load the lockfile: {err:?}");
+                continue;
+            }
+        };
+        if lockfile_node.flake && is_up_to_date(cli, target, Some(flake), &lockfile_node)? {
+            matching.push(flake);
+        }
+    }
+    if matching.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "{}",
+        format_args!(
+            "The gcroots of {} flakes matching the target:",
+            matching.len()
+        )
+        .heading()
+    );
+    for flake in &matching {
+        eprintln!("{}", flake.directory.display().value());
+        print_gcroots(flake);
+    }
+    let gcroots: Vec<_> = matching
+        .iter()
+        .flat_map(|flake| flake.gcroots.iter().cloned())
+        .collect();
+    print_reclaimable(&gcroots);
+
+    if !update_args.allow_write {
+        eprintln!("{}", "Dry run, not modifying files".warning());
+        return Ok(());
+    }
+    let profile_links = if update_args.yes {
+        // Deleting them is a separate opt-in, since `--yes` only confirms deleting the gcroots
+        update_args.delete_profile_links && print_profile_links(&matching)
+    } else {
+        let answer = read_line("Delete the gcroots of all of them? [y,N] ".prompt())?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
+        ask_profile_links(&matching)?
+    };
+    for flake in matching {
+        if let Err(err) = remove_gcroots(gcroots_elevate(flake, update_args), flake, profile_links)
+        {
+            eprintln!(
+                "{}",
+                format_args!(
+                    "Failed to delete the gcroots of {}: {err}",
+                    flake.directory.display()
+                )
+                .bad()
+            );
         }
     }
     Ok(())
 }
 
-fn git_commit_changes(
+/// Prints a flake whose `flake.nix` is missing and offers to delete its gcroots.
+pub fn delete_stale_gcroots(
+    flake: &Flake,
+    cli: &crate::Cli,
     update_args: &UpdateArgs,
+) -> Result<()> {
+    println!();
+    print_missing_manifest(flake, cli);
+    if flake.gcroots.is_empty() || !update_args.allow_write || update_args.non_interactive {
+        return Ok(());
+    }
+    print_reclaimable(&flake.gcroots);
+    let answer = read_line("Delete the gcroots? [y,N] ".prompt())?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        let profile_links = ask_profile_links(&[flake])?;
+        remove_gcroots(gcroots_elevate(flake, update_args), flake, profile_links)?;
+    }
+    Ok(())
+}
+
+/// Returns the program to run the steps modifying the flake with if the user can't write it, or
+/// breaks if the flake should be skipped.
+fn check_writable<'a>(
+    flake: &Flake,
+    update_args: &'a UpdateArgs,
+) -> Result<ControlFlow<(), Option<&'a str>>> {
+    if flake.is_in_store() {
+        println!();
+        println!(
+            "{} {}",
+            flake.directory.display().muted(),
+            "is in the Nix store, which is read-only. Skipping it.".notice()
+        );
+        return Ok(ControlFlow::Break(()));
+    }
+    if flake.is_writable() || !update_args.allow_write {
+        return Ok(ControlFlow::Continue(None));
+    }
+    let program = ask_elevate(flake, update_args)?;
+    if program.is_none() {
+        eprintln!("{}", "Skipping the flake".muted());
+        return Ok(ControlFlow::Break(()));
+    }
+    Ok(ControlFlow::Continue(program))
+}
+
+/// Asks whether to modify the flake the user can't write with `--elevate-with`, and returns the
+/// program if so.
+fn ask_elevate<'a>(flake: &Flake, update_args: &'a UpdateArgs) -> Result<Option<&'a str>> {
+    let program = update_args
+        .elevate_with
+        .as_deref()
+        .unwrap_or(elevate::DEFAULT_PROGRAM);
+    println!();
+    if update_args.non_interactive {
+        eprintln!(
+            "{} {}",
+            flake.directory.display().muted(),
+            "isn't writable by you, and asking to elevate needs a terminal.".warning()
+        );
+        return Ok(None);
+    }
+    let answer = read_line(
+        format_args!(
+            "{} isn't writable by you. Modify it with {program}? [Y,n] ",
+            flake.directory.display()
+        )
+        .prompt(),
+    )?;
+    Ok((!answer.trim().eq_ignore_ascii_case("n")).then_some(program))
+}
+
+/// A symbolic link in `.direnv`, like nix-direnv's profile link, with its target and when it was
+/// created.
+type DirenvLink = (PathBuf, PathBuf, Option<SystemTime>);
+
+/// Returns the symbolic links in the `.direnv` directory of `directory`, to tell whether
+/// reloading built a new environment.
+fn direnv_links(directory: &Path) -> Vec<DirenvLink> {
+    let Ok(entries) = fs::read_dir(directory.join(".direnv")) else {
+        return Vec::new();
+    };
+    let mut links: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.path().symlink_metadata().ok()?;
+            let target = fs::read_link(entry.path()).ok()?;
+            Some((entry.path(), target, metadata.modified().ok()))
+        })
+        .collect();
+    links.sort();
+    links
+}
+
+/// Reloads direnv in `directory`, printing its log if it failed, and returns whether it succeeded.
+///
+/// Uses nix-direnv's `nix-direnv-reload` when it's installed in `.direnv/bin`, which is the only
+/// way with `nix_direnv_manual_reload`, and otherwise `direnv reload` to mark the environment as
+/// stale and `direnv exec` to build it. Succeeds only if a link in `.direnv` changed, when there
+/// were any.
+fn reload_direnv(directory: &Path) -> Result<bool> {
+    let setup = envrc::setup(directory);
+    match &setup.nix_direnv_version {
+        Some(version) => eprintln!(
+            "{}",
+            format_args!("Reloading direnv with nix-direnv {version}...").muted()
+        ),
+        None => eprintln!("{}", "Reloading direnv...".muted()),
+    }
+    if setup.manual_reload && !setup.has_reload_script {
+        eprintln!(
+            "{} {} {}",
+            "nix_direnv_manual_reload is set, but".warning(),
+            DirenvSetup::RELOAD_SCRIPT.value(),
+            "is missing, so the cached environment may be kept. Enter the directory to create it and run it".warning()
+        );
+    }
+    let links_before = direnv_links(directory);
+    let output = if setup.has_reload_script {
+        sigint_guard::output(
+            Command::new(directory.join(DirenvSetup::RELOAD_SCRIPT))
+                .current_dir(directory)
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped()),
+        )?
+    } else {
+        if !run_cmd("direnv", &["reload"], directory)? {
+            eprintln!("{}", "Failed to reload direnv.".bad());
+            return Ok(false);
+        }
+        // `direnv reload` only marks the environment as stale for the next shell prompt
+        sigint_guard::output(
+            Command::new("direnv")
+                .args(["exec", ".", "true"])
+                .current_dir(directory)
+                .stderr(Stdio::piped()),
+        )?
+    };
+    if setup.loader == Some(NixLoader::Nix) && !setup.watches_lockfile {
+        eprintln!(
+            "{} {} {}",
+            "`use nix` doesn't watch flake.lock, so direnv keeps the cached shell after it changes until".notice(),
+            "touch .envrc".command(),
+            "or adding `watch_file flake.lock` to .envrc".notice()
+        );
+    }
+    let log = String::from_utf8_lossy(&output.stderr);
+    let fell_back = NIX_DIRENV_FALLBACK_MESSAGES
+        .iter()
+        .any(|message| log.contains(message));
+    let links_after = direnv_links(directory);
+    let unchanged = !links_before.is_empty() && links_after == links_before;
+
+    if output.status.success() && !fell_back && !unchanged {
+        eprintln!("{}", "Reloaded direnv".good());
+        return Ok(true);
+    }
+    eprint!("{log}");
+    if fell_back {
+        eprintln!(
+            "{}",
+            "Failed to reload direnv: nix-direnv kept the previous environment.".bad()
+        );
+    } else if output.status.success() {
+        eprintln!(
+            "{}",
+            "Failed to reload direnv: the environment in .direnv didn't change.".bad()
+        );
+    } else {
+        eprintln!("{}", "Failed to reload direnv.".bad());
+    }
+    Ok(false)
+}
+
+fn commit_changes(
+    update_args: &UpdateArgs,
+    session: &mut Session,
     flake: &Flake<'_>,
 ) -> Result<(), color_eyre::eyre::Error> {
-    let is_empty = !run_cmd("git", &["log", "-0"], &flake.directory)?;
-    let stage_is_dirty = !run_cmd(
-        "git",
-        &["diff", "--quiet", "--cached", "--exit-code"],
+    let Some(vcs) = Vcs::detect(&flake.directory) else {
+        eprintln!("{}", "The flake isn't in a repository".warning());
+        return Ok(());
+    };
+    let amend = vcs == Vcs::Git && amend_bump_commit(update_args, session, flake)?;
+    let old = if amend {
+        parent_lockfile_input(&flake.directory, flake.project, flake.id)
+    } else {
+        head_lockfile_input(&flake.directory, flake.project, flake.id)
+    };
+    let commit_msg = commit_message(
+        update_args,
         &flake.directory,
+        flake.project,
+        flake.id,
+        old.as_ref(),
     )?;
+
+    let answer = update_args.always_commit
+        || remembered_answer(&mut session.commit, session.batch, || {
+            prompt_commit(vcs, flake, &commit_msg)
+        })?;
+    if answer {
+        if update_args.allow_write {
+            let stashed = vcs == Vcs::Git && stash_other_changes(update_args, session, flake)?;
+            let committed = vcs.commit(
+                update_args,
+                &flake.directory,
+                flake.project,
+                &commit_msg,
+                amend,
+            )?;
+            if stashed {
+                unstash_other_changes(flake)?;
+            }
+            if committed {
+                if vcs == Vcs::Git {
+                    if git_checkout(&flake.directory) == Some(GitCheckout::Submodule) {
+                        eprintln!(
+                            "{}",
+                            "The flake is in a submodule. Remember to commit the new submodule revision in the superproject".notice()
+                        );
+                    }
+                    git_push(update_args, session, flake)?;
+                }
+            } else {
+                eprintln!("{}", "Failed to commit.".bad());
+            }
+        } else {
+            eprintln!("{}", "Dry run, not modifying files".warning());
+        }
+    }
+    Ok(())
+}
+
+/// Offers to amend the checked out commit if it's an unpushed bump of the same input from an
+/// earlier run, so that repeated runs don't stack near-identical commits, and returns whether to
+/// amend it.
+fn amend_bump_commit(
+    update_args: &UpdateArgs,
+    session: &Session,
+    flake: &Flake<'_>,
+) -> Result<bool> {
+    if Vcs::Git.is_empty(&flake.directory)? {
+        return Ok(false);
+    }
+    let Some(message) = cmd_output("git", &["log", "-1", "--format=%B"], &flake.directory)? else {
+        return Ok(false);
+    };
+    let pattern = commit_message_pattern(&commit_message_template(update_args), flake.id)?;
+    if !pattern.is_match(message.trim_end()) || vcs::git_head_is_pushed(&flake.directory)? {
+        return Ok(false);
+    }
+    Ok(update_args.amend
+        || (!session.batch
+            && read_line(
+                format_args!(
+                    "The last commit is an unpushed bump of {}. Amend it instead of committing again? [y,N] ",
+                    flake.id
+                )
+                .prompt(),
+            )?
+            .trim()
+            .eq_ignore_ascii_case("y")))
+}
+
+/// Offers to stash the uncommitted changes in the repository besides `flake.nix` and
+/// `flake.lock` before committing, so that hooks only see the bump, and returns whether they were
+/// stashed.
+fn stash_other_changes(
+    update_args: &UpdateArgs,
+    session: &Session,
+    flake: &Flake<'_>,
+) -> Result<bool> {
+    if !vcs::git_has_other_changes(&flake.directory, flake.project)? {
+        return Ok(false);
+    }
+    let stash = update_args.stash
+        || (!session.batch
+            && read_line(
+                "The repository has other uncommitted changes. Stash them while committing? [y,N] "
+                    .prompt(),
+            )?
+            .trim()
+            .eq_ignore_ascii_case("y"));
+    if !stash {
+        return Ok(false);
+    }
+    let message = format!("{}: before bumping {}", env!("CARGO_PKG_NAME"), flake.id);
+    if vcs::git_stash_others(&flake.directory, flake.project, &message)? {
+        Ok(true)
+    } else {
+        eprintln!("{}", "Failed to stash, committing anyway".bad());
+        Ok(false)
+    }
+}
+
+/// Restores the changes stashed by [`stash_other_changes`].
+fn unstash_other_changes(flake: &Flake<'_>) -> Result<()> {
+    if !run_cmd("git", &["stash", "pop", "--index"], &flake.directory)? {
+        eprintln!(
+            "{} {}",
+            "Failed to restore the stashed changes. They're still in".bad(),
+            "git stash list".command()
+        );
+    }
+    Ok(())
+}
+
+/// Offers to push the current branch to its upstream.
+fn git_push(update_args: &UpdateArgs, session: &mut Session, flake: &Flake<'_>) -> Result<()> {
+    #[expect(
+        clippy::literal_string_with_formatting_args,
+        reason = "`@{upstream}` is Git's syntax"
+    )]
+    let upstream = sigint_guard::output(
+        Command::new("git")
+            .args([
+                "rev-parse",
+                "--abbrev-ref",
+                "--symbolic-full-name",
+                "@{upstream}",
+            ])
+            .current_dir(&flake.directory)
+            .stderr(Stdio::null()),
+    )?;
+    if !upstream.status.success() {
+        eprintln!("{}", "The branch has no upstream, not pushing".muted());
+        return Ok(());
+    }
+    let upstream = String::from_utf8(upstream.stdout)?;
+    let upstream = upstream.trim();
+
+    let answer = update_args.always_push
+        || remembered_answer(&mut session.push, session.batch, || {
+            read_line(format_args!(
+                "{} {} {} ",
+                "Push to".prompt(),
+                upstream.highlight(),
+                "[y,N,Y!,N!]".prompt()
+            ))
+        })?;
+    if answer {
+        if run_cmd("git", &["push"], &flake.directory)? {
+            eprintln!("{} {}", "Pushed to".good(), upstream.highlight());
+        } else {
+            eprintln!("{}", "Failed to push.".bad());
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether to commit and returns the raw answer.
+fn prompt_commit(vcs: Vcs, flake: &Flake<'_>, commit_msg: &str) -> Result<String> {
+    let is_empty = vcs.is_empty(&flake.directory)?;
     eprint!(
         "{} {} {} {} {} ",
-        "Commit".blue(),
-        "flake.nix".cyan().bold(),
-        "and".blue(),
-        "flake.lock".cyan().bold(),
-        "into Git?".blue()
+        "Commit".prompt(),
+        "flake.nix".highlight(),
+        "and".prompt(),
+        "flake.lock".highlight(),
+        format_args!("into {vcs}?").prompt()
     );
     if is_empty {
-        eprint!("{} ", "(No commits yet)".yellow());
+        eprint!("{} ", "(No commits yet)".warning());
     }
-    if stage_is_dirty {
-        eprint!("{} ", "(Stage is dirty)".yellow());
+
+    eprintln!();
+
+    read_line(format_args!(
+        "{} {} {} ",
+        "Commit message:".prompt(),
+        commit_msg.highlight(),
+        "[y,N,Y!,N!]".prompt(),
+    ))
+}
+
+/// Returns the arguments to `git` that commit the files of `project` with `commit_msg`, signing
+/// the commit when requested.
+///
+/// Both files must be known to Git, for example with `git add --intent-to-add`.
+pub fn git_commit_args(
+    update_args: &UpdateArgs,
+    project: ProjectKind,
+    commit_msg: &str,
+    amend: bool,
+) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(format) = update_args.signing_format {
+        args.push("-c".to_owned());
+        args.push(format!("gpg.format={format}"));
+    }
+    args.push("commit".to_owned());
+    if amend {
+        args.push("--amend".to_owned());
+    }
+    match update_args.sign_commits.as_deref() {
+        Some("") => args.push("-S".to_owned()),
+        Some(key_id) => args.push(format!("-S{key_id}")),
+        None => {}
     }
+    args.push("-m".to_owned());
+    args.push(commit_msg.to_owned());
+    // Commit only these paths so that unrelated staged changes stay staged
+    args.push("--".to_owned());
+    args.extend(project.files().map(str::to_owned));
+    args
+}
 
-    let commit_msg = format!("chore: bump flake input {}", flake.id);
-    eprint!(
-        "\n{} {} {} ",
-        "Commit message:".blue(),
-        commit_msg.cyan().bold(),
-        "[y,N]".blue(),
-    );
+/// The `gpg.format` to sign commits with.
+#[derive(Clone, Copy, Debug, clap::ValueEnum, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum SigningFormat {
+    Openpgp,
+    Ssh,
+    X509,
+}
 
-    let buf = read_line()?;
-    if buf.trim() == "y" {
-        if update_args.allow_write {
-            if run_cmd("git", &["add", "flake.nix", "flake.lock"], &flake.directory)? {
-                if !run_cmd("git", &["commit", "-m", &commit_msg], &flake.directory)? {
-                    eprintln!("{}", "Failed to commit.".red());
-                }
-            } else {
-                eprintln!("{}", "Failed to stage files.".red());
+const DEFAULT_COMMIT_TYPE: &str = "chore";
+
+const COMMIT_MESSAGE_PLACEHOLDERS: &[&str] = &["input", "old_rev", "new_rev", "old_ref", "new_ref"];
+
+/// Returns `--commit-message-template`, or the Conventional Commits template from
+/// `--commit-type` and `--commit-scope`.
+pub fn commit_message_template(update_args: &UpdateArgs) -> Cow<'_, str> {
+    update_args.commit_message_template.as_deref().map_or_else(
+        || {
+            Cow::Owned(conventional_template(
+                update_args
+                    .commit_type
+                    .as_deref()
+                    .unwrap_or(DEFAULT_COMMIT_TYPE),
+                update_args.commit_scope.as_deref(),
+            ))
+        },
+        Cow::Borrowed,
+    )
+}
+
+/// Returns the commit message template with the Conventional Commits `commit_type` and `scope`.
+fn conventional_template(commit_type: &str, scope: Option<&str>) -> String {
+    let scope = scope.map(|scope| format!("({scope})")).unwrap_or_default();
+    format!("{commit_type}{scope}: bump flake input {{input}}")
+}
+
+/// Returns a pattern matching the messages `template` renders for bumping `input_id`, whatever
+/// the revisions and refs are.
+fn commit_message_pattern(template: &str, input_id: &str) -> Result<regex::Regex> {
+    // `regex::escape` keeps NUL as is, so it marks where the other placeholders were
+    let rendered = render_template(template, |name| match name {
+        "input" => Some(input_id),
+        _ => COMMIT_MESSAGE_PLACEHOLDERS.contains(&name).then_some("\0"),
+    })?;
+    let pattern = regex::escape(&rendered).replace('\0', ".*");
+    Ok(regex::Regex::new(&format!("(?s)^{pattern}$"))?)
+}
+
+/// Checks that `template` only uses known placeholders.
+pub fn validate_commit_message_template(template: &str) -> Result<()> {
+    render_template(template, |name| {
+        COMMIT_MESSAGE_PLACEHOLDERS.contains(&name).then_some("")
+    })
+    .map(drop)
+    .wrap_err("Invalid commit message template")
+}
+
+/// Checks that the `--git-branch` template only uses `{input}`.
+pub fn validate_branch_template(template: &str) -> Result<()> {
+    render_template(template, |name| (name == "input").then_some(""))
+        .map(drop)
+        .wrap_err("Invalid branch name template")
+}
+
+/// Renders the branch name for bumping `input_id` from `--git-branch`.
+pub fn branch_name(update_args: &UpdateArgs, input_id: &str) -> Result<String> {
+    render_template(&update_args.git_branch, |name| {
+        (name == "input").then_some(input_id)
+    })
+}
+
+/// Replaces `{name}` placeholders in `template` with `value(name)`, failing on unknown names.
+///
+/// Braces that don't enclose a placeholder name are kept as is.
+fn render_template<'a>(template: &str, value: impl Fn(&str) -> Option<&'a str>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = rest[1..]
+            .find('}')
+            .map(|end| &rest[1..=end])
+            .filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        match name {
+            Some(name) if !name.is_empty() => {
+                let Some(value) = value(name) else {
+                    bail!("Unknown placeholder {{{name}}}");
+                };
+                rendered.push_str(value);
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                rendered.push('{');
+                rest = &rest[1..];
             }
-        } else {
-            eprintln!("{}", "Dry run, not modifying files".yellow());
         }
     }
-    Ok(())
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Returns the input as locked in the checked out commit's `flake.lock`, if any.
+pub fn head_lockfile_input(
+    directory: &Path,
+    project: ProjectKind,
+    input_id: &str,
+) -> Option<LockfileNode> {
+    let contents = Vcs::detect(directory)?.show_head(directory, project.lockfile())?;
+    parse_lockfile_input(&contents, input_id).ok()
+}
+
+/// Returns the locked input in the lock file of the parent of the checked out Git commit.
+fn parent_lockfile_input(
+    directory: &Path,
+    project: ProjectKind,
+    input_id: &str,
+) -> Option<LockfileNode> {
+    let output = sigint_guard::output(
+        Command::new("git")
+            .args(["show", &format!("HEAD^:./{}", project.lockfile())])
+            .current_dir(directory)
+            .stderr(Stdio::null()),
+    )
+    .ok()?;
+    let contents = output.status.success().then_some(output.stdout)?;
+    parse_lockfile_input(&contents, input_id).ok()
+}
+
+/// Renders the commit message for the flake in `directory` from `--commit-message-template`,
+/// taking the old values from `old`.
+pub fn commit_message(
+    update_args: &UpdateArgs,
+    directory: &Path,
+    project: ProjectKind,
+    input_id: &str,
+    old: Option<&LockfileNode>,
+) -> Result<String> {
+    let new = parse_lockfile_input(&fs::read(directory.join(project.lockfile()))?, input_id)?;
+    render_template(&commit_message_template(update_args), |name| {
+        Some(
+            match name {
+                "input" => Some(input_id),
+                "old_rev" => old.and_then(|old| old.locked.rev()),
+                "new_rev" => new.locked.rev(),
+                "old_ref" => old.and_then(|old| old.original.inner.ref_()),
+                "new_ref" => new.original.inner.ref_(),
+                _ => return None,
+            }
+            .unwrap_or_default(),
+        )
+    })
+}
+
+/// Prints the changed lines with `context` unchanged lines around them.
+pub fn print_diff(old_contents: &str, new_contents: &str, context: usize) {
+    let diff = diff::lines(old_contents, new_contents);
+    let diff = reduce_diff_context(&diff, context);
+    for line in diff {
+        match line {
+            diff::Result::Left(line) => println!("{}", format_args!("-{line}").bad()),
+            diff::Result::Both(line, _) => println!(" {line}"),
+            diff::Result::Right(line) => println!("{}", format_args!("+{line}").good()),
+        }
+    }
 }
 
-fn read_line() -> Result<String> {
-    stderr().flush()?;
-    let mut buf = String::new();
-    stdin().read_line(&mut buf)?;
-    Ok(buf)
+fn reduce_diff_context<T: PartialEq>(
+    input: &[diff::Result<T>],
+    context: usize,
+) -> Vec<&diff::Result<T>> {
+    let mut result = Vec::new();
+
+    let mut diff_indices = Vec::new();
+    for (idx, res) in input.iter().enumerate() {
+        if matches!(res, diff::Result::Left(_) | diff::Result::Right(_)) {
+            diff_indices.push(idx);
+        }
+    }
+
+    let len = input.len();
+
+    for &diff_idx in &diff_indices {
+        // Determine the range: `Both` lines before and after the diff
+        let start = diff_idx.saturating_sub(context);
+        let end = (diff_idx + context + 1).min(len);
+
+        for diff in input.iter().take(end).skip(start) {
+            // Avoid duplicates
+            if !result.contains(&diff) {
+                result.push(diff);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template_placeholders() {
+        let value = |name: &str| match name {
+            "input" => Some("nixpkgs"),
+            "old_rev" => Some(""),
+            _ => None,
+        };
+        assert_eq!(
+            render_template("bump {input} from {old_rev}", value).unwrap(),
+            "bump nixpkgs from "
+        );
+        assert_eq!(
+            render_template("{ {} {input-x} {input", value).unwrap(),
+            "{ {} {input-x} {input"
+        );
+        assert!(render_template("{new_rev}", value).is_err());
+    }
+
+    #[test]
+    fn commit_message_pattern_matches_rendered() {
+        let pattern =
+            commit_message_pattern("bump {input} ({old_rev}..{new_rev})", "nixpkgs").unwrap();
+        assert!(pattern.is_match("bump nixpkgs (abc..def)"));
+        assert!(pattern.is_match("bump nixpkgs (..def)"));
+        assert!(!pattern.is_match("bump home-manager (abc..def)"));
+        assert!(!pattern.is_match("bump nixpkgs (abc..def)\n\nfix"));
+        assert!(
+            commit_message_pattern(&conventional_template(DEFAULT_COMMIT_TYPE, None), "nixpkgs")
+                .unwrap()
+                .is_match("chore: bump flake input nixpkgs")
+        );
+    }
+
+    #[test]
+    fn default_template_is_valid() {
+        for name in COMMIT_MESSAGE_PLACEHOLDERS {
+            validate_commit_message_template(&format!("{{{name}}}")).unwrap();
+        }
+        validate_commit_message_template(&conventional_template(DEFAULT_COMMIT_TYPE, None))
+            .unwrap();
+    }
+
+    #[test]
+    fn direnv_profile_links() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join(".direnv")).unwrap();
+        fs::write(root.join(".direnv/flake-profile.rc"), "").unwrap();
+        std::os::unix::fs::symlink("/nix/store/abc-env", root.join(".direnv/flake-profile"))
+            .unwrap();
+
+        let links = direnv_links(root);
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].1, Path::new("/nix/store/abc-env"));
+        assert!(direnv_links(&root.join("missing")).is_empty());
+    }
+
+    #[test]
+    fn conventional_templates() {
+        assert_eq!(
+            conventional_template("chore", None),
+            "chore: bump flake input {input}"
+        );
+        assert_eq!(
+            conventional_template("build", Some("deps")),
+            "build(deps): bump flake input {input}"
+        );
+    }
 }
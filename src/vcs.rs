@@ -0,0 +1,242 @@
+//! Committing `flake.nix` and `flake.lock` with the version control system of the flake.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use color_eyre::Result;
+use fs_err as fs;
+
+use nixpkgsupd::{
+    command::{cmd_output, run_cmd},
+    discovery::ProjectKind,
+    sigint_guard,
+};
+
+use crate::{UpdateArgs, ui::Themed, update::git_commit_args};
+
+/// Returns the Git directory of the working tree at `toplevel`.
+///
+/// `.git` is a directory in a normal checkout, and a file pointing to the actual Git directory
+/// with `gitdir: <path>` in linked worktrees and submodules.
+fn git_dir(toplevel: &Path) -> Option<PathBuf> {
+    let dot_git = toplevel.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let contents = fs::read_to_string(&dot_git).ok()?;
+    let git_dir = toplevel.join(contents.strip_prefix("gitdir:")?.trim());
+    git_dir.is_dir().then_some(git_dir)
+}
+
+/// What kind of Git working tree a directory is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum GitCheckout {
+    Main,
+    /// Created with `git worktree add`.
+    Worktree,
+    /// The working tree of a submodule, whose commits must also be recorded in the superproject.
+    Submodule,
+}
+
+/// Returns the kind of the innermost Git working tree containing `directory`, if any.
+pub fn git_checkout(directory: &Path) -> Option<GitCheckout> {
+    let git_dir = directory.ancestors().find_map(git_dir)?;
+    Some(if git_dir.join("commondir").is_file() {
+        GitCheckout::Worktree
+    } else if git_dir.ancestors().any(|dir| dir.ends_with(".git/modules")) {
+        GitCheckout::Submodule
+    } else {
+        GitCheckout::Main
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+pub enum Vcs {
+    Git,
+    Mercurial,
+    Sapling,
+}
+
+impl Vcs {
+    /// Detects the version control system of the innermost repository containing `directory`.
+    pub fn detect(directory: &Path) -> Option<Self> {
+        directory.ancestors().find_map(|path| {
+            if git_dir(path).is_some() {
+                Some(Self::Git)
+            } else if path.join(".hg").is_dir() {
+                Some(Self::Mercurial)
+            } else if path.join(".sl").is_dir() {
+                Some(Self::Sapling)
+            } else {
+                None
+            }
+        })
+    }
+
+    const fn program(self) -> &'static str {
+        match self {
+            Self::Git => "git",
+            Self::Mercurial => "hg",
+            Self::Sapling => "sl",
+        }
+    }
+
+    /// Returns the contents of `file` in the checked out commit, if it's tracked there.
+    pub fn show_head(self, directory: &Path, file: &str) -> Option<Vec<u8>> {
+        let mut command = Command::new(self.program());
+        match self {
+            Self::Git => command.args(["show", &format!("HEAD:./{file}")]),
+            Self::Mercurial | Self::Sapling => command.args(["cat", "--rev", ".", file]),
+        };
+        let output =
+            sigint_guard::output(command.current_dir(directory).stderr(Stdio::null())).ok()?;
+        output.status.success().then_some(output.stdout)
+    }
+
+    /// Returns whether the repository has no commits yet. Only detected for Git.
+    pub fn is_empty(self, directory: &Path) -> Result<bool> {
+        Ok(self == Self::Git && !run_cmd("git", &["log", "-0"], directory)?)
+    }
+
+    /// Commits the manifest and lock file of `project` in `directory` and returns whether it
+    /// succeeded.
+    pub fn commit(
+        self,
+        update_args: &UpdateArgs,
+        directory: &Path,
+        project: ProjectKind,
+        message: &str,
+        amend: bool,
+    ) -> Result<bool> {
+        let files = project.files();
+        if self == Self::Git {
+            // A new lock file must be known to Git to commit it by path
+            if !run_cmd(
+                "git",
+                &["add", "--intent-to-add", files[0], files[1]],
+                directory,
+            )? {
+                eprintln!("{}", "Failed to track files.".bad());
+                return Ok(false);
+            }
+            let args = git_commit_args(update_args, project, message, amend);
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            return run_cmd("git", &args, directory);
+        }
+
+        if update_args.sign_commits.is_some() {
+            eprintln!(
+                "{}",
+                format_args!("Signing commits isn't supported with {self}, not signing").warning()
+            );
+        }
+        // Only the given files are committed, and `--addremove` tracks `flake.lock` if it's new
+        run_cmd(
+            self.program(),
+            &["commit", "--addremove", "-m", message, files[0], files[1]],
+            directory,
+        )
+    }
+}
+
+/// Returns the pathspecs matching the whole Git repository containing `directory` except the
+/// files of `project`, when run in `directory`.
+fn git_other_pathspecs(project: ProjectKind) -> Vec<String> {
+    std::iter::once(":/".to_owned())
+        .chain(project.files().map(|file| format!(":(exclude){file}")))
+        .collect()
+}
+
+/// Returns whether the Git repository containing `directory` has uncommitted changes, including
+/// untracked files, besides the files of `project`.
+pub fn git_has_other_changes(directory: &Path, project: ProjectKind) -> Result<bool> {
+    let output = sigint_guard::output(
+        Command::new("git")
+            .args(["status", "--porcelain", "--"])
+            .args(git_other_pathspecs(project))
+            .current_dir(directory)
+            .stderr(Stdio::null()),
+    )?;
+    Ok(output.status.success() && !output.stdout.is_empty())
+}
+
+/// Stashes the uncommitted changes besides the files of `project`, including untracked files,
+/// and returns whether it succeeded.
+pub fn git_stash_others(directory: &Path, project: ProjectKind, message: &str) -> Result<bool> {
+    let mut args = vec![
+        "stash".to_owned(),
+        "push".to_owned(),
+        "--include-untracked".to_owned(),
+        "-m".to_owned(),
+        message.to_owned(),
+        "--".to_owned(),
+    ];
+    args.extend(git_other_pathspecs(project));
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    run_cmd("git", &args, directory)
+}
+
+/// Returns whether the checked out commit is on a remote-tracking branch, so it was pushed. It's
+/// assumed to be if that can't be determined.
+pub fn git_head_is_pushed(directory: &Path) -> Result<bool> {
+    Ok(cmd_output(
+        "git",
+        &["branch", "--remotes", "--contains", "HEAD"],
+        directory,
+    )?
+    .is_none_or(|branches| !branches.trim().is_empty()))
+}
+
+/// Returns whether the local branch `branch` exists.
+pub fn git_branch_exists(directory: &Path, branch: &str) -> Result<bool> {
+    run_cmd(
+        "git",
+        &[
+            "show-ref",
+            "--verify",
+            "--quiet",
+            &format!("refs/heads/{branch}"),
+        ],
+        directory,
+    )
+}
+
+/// Returns whether `directory` is in a Git repository.
+pub fn in_git_repo(directory: &Path) -> bool {
+    Vcs::detect(directory) == Some(Vcs::Git)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_checkout_kinds() {
+        let root = tempfile::tempdir().unwrap();
+        let main = root.path().join("main");
+        let linked = root.path().join("linked");
+        let submodule = main.join("sub");
+        fs::create_dir_all(main.join(".git/worktrees/linked")).unwrap();
+        fs::create_dir_all(main.join(".git/modules/sub")).unwrap();
+        fs::create_dir_all(&linked).unwrap();
+        fs::create_dir_all(submodule.join("flake")).unwrap();
+        fs::write(main.join(".git/worktrees/linked/commondir"), "../..").unwrap();
+        fs::write(
+            linked.join(".git"),
+            "gitdir: ../main/.git/worktrees/linked\n",
+        )
+        .unwrap();
+        fs::write(submodule.join(".git"), "gitdir: ../.git/modules/sub\n").unwrap();
+
+        assert_eq!(git_checkout(&main), Some(GitCheckout::Main));
+        assert_eq!(git_checkout(&linked), Some(GitCheckout::Worktree));
+        assert_eq!(
+            git_checkout(&submodule.join("flake")),
+            Some(GitCheckout::Submodule)
+        );
+        assert_eq!(Vcs::detect(&linked), Some(Vcs::Git));
+    }
+}
@@ -0,0 +1,179 @@
+//! Updating flakes in a temporary Git worktree, leaving the user's checkout untouched until the
+//! result is merged.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+use fs_err as fs;
+
+use nixpkgsupd::{
+    command::{cmd_output, run_cmd},
+    discovery::Flake,
+};
+
+use crate::{
+    UpdateArgs,
+    prompt::read_line,
+    ui::Themed,
+    update::{branch_name, commit_message, git_commit_args, head_lockfile_input, lock},
+    vcs::git_branch_exists,
+};
+
+/// Configuration that may resolve differently in the temporary worktree, for example because of
+/// `includeIf` conditions or relative paths.
+const INHERITED_CONFIG: &[&str] = &[
+    "user.name",
+    "user.email",
+    "user.signingKey",
+    "commit.gpgSign",
+    "gpg.format",
+    "core.hooksPath",
+];
+
+/// Returns `-c` arguments that make Git in the worktree behave like in the checkout.
+fn inherited_config(toplevel: &Path) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    for key in INHERITED_CONFIG {
+        let Some(value) = cmd_output("git", &["config", "--type=path", "--get", key], toplevel)?
+        else {
+            continue;
+        };
+        let mut value = value.trim_end_matches('\n').to_owned();
+        // Relative hook paths are relative to the root of the working tree the hook runs in
+        if *key == "core.hooksPath" && Path::new(&value).is_relative() {
+            value = toplevel.join(value).display().to_string();
+        }
+        args.push("-c".to_owned());
+        args.push(format!("{key}={value}"));
+    }
+    Ok(args)
+}
+
+/// A `git worktree` that is removed on drop.
+struct Worktree {
+    repo: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    /// Adds a worktree on the new branch `branch`. An existing branch is never reset, since it
+    /// may have commits from an earlier run that weren't merged yet.
+    fn add(repo: &Path, branch: &str) -> Result<Self> {
+        if git_branch_exists(repo, branch)? {
+            bail!(
+                "The branch {branch} already exists. Merge or delete it, or choose another name with --git-branch."
+            );
+        }
+        let path = std::env::temp_dir().join(format!(
+            "{}-worktree-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let path_str = path
+            .to_str()
+            .ok_or_eyre("Temporary directory is not UTF-8")?;
+        if !run_cmd(
+            "git",
+            &["worktree", "add", "-b", branch, path_str, "HEAD"],
+            repo,
+        )? {
+            bail!("Failed to create a worktree");
+        }
+        Ok(Self {
+            repo: repo.to_owned(),
+            path,
+        })
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.to_str() {
+            let _ = run_cmd("git", &["worktree", "remove", "--force", path], &self.repo);
+        }
+    }
+}
+
+/// Writes `new_flake_nix`, locks and commits in a temporary worktree on a separate branch.
+///
+/// Returns whether the commit was merged into the checkout. With `--non-interactive` the branch is
+/// always left unmerged.
+pub fn update_in_worktree(
+    flake: &Flake,
+    new_flake_nix: &str,
+    update_args: &UpdateArgs,
+) -> Result<bool> {
+    let toplevel = cmd_output("git", &["rev-parse", "--show-toplevel"], &flake.directory)?
+        .ok_or_eyre("Failed to find the root of the Git repository")?;
+    let toplevel = fs::canonicalize(toplevel.trim())?;
+    let subdir = fs::canonicalize(&flake.directory)?
+        .strip_prefix(&toplevel)
+        .wrap_err("Flake is outside of its Git repository")?
+        .to_owned();
+
+    let config = inherited_config(&toplevel)?;
+    let branch = branch_name(update_args, flake.id)?;
+    let worktree = Worktree::add(&toplevel, &branch)?;
+    let directory = worktree.path.join(subdir);
+
+    let files = flake.project.files();
+    fs::write(directory.join(flake.project.manifest()), new_flake_nix)?;
+    if !lock(flake.project, &directory, flake.id, None)? {
+        bail!("Failed to lock the flake in the worktree");
+    }
+    let old = head_lockfile_input(&directory, flake.project, flake.id);
+    let commit_msg = commit_message(
+        update_args,
+        &directory,
+        flake.project,
+        flake.id,
+        old.as_ref(),
+    )?;
+    let commit_args = [
+        config,
+        git_commit_args(update_args, flake.project, &commit_msg, false),
+    ]
+    .concat();
+    let commit_args = commit_args.iter().map(String::as_str).collect::<Vec<_>>();
+    if !run_cmd("git", &["add", files[0], files[1]], &directory)?
+        || !run_cmd("git", &commit_args, &directory)?
+    {
+        bail!("Failed to commit in the worktree");
+    }
+    drop(worktree);
+
+    if update_args.non_interactive {
+        eprintln!(
+            "{} {}",
+            "Committed to branch, leaving it unmerged:".warning(),
+            branch.highlight()
+        );
+        return Ok(false);
+    }
+
+    let answer = read_line(format_args!(
+        "{} {} {} ",
+        "Committed to branch".prompt(),
+        branch.highlight(),
+        "Merge it into the checkout? [y,N]".prompt()
+    ))?;
+    if answer.trim() != "y" {
+        eprintln!("{}", "Left the branch unmerged".warning());
+        return Ok(false);
+    }
+
+    if !run_cmd("git", &["merge", "--ff-only", &branch], &toplevel)? {
+        eprintln!(
+            "{} {}",
+            "Failed to fast-forward. Merge it manually:".bad(),
+            branch.highlight()
+        );
+        return Ok(false);
+    }
+    run_cmd("git", &["branch", "-d", &branch], &toplevel)?;
+
+    Ok(true)
+}